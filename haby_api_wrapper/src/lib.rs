@@ -1,5 +1,18 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
 pub use haby_core as core;
+use haby_core::api::{ErrorBody, ServerInfo, API_VERSION, IDEMPOTENCY_KEY, REQUEST_ID};
 pub use haby_core::VERSION;
+use reqwest::Method;
+
+mod live;
+mod retry;
+
+pub use retry::RetryPolicy;
 
 #[cfg(not(debug_assertions))]
 const HOST: &str = "https://haby.vivax.dev/api";
@@ -7,79 +20,654 @@ const HOST: &str = "https://haby.vivax.dev/api";
 #[cfg(debug_assertions)]
 const HOST: &str = "http://localhost:8000";
 
-#[derive(Default)]
+/// A new id for the `X-Request-Id` header, the server logs it with everything the request does
+fn request_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// Why a request failed
+#[derive(Debug)]
+pub enum Error {
+    /// The server does not serve the `API_VERSION` this client was built for
+    Incompatible {
+        /// The `core` version of the server, `None` for servers from before API versions
+        server: Option<String>,
+        supported: Vec<u32>,
+    },
+    Request(reqwest::Error),
+    /// No response arrived in time, not even after retrying
+    Timeout(Duration),
+    /// The server answered with an error, even after retrying
+    Status {
+        status: reqwest::StatusCode,
+        /// The message of the answer, JSON `ValidationErrors` for invalid input
+        message: String,
+        /// The `X-Request-Id` the server logged the request under, for bug reports
+        request_id: Option<String>,
+    },
+    /// The answer was not what the route promises
+    Decode(serde_json::Error),
+    /// The API token has control characters like newlines, which can't be sent in a header
+    InvalidToken,
+    /// `record_events` failed after the server already recorded some of the batches
+    Partial {
+        /// The results of the events before the batch that failed, in order
+        recorded: Vec<haby_core::api::BatchItem>,
+        error: Box<Error>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Incompatible {
+                server: Some(server),
+                supported,
+            } => write!(
+                f,
+                "The server (version {server}) supports API versions {supported:?}, but this \
+                client (version {VERSION}) needs version {API_VERSION}"
+            ),
+            Error::Incompatible { server: None, .. } => write!(
+                f,
+                "The server is too old for this client (version {VERSION}), it needs API version \
+                {API_VERSION}"
+            ),
+            Error::Request(err) => write!(f, "The server could not be reached: {err}"),
+            Error::Timeout(timeout) => write!(f, "The server did not answer within {timeout:?}"),
+            // Just the message, so `ValidationErrors` can be parsed back out of it
+            Error::Status { message, .. } => write!(f, "{message}"),
+            Error::Decode(err) => write!(f, "The server sent an unexpected answer: {err}"),
+            Error::InvalidToken => write!(f, "The API token has characters that can't be sent"),
+            Error::Partial { recorded, error } => write!(
+                f,
+                "Only the first {} events were recorded: {error}",
+                recorded.len()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Request(err)
+    }
+}
+
+/// For callers that only show errors, server errors stay the text the server answered with
+impl From<Error> for String {
+    fn from(err: Error) -> Self {
+        err.to_string()
+    }
+}
+
 pub struct ApiWrapper {
     client: reqwest::Client,
+    host: String,
+    /// Set once the server was found to support `API_VERSION`
+    compatible: OnceLock<ServerInfo>,
+    /// How long to wait for the response headers, bodies like `/live` may take as long as they want
+    timeout: Duration,
+    retry: RetryPolicy,
+    /// The `Authorization` header of every request
+    token: Option<reqwest::header::HeaderValue>,
+}
+
+/// A request under construction, `send` it to have it retried according to the `RetryPolicy`
+struct Request<'a> {
+    wrapper: &'a ApiWrapper,
+    builder: reqwest::RequestBuilder,
+}
+
+impl Request<'_> {
+    fn json<T: serde::Serialize + ?Sized>(self, json: &T) -> Self {
+        Self {
+            builder: self.builder.json(json),
+            ..self
+        }
+    }
+
+    fn query<T: serde::Serialize + ?Sized>(self, query: &T) -> Self {
+        Self {
+            builder: self.builder.query(query),
+            ..self
+        }
+    }
+
+    fn body(self, body: impl Into<reqwest::Body>) -> Self {
+        Self {
+            builder: self.builder.body(body),
+            ..self
+        }
+    }
+
+    async fn send(self) -> Result<reqwest::Response, Error> {
+        self.wrapper.execute(self.builder.build()?).await
+    }
+
+    /// `send`, with answers other than 2xx turned into `Error::Status`
+    async fn fetch(self) -> Result<reqwest::Response, Error> {
+        error_for_status(self.send().await?).await
+    }
+}
+
+async fn error_for_status(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let request_id = response
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .map(String::from);
+    let body = response.text().await?;
+    // Everything but `ValidationErrors` comes as an `ErrorBody`, which only the message is kept of
+    let message = match serde_json::from_str::<ErrorBody>(&body) {
+        Ok(error) => error.message,
+        Err(_) => body,
+    };
+    Err(Error::Status {
+        status,
+        message,
+        request_id,
+    })
+}
+
+/// Read a JSON body, a body that does not parse is an `Error::Decode` rather than `Error::Request`
+async fn json<T: serde::de::DeserializeOwned>(response: reqwest::Response) -> Result<T, Error> {
+    let body = response.text().await?;
+    serde_json::from_str(&body).map_err(Error::Decode)
+}
+
+impl Default for ApiWrapper {
+    fn default() -> Self {
+        Self::new(HOST)
+    }
 }
 
 impl ApiWrapper {
-    pub async fn clear_db(&self) {
-        self.client
-            .post(format!("{HOST}/test/clear"))
-            .send()
-            .await
-            .unwrap();
+    /// Talk to the server at `host`, for example `http://localhost:8000`
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::default(),
+            host: host.into().trim_end_matches('/').to_owned(),
+            compatible: OnceLock::new(),
+            timeout: Duration::from_secs(30),
+            retry: RetryPolicy::default(),
+            token: None,
+        }
     }
 
-    pub async fn get_version(&self) -> String {
-        let response = self
+    /// Give up on an attempt when the server takes longer than `timeout` to answer
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retry failed requests according to `policy`, use `RetryPolicy::none()` to never retry
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Where the routes of `API_VERSION` live
+    fn base(&self) -> String {
+        format!("{}/api/v{API_VERSION}", self.host)
+    }
+
+    /// A request to `path` under the API base, checking the server is compatible the first time
+    ///
+    /// Writes get a fresh `Idempotency-Key`, it stays the same when the request is cloned for a
+    /// retry so the server only handles it once. The token of `with_token` is added here, so the
+    /// client keeps its own configuration.
+    async fn request(&self, method: Method, path: &str) -> Result<Request<'_>, Error> {
+        self.check_compatibility().await?;
+        let keyed = matches!(method, Method::POST | Method::PUT | Method::PATCH);
+        // Retries send the same id, so all their attempts can be found in the server logs
+        let mut builder = self
             .client
-            .get(format!("{HOST}/version"))
-            .send()
-            .await
-            .unwrap();
-        response.text().await.unwrap()
+            .request(method, format!("{}{path}", self.base()))
+            .header(REQUEST_ID, request_id());
+        if keyed {
+            builder = builder.header(IDEMPOTENCY_KEY, uuid::Uuid::new_v4().to_string());
+        }
+        if let Some(token) = &self.token {
+            builder = builder.header(reqwest::header::AUTHORIZATION, token.clone());
+        }
+        Ok(Request {
+            wrapper: self,
+            builder,
+        })
     }
 
-    pub async fn get_habits(&self) -> Vec<haby_core::Habit> {
-        let response = self
+    /// Send `request`, retrying it while that is safe and the failure looks temporary
+    ///
+    /// After the last attempt the failed response is returned as is, so callers still see the
+    /// status and message of the server.
+    async fn execute(&self, request: reqwest::Request) -> Result<reqwest::Response, Error> {
+        let mut retry = 0;
+        while retry::retryable(&request) && retry + 1 < self.retry.attempts {
+            // Bodies that can't be cloned, like streams, only get one try
+            let Some(attempt) = request.try_clone() else {
+                break;
+            };
+            let wait = match retry::timeout(self.timeout, self.client.execute(attempt)).await {
+                Some(Ok(response)) if retry::transient(response.status()) => {
+                    match retry::retry_after(&response) {
+                        Some(wait) if wait > self.retry.max_delay => return Ok(response),
+                        Some(wait) => wait,
+                        None => self.retry.backoff(retry),
+                    }
+                }
+                Some(Ok(response)) => return Ok(response),
+                Some(Err(err)) if retry::transient_error(&err) => self.retry.backoff(retry),
+                Some(Err(err)) => return Err(err.into()),
+                None => self.retry.backoff(retry),
+            };
+            retry::sleep(wait).await;
+            retry += 1;
+        }
+
+        match retry::timeout(self.timeout, self.client.execute(request)).await {
+            Some(response) => Ok(response?),
+            None => Err(Error::Timeout(self.timeout)),
+        }
+    }
+
+    /// The versions of the server, this works with any server version
+    pub async fn server_info(&self) -> Result<ServerInfo, Error> {
+        let request = self
             .client
-            .get(format!("{HOST}/habits"))
-            .send()
-            .await
-            .unwrap();
-        response.json().await.unwrap()
+            .get(format!("{}/api", self.host))
+            .header(REQUEST_ID, request_id())
+            .build()?;
+        let response = self.execute(request).await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(Error::Incompatible {
+                server: None,
+                supported: Vec::new(),
+            });
+        }
+        json(error_for_status(response).await?).await
+    }
+
+    /// Make sure the server serves the API version this client speaks
+    ///
+    /// Every other method does this before its first request, so there is no need to call it,
+    /// but it is a good way to fail early with a clear message.
+    pub async fn check_compatibility(&self) -> Result<&ServerInfo, Error> {
+        if let Some(info) = self.compatible.get() {
+            return Ok(info);
+        }
+
+        let info = self.server_info().await?;
+        if !info.api_versions.contains(&API_VERSION) {
+            return Err(Error::Incompatible {
+                server: Some(info.version),
+                supported: info.api_versions,
+            });
+        }
+        Ok(self.compatible.get_or_init(|| info))
+    }
+
+    /// Send `token` with every request, for servers that require API tokens
+    pub fn with_token(mut self, token: &str) -> Result<Self, Error> {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {token}"))
+            .map_err(|_| Error::InvalidToken)?;
+        value.set_sensitive(true);
+        self.token = Some(value);
+        Ok(self)
+    }
+
+    pub async fn clear_db(&self) -> Result<(), Error> {
+        self.request(Method::POST, "/test/clear")
+            .await?
+            .fetch()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_version(&self) -> Result<String, Error> {
+        let response = self.request(Method::GET, "/version").await?.fetch().await?;
+        Ok(response.text().await?)
+    }
+
+    pub async fn get_habits(&self) -> Result<Vec<haby_core::Habit>, Error> {
+        let response = self.request(Method::GET, "/habits").await?.fetch().await?;
+        json(response).await
     }
 
     pub async fn create_habit(
         &self,
         habit: haby_core::api::CreateHabit,
-    ) -> Result<haby_core::Habit, String> {
+    ) -> Result<haby_core::Habit, Error> {
+        // The server stores the name trimmed, so the returned habit should have it trimmed too
+        let habit = habit.normalized();
         let response = self
-            .client
-            .post(format!("{HOST}/habits"))
+            .request(Method::POST, "/habits")
+            .await?
             .json(&habit)
-            .send()
-            .await
-            .unwrap();
+            .fetch()
+            .await?;
+        Ok(habit.with_id(json(response).await?))
+    }
 
-        let success = response.status().is_success();
-        let text = response.text().await.unwrap();
+    pub async fn update_habit(&self, habit: &haby_core::Habit) -> Result<(), Error> {
+        self.request(Method::PUT, &format!("/habit/{}", habit.id))
+            .await?
+            .json(&habit.as_create())
+            .fetch()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_habit(&self, id: i32) -> Result<(), Error> {
+        self.request(Method::DELETE, &format!("/habit/{id}"))
+            .await?
+            .fetch()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn record_event(
+        &self,
+        event: haby_core::api::CreateEvent,
+    ) -> Result<haby_core::Event, Error> {
+        let response = self
+            .request(Method::POST, "/events")
+            .await?
+            .json(&event)
+            .fetch()
+            .await?;
+        Ok(event.with_id(json(response).await?))
+    }
 
-        if !success {
-            return Err(text);
+    /// Record many events in one request, see `haby_core::api::BatchEvent` for safe retries
+    ///
+    /// Larger lists than `MAX_BATCH` are sent in several requests, the results keep their order.
+    /// When one of them fails after others went through, the error is an `Error::Partial` with
+    /// the results so far.
+    pub async fn record_events(
+        &self,
+        events: &[haby_core::api::BatchEvent],
+    ) -> Result<Vec<haby_core::api::BatchItem>, Error> {
+        let mut results = Vec::with_capacity(events.len());
+        for batch in events.chunks(haby_core::api::MAX_BATCH) {
+            let sent = async {
+                let response = self
+                    .request(Method::POST, "/events/batch")
+                    .await?
+                    .json(batch)
+                    .fetch()
+                    .await?;
+                json::<Vec<haby_core::api::BatchItem>>(response).await
+            }
+            .await;
+            match sent {
+                Ok(items) => results.extend(items),
+                Err(error) if results.is_empty() => return Err(error),
+                Err(error) => {
+                    return Err(Error::Partial {
+                        recorded: results,
+                        error: Box::new(error),
+                    })
+                }
+            }
         }
+        Ok(results)
+    }
+
+    /// Pull every change since the replica was last synced and apply it
+    pub async fn sync(&self, replica: &mut haby_core::sync::Replica) -> Result<(), Error> {
+        let response = self
+            .request(Method::GET, "/sync")
+            .await?
+            .query(&[("since", replica.cursor)])
+            .fetch()
+            .await?;
+        replica.apply(json(response).await?);
+        Ok(())
+    }
+
+    /// Subscribe to every habit and event change as it happens
+    ///
+    /// The stream ends when the connection to the server is lost.
+    pub async fn subscribe(&self) -> Result<impl Stream<Item = haby_core::api::LiveUpdate>, Error> {
+        let response = self.request(Method::GET, "/live").await?.fetch().await?;
+
+        let state = (
+            Box::pin(response.bytes_stream()),
+            live::EventParser::default(),
+            VecDeque::<String>::new(),
+        );
+        Ok(futures::stream::unfold(
+            state,
+            |(mut chunks, mut parser, mut pending)| async move {
+                loop {
+                    if let Some(data) = pending.pop_front() {
+                        if let Ok(update) =
+                            serde_json::from_str::<haby_core::api::LiveUpdate>(&data)
+                        {
+                            return Some((update, (chunks, parser, pending)));
+                        }
+                        continue;
+                    }
+
+                    let chunk = chunks.next().await?.ok()?;
+                    pending.extend(parser.feed(&chunk));
+                }
+            },
+        ))
+    }
 
-        let id = text.parse().unwrap();
-        Ok(habit.with_id(id))
+    /// Where to download an export from, useful for plain links in the browser
+    pub fn export_url(&self, format: haby_core::api::ExportFormat) -> String {
+        format!("{}/export?format={}", self.base(), format.as_str())
     }
 
-    pub async fn update_habit(&self, habit: &haby_core::Habit) -> Result<(), String> {
+    /// Download every habit and event in the given format
+    pub async fn export(&self, format: haby_core::api::ExportFormat) -> Result<String, Error> {
         let response = self
-            .client
-            .put(format!("{HOST}/habit/{}", habit.id))
-            .json(&habit.as_create())
-            .send()
-            .await
-            .unwrap();
+            .request(Method::GET, "/export")
+            .await?
+            .query(&[("format", format.as_str())])
+            .fetch()
+            .await?;
+        Ok(response.text().await?)
+    }
+
+    /// Import a JSON export, see `haby_core::api::ConflictPolicy` for how existing habits are handled
+    pub async fn import(
+        &self,
+        export: &haby_core::api::Export,
+        options: haby_core::api::ImportOptions,
+    ) -> Result<haby_core::api::ImportReport, Error> {
+        let response = self
+            .request(Method::POST, "/import")
+            .await?
+            .query(&options)
+            .json(export)
+            .fetch()
+            .await?;
+        json(response).await
+    }
+
+    /// Import the `Checkmarks.csv` file from a Loop Habit Tracker export
+    pub async fn import_loop(
+        &self,
+        checkmarks: String,
+        options: haby_core::api::ImportOptions,
+    ) -> Result<haby_core::api::ImportReport, Error> {
+        let response = self
+            .request(Method::POST, "/import/loop")
+            .await?
+            .query(&options)
+            .body(checkmarks)
+            .fetch()
+            .await?;
+        json(response).await
+    }
+
+    /// Create a secret token for subscribing to the calendar feed
+    pub async fn create_calendar_token(&self) -> Result<String, Error> {
+        let response = self
+            .request(Method::POST, "/calendar/tokens")
+            .await?
+            .fetch()
+            .await?;
+        Ok(response.text().await?)
+    }
+
+    /// The url calendar apps should subscribe to
+    pub fn calendar_url(&self, token: &str) -> String {
+        format!("{}/calendar.ics?token={token}", self.base())
+    }
+
+    pub async fn get_reminder_settings(
+        &self,
+        habit_id: i32,
+    ) -> Result<haby_core::api::ReminderSettings, Error> {
+        let response = self
+            .request(Method::GET, &format!("/habit/{habit_id}/reminders"))
+            .await?
+            .fetch()
+            .await?;
+        json(response).await
+    }
+
+    pub async fn set_reminder_settings(
+        &self,
+        habit_id: i32,
+        settings: &haby_core::api::ReminderSettings,
+    ) -> Result<(), Error> {
+        self.request(Method::PUT, &format!("/habit/{habit_id}/reminders"))
+            .await?
+            .json(settings)
+            .fetch()
+            .await?;
+        Ok(())
+    }
+
+    /// Hold off reminders for a habit, returns when they start again
+    pub async fn snooze(
+        &self,
+        habit_id: i32,
+        minutes: u32,
+    ) -> Result<chrono::NaiveDateTime, Error> {
+        let response = self
+            .request(Method::POST, &format!("/habit/{habit_id}/snooze"))
+            .await?
+            .query(&[("minutes", minutes)])
+            .fetch()
+            .await?;
+        json(response).await
+    }
 
-        let success = response.status().is_success();
+    /// The key to subscribe to push reminders with, `None` when the server has push turned off
+    pub async fn push_key(&self) -> Result<Option<String>, Error> {
+        let response = self.request(Method::GET, "/push/key").await?.send().await?;
 
-        if !success {
-            let text = response.text().await.unwrap();
-            return Err(text);
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
         }
+        let response = error_for_status(response).await?;
+        Ok(Some(response.text().await?))
+    }
+
+    pub async fn add_push_subscription(
+        &self,
+        subscription: &haby_core::api::PushSubscription,
+    ) -> Result<(), Error> {
+        self.request(Method::POST, "/push/subscriptions")
+            .await?
+            .json(subscription)
+            .fetch()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_webhooks(&self) -> Result<Vec<haby_core::api::Webhook>, Error> {
+        let response = self
+            .request(Method::GET, "/webhooks")
+            .await?
+            .fetch()
+            .await?;
+        json(response).await
+    }
+
+    /// Subscribe a url to changes, the returned secret is what payloads are signed with
+    pub async fn create_webhook(
+        &self,
+        webhook: &haby_core::api::CreateWebhook,
+    ) -> Result<haby_core::api::NewWebhook, Error> {
+        let response = self
+            .request(Method::POST, "/webhooks")
+            .await?
+            .json(webhook)
+            .fetch()
+            .await?;
+        json(response).await
+    }
+
+    pub async fn delete_webhook(&self, id: i32) -> Result<(), Error> {
+        self.request(Method::DELETE, &format!("/webhooks/{id}"))
+            .await?
+            .fetch()
+            .await?;
+        Ok(())
+    }
+
+    /// The latest deliveries to a webhook, newest first
+    pub async fn webhook_deliveries(
+        &self,
+        id: i32,
+    ) -> Result<Vec<haby_core::api::WebhookDelivery>, Error> {
+        let response = self
+            .request(Method::GET, &format!("/webhooks/{id}/deliveries"))
+            .await?
+            .fetch()
+            .await?;
+        json(response).await
+    }
+
+    /// Send a `ping` to the webhook right away
+    pub async fn test_webhook(&self, id: i32) -> Result<haby_core::api::WebhookDelivery, Error> {
+        let response = self
+            .request(Method::POST, &format!("/webhooks/{id}/test"))
+            .await?
+            .fetch()
+            .await?;
+        json(response).await
+    }
+
+    pub async fn get_tokens(&self) -> Result<Vec<haby_core::api::ApiToken>, Error> {
+        let response = self.request(Method::GET, "/tokens").await?.fetch().await?;
+        json(response).await
+    }
+
+    /// Create a new API token, its secret is only returned this once
+    pub async fn create_token(
+        &self,
+        token: &haby_core::api::CreateApiToken,
+    ) -> Result<haby_core::api::NewApiToken, Error> {
+        let response = self
+            .request(Method::POST, "/tokens")
+            .await?
+            .json(token)
+            .fetch()
+            .await?;
+        json(response).await
+    }
 
+    pub async fn revoke_token(&self, id: i32) -> Result<(), Error> {
+        self.request(Method::DELETE, &format!("/tokens/{id}"))
+            .await?
+            .fetch()
+            .await?;
         Ok(())
     }
 }
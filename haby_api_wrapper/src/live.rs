@@ -0,0 +1,54 @@
+/// Incrementally parses a `text/event-stream` body into the data of each event
+///
+/// Only `data` fields are kept, comments (like the server heartbeat) and other fields are dropped.
+#[derive(Default)]
+pub(crate) struct EventParser {
+    buffer: Vec<u8>,
+    data: String,
+}
+
+impl EventParser {
+    /// Feed in the next chunk of the body, returning every event it completed
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(std::mem::take(&mut self.data));
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                if !self.data.is_empty() {
+                    self.data.push('\n');
+                }
+                self.data.push_str(data.strip_prefix(' ').unwrap_or(data));
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_comments() {
+        let mut parser = EventParser::default();
+        let events = parser.feed(b"data:{\"type\":\"lagged\"}\n:\n\n:\n\n");
+        assert_eq!(events, vec![String::from("{\"type\":\"lagged\"}")]);
+    }
+
+    #[test]
+    fn events_split_across_chunks() {
+        let mut parser = EventParser::default();
+        assert_eq!(parser.feed(b"data: a\nda"), Vec::<String>::new());
+        assert_eq!(parser.feed(b"ta: b\n\ndata:c"), vec![String::from("a\nb")]);
+        assert_eq!(parser.feed(b"\n\n"), vec![String::from("c")]);
+    }
+}
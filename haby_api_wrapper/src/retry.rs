@@ -0,0 +1,150 @@
+//! Timeouts and retries, with timers that work natively and in the browser
+
+use std::time::Duration;
+
+use futures::future::{select, Either};
+use haby_core::api::IDEMPOTENCY_KEY;
+use reqwest::{Method, StatusCode};
+
+/// How often and how patiently failed requests are retried
+///
+/// Only requests that are safe to send twice are retried: idempotent methods, and writes that
+/// carry an `Idempotency-Key` (which every write from `ApiWrapper` does).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How often a request is sent at most, including the first time
+    pub attempts: u32,
+    /// The longest possible wait before the first retry, it doubles after every attempt
+    pub base_delay: Duration,
+    /// The wait is never longer than this, a `Retry-After` asking for more gives up instead
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Send every request once
+    pub fn none() -> Self {
+        Self {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// A random wait of up to `base_delay * 2^retry` ("full jitter"), so clients that failed
+    /// together don't all come back at the same moment
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        ceiling.mul_f64(fastrand::f64())
+    }
+
+    /// Wait as long as before retry number `retry`, for things `ApiWrapper` doesn't retry by
+    /// itself like reconnecting after a `subscribe` stream ended
+    pub async fn wait(&self, retry: u32) {
+        sleep(self.backoff(retry)).await
+    }
+}
+
+/// Whether sending `request` twice does no harm
+pub(crate) fn retryable(request: &reqwest::Request) -> bool {
+    matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+    ) || request.headers().contains_key(IDEMPOTENCY_KEY)
+}
+
+/// Responses that might turn out differently a moment later
+pub(crate) fn transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Errors where the server most likely never got to handle the request
+pub(crate) fn transient_error(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.is_request()
+}
+
+/// How long the server asked to wait, in seconds or as an HTTP date
+pub(crate) fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.to_utc() - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// `None` when `future` took longer than `timeout`
+pub(crate) async fn timeout<F: std::future::Future>(
+    timeout: Duration,
+    future: F,
+) -> Option<F::Output> {
+    match select(std::pin::pin!(future), std::pin::pin!(sleep(timeout))).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn sleep(duration: Duration) {
+    gloo_timers::future::sleep(duration).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+        for _ in 0..100 {
+            assert!(policy.backoff(0) <= Duration::from_millis(100));
+            assert!(policy.backoff(2) <= Duration::from_millis(400));
+            assert!(policy.backoff(30) <= Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn only_safe_requests_are_retried() {
+        let client = reqwest::Client::new();
+        let get = client.get("http://localhost/habits").build().unwrap();
+        let post = client.post("http://localhost/habits").build().unwrap();
+        let keyed = client
+            .post("http://localhost/habits")
+            .header(IDEMPOTENCY_KEY, "key")
+            .build()
+            .unwrap();
+
+        assert!(retryable(&get));
+        assert!(!retryable(&post));
+        assert!(retryable(&keyed));
+    }
+}
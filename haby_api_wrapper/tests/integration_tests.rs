@@ -3,19 +3,165 @@ use haby_api_wrapper::ApiWrapper;
 #[tokio::test]
 async fn version() {
     let client = ApiWrapper::default();
-    client.clear_db().await;
+    client.clear_db().await.unwrap();
 
-    let version = client.get_version().await;
+    let version = client.get_version().await.unwrap();
 
     assert_eq!(version, haby_core::VERSION);
 }
 
+#[tokio::test]
+async fn server_is_compatible() {
+    let client = ApiWrapper::default();
+
+    let info = client.check_compatibility().await.unwrap();
+
+    assert!(info.api_versions.contains(&haby_core::api::API_VERSION));
+}
+
+/// A server that answers requests with `responses` in order, repeating the last one forever
+async fn mock_server(responses: Vec<&'static str>) -> String {
+    use tokio::io::AsyncWriteExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut responses = responses.into_iter();
+        let mut response = "";
+        while let Ok((mut stream, _)) = listener.accept().await {
+            if let Some(next) = responses.next() {
+                response = next;
+            }
+            let _ = read_request(&mut stream).await;
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    host
+}
+
+/// Read the whole request, so answering doesn't reset the connection while a body is still sent
+async fn read_request(stream: &mut tokio::net::TcpStream) -> std::io::Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+        let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+            continue;
+        };
+        let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+        let length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .and_then(|length| length.trim().parse().ok())
+            .unwrap_or(0);
+        if request.len() >= end + 4 + length {
+            return Ok(());
+        }
+    }
+}
+
+/// A `200 OK` with `body` as JSON
+fn json_response(body: &str) -> &'static str {
+    format!(
+        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .leak()
+}
+
+#[tokio::test]
+async fn incompatible_server() {
+    let response = json_response(r#"{"version":"9.0.0","api_versions":[9]}"#);
+    let client = ApiWrapper::new(mock_server(vec![response]).await);
+
+    let err = client.delete_habit(1).await.unwrap_err().to_string();
+    assert!(err.contains("9.0.0"), "{err}");
+
+    let old = "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+    let client = ApiWrapper::new(mock_server(vec![old]).await);
+    assert!(matches!(
+        client.check_compatibility().await,
+        Err(haby_api_wrapper::Error::Incompatible { server: None, .. })
+    ));
+}
+
+fn quick_retries(attempts: u32) -> haby_api_wrapper::RetryPolicy {
+    haby_api_wrapper::RetryPolicy {
+        attempts,
+        base_delay: std::time::Duration::from_millis(10),
+        max_delay: std::time::Duration::from_secs(1),
+    }
+}
+
+#[tokio::test]
+async fn retries_transient_errors() {
+    let info = json_response(&format!(
+        r#"{{"version":"{}","api_versions":[{}]}}"#,
+        haby_core::VERSION,
+        haby_core::api::API_VERSION
+    ));
+    let unavailable = "HTTP/1.1 503 Service Unavailable\r\nretry-after: 0\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+    let bad_gateway =
+        "HTTP/1.1 502 Bad Gateway\r\ncontent-length: 11\r\nconnection: close\r\n\r\nBad Gateway";
+    let version = "HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\n9.9.9";
+
+    let host = mock_server(vec![info, unavailable, bad_gateway, version]).await;
+    let client = ApiWrapper::new(host).with_retry(quick_retries(3));
+    assert_eq!(client.get_version().await.unwrap(), "9.9.9");
+
+    // Once the attempts are used up the failure is passed on
+    let host = mock_server(vec![info, bad_gateway]).await;
+    let client = ApiWrapper::new(host).with_retry(quick_retries(2));
+    assert_eq!(
+        client.delete_habit(1).await.unwrap_err().to_string(),
+        "Bad Gateway"
+    );
+
+    // Reads fail the same way instead of panicking
+    let host = mock_server(vec![info, bad_gateway]).await;
+    let client = ApiWrapper::new(host).with_retry(quick_retries(2));
+    assert!(matches!(
+        client.get_habits().await,
+        Err(haby_api_wrapper::Error::Status { status, .. }) if status == 502
+    ));
+
+    // As does an answer that isn't what the route promises
+    let host = mock_server(vec![info, json_response("not json")]).await;
+    let client = ApiWrapper::new(host);
+    assert!(matches!(
+        client.get_habits().await,
+        Err(haby_api_wrapper::Error::Decode(_))
+    ));
+}
+
+#[tokio::test]
+async fn times_out() {
+    // Connections are accepted by the OS but never answered
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = format!("http://{}", listener.local_addr().unwrap());
+
+    let timeout = std::time::Duration::from_millis(100);
+    let client = ApiWrapper::new(host)
+        .with_timeout(timeout)
+        .with_retry(quick_retries(2));
+    assert!(matches!(
+        client.check_compatibility().await,
+        Err(haby_api_wrapper::Error::Timeout(t)) if t == timeout
+    ));
+}
+
 #[tokio::test]
 async fn get_habits() {
     let client = ApiWrapper::default();
-    client.clear_db().await;
+    client.clear_db().await.unwrap();
 
-    let habits = client.get_habits().await;
+    let habits = client.get_habits().await.unwrap();
 
     assert_eq!(habits, vec![]);
 }
@@ -23,7 +169,7 @@ async fn get_habits() {
 #[tokio::test]
 async fn create_habit() {
     let client = ApiWrapper::default();
-    client.clear_db().await;
+    client.clear_db().await.unwrap();
 
     let habit = haby_core::api::CreateHabit {
         name: String::from("Test Habit"),
@@ -34,14 +180,14 @@ async fn create_habit() {
     };
 
     let habit = client.create_habit(habit).await.unwrap();
-    let habbits = client.get_habits().await;
+    let habbits = client.get_habits().await.unwrap();
     assert_eq!(habbits, vec![habit]);
 }
 
 #[tokio::test]
 async fn create_habit_error() {
     let client = ApiWrapper::default();
-    client.clear_db().await;
+    client.clear_db().await.unwrap();
 
     let habit = haby_core::api::CreateHabit {
         name: String::from("Test Habit"),
@@ -59,7 +205,7 @@ async fn create_habit_error() {
 #[tokio::test]
 async fn update_habit() {
     let client = ApiWrapper::default();
-    client.clear_db().await;
+    client.clear_db().await.unwrap();
 
     let habit = haby_core::api::CreateHabit {
         name: String::from("Test Habit"),
@@ -73,14 +219,14 @@ async fn update_habit() {
     habit.name = String::from("Updated Habit");
     client.update_habit(&habit).await.unwrap();
 
-    let habits = client.get_habits().await;
+    let habits = client.get_habits().await.unwrap();
     assert_eq!(habits, vec![habit]);
 }
 
 #[tokio::test]
 async fn update_habit_errors() {
     let client = ApiWrapper::default();
-    client.clear_db().await;
+    client.clear_db().await.unwrap();
 
     let habit = haby_core::api::CreateHabit {
         name: String::from("1"),
@@ -98,3 +244,338 @@ async fn update_habit_errors() {
     let res = client.update_habit(&habit).await;
     assert!(res.is_err());
 }
+
+#[tokio::test]
+async fn sync() {
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let mut replica = haby_core::sync::Replica::default();
+    client.sync(&mut replica).await.unwrap();
+    assert_eq!(replica.habits().count(), 0);
+
+    let mut habit = client
+        .create_habit(haby_core::api::CreateHabit::default())
+        .await
+        .unwrap();
+    client.sync(&mut replica).await.unwrap();
+    assert_eq!(replica.habits().collect::<Vec<_>>(), vec![&habit]);
+
+    habit.name = String::from("Updated Habit");
+    client.update_habit(&habit).await.unwrap();
+    client.sync(&mut replica).await.unwrap();
+    assert_eq!(replica.habits().collect::<Vec<_>>(), vec![&habit]);
+}
+
+#[tokio::test]
+async fn subscribe() {
+    use futures::StreamExt;
+
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let updates = client.subscribe().await.unwrap();
+    let habit = client
+        .create_habit(haby_core::api::CreateHabit::default())
+        .await
+        .unwrap();
+
+    let update = std::pin::pin!(updates).next().await.unwrap();
+    match update {
+        haby_core::api::LiveUpdate::Change(change) => assert_eq!(change.id, habit.id),
+        haby_core::api::LiveUpdate::Lagged => panic!("Expected a change, got {update:?}"),
+    }
+}
+
+#[tokio::test]
+async fn export() {
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let habit = client
+        .create_habit(haby_core::api::CreateHabit::default())
+        .await
+        .unwrap();
+
+    let export = client
+        .export(haby_core::api::ExportFormat::Json)
+        .await
+        .unwrap();
+    let export: haby_core::api::Export = serde_json::from_str(&export).unwrap();
+    assert_eq!(export.version, haby_core::VERSION);
+    assert_eq!(export.habits, vec![habit]);
+}
+
+#[tokio::test]
+async fn import_roundtrip() {
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let habit = client
+        .create_habit(haby_core::api::CreateHabit::default())
+        .await
+        .unwrap();
+    let export = client
+        .export(haby_core::api::ExportFormat::Json)
+        .await
+        .unwrap();
+    let export: haby_core::api::Export = serde_json::from_str(&export).unwrap();
+
+    client.clear_db().await.unwrap();
+    let options = haby_core::api::ImportOptions::default();
+    let report = client.import(&export, options).await.unwrap();
+    assert_eq!(report.habits_created, vec![habit.name.clone()]);
+
+    let habits = client.get_habits().await.unwrap();
+    assert_eq!(habits.len(), 1);
+    assert_eq!(habits[0].as_create(), habit.as_create());
+}
+
+#[tokio::test]
+async fn record_event_and_delete_habit() {
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let habit = client
+        .create_habit(haby_core::api::CreateHabit::default())
+        .await
+        .unwrap();
+    let event = client
+        .record_event(haby_core::api::CreateEvent {
+            habit_id: habit.id,
+            time: "2024-08-03T12:00:00".parse().unwrap(),
+            span_part: None,
+        })
+        .await
+        .unwrap();
+
+    let mut replica = haby_core::sync::Replica::default();
+    client.sync(&mut replica).await.unwrap();
+    assert_eq!(
+        replica.events_for(habit.id).collect::<Vec<_>>(),
+        vec![&event]
+    );
+
+    client.delete_habit(habit.id).await.unwrap();
+    client.sync(&mut replica).await.unwrap();
+    assert_eq!(replica.habits().count(), 0);
+    assert_eq!(replica.events_for(habit.id).count(), 0);
+}
+
+#[tokio::test]
+async fn record_events() {
+    use haby_core::api::{BatchEvent, BatchItem, CreateEvent};
+
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let habit = client
+        .create_habit(haby_core::api::CreateHabit::default())
+        .await
+        .unwrap();
+    let events: Vec<_> = (1..=3)
+        .map(|day| BatchEvent {
+            event: CreateEvent {
+                habit_id: habit.id,
+                time: format!("2024-08-0{day}T12:00:00").parse().unwrap(),
+                span_part: None,
+            },
+            idempotency_key: Some(format!("day-{day}")),
+        })
+        .collect();
+
+    let results = client.record_events(&events).await.unwrap();
+    assert!(results
+        .iter()
+        .all(|result| matches!(result, BatchItem::Created { .. })));
+
+    let results = client.record_events(&events).await.unwrap();
+    assert!(results
+        .iter()
+        .all(|result| matches!(result, BatchItem::Duplicate { .. })));
+
+    let mut replica = haby_core::sync::Replica::default();
+    client.sync(&mut replica).await.unwrap();
+    assert_eq!(replica.events_for(habit.id).count(), 3);
+}
+
+#[tokio::test]
+async fn record_events_keeps_results_of_earlier_batches() {
+    use haby_core::api::{BatchEvent, BatchItem, CreateEvent, MAX_BATCH};
+
+    let event = CreateEvent {
+        habit_id: 1,
+        time: "2024-08-01T12:00:00".parse().unwrap(),
+        span_part: None,
+    };
+    let events = vec![
+        BatchEvent {
+            event,
+            idempotency_key: None,
+        };
+        MAX_BATCH + 1
+    ];
+    let recorded: Vec<_> = (1..=MAX_BATCH as i32)
+        .map(|id| BatchItem::Created {
+            event: event.with_id(id),
+        })
+        .collect();
+
+    let info = json_response(&format!(
+        r#"{{"version":"{}","api_versions":[{}]}}"#,
+        haby_core::VERSION,
+        haby_core::api::API_VERSION
+    ));
+    let first = json_response(&serde_json::to_string(&recorded).unwrap());
+    let bad_gateway =
+        "HTTP/1.1 502 Bad Gateway\r\ncontent-length: 11\r\nconnection: close\r\n\r\nBad Gateway";
+    let host = mock_server(vec![info, first, bad_gateway]).await;
+    let client = ApiWrapper::new(host).with_retry(quick_retries(2));
+
+    match client.record_events(&events).await {
+        Err(haby_api_wrapper::Error::Partial {
+            recorded: items,
+            error,
+        }) => {
+            assert_eq!(items, recorded);
+            assert_eq!(error.to_string(), "Bad Gateway");
+        }
+        other => panic!("{other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn idempotency_key() {
+    use haby_core::api::{IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED};
+
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    // Every call is a new request, so the second one is not a replay of the first
+    let habit = haby_core::api::CreateHabit::default();
+    client.create_habit(habit.clone()).await.unwrap();
+    assert!(client.create_habit(habit).await.is_err());
+
+    let retry = || {
+        reqwest::Client::new()
+            .post("http://localhost:8000/api/v1/habits")
+            .header(IDEMPOTENCY_KEY, "integration-test")
+            .json(&haby_core::api::CreateHabit {
+                name: String::from("Retried"),
+                ..Default::default()
+            })
+            .send()
+    };
+    let first = retry().await.unwrap();
+    assert!(first.headers().get(IDEMPOTENT_REPLAYED).is_none());
+    let id = first.text().await.unwrap();
+
+    let second = retry().await.unwrap();
+    assert!(second.headers().get(IDEMPOTENT_REPLAYED).is_some());
+    assert_eq!(second.text().await.unwrap(), id);
+    assert_eq!(client.get_habits().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn reminder_settings_and_snooze() {
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let habit = client
+        .create_habit(haby_core::api::CreateHabit::default())
+        .await
+        .unwrap();
+    let settings = haby_core::api::ReminderSettings {
+        quiet_hours: Some("22:00-07:00".parse().unwrap()),
+        snoozed_until: None,
+    };
+    client
+        .set_reminder_settings(habit.id, &settings)
+        .await
+        .unwrap();
+
+    let until = client.snooze(habit.id, 30).await.unwrap();
+    let settings = client.get_reminder_settings(habit.id).await.unwrap();
+    assert_eq!(settings.snoozed_until, Some(until));
+    assert_eq!(settings.quiet_hours, Some("22:00-07:00".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn webhooks() {
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let created = client
+        .create_webhook(&haby_core::api::CreateWebhook {
+            // Nothing listens here, so the ping fails
+            url: String::from("http://127.0.0.1:9/hook"),
+            events: vec![haby_core::WebhookEvent::HabitCreated],
+            secret: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get_webhooks().await.unwrap(),
+        vec![created.webhook.clone()]
+    );
+
+    let delivery = client.test_webhook(created.webhook.id).await.unwrap();
+    assert!(!delivery.succeeded);
+    assert_eq!(
+        client.webhook_deliveries(created.webhook.id).await.unwrap(),
+        vec![delivery]
+    );
+
+    client.delete_webhook(created.webhook.id).await.unwrap();
+    assert_eq!(client.get_webhooks().await.unwrap(), vec![]);
+
+    // Errors come with the message and the id the server logged them under
+    match client.delete_webhook(created.webhook.id).await {
+        Err(haby_api_wrapper::Error::Status {
+            status,
+            message,
+            request_id,
+        }) => {
+            assert_eq!(status, 404);
+            assert_eq!(
+                message,
+                format!("No webhook with id {}", created.webhook.id)
+            );
+            assert_eq!(request_id.map(|id| id.len()), Some(32));
+        }
+        other => panic!("Expected a 404, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn api_tokens() {
+    let client = ApiWrapper::default();
+    client.clear_db().await.unwrap();
+
+    let created = client
+        .create_token(&haby_core::api::CreateApiToken {
+            name: String::from("ci"),
+            scopes: vec![haby_core::TokenScope::ReadHabits],
+        })
+        .await
+        .unwrap();
+
+    let with_token = ApiWrapper::default().with_token(&created.secret).unwrap();
+    with_token.get_habits().await.unwrap();
+    assert!(with_token
+        .create_habit(haby_core::api::CreateHabit::default())
+        .await
+        .is_err());
+
+    let tokens = client.get_tokens().await.unwrap();
+    assert_eq!(tokens.len(), 1);
+    assert!(tokens[0].last_used_at.is_some());
+
+    assert!(matches!(
+        ApiWrapper::default().with_token("haby_\n"),
+        Err(haby_api_wrapper::Error::InvalidToken)
+    ));
+
+    client.revoke_token(created.token.id).await.unwrap();
+    assert_eq!(client.get_tokens().await.unwrap(), vec![]);
+}
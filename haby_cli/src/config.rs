@@ -0,0 +1,32 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Read from `haby/config.toml` in the platform config directory
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Config {
+    /// The server to talk to, for example `https://haby.vivax.dev/api`
+    pub server: Option<String>,
+    /// An API token, for servers that require one
+    pub token: Option<String>,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("haby").join("config.toml"))
+    }
+
+    /// A missing config file is the same as an empty one
+    pub fn load() -> Result<Self, String> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|err| format!("Invalid config {}: {err}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(format!("Could not read {}: {err}", path.display())),
+        }
+    }
+}
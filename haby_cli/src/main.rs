@@ -0,0 +1,350 @@
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand};
+use haby_api_wrapper::core::api::{CreateEvent, CreateHabit, ExportFormat};
+use haby_api_wrapper::core::color::{self, Color, ParseColorError};
+use haby_api_wrapper::core::reminders::QuietHours;
+use haby_api_wrapper::core::stats::{self, Streak};
+use haby_api_wrapper::core::sync::Replica;
+use haby_api_wrapper::core::{Event, Habit, HabitKind, RecordingType, SpanPart};
+use haby_api_wrapper::ApiWrapper;
+use serde::Serialize;
+
+mod config;
+mod tui;
+
+type Result<T = ()> = std::result::Result<T, String>;
+
+/// Track your habits from the terminal
+#[derive(Parser)]
+#[command(name = "haby", version = haby_api_wrapper::VERSION)]
+struct Cli {
+    /// The server to talk to, overrides the config file
+    #[arg(long, global = true, env = "HABY_SERVER")]
+    server: Option<String>,
+
+    /// The API token to send, overrides the config file
+    #[arg(long, global = true, env = "HABY_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Print machine readable JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List all habits
+    List,
+    /// Check in on a habit right now
+    Check { habit: String },
+    /// Start a span habit
+    Start { habit: String },
+    /// Stop a running span, the habit can be left out when only one is running
+    Stop { habit: Option<String> },
+    /// Show the current and longest streak of every habit
+    Streaks,
+    /// Hold off reminders for a habit
+    Snooze {
+        habit: String,
+        #[arg(long, default_value = "60")]
+        minutes: u32,
+    },
+    /// Set the hours without reminders for a habit, like `22:00-07:00`, leave out to clear them
+    Quiet {
+        habit: String,
+        hours: Option<QuietHours>,
+    },
+    /// Create, edit and delete habits
+    #[command(subcommand)]
+    Habit(HabitCommand),
+    /// Print all data as json or csv
+    Export {
+        #[arg(long, default_value = "json")]
+        format: ExportFormat,
+    },
+    /// Open an interactive dashboard that follows changes live
+    Dashboard,
+}
+
+#[derive(Subcommand)]
+enum HabitCommand {
+    Add {
+        name: String,
+        #[command(flatten)]
+        settings: HabitSettings,
+    },
+    Edit {
+        habit: String,
+        #[arg(long)]
+        name: Option<String>,
+        #[command(flatten)]
+        settings: HabitSettings,
+    },
+    Delete {
+        habit: String,
+    },
+}
+
+#[derive(Args)]
+struct HabitSettings {
+    /// Hex color, like `#00FF00` or `#0F0`, new habits get one from the palette by default
+    #[arg(long, value_parser = parse_color)]
+    color: Option<Color>,
+    /// Track something to avoid rather than something to do
+    #[arg(long)]
+    addiction: bool,
+    /// Record start and end times instead of single check-ins
+    #[arg(long)]
+    span: bool,
+    /// Should be done every this many days
+    #[arg(long)]
+    every: Option<i32>,
+}
+
+impl HabitSettings {
+    fn apply(&self, habit: &mut CreateHabit) {
+        if let Some(color) = self.color {
+            habit.color = color;
+        }
+        if self.addiction {
+            habit.kind = HabitKind::Addiction;
+        }
+        if self.span {
+            habit.recording_type = RecordingType::Span;
+        }
+        if self.every.is_some() {
+            habit.every = self.every;
+        }
+    }
+}
+
+fn parse_color(hex: &str) -> Result<Color> {
+    hex.parse().map_err(|err: ParseColorError| err.to_string())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result {
+    let config = config::Config::load()?;
+    let mut client = match cli.server.or(config.server) {
+        Some(server) => ApiWrapper::new(server),
+        None => ApiWrapper::default(),
+    };
+    if let Some(token) = cli.token.or(config.token) {
+        client = client.with_token(&token)?;
+    }
+    let out = Output { json: cli.json };
+    client.check_compatibility().await?;
+
+    if let Command::Dashboard = cli.command {
+        return tui::run(&client).await;
+    }
+
+    let mut replica = Replica::default();
+    client.sync(&mut replica).await?;
+    let today = chrono::Local::now().date_naive();
+
+    match cli.command {
+        Command::List => {
+            let habits: Vec<_> = replica.habits().collect();
+            out.print(&habits, || {
+                for habit in &habits {
+                    let due = stats::next_due(habit, replica.events_for(habit.id))
+                        .map(|due| format!("due {due}"))
+                        .unwrap_or_default();
+                    println!("{:<24} {:<10} {due}", habit.name, describe(habit));
+                }
+            });
+        }
+        Command::Check { habit } => {
+            let habit = find_habit(&replica, &habit)?;
+            if habit.recording_type == RecordingType::Span {
+                return Err(format!(
+                    "{} is a span habit, use `start` and `stop`",
+                    habit.name
+                ));
+            }
+            let event = record(&client, habit, None).await?;
+            out.print(&event, || println!("Checked in on {}", habit.name));
+        }
+        Command::Start { habit } => {
+            let habit = find_habit(&replica, &habit)?;
+            if habit.recording_type != RecordingType::Span {
+                return Err(format!("{} is not a span habit, use `check`", habit.name));
+            }
+            if is_running(&replica, habit) {
+                return Err(format!("{} is already running", habit.name));
+            }
+            let event = record(&client, habit, Some(SpanPart::Start)).await?;
+            out.print(&event, || println!("Started {}", habit.name));
+        }
+        Command::Stop { habit } => {
+            let habit = match habit {
+                Some(habit) => {
+                    let habit = find_habit(&replica, &habit)?;
+                    if !is_running(&replica, habit) {
+                        return Err(format!("{} is not running", habit.name));
+                    }
+                    habit
+                }
+                None => {
+                    let running: Vec<_> = replica
+                        .habits()
+                        .filter(|habit| is_running(&replica, habit))
+                        .collect();
+                    match running.as_slice() {
+                        [habit] => *habit,
+                        [] => return Err(String::from("Nothing is running")),
+                        _ => {
+                            return Err(String::from(
+                                "More than one span is running, say which one to stop",
+                            ))
+                        }
+                    }
+                }
+            };
+            let event = record(&client, habit, Some(SpanPart::End)).await?;
+            out.print(&event, || println!("Stopped {}", habit.name));
+        }
+        Command::Streaks => {
+            #[derive(Serialize)]
+            struct HabitStreak<'a> {
+                habit: &'a Habit,
+                streak: Streak,
+            }
+
+            let streaks: Vec<_> = replica
+                .habits()
+                .map(|habit| HabitStreak {
+                    habit,
+                    streak: stats::streak(habit, replica.events_for(habit.id), today),
+                })
+                .collect();
+            out.print(&streaks, || {
+                for HabitStreak { habit, streak } in &streaks {
+                    println!(
+                        "{:<24} {:>4} (longest {})",
+                        habit.name, streak.current, streak.longest
+                    );
+                }
+            });
+        }
+        Command::Snooze { habit, minutes } => {
+            let habit = find_habit(&replica, &habit)?;
+            let until = client.snooze(habit.id, minutes).await?;
+            out.print(&until, || {
+                println!("Snoozed {} until {}", habit.name, until.format("%H:%M"))
+            });
+        }
+        Command::Quiet { habit, hours } => {
+            let habit = find_habit(&replica, &habit)?;
+            let mut settings = client.get_reminder_settings(habit.id).await?;
+            settings.quiet_hours = hours;
+            client.set_reminder_settings(habit.id, &settings).await?;
+            out.print(&settings, || match hours {
+                Some(hours) => println!("No reminders for {} between {hours}", habit.name),
+                None => println!("Cleared quiet hours for {}", habit.name),
+            });
+        }
+        Command::Habit(HabitCommand::Add { name, settings }) => {
+            let habits: Vec<_> = replica.habits().cloned().collect();
+            let mut habit = CreateHabit {
+                name,
+                color: color::next_color(&habits),
+                ..Default::default()
+            };
+            settings.apply(&mut habit);
+            let habit = client.create_habit(habit).await?;
+            out.print(&habit, || println!("Created {}", habit.name));
+        }
+        Command::Habit(HabitCommand::Edit {
+            habit,
+            name,
+            settings,
+        }) => {
+            let existing = find_habit(&replica, &habit)?;
+            let mut habit = existing.as_create();
+            if let Some(name) = name {
+                habit.name = name;
+            }
+            settings.apply(&mut habit);
+            let habit = habit.with_id(existing.id);
+            client.update_habit(&habit).await?;
+            out.print(&habit, || println!("Updated {}", habit.name));
+        }
+        Command::Habit(HabitCommand::Delete { habit }) => {
+            let habit = find_habit(&replica, &habit)?;
+            client.delete_habit(habit.id).await?;
+            out.print(habit, || println!("Deleted {}", habit.name));
+        }
+        Command::Export { format } => {
+            print!("{}", client.export(format).await?);
+        }
+        Command::Dashboard => unreachable!("the dashboard is started before syncing"),
+    }
+
+    Ok(())
+}
+
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    /// Print `value` as json, or run `human` to print it as text
+    fn print<T: Serialize + ?Sized>(&self, value: &T, human: impl FnOnce()) {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(value).unwrap());
+        } else {
+            human();
+        }
+    }
+}
+
+/// Habits are looked up by name, ignoring case
+fn find_habit<'a>(replica: &'a Replica, name: &str) -> Result<&'a Habit> {
+    replica
+        .habits()
+        .find(|habit| habit.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("No habit called {name:?}, see `haby list`"))
+}
+
+fn is_running(replica: &Replica, habit: &Habit) -> bool {
+    stats::running_since(habit, replica.events_for(habit.id)).is_some()
+}
+
+async fn record(client: &ApiWrapper, habit: &Habit, span_part: Option<SpanPart>) -> Result<Event> {
+    let event = client
+        .record_event(CreateEvent {
+            habit_id: habit.id,
+            time: chrono::Local::now().naive_local(),
+            span_part,
+        })
+        .await?;
+    Ok(event)
+}
+
+fn describe(habit: &Habit) -> String {
+    let mut description = match habit.kind {
+        HabitKind::Habit => String::from("habit"),
+        HabitKind::Addiction => String::from("addiction"),
+    };
+    if habit.recording_type == RecordingType::Span {
+        description.push_str(" span");
+    }
+    description
+}
@@ -0,0 +1,316 @@
+use chrono::{Datelike, Days, NaiveDate};
+use crossterm::event::{Event as TermEvent, EventStream, KeyCode, KeyEventKind};
+use futures::StreamExt;
+use haby_api_wrapper::core::api::CreateEvent;
+use haby_api_wrapper::core::sync::Replica;
+use haby_api_wrapper::core::{stats, Habit, HabitKind, RecordingType, SpanPart};
+use haby_api_wrapper::ApiWrapper;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::Result;
+
+/// How many weeks the heatmap goes back
+const HEATMAP_WEEKS: u64 = 12;
+
+struct App {
+    replica: Replica,
+    table: TableState,
+    status: String,
+}
+
+/// Run the dashboard until the user quits, the terminal is restored even on errors
+pub async fn run(client: &ApiWrapper) -> Result {
+    let mut replica = Replica::default();
+    client.sync(&mut replica).await?;
+    let mut app = App {
+        replica,
+        table: TableState::default().with_selected(Some(0)),
+        status: String::new(),
+    };
+
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, client, &mut app).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(terminal: &mut DefaultTerminal, client: &ApiWrapper, app: &mut App) -> Result {
+    let mut keys = EventStream::new();
+    let mut live = Box::pin(client.subscribe().await?);
+    let mut live_open = true;
+
+    loop {
+        terminal
+            .draw(|frame| app.draw(frame))
+            .map_err(|err| err.to_string())?;
+
+        tokio::select! {
+            event = keys.next() => {
+                let Some(event) = event else { return Ok(()) };
+                let TermEvent::Key(key) = event.map_err(|err| err.to_string())? else {
+                    continue;
+                };
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('j') | KeyCode::Down => app.move_selection(1),
+                    KeyCode::Char('k') | KeyCode::Up => app.move_selection(-1),
+                    KeyCode::Char('r') => {
+                        app.status = match client.sync(&mut app.replica).await {
+                            Ok(()) => String::from("Refreshed"),
+                            Err(err) => err.to_string(),
+                        };
+                    }
+                    KeyCode::Enter | KeyCode::Char(' ') | KeyCode::Char('c') => {
+                        app.status = match app.check_in(client).await {
+                            Ok(status) | Err(status) => status,
+                        };
+                        if let Err(err) = client.sync(&mut app.replica).await {
+                            app.status = err.to_string();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            update = live.next(), if live_open => {
+                if update.is_some() {
+                    if let Err(err) = client.sync(&mut app.replica).await {
+                        app.status = err.to_string();
+                    }
+                } else {
+                    live_open = false;
+                    app.status = String::from("Lost live updates, press r to refresh");
+                }
+            }
+        }
+    }
+}
+
+impl App {
+    fn habits(&self) -> Vec<&Habit> {
+        self.replica.habits().collect()
+    }
+
+    fn selected(&self) -> Option<&Habit> {
+        let index = self.table.selected()?;
+        self.replica.habits().nth(index)
+    }
+
+    fn move_selection(&mut self, by: isize) {
+        let count = self.replica.habits().count();
+        if count == 0 {
+            return;
+        }
+        let current = self.table.selected().unwrap_or(0) as isize;
+        let next = (current + by).rem_euclid(count as isize);
+        self.table.select(Some(next as usize));
+    }
+
+    /// Check in on the selected habit, or start and stop it if it is a span habit
+    async fn check_in(&self, client: &ApiWrapper) -> Result<String> {
+        let habit = self
+            .selected()
+            .ok_or_else(|| String::from("No habit selected"))?;
+
+        let (span_part, done) = match habit.recording_type {
+            RecordingType::Point => (None, "Checked in on"),
+            RecordingType::Span => {
+                if stats::running_since(habit, self.replica.events_for(habit.id)).is_some() {
+                    (Some(SpanPart::End), "Stopped")
+                } else {
+                    (Some(SpanPart::Start), "Started")
+                }
+            }
+        };
+
+        client
+            .record_event(CreateEvent {
+                habit_id: habit.id,
+                time: chrono::Local::now().naive_local(),
+                span_part,
+            })
+            .await?;
+        Ok(format!("{done} {}", habit.name))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let today = chrono::Local::now().date_naive();
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [table, heatmap] = Layout::horizontal([
+            Constraint::Min(40),
+            Constraint::Length(HEATMAP_WEEKS as u16 * 2 + 6),
+        ])
+        .areas(body);
+
+        self.draw_today(frame, header, today);
+        self.draw_table(frame, table, today);
+        self.draw_heatmap(frame, heatmap, today);
+
+        let help = "q quit  j/k move  enter check in / start / stop  r refresh";
+        let footer_line = Line::from(vec![
+            Span::raw(help).dim(),
+            Span::raw("  "),
+            Span::raw(self.status.as_str()),
+        ]);
+        frame.render_widget(Paragraph::new(footer_line), footer);
+    }
+
+    /// Which habits are due today and which spans are running
+    fn draw_today(&self, frame: &mut Frame, area: Rect, today: NaiveDate) {
+        let due: Vec<_> = self
+            .replica
+            .habits()
+            .filter(|habit| is_due(habit, &self.replica, today))
+            .map(|habit| Span::styled(habit.name.as_str(), habit_style(habit)))
+            .collect();
+        let running: Vec<_> = self
+            .replica
+            .habits()
+            .filter_map(|habit| {
+                let since = stats::running_since(habit, self.replica.events_for(habit.id))?;
+                Some(Span::styled(
+                    format!("{} (since {})", habit.name, since.format("%H:%M")),
+                    habit_style(habit),
+                ))
+            })
+            .collect();
+
+        let lines = vec![
+            labelled("Due today: ", due),
+            labelled("Running:   ", running),
+        ];
+        let block = Block::bordered().title(format!(" haby - {today} "));
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_table(&mut self, frame: &mut Frame, area: Rect, today: NaiveDate) {
+        let rows: Vec<_> = self
+            .habits()
+            .into_iter()
+            .map(|habit| {
+                let events = self.replica.events_for(habit.id);
+                let streak = stats::streak(habit, events, today);
+                Row::new(vec![
+                    Span::styled(habit.name.clone(), habit_style(habit)),
+                    Span::raw(status(habit, &self.replica, today)),
+                    Span::raw(format!("{} ({})", streak.current, streak.longest)),
+                ])
+            })
+            .collect();
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(20),
+                Constraint::Length(12),
+            ],
+        )
+        .header(Row::new(["Habit", "Status", "Streak"]).bold())
+        .block(Block::bordered().title(" Habits "))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+
+    /// A week per column, the same way the web frontend lays it out
+    fn draw_heatmap(&self, frame: &mut Frame, area: Rect, today: NaiveDate) {
+        let block = Block::bordered().title(" History ");
+        let Some(habit) = self.selected() else {
+            frame.render_widget(block, area);
+            return;
+        };
+
+        let days: Vec<_> = self
+            .replica
+            .events_for(habit.id)
+            .filter(|event| event.span_part != Some(SpanPart::End))
+            .map(|event| event.time.date())
+            .collect();
+
+        let this_monday = today - Days::new(u64::from(today.weekday().num_days_from_monday()));
+        let first_monday = this_monday - Days::new((HEATMAP_WEEKS - 1) * 7);
+        // A day with more check-ins gets a stronger color, up to the habit color itself
+        let shades = [0.5, 0.25, 0.0].map(|amount| rgb(habit.color.lighten(amount)));
+
+        let lines: Vec<_> = ["Mo", "Tu", "We", "Th", "Fr", "Sa", "Su"]
+            .into_iter()
+            .zip(0..)
+            .map(|(name, weekday)| {
+                let mut spans = vec![Span::raw(format!("{name} ")).dim()];
+                spans.extend((0..HEATMAP_WEEKS).map(|week| {
+                    let day = first_monday + Days::new(week * 7 + weekday);
+                    if day > today {
+                        Span::raw("  ")
+                    } else {
+                        match days.iter().filter(|d| **d == day).count() {
+                            0 => Span::raw("· ").dim(),
+                            n => Span::styled("■ ", Style::new().fg(shades[n.min(3) - 1])),
+                        }
+                    }
+                }));
+                Line::from(spans)
+            })
+            .collect();
+
+        let block = block.title(format!(" {} ", habit.name));
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+}
+
+fn labelled<'a>(label: &'a str, items: Vec<Span<'a>>) -> Line<'a> {
+    let mut spans = vec![Span::raw(label).bold()];
+    if items.is_empty() {
+        spans.push(Span::raw("nothing").dim());
+    }
+    for (index, item) in items.into_iter().enumerate() {
+        if index > 0 {
+            spans.push(Span::raw(", "));
+        }
+        spans.push(item);
+    }
+    Line::from(spans)
+}
+
+fn is_due(habit: &Habit, replica: &Replica, today: NaiveDate) -> bool {
+    habit.every.is_some()
+        && stats::next_due(habit, replica.events_for(habit.id)).is_none_or(|due| due <= today)
+}
+
+fn status(habit: &Habit, replica: &Replica, today: NaiveDate) -> String {
+    if let Some(since) = stats::running_since(habit, replica.events_for(habit.id)) {
+        return format!("running since {}", since.format("%H:%M"));
+    }
+    if habit.kind == HabitKind::Addiction {
+        return String::new();
+    }
+    match stats::next_due(habit, replica.events_for(habit.id)) {
+        _ if habit.every.is_none() => String::new(),
+        None => String::from("due today"),
+        Some(due) if due == today => String::from("due today"),
+        Some(due) if due < today => format!("overdue {} days", (today - due).num_days()),
+        Some(due) => format!("due in {} days", (due - today).num_days()),
+    }
+}
+
+fn rgb(color: haby_api_wrapper::core::Color) -> Color {
+    Color::Rgb(color.r, color.g, color.b)
+}
+
+fn habit_color(habit: &Habit) -> Color {
+    rgb(habit.color)
+}
+
+fn habit_style(habit: &Habit) -> Style {
+    Style::new().fg(habit_color(habit))
+}
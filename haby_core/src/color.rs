@@ -0,0 +1,304 @@
+//! Habit colors, how they are parsed and stored, and what goes well with them
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Habit;
+
+/// Serialized as `{"r": 0, "g": 0, "b": 255}`, but hex strings like `"#0000FF"` are accepted too
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "ColorRepr")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+/// Colors new habits get, in the order they are handed out
+///
+/// They are far enough apart to tell habits apart and readable on light and dark backgrounds.
+pub const PALETTE: [Color; 12] = [
+    Color::rgb(0x3B, 0x82, 0xF6), // blue
+    Color::rgb(0xF9, 0x73, 0x16), // orange
+    Color::rgb(0x22, 0xC5, 0x5E), // green
+    Color::rgb(0xEC, 0x48, 0x99), // pink
+    Color::rgb(0xA8, 0x55, 0xF7), // purple
+    Color::rgb(0x14, 0xB8, 0xA6), // teal
+    Color::rgb(0xEF, 0x44, 0x44), // red
+    Color::rgb(0xEA, 0xB3, 0x08), // yellow
+    Color::rgb(0x63, 0x66, 0xF1), // indigo
+    Color::rgb(0x84, 0xCC, 0x16), // lime
+    Color::rgb(0x06, 0xB6, 0xD4), // cyan
+    Color::rgb(0x78, 0x71, 0x6C), // stone
+];
+
+/// The palette color the fewest of `habits` use, so new habits look different from the old ones
+pub fn next_color(habits: &[Habit]) -> Color {
+    PALETTE
+        .iter()
+        .copied()
+        .min_by_key(|color| habits.iter().filter(|habit| habit.color == *color).count())
+        .expect("The palette is not empty")
+}
+
+/// A string that is not a `#RRGGBB` or `#RGB` hex color
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseColorError(String);
+
+impl fmt::Display for ParseColorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not a #RRGGBB or #RGB hex color", self.0)
+    }
+}
+
+impl std::error::Error for ParseColorError {}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Like parsing, for callers that don't care why it failed
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        hex.parse().ok()
+    }
+
+    /// `RRGGBB` in upper case and without `#`, which is how the database stores it
+    pub fn to_hex(&self) -> String {
+        format!("{:02X}{:02X}{:02X}", self.r, self.g, self.b)
+    }
+
+    /// Relative luminance as defined by WCAG, from 0 for black to 1 for white
+    pub fn luminance(&self) -> f64 {
+        fn linear(channel: u8) -> f64 {
+            let c = f64::from(channel) / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }
+        0.2126 * linear(self.r) + 0.7152 * linear(self.g) + 0.0722 * linear(self.b)
+    }
+
+    /// The WCAG contrast ratio, from 1 for the same color to 21 for black on white
+    pub fn contrast(&self, other: Color) -> f64 {
+        let (a, b) = (self.luminance(), other.luminance());
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    }
+
+    /// Black or white, whichever is easier to read on top of this color
+    pub fn text_color(&self) -> Color {
+        if self.contrast(Color::BLACK) >= self.contrast(Color::WHITE) {
+            Color::BLACK
+        } else {
+            Color::WHITE
+        }
+    }
+
+    /// Blend towards `other`, `amount` 0 keeps this color and 1 gives `other`
+    pub fn mix(&self, other: Color, amount: f64) -> Color {
+        let amount = amount.clamp(0.0, 1.0);
+        let channel =
+            |a: u8, b: u8| (f64::from(a) + (f64::from(b) - f64::from(a)) * amount).round() as u8;
+        Color {
+            r: channel(self.r, other.r),
+            g: channel(self.g, other.g),
+            b: channel(self.b, other.b),
+        }
+    }
+
+    /// Towards white, for the lower intensities of a heatmap
+    pub fn lighten(&self, amount: f64) -> Color {
+        self.mix(Color::WHITE, amount)
+    }
+
+    /// Towards black
+    pub fn darken(&self, amount: f64) -> Color {
+        self.mix(Color::BLACK, amount)
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.to_hex())
+    }
+}
+
+impl FromStr for Color {
+    type Err = ParseColorError;
+
+    /// Accepts `RRGGBB` and `RGB`, with or without a leading `#`, in any case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseColorError(s.to_owned());
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(error());
+        }
+
+        let channel = |digits: &str| u8::from_str_radix(digits, 16).map_err(|_| error());
+        match hex.len() {
+            6 => Ok(Color {
+                r: channel(&hex[0..2])?,
+                g: channel(&hex[2..4])?,
+                b: channel(&hex[4..6])?,
+            }),
+            // `#ABC` is short for `#AABBCC`
+            3 => Ok(Color {
+                r: channel(&hex[0..1])? * 0x11,
+                g: channel(&hex[1..2])? * 0x11,
+                b: channel(&hex[2..3])? * 0x11,
+            }),
+            _ => Err(error()),
+        }
+    }
+}
+
+impl TryFrom<&str> for Color {
+    type Error = ParseColorError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = ParseColorError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Everything a color can be deserialized from
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorRepr {
+    Hex(String),
+    Rgb { r: u8, g: u8, b: u8 },
+}
+
+impl TryFrom<ColorRepr> for Color {
+    type Error = ParseColorError;
+
+    fn try_from(value: ColorRepr) -> Result<Self, Self::Error> {
+        match value {
+            ColorRepr::Hex(hex) => hex.parse(),
+            ColorRepr::Rgb { r, g, b } => Ok(Color { r, g, b }),
+        }
+    }
+}
+
+// Stored as `RRGGBB` text, a row with anything else is an error rather than a silently wrong color
+impl<DB: sqlx::Database> sqlx::Type<DB> for Color
+where
+    String: sqlx::Type<DB>,
+{
+    fn type_info() -> DB::TypeInfo {
+        <String as sqlx::Type<DB>>::type_info()
+    }
+
+    fn compatible(ty: &DB::TypeInfo) -> bool {
+        <String as sqlx::Type<DB>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: sqlx::Database> sqlx::Decode<'r, DB> for Color
+where
+    String: sqlx::Decode<'r, DB>,
+{
+    fn decode(value: DB::ValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        Ok(String::decode(value)?.parse()?)
+    }
+}
+
+impl<'q, DB: sqlx::Database> sqlx::Encode<'q, DB> for Color
+where
+    String: sqlx::Encode<'q, DB>,
+{
+    fn encode_by_ref(
+        &self,
+        buf: &mut DB::ArgumentBuffer<'q>,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        self.to_hex().encode(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_forms() {
+        let color = Color::rgb(0xAA, 0xBB, 0xCC);
+        for hex in ["AABBCC", "#AABBCC", "#aabbcc", "abc", "#ABC"] {
+            assert_eq!(hex.parse(), Ok(color), "{hex}");
+        }
+        for hex in [
+            "", "#", "##ABC", "ABCD", "#GGGGGG", "+1+2+3", "AABBCC ", "ÄBC",
+        ] {
+            assert!(hex.parse::<Color>().is_err(), "{hex}");
+        }
+        assert_eq!(color.to_string(), "#AABBCC");
+    }
+
+    #[test]
+    fn deserialize_validates() {
+        let color = Color::rgb(0, 0, 255);
+        let parse = serde_json::from_str::<Color>;
+        assert_eq!(parse(r#"{"r":0,"g":0,"b":255}"#).unwrap(), color);
+        assert_eq!(parse(r##""#0000ff""##).unwrap(), color);
+        assert!(parse(r#""blue""#).is_err());
+        assert!(parse(r#"{"r":0,"g":0,"b":256}"#).is_err());
+        assert_eq!(
+            serde_json::to_string(&color).unwrap(),
+            r#"{"r":0,"g":0,"b":255}"#
+        );
+    }
+
+    #[test]
+    fn contrast() {
+        assert_eq!(Color::BLACK.contrast(Color::WHITE).round(), 21.0);
+        assert_eq!(
+            Color::rgb(0x12, 0x34, 0x56).contrast(Color::rgb(0x12, 0x34, 0x56)),
+            1.0
+        );
+        assert_eq!(Color::rgb(0xEA, 0xB3, 0x08).text_color(), Color::BLACK);
+        assert_eq!(Color::rgb(0x1E, 0x3A, 0x8A).text_color(), Color::WHITE);
+    }
+
+    #[test]
+    fn lighten_and_darken() {
+        let color = Color::rgb(0x40, 0x80, 0xC0);
+        assert_eq!(color.lighten(0.0), color);
+        assert_eq!(color.lighten(1.0), Color::WHITE);
+        assert_eq!(color.darken(2.0), Color::BLACK);
+        assert_eq!(color.darken(0.5), Color::rgb(0x20, 0x40, 0x60));
+        assert!(color.lighten(0.5).luminance() > color.luminance());
+    }
+
+    #[test]
+    fn palette_is_handed_out_evenly() {
+        let mut habits = Vec::new();
+        for i in 0..PALETTE.len() * 2 {
+            let habit = crate::api::CreateHabit {
+                color: next_color(&habits),
+                ..Default::default()
+            };
+            assert_eq!(habit.color, PALETTE[i % PALETTE.len()]);
+            habits.push(habit.with_id(i as i32));
+        }
+
+        for (i, a) in PALETTE.iter().enumerate() {
+            for b in &PALETTE[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+}
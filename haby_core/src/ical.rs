@@ -0,0 +1,252 @@
+//! A minimal iCalendar (RFC 5545) writer for habit schedules and logged spans
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+use crate::{Event, Habit, RecordingType, SpanPart};
+
+/// Lines longer than this many bytes are folded onto continuation lines
+const MAX_LINE: usize = 75;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum When {
+    /// A whole day
+    Date(NaiveDate),
+    /// A floating local time, which is how events are stored
+    DateTime(NaiveDateTime),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CalendarEvent {
+    pub uid: String,
+    pub summary: String,
+    pub start: When,
+    pub end: Option<When>,
+    /// Repeat every this many days
+    pub every_days: Option<i32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Calendar {
+    pub events: Vec<CalendarEvent>,
+}
+
+impl Calendar {
+    /// Schedules for every habit with an `every`, and an entry for each finished span
+    ///
+    /// A schedule starts on the day of the last event of the habit, or `today` if it has none,
+    /// so the calendar shows when it is due next.
+    pub fn from_habits(habits: &[Habit], events: &[Event], today: NaiveDate) -> Self {
+        let mut calendar = Calendar::default();
+
+        for habit in habits {
+            let mut habit_events: Vec<_> = events
+                .iter()
+                .filter(|event| event.habit_id == habit.id)
+                .collect();
+            habit_events.sort_by_key(|event| event.time);
+
+            if let Some(every) = habit.every {
+                let start = habit_events
+                    .last()
+                    .map(|event| event.time.date())
+                    .unwrap_or(today);
+                calendar.events.push(CalendarEvent {
+                    uid: format!("habit-{}@haby", habit.id),
+                    summary: habit.name.clone(),
+                    start: When::Date(start),
+                    end: None,
+                    every_days: Some(every),
+                });
+            }
+
+            if habit.recording_type == RecordingType::Span {
+                calendar.events.extend(spans(habit, &habit_events));
+            }
+        }
+
+        calendar
+    }
+
+    /// Serialize with `stamp` as the UTC time the calendar was generated
+    pub fn to_ics(&self, stamp: NaiveDateTime) -> String {
+        let mut out = String::new();
+        line(&mut out, "BEGIN:VCALENDAR");
+        line(&mut out, "VERSION:2.0");
+        line(&mut out, "PRODID:-//haby//haby//EN");
+        line(&mut out, "CALSCALE:GREGORIAN");
+
+        for event in &self.events {
+            line(&mut out, "BEGIN:VEVENT");
+            line(&mut out, &format!("UID:{}", escape(&event.uid)));
+            line(
+                &mut out,
+                &format!("DTSTAMP:{}", stamp.format("%Y%m%dT%H%M%SZ")),
+            );
+            line(&mut out, &format!("DTSTART{}", when(event.start)));
+            if let Some(end) = event.end {
+                line(&mut out, &format!("DTEND{}", when(end)));
+            }
+            if let Some(every) = event.every_days {
+                line(&mut out, &format!("RRULE:FREQ=DAILY;INTERVAL={every}"));
+            }
+            line(&mut out, &format!("SUMMARY:{}", escape(&event.summary)));
+            line(&mut out, "END:VEVENT");
+        }
+
+        line(&mut out, "END:VCALENDAR");
+        out
+    }
+}
+
+/// Pair up the start and end events of a span habit, spans that are still running are left out
+fn spans(habit: &Habit, events: &[&Event]) -> Vec<CalendarEvent> {
+    let mut result = Vec::new();
+    let mut start = None;
+
+    for event in events {
+        match event.span_part {
+            Some(SpanPart::Start) => start = Some(*event),
+            Some(SpanPart::End) => {
+                if let Some(start) = start.take() {
+                    result.push(CalendarEvent {
+                        uid: format!("event-{}@haby", start.id),
+                        summary: habit.name.clone(),
+                        start: When::DateTime(start.time),
+                        end: Some(When::DateTime(event.time)),
+                        every_days: None,
+                    });
+                }
+            }
+            None => {}
+        }
+    }
+
+    result
+}
+
+/// The parameters and value of a date property, to come right after the property name
+fn when(when: When) -> String {
+    match when {
+        When::Date(date) => format!(";VALUE=DATE:{}", date.format("%Y%m%d")),
+        When::DateTime(time) => format!(":{}", time.format("%Y%m%dT%H%M%S")),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Write a content line, folding it so no line is longer than `MAX_LINE` bytes
+fn line(out: &mut String, content: &str) {
+    let mut width = 0;
+    for c in content.chars() {
+        if width + c.len_utf8() > MAX_LINE {
+            out.push_str("\r\n ");
+            // The leading space counts towards the length of the continuation line
+            width = 1;
+        }
+        width += c.len_utf8();
+        out.push(c);
+    }
+    out.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::CreateHabit;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn time(s: &str) -> NaiveDateTime {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(escape("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+    }
+
+    #[test]
+    fn folds_long_lines() {
+        let mut out = String::new();
+        line(&mut out, &"x".repeat(100));
+        let lines: Vec<_> = out.split("\r\n").collect();
+        assert_eq!(lines[0].len(), 75);
+        assert_eq!(lines[1], format!(" {}", "x".repeat(25)));
+    }
+
+    #[test]
+    fn folds_on_char_boundaries() {
+        let mut out = String::new();
+        line(&mut out, &"ø".repeat(50));
+        assert!(out.split("\r\n").all(|line| line.len() <= MAX_LINE));
+    }
+
+    #[test]
+    fn schedule_starts_at_last_event() {
+        let habit = CreateHabit {
+            every: Some(2),
+            ..Default::default()
+        }
+        .with_id(1);
+        let event = Event {
+            id: 1,
+            habit_id: 1,
+            time: time("2024-08-01T08:00:00"),
+            span_part: None,
+        };
+
+        let calendar = Calendar::from_habits(&[habit], &[event], date("2024-08-05"));
+        let ics = calendar.to_ics(time("2024-08-05T00:00:00"));
+
+        assert!(ics.contains("DTSTART;VALUE=DATE:20240801\r\n"));
+        assert!(ics.contains("RRULE:FREQ=DAILY;INTERVAL=2\r\n"));
+        assert!(ics.contains("SUMMARY:New Habit\r\n"));
+    }
+
+    #[test]
+    fn spans_are_paired() {
+        let habit = CreateHabit {
+            recording_type: RecordingType::Span,
+            ..Default::default()
+        }
+        .with_id(1);
+        let event = |id, at, span_part| Event {
+            id,
+            habit_id: 1,
+            time: time(at),
+            span_part: Some(span_part),
+        };
+        let events = [
+            event(1, "2024-08-01T08:00:00", SpanPart::Start),
+            event(2, "2024-08-01T09:30:00", SpanPart::End),
+            event(3, "2024-08-02T08:00:00", SpanPart::Start),
+        ];
+
+        let calendar = Calendar::from_habits(&[habit], &events, date("2024-08-05"));
+        assert_eq!(
+            calendar.events,
+            vec![CalendarEvent {
+                uid: String::from("event-1@haby"),
+                summary: String::from("New Habit"),
+                start: When::DateTime(time("2024-08-01T08:00:00")),
+                end: Some(When::DateTime(time("2024-08-01T09:30:00"))),
+                every_days: None,
+            }]
+        );
+    }
+}
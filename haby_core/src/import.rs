@@ -0,0 +1,123 @@
+//! Converters from other habit trackers into our own export format
+
+use chrono::NaiveDate;
+
+use crate::api::{CreateHabit, Export};
+use crate::{Event, VERSION};
+
+/// Loop writes this for days that were checked in by hand
+const LOOP_YES_MANUAL: &str = "2";
+
+/// Convert the `Checkmarks.csv` from a Loop Habit Tracker export
+///
+/// The file has a `Date` column followed by one column per habit, and Loop ends every line with a
+/// comma, so columns without a name are skipped. Only manual check-ins are imported, the days Loop
+/// fills in automatically to satisfy a frequency are not real events.
+pub fn loop_habit_tracker(checkmarks: &str) -> Result<Export, String> {
+    let mut reader = csv::Reader::from_reader(checkmarks.as_bytes());
+
+    let headers = reader.headers().map_err(|err| err.to_string())?.clone();
+    if headers.get(0).map(str::trim) != Some("Date") {
+        return Err(String::from("Expected the first column to be `Date`"));
+    }
+
+    // The index of every habit column with the id of its habit
+    let columns: Vec<(usize, i32)> = headers
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, name)| !name.trim().is_empty())
+        .map(|(column, _)| column)
+        .zip(1..)
+        .collect();
+    let habits = columns
+        .iter()
+        .map(|&(column, id)| {
+            CreateHabit {
+                name: headers[column].trim().to_owned(),
+                ..Default::default()
+            }
+            .with_id(id)
+        })
+        .collect();
+
+    let mut events = Vec::new();
+    for (line, record) in reader.records().enumerate() {
+        let record = record.map_err(|err| err.to_string())?;
+        let date = record.get(0).unwrap_or_default().trim();
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|err| format!("Invalid date {date:?} on line {}: {err}", line + 2))?;
+
+        for &(column, habit_id) in &columns {
+            if record.get(column).map(str::trim) == Some(LOOP_YES_MANUAL) {
+                events.push(Event {
+                    id: events.len() as i32 + 1,
+                    habit_id,
+                    time: date.and_time(chrono::NaiveTime::MIN),
+                    span_part: None,
+                });
+            }
+        }
+    }
+
+    Ok(Export {
+        version: VERSION.to_owned(),
+        habits,
+        events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loop_only_imports_manual_checks() {
+        let csv = "Date,Water,Reading\n2024-08-02,2,1\n2024-08-01,0,2\n";
+        let export = loop_habit_tracker(csv).unwrap();
+
+        let names: Vec<_> = export.habits.iter().map(|habit| &habit.name).collect();
+        assert_eq!(names, vec!["Water", "Reading"]);
+
+        let events: Vec<_> = export
+            .events
+            .iter()
+            .map(|event| (event.habit_id, event.time.date().to_string()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (1, String::from("2024-08-02")),
+                (2, String::from("2024-08-01"))
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_skips_the_empty_column_of_trailing_commas() {
+        let export = loop_habit_tracker(include_str!("../fixtures/loop_checkmarks.csv")).unwrap();
+
+        let names: Vec<_> = export.habits.iter().map(|habit| &habit.name).collect();
+        assert_eq!(names, vec!["Meditate", "Read a book", "Water plants"]);
+        let events: Vec<_> = export
+            .events
+            .iter()
+            .map(|event| (event.habit_id, event.time.date().to_string()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (1, String::from("2024-08-03")),
+                (2, String::from("2024-08-02")),
+                (3, String::from("2024-08-02")),
+                (1, String::from("2024-08-01")),
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_rejects_other_csv() {
+        assert!(loop_habit_tracker("Name,Value\nWater,2\n").is_err());
+        assert!(loop_habit_tracker("Date,Water\nyesterday,2\n").is_err());
+    }
+}
@@ -1,45 +1,22 @@
 use serde::{Deserialize, Serialize};
 
+pub mod color;
+pub mod ical;
+pub mod import;
+pub mod reminders;
+pub mod stats;
+pub mod sync;
+pub mod validation;
+
+pub use color::{Color, ParseColorError};
+
 /// The common version of the project
 ///
 /// I dont bother to update all the cargo files, so this should be considerd the actual version!
 pub const VERSION: &str = "0.0.1";
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
-pub struct Color {
-    pub r: u8,
-    pub g: u8,
-    pub b: u8,
-}
-
-impl Color {
-    pub fn from_hex(hex: &str) -> Option<Self> {
-        if hex.len() != 6 || !hex.is_ascii() {
-            return None;
-        }
-
-        let r = &hex[0..2];
-        let g = &hex[2..4];
-        let b = &hex[4..6];
-
-        let r = u8::from_str_radix(r, 16).ok()?;
-        let g = u8::from_str_radix(g, 16).ok()?;
-        let b = u8::from_str_radix(b, 16).ok()?;
-
-        Some(Color { r, g, b })
-    }
-    pub fn to_hex(&self) -> String {
-        format!("{:02X}{:02X}{:02X}", self.r, self.g, self.b)
-    }
-}
-
-impl From<String> for Color {
-    fn from(value: String) -> Self {
-        Color::from_hex(&value).unwrap_or(Color { r: 0, g: 0, b: 0 })
-    }
-}
-
 #[derive(Serialize, Deserialize, sqlx::Type, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[sqlx(type_name = "habit_kind", rename_all = "lowercase")]
 pub enum HabitKind {
     Habit,
@@ -47,6 +24,7 @@ pub enum HabitKind {
 }
 
 #[derive(Serialize, Deserialize, sqlx::Type, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[sqlx(type_name = "recording_type", rename_all = "lowercase")]
 pub enum RecordingType {
     Point,
@@ -54,6 +32,7 @@ pub enum RecordingType {
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Habit {
     pub id: i32,
     pub name: String,
@@ -63,6 +42,59 @@ pub struct Habit {
     pub every: Option<i32>,
 }
 
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[sqlx(type_name = "span_part", rename_all = "lowercase")]
+pub enum SpanPart {
+    Start,
+    End,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Event {
+    pub id: i32,
+    pub habit_id: i32,
+    pub time: chrono::NaiveDateTime,
+    /// Always `None` for `Point` habits, and always set for `Span` habits
+    pub span_part: Option<SpanPart>,
+}
+
+/// The kind of row a change in the change log refers to
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[sqlx(type_name = "entity_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum EntityKind {
+    Habit,
+    Event,
+}
+
+/// What an outgoing webhook can be subscribed to
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[sqlx(type_name = "webhook_event", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    HabitCreated,
+    HabitUpdated,
+    HabitDeleted,
+    EventRecorded,
+    /// Only sent by the test-fire endpoint
+    Ping,
+}
+
+/// What an API token is allowed to do, `Admin` allows everything
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[sqlx(type_name = "token_scope", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    ReadHabits,
+    WriteEvents,
+    Admin,
+}
+
 impl Habit {
     pub fn as_create(&self) -> api::CreateHabit {
         api::CreateHabit {
@@ -79,7 +111,54 @@ pub mod api {
 
     use super::*;
 
+    /// The version of the HTTP API, served under `/api/v{API_VERSION}`
+    ///
+    /// Only bumped for breaking changes, adding routes or optional fields keeps it.
+    pub const API_VERSION: u32 = 1;
+
+    /// What `GET /api` returns, so clients can check they speak the same API before anything else
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct ServerInfo {
+        /// The `core` version of the server
+        pub version: String,
+        /// Every API version the server has mounted
+        pub api_versions: Vec<u32>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    #[serde(rename_all = "lowercase")]
+    pub enum HealthStatus {
+        Up,
+        Down,
+    }
+
+    /// What `GET /health/live` and `GET /health/ready` return, `503` when `status` is down
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct Health {
+        /// Down when any of the checks is
+        pub status: HealthStatus,
+        /// The `core` version of the server
+        pub version: String,
+        /// By component, like `database` or `migrations`, liveness doesn't check anything
+        pub checks: std::collections::BTreeMap<String, HealthCheck>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct HealthCheck {
+        pub status: HealthStatus,
+        /// How long the check took
+        pub latency_ms: f64,
+        /// Why the component is down
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub error: Option<String>,
+    }
+
     #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
     pub struct CreateHabit {
         pub name: String,
         pub color: Color,
@@ -112,6 +191,318 @@ pub mod api {
         }
     }
 
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct CreateEvent {
+        pub habit_id: i32,
+        pub time: chrono::NaiveDateTime,
+        pub span_part: Option<SpanPart>,
+    }
+
+    impl CreateEvent {
+        pub fn with_id(self, id: i32) -> Event {
+            Event {
+                id,
+                habit_id: self.habit_id,
+                time: self.time,
+                span_part: self.span_part,
+            }
+        }
+    }
+
+    /// The most events a single `POST /events/batch` may hold
+    pub const MAX_BATCH: usize = 1000;
+
+    /// Sent with writes so a retried request is only handled once, the server answers retries
+    /// with the response to the first one
+    pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+    /// Set on responses that are replayed for a retry
+    pub const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+
+    /// Identifies a request in the server logs, clients can send their own and the server always
+    /// answers with the one it used
+    pub const REQUEST_ID: &str = "X-Request-Id";
+
+    /// What errors other than invalid fields are answered with, those send `ValidationErrors`
+    /// with a `request_id` added
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct ErrorBody {
+        pub message: String,
+        /// The `X-Request-Id` of the request, to find it in the server logs
+        pub request_id: String,
+    }
+
+    /// An event of a `POST /events/batch`
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct BatchEvent {
+        #[serde(flatten)]
+        pub event: CreateEvent,
+        /// Sending a key again returns the event recorded the first time instead of a duplicate
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub idempotency_key: Option<String>,
+    }
+
+    impl From<CreateEvent> for BatchEvent {
+        fn from(event: CreateEvent) -> Self {
+            Self {
+                event,
+                idempotency_key: None,
+            }
+        }
+    }
+
+    /// What happened to an event of a batch, in the same order as the request
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    #[serde(tag = "status", rename_all = "lowercase")]
+    pub enum BatchItem {
+        Created {
+            event: Event,
+        },
+        /// The idempotency key was sent before, this is the event that was recorded then
+        Duplicate {
+            event: Event,
+        },
+        Rejected {
+            error: String,
+        },
+    }
+
+    /// Marks a row that has been deleted since the cursor the client synced from
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct Tombstone {
+        pub entity: EntityKind,
+        pub id: i32,
+    }
+
+    /// Everything that changed after the `since` cursor of a sync request
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct SyncResponse {
+        /// Pass this as `since` on the next sync to only get newer changes
+        pub cursor: i64,
+        pub habits: Vec<Habit>,
+        pub events: Vec<Event>,
+        pub tombstones: Vec<Tombstone>,
+    }
+
+    /// A single row that was changed, as pushed to live subscribers
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct Change {
+        pub entity: EntityKind,
+        pub id: i32,
+        pub deleted: bool,
+        /// The sync cursor of this change
+        pub cursor: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    pub enum LiveUpdate {
+        Change(Change),
+        /// The subscriber fell behind and missed changes, it should do a full refresh
+        Lagged,
+    }
+
+    /// Everything in the database, as produced by `GET /export?format=json`
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct Export {
+        /// The `VERSION` of the server that made the export
+        pub version: String,
+        pub habits: Vec<Habit>,
+        pub events: Vec<Event>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    #[serde(rename_all = "lowercase")]
+    pub enum ExportFormat {
+        #[default]
+        Json,
+        /// One row per event, with the habit columns repeated on each row
+        Csv,
+    }
+
+    impl ExportFormat {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                ExportFormat::Json => "json",
+                ExportFormat::Csv => "csv",
+            }
+        }
+    }
+
+    impl std::str::FromStr for ExportFormat {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "json" => Ok(ExportFormat::Json),
+                "csv" => Ok(ExportFormat::Csv),
+                _ => Err(format!("Unknown export format {s:?}, expected json or csv")),
+            }
+        }
+    }
+
+    /// What to do when an imported habit has the same name as an existing one
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    #[serde(rename_all = "lowercase")]
+    pub enum ConflictPolicy {
+        /// Keep the existing habit as is, but add the imported events to it
+        #[default]
+        Merge,
+        /// Leave the existing habit and its events alone, dropping the imported events
+        Skip,
+        /// Replace the existing habit settings with the imported ones, then merge the events
+        Overwrite,
+    }
+
+    impl ConflictPolicy {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                ConflictPolicy::Merge => "merge",
+                ConflictPolicy::Skip => "skip",
+                ConflictPolicy::Overwrite => "overwrite",
+            }
+        }
+    }
+
+    impl std::str::FromStr for ConflictPolicy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "merge" => Ok(ConflictPolicy::Merge),
+                "skip" => Ok(ConflictPolicy::Skip),
+                "overwrite" => Ok(ConflictPolicy::Overwrite),
+                _ => Err(format!(
+                    "Unknown conflict policy {s:?}, expected merge, skip or overwrite"
+                )),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct ImportOptions {
+        /// Report what would be imported without changing anything
+        pub dry_run: bool,
+        pub on_conflict: ConflictPolicy,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct ImportReport {
+        pub dry_run: bool,
+        pub habits_created: Vec<String>,
+        /// Existing habits the events were merged into
+        pub habits_merged: Vec<String>,
+        pub habits_overwritten: Vec<String>,
+        pub habits_skipped: Vec<String>,
+        pub events_created: usize,
+        /// Events that already existed, or belong to a skipped habit
+        pub events_skipped: usize,
+    }
+
+    /// Per-habit reminder settings, times are in the server's local time like events are
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct ReminderSettings {
+        pub quiet_hours: Option<crate::reminders::QuietHours>,
+        pub snoozed_until: Option<chrono::NaiveDateTime>,
+    }
+
+    /// The browser `PushSubscription` as returned by its `toJSON`
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct PushSubscription {
+        pub endpoint: String,
+        pub keys: PushKeys,
+    }
+
+    /// Both base64url encoded
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct PushKeys {
+        pub p256dh: String,
+        pub auth: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct CreateWebhook {
+        pub url: String,
+        pub events: Vec<WebhookEvent>,
+        /// Used to sign every payload, generated when left out
+        pub secret: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct Webhook {
+        pub id: i32,
+        pub url: String,
+        pub events: Vec<WebhookEvent>,
+    }
+
+    /// The secret is only ever shown when the webhook is created
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct NewWebhook {
+        pub webhook: Webhook,
+        pub secret: String,
+    }
+
+    /// One payload sent to a webhook, with the outcome of its latest attempt
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct WebhookDelivery {
+        pub id: i64,
+        pub webhook_id: i32,
+        pub event: WebhookEvent,
+        pub attempts: i32,
+        pub status_code: Option<i32>,
+        pub error: Option<String>,
+        pub succeeded: bool,
+        pub created_at: chrono::NaiveDateTime,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct CreateApiToken {
+        /// What the token is for, like `ci` or `home assistant`
+        pub name: String,
+        pub scopes: Vec<TokenScope>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct ApiToken {
+        pub id: i32,
+        pub name: String,
+        /// The start of the token, to tell them apart
+        pub prefix: String,
+        pub scopes: Vec<TokenScope>,
+        pub created_at: chrono::NaiveDateTime,
+        pub last_used_at: Option<chrono::NaiveDateTime>,
+    }
+
+    /// Only a hash of the secret is stored, so this is the one time it can be seen
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+    pub struct NewApiToken {
+        pub token: ApiToken,
+        pub secret: String,
+    }
+
     impl CreateHabit {
         pub fn with_id(self, id: i32) -> Habit {
             Habit {
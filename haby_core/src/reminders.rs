@@ -0,0 +1,187 @@
+//! When to nudge someone about a habit, shared by the server scheduler and the clients
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+use crate::api::ReminderSettings;
+use crate::{stats, Event, Habit, HabitKind};
+
+/// A part of the day without reminders, wraps around midnight when `end` is before `start`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Parses `22:00-07:00`
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{s:?} is not a range like 22:00-07:00");
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let parse =
+            |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// What every sink sends, as JSON or as text
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Reminder {
+    pub habit_id: i32,
+    pub habit: String,
+    pub due: NaiveDate,
+    pub overdue_days: i64,
+}
+
+impl Reminder {
+    pub fn title(&self) -> String {
+        format!("Time for {}", self.habit)
+    }
+
+    pub fn body(&self) -> String {
+        match self.overdue_days {
+            0 => String::from("Due today"),
+            1 => String::from("Overdue since yesterday"),
+            days => format!("Overdue by {days} days"),
+        }
+    }
+}
+
+/// The reminder to send at `now`, if the habit is due and not snoozed or in its quiet hours
+///
+/// Only habits with a schedule get reminders, a habit that was never done is due right away.
+pub fn due<'a>(
+    habit: &Habit,
+    events: impl IntoIterator<Item = &'a Event>,
+    settings: &ReminderSettings,
+    now: NaiveDateTime,
+) -> Option<Reminder> {
+    if habit.kind == HabitKind::Addiction || habit.every.is_none() {
+        return None;
+    }
+
+    let today = now.date();
+    let due = stats::next_due(habit, events).unwrap_or(today);
+    if due > today {
+        return None;
+    }
+    if settings.snoozed_until.is_some_and(|until| now < until) {
+        return None;
+    }
+    if settings
+        .quiet_hours
+        .is_some_and(|quiet| quiet.contains(now.time()))
+    {
+        return None;
+    }
+
+    Some(Reminder {
+        habit_id: habit.id,
+        habit: habit.name.clone(),
+        due,
+        overdue_days: (today - due).num_days(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::CreateHabit;
+
+    fn time(time: &str) -> NaiveDateTime {
+        time.parse().unwrap()
+    }
+
+    fn every_day() -> Habit {
+        CreateHabit {
+            name: String::from("Read"),
+            every: Some(1),
+            ..Default::default()
+        }
+        .with_id(1)
+    }
+
+    fn done_at(time_: &str) -> Event {
+        Event {
+            id: 1,
+            habit_id: 1,
+            time: time(time_),
+            span_part: None,
+        }
+    }
+
+    #[test]
+    fn quiet_hours_wrap_midnight() {
+        let quiet: QuietHours = "22:00-07:00".parse().unwrap();
+        assert!(quiet.contains(NaiveTime::from_hms_opt(23, 30, 0).unwrap()));
+        assert!(quiet.contains(NaiveTime::from_hms_opt(6, 59, 0).unwrap()));
+        assert!(!quiet.contains(NaiveTime::from_hms_opt(7, 0, 0).unwrap()));
+        assert!(!quiet.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        assert_eq!(quiet.to_string(), "22:00-07:00");
+    }
+
+    #[test]
+    fn due_once_every_passed() {
+        let habit = every_day();
+        let events = [done_at("2024-08-10T09:00:00")];
+        let settings = ReminderSettings::default();
+
+        assert_eq!(
+            due(&habit, &events, &settings, time("2024-08-10T20:00:00")),
+            None
+        );
+
+        let reminder = due(&habit, &events, &settings, time("2024-08-13T08:00:00")).unwrap();
+        assert_eq!(reminder.due, "2024-08-11".parse().unwrap());
+        assert_eq!(reminder.overdue_days, 2);
+    }
+
+    #[test]
+    fn snooze_and_quiet_hours_hold_reminders() {
+        let habit = every_day();
+        let now = time("2024-08-13T23:00:00");
+        let snoozed = ReminderSettings {
+            snoozed_until: Some(time("2024-08-13T23:30:00")),
+            ..Default::default()
+        };
+        let quiet = ReminderSettings {
+            quiet_hours: Some("22:00-07:00".parse().unwrap()),
+            ..Default::default()
+        };
+
+        assert!(due(&habit, &[], &ReminderSettings::default(), now).is_some());
+        assert_eq!(due(&habit, &[], &snoozed, now), None);
+        assert_eq!(due(&habit, &[], &quiet, now), None);
+    }
+}
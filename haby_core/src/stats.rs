@@ -0,0 +1,205 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::{Event, Habit, HabitKind, SpanPart};
+
+/// For habits this counts check-ins, for addictions it counts days without an event
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Streak {
+    pub current: u32,
+    pub longest: u32,
+}
+
+/// The days the habit has events on, sorted and without duplicates
+///
+/// Spans count on the day they started.
+fn active_days<'a>(habit: &Habit, events: impl IntoIterator<Item = &'a Event>) -> Vec<NaiveDate> {
+    let mut days: Vec<_> = events
+        .into_iter()
+        .filter(|event| event.habit_id == habit.id && event.span_part != Some(SpanPart::End))
+        .map(|event| event.time.date())
+        .collect();
+    days.sort();
+    days.dedup();
+    days
+}
+
+/// Calculate the streak of a habit as of `today`
+///
+/// A habit keeps its streak as long as there are never more than `every` days (or one day if it
+/// has no schedule) between check-ins, including between the last check-in and `today`.
+pub fn streak<'a>(
+    habit: &Habit,
+    events: impl IntoIterator<Item = &'a Event>,
+    today: NaiveDate,
+) -> Streak {
+    let days = active_days(habit, events);
+    let Some(last) = days.last() else {
+        return Streak::default();
+    };
+
+    match habit.kind {
+        HabitKind::Habit => {
+            let allowed_gap = i64::from(habit.every.unwrap_or(1));
+
+            let mut longest = 0;
+            let mut run = 0;
+            let mut previous: Option<NaiveDate> = None;
+            for day in &days {
+                run = match previous {
+                    Some(previous) if (*day - previous).num_days() <= allowed_gap => run + 1,
+                    _ => 1,
+                };
+                longest = longest.max(run);
+                previous = Some(*day);
+            }
+
+            let current = if (today - *last).num_days() <= allowed_gap {
+                run
+            } else {
+                0
+            };
+            Streak { current, longest }
+        }
+        HabitKind::Addiction => {
+            let current = (today - *last).num_days().max(0) as u32;
+            let longest = days
+                .windows(2)
+                .map(|pair| ((pair[1] - pair[0]).num_days() - 1) as u32)
+                .fold(current, u32::max);
+            Streak { current, longest }
+        }
+    }
+}
+
+/// The day the habit should be done next, `None` for habits without a schedule
+pub fn next_due<'a>(
+    habit: &Habit,
+    events: impl IntoIterator<Item = &'a Event>,
+) -> Option<NaiveDate> {
+    let every = habit.every?;
+    let last = *active_days(habit, events).last()?;
+    Some(last + chrono::Days::new(every as u64))
+}
+
+/// When the span of the habit that is still running was started, if there is one
+pub fn running_since<'a>(
+    habit: &Habit,
+    events: impl IntoIterator<Item = &'a Event>,
+) -> Option<NaiveDateTime> {
+    let last = events
+        .into_iter()
+        .filter(|event| event.habit_id == habit.id)
+        .max_by_key(|event| (event.time, event.id))?;
+    (last.span_part == Some(SpanPart::Start)).then_some(last.time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::CreateHabit;
+
+    fn events(days: &[&str]) -> Vec<Event> {
+        days.iter()
+            .zip(1..)
+            .map(|(day, id)| Event {
+                id,
+                habit_id: 1,
+                time: day
+                    .parse::<NaiveDate>()
+                    .unwrap()
+                    .and_hms_opt(12, 0, 0)
+                    .unwrap(),
+                span_part: None,
+            })
+            .collect()
+    }
+
+    fn day(day: &str) -> NaiveDate {
+        day.parse().unwrap()
+    }
+
+    #[test]
+    fn daily_streak() {
+        let habit = CreateHabit::default().with_id(1);
+        let events = events(&["2024-08-01", "2024-08-02", "2024-08-04", "2024-08-05"]);
+
+        assert_eq!(
+            streak(&habit, &events, day("2024-08-06")),
+            Streak {
+                current: 2,
+                longest: 2
+            }
+        );
+        assert_eq!(streak(&habit, &events, day("2024-08-07")).current, 0);
+    }
+
+    #[test]
+    fn streak_respects_every() {
+        let habit = CreateHabit {
+            every: Some(3),
+            ..Default::default()
+        }
+        .with_id(1);
+        let events = events(&["2024-08-01", "2024-08-04", "2024-08-06"]);
+
+        assert_eq!(
+            streak(&habit, &events, day("2024-08-09")),
+            Streak {
+                current: 3,
+                longest: 3
+            }
+        );
+    }
+
+    #[test]
+    fn addiction_counts_clean_days() {
+        let habit = CreateHabit {
+            kind: HabitKind::Addiction,
+            ..Default::default()
+        }
+        .with_id(1);
+        let events = events(&["2024-08-01", "2024-08-10", "2024-08-12"]);
+
+        assert_eq!(
+            streak(&habit, &events, day("2024-08-15")),
+            Streak {
+                current: 3,
+                longest: 8
+            }
+        );
+    }
+
+    #[test]
+    fn no_events_no_streak() {
+        let habit = CreateHabit::default().with_id(1);
+        assert_eq!(streak(&habit, &[], day("2024-08-15")), Streak::default());
+    }
+
+    #[test]
+    fn due_after_every_days() {
+        let habit = CreateHabit {
+            every: Some(2),
+            ..Default::default()
+        }
+        .with_id(1);
+        let events = events(&["2024-08-01", "2024-08-03"]);
+        assert_eq!(next_due(&habit, &events), Some(day("2024-08-05")));
+    }
+
+    #[test]
+    fn running_until_ended() {
+        let habit = CreateHabit {
+            recording_type: crate::RecordingType::Span,
+            ..Default::default()
+        }
+        .with_id(1);
+        let mut events = events(&["2024-08-01", "2024-08-02"]);
+        events[0].span_part = Some(SpanPart::Start);
+        events[1].span_part = Some(SpanPart::Start);
+        assert_eq!(running_since(&habit, &events), Some(events[1].time));
+
+        events[1].span_part = Some(SpanPart::End);
+        assert_eq!(running_since(&habit, &events), None);
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::SyncResponse;
+use crate::{EntityKind, Event, Habit};
+
+/// A local copy of the server data, kept up to date by applying sync responses
+///
+/// Serializable so clients can persist it between runs and keep syncing incrementally.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct Replica {
+    /// The cursor of the last applied sync, `0` means nothing has been synced yet
+    pub cursor: i64,
+    pub habits: BTreeMap<i32, Habit>,
+    pub events: BTreeMap<i32, Event>,
+}
+
+impl Replica {
+    pub fn apply(&mut self, changes: SyncResponse) {
+        for habit in changes.habits {
+            self.habits.insert(habit.id, habit);
+        }
+        for event in changes.events {
+            self.events.insert(event.id, event);
+        }
+
+        for tombstone in changes.tombstones {
+            match tombstone.entity {
+                EntityKind::Habit => {
+                    self.habits.remove(&tombstone.id);
+                    // Events can not outlive their habit, mirroring the `ON DELETE CASCADE`
+                    self.events
+                        .retain(|_, event| event.habit_id != tombstone.id);
+                }
+                EntityKind::Event => {
+                    self.events.remove(&tombstone.id);
+                }
+            }
+        }
+
+        self.cursor = self.cursor.max(changes.cursor);
+    }
+
+    pub fn habits(&self) -> impl Iterator<Item = &Habit> {
+        self.habits.values()
+    }
+
+    pub fn events_for(&self, habit_id: i32) -> impl Iterator<Item = &Event> {
+        self.events
+            .values()
+            .filter(move |event| event.habit_id == habit_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{CreateHabit, Tombstone};
+
+    fn event(id: i32, habit_id: i32) -> Event {
+        Event {
+            id,
+            habit_id,
+            time: chrono::NaiveDateTime::default(),
+            span_part: None,
+        }
+    }
+
+    #[test]
+    fn apply_upserts() {
+        let mut replica = Replica::default();
+        let habit = CreateHabit::default().with_id(1);
+        replica.apply(SyncResponse {
+            cursor: 1,
+            habits: vec![habit.clone()],
+            ..Default::default()
+        });
+
+        let mut renamed = habit.clone();
+        renamed.name = String::from("Renamed");
+        replica.apply(SyncResponse {
+            cursor: 2,
+            habits: vec![renamed.clone()],
+            ..Default::default()
+        });
+
+        assert_eq!(replica.cursor, 2);
+        assert_eq!(replica.habits().collect::<Vec<_>>(), vec![&renamed]);
+    }
+
+    #[test]
+    fn habit_tombstone_removes_its_events() {
+        let mut replica = Replica::default();
+        replica.apply(SyncResponse {
+            cursor: 3,
+            habits: vec![
+                CreateHabit::default().with_id(1),
+                CreateHabit::default().with_id(2),
+            ],
+            events: vec![event(1, 1), event(2, 2)],
+            tombstones: vec![],
+        });
+        replica.apply(SyncResponse {
+            cursor: 4,
+            tombstones: vec![Tombstone {
+                entity: EntityKind::Habit,
+                id: 1,
+            }],
+            ..Default::default()
+        });
+
+        assert_eq!(replica.habits.keys().collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(replica.events.keys().collect::<Vec<_>>(), vec![&2]);
+    }
+
+    #[test]
+    fn cursor_never_goes_backwards() {
+        let mut replica = Replica {
+            cursor: 10,
+            ..Default::default()
+        };
+        replica.apply(SyncResponse::default());
+        assert_eq!(replica.cursor, 10);
+    }
+}
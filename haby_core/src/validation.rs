@@ -0,0 +1,215 @@
+//! Checks for habits people type in, shared by the server and the frontend form so both reject
+//! the same things
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::CreateHabit;
+use crate::Habit;
+
+/// In characters, after trimming
+pub const MAX_NAME_LENGTH: usize = 100;
+
+/// `every` is in days, so a habit can be at most a year apart
+pub const MAX_EVERY: i32 = 365;
+
+/// The message for a name that is already in use, the server sends it when the database says so
+pub const NAME_TAKEN: &str = "There already is a habit with this name";
+
+/// What is wrong with one field of the input
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FieldError {
+    /// The field name, like `name`, or a path like `habits[2].name` for nested input
+    pub field: String,
+    pub message: String,
+}
+
+/// The body of a `422 Unprocessable Entity`, every problem is listed instead of just the first
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// The first problem with `field`, handy to show next to a form input
+    pub fn field(&self, field: &str) -> Option<&str> {
+        self.errors
+            .iter()
+            .find(|error| error.field == field)
+            .map(|error| error.message.as_str())
+    }
+
+    /// Prefix every field with `path`, for errors of nested input
+    pub fn nested(self, path: &str) -> Self {
+        Self {
+            errors: self
+                .errors
+                .into_iter()
+                .map(|error| FieldError {
+                    field: format!("{path}.{}", error.field),
+                    message: error.message,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// What names are compared by, two habits can't have names that only differ in case or
+/// surrounding whitespace
+pub fn name_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+impl CreateHabit {
+    /// The habit as it should be stored, with the whitespace around the name removed
+    pub fn normalized(mut self) -> Self {
+        let trimmed = self.name.trim();
+        if trimmed.len() != self.name.len() {
+            self.name = trimmed.to_owned();
+        }
+        self
+    }
+
+    /// Check every field on its own, the server also checks the name is not taken
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        let name = self.name.trim();
+        if name.is_empty() {
+            errors.add("name", "The name can't be empty");
+        } else if name.chars().count() > MAX_NAME_LENGTH {
+            errors.add(
+                "name",
+                format!("The name can be at most {MAX_NAME_LENGTH} characters long"),
+            );
+        } else if name.chars().any(char::is_control) {
+            errors.add("name", "The name can't contain control characters");
+        }
+
+        if let Some(every) = self.every {
+            if !(1..=MAX_EVERY).contains(&every) {
+                errors.add(
+                    "every",
+                    format!("Every has to be between 1 and {MAX_EVERY} days"),
+                );
+            }
+        }
+
+        errors.into_result()
+    }
+
+    /// Like [`Self::validate`], and the name may not be used by any of `habits` other than the
+    /// one with `id`, which is being updated
+    pub fn validate_among(
+        &self,
+        habits: &[Habit],
+        id: Option<i32>,
+    ) -> Result<(), ValidationErrors> {
+        let mut errors = self.validate().err().unwrap_or_default();
+
+        let key = name_key(&self.name);
+        let taken = habits
+            .iter()
+            .any(|habit| Some(habit.id) != id && name_key(&habit.name) == key);
+        if taken && errors.field("name").is_none() {
+            errors.add("name", NAME_TAKEN);
+        }
+
+        errors.into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str) -> CreateHabit {
+        CreateHabit {
+            name: String::from(name),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn names_are_trimmed_and_checked() {
+        assert_eq!(named("  Run ").normalized().name, "Run");
+        assert!(named(" Run ").validate().is_ok());
+
+        for name in ["", "   ", "a\u{0}b"] {
+            let errors = named(name).validate().unwrap_err();
+            assert!(errors.field("name").is_some(), "{name:?}");
+        }
+        let long = "x".repeat(MAX_NAME_LENGTH + 1);
+        assert!(named(&long).validate().is_err());
+        assert!(named(&"ö".repeat(MAX_NAME_LENGTH)).validate().is_ok());
+    }
+
+    #[test]
+    fn every_is_in_range() {
+        for (every, valid) in [
+            (None, true),
+            (Some(1), true),
+            (Some(0), false),
+            (Some(-3), false),
+        ] {
+            let habit = CreateHabit {
+                every,
+                ..Default::default()
+            };
+            assert_eq!(habit.validate().is_ok(), valid, "{every:?}");
+        }
+
+        let habit = CreateHabit {
+            name: String::new(),
+            every: Some(MAX_EVERY + 1),
+            ..Default::default()
+        };
+        assert_eq!(habit.validate().unwrap_err().errors.len(), 2);
+    }
+
+    #[test]
+    fn names_are_unique_ignoring_case() {
+        let habits = vec![named("Run").with_id(1), named("Read").with_id(2)];
+
+        let errors = named(" run").validate_among(&habits, None).unwrap_err();
+        assert_eq!(errors.field("name"), Some(NAME_TAKEN));
+        assert!(named("RUN").validate_among(&habits, Some(1)).is_ok());
+        assert!(named("Swim").validate_among(&habits, None).is_ok());
+    }
+}
@@ -0,0 +1,22 @@
+// Shows the reminders the server pushes, see `WebPush` in haby_server
+self.addEventListener("push", (event) => {
+  const { title, body, reminder } = event.data.json();
+  event.waitUntil(
+    self.registration.showNotification(title, {
+      body,
+      tag: `habit-${reminder.habit_id}`,
+    }),
+  );
+});
+
+self.addEventListener("notificationclick", (event) => {
+  event.notification.close();
+  event.waitUntil(
+    self.clients.matchAll({ type: "window" }).then((windows) => {
+      if (windows.length > 0) {
+        return windows[0].focus();
+      }
+      return self.clients.openWindow("/");
+    }),
+  );
+});
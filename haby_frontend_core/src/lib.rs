@@ -1,6 +1,10 @@
 use std::rc::Rc;
 
+use futures::future::abortable;
+use futures::StreamExt;
 use haby_api_wrapper::core;
+use haby_api_wrapper::core::validation::ValidationErrors;
+use haby_api_wrapper::RetryPolicy;
 use leptos::{
     component,
     create_action,
@@ -11,7 +15,9 @@ use leptos::{
     event_target_value,
     expect_context,
     mount_to_body,
+    on_cleanup,
     provide_context,
+    spawn_local,
     view,
     For,
     IntoView,
@@ -24,7 +30,10 @@ use leptos::{
     SignalUpdate,
     SignalWith,
     Transition,
+    WriteSignal,
 };
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
 
 fn get_client() -> Rc<haby_api_wrapper::ApiWrapper> {
     expect_context()
@@ -75,7 +84,7 @@ fn HabitCreator(habits_resource: Resource<(), Vec<core::Habit>>) -> impl IntoVie
                     });
                     Ok(())
                 }
-                Err(err) => Err(err),
+                Err(err) => Err(err.to_string()),
             }
         }
     });
@@ -86,26 +95,180 @@ fn HabitCreator(habits_resource: Resource<(), Vec<core::Habit>>) -> impl IntoVie
         |habit, name| habit.name = name,
     );
 
+    // Checked here first with the same rules as the server, which can still reject the habit
+    let (errors, set_errors) = create_signal(ValidationErrors::default());
+    let create = move |_| {
+        let habits = habits_resource.get().unwrap_or_default();
+        // There is no color picker yet, so every new habit gets the next palette color
+        let habit = core::api::CreateHabit {
+            color: core::color::next_color(&habits),
+            ..habit()
+        };
+        match habit.validate_among(&habits, None) {
+            Ok(()) => {
+                set_errors(ValidationErrors::default());
+                insert_habit.dispatch(habit);
+            }
+            Err(invalid) => set_errors(invalid),
+        }
+    };
+    // Field errors are sent as JSON, anything else like a rate limit is shown as it is
+    let server_errors = move || match insert_habit.value().get() {
+        Some(Err(err)) => serde_json::from_str::<ValidationErrors>(&err).map_err(|_| err),
+        _ => Ok(ValidationErrors::default()),
+    };
+    let field_error = move |field: &'static str| {
+        move || {
+            errors
+                .with(|errors| errors.field(field).map(String::from))
+                .or_else(|| server_errors().ok()?.field(field).map(String::from))
+        }
+    };
+    let other_error = move || server_errors().err();
+
     view! {
         <h2>Create New Habit</h2>
 
-        <TextInput getter=name setter=set_name /> <br />
+        <TextInput getter=name setter=set_name />
+        <span class="error">{field_error("name")}</span>
+        <br />
+        <span class="error">{field_error("every")}</span>
+        <br />
+        <span class="error">{other_error}</span>
 
         <DebugPrint obj=habit/>
         <DebugPrint obj=insert_habit.value()/>
-        <button on:click=move |_| insert_habit.dispatch(habit())>Create</button>
+        <button on:click=create>Create</button>
+    }
+}
+
+#[component]
+fn ExportLinks() -> impl IntoView {
+    let client = get_client();
+    let json = client.export_url(core::api::ExportFormat::Json);
+    let csv = client.export_url(core::api::ExportFormat::Csv);
+
+    view! {
+        <a href=json download="">Export JSON</a>
+        " "
+        <a href=csv download="">Export CSV</a>
+        <br/>
+    }
+}
+
+/// Subscribe this browser to push reminders, see `habySubscribePush` in `index.html`
+async fn enable_push() -> Result<(), String> {
+    let client = get_client();
+    let key = client
+        .push_key()
+        .await?
+        .ok_or_else(|| String::from("The server has push reminders turned off"))?;
+
+    let js_error = |err: JsValue| format!("{err:?}");
+    let window = leptos::window();
+    let subscribe: js_sys::Function =
+        js_sys::Reflect::get(&window, &JsValue::from_str("habySubscribePush"))
+            .map_err(js_error)?
+            .dyn_into()
+            .map_err(js_error)?;
+    let promise: js_sys::Promise = subscribe
+        .call1(&window, &JsValue::from_str(&key))
+        .map_err(js_error)?
+        .unchecked_into();
+    let subscription = JsFuture::from(promise)
+        .await
+        .map_err(js_error)?
+        .as_string()
+        .unwrap_or_default();
+
+    let subscription = serde_json::from_str(&subscription).map_err(|err| err.to_string())?;
+    client.add_push_subscription(&subscription).await?;
+    Ok(())
+}
+
+#[component]
+fn PushToggle() -> impl IntoView {
+    let enable = create_action(|_: &()| enable_push());
+
+    view! {
+        <button on:click=move |_| enable.dispatch(())>Enable reminders</button>
+        {move || {
+            enable
+                .value()
+                .get()
+                .map(|result| match result {
+                    Ok(()) => String::from(" Reminders enabled"),
+                    Err(err) => format!(" {err}"),
+                })
+        }}
+        <br/>
+    }
+}
+
+/// Refetch `habits` on every change, reconnecting with backoff whenever the connection is lost
+async fn follow_updates(
+    client: Rc<haby_api_wrapper::ApiWrapper>,
+    habits: Resource<(), Vec<core::Habit>>,
+    set_live_error: WriteSignal<Option<String>>,
+) {
+    let policy = RetryPolicy::default();
+    let mut retry = 0;
+    let mut connected_before = false;
+    loop {
+        match client.subscribe().await {
+            Ok(updates) => {
+                set_live_error(None);
+                retry = 0;
+                // Whatever changed while disconnected was missed
+                if connected_before {
+                    habits.refetch();
+                }
+                connected_before = true;
+
+                let mut updates = std::pin::pin!(updates);
+                while updates.next().await.is_some() {
+                    habits.refetch();
+                }
+                set_live_error(Some(String::from("Live updates were lost, reconnecting")));
+            }
+            Err(err) => set_live_error(Some(format!("Live updates failed, retrying: {err}"))),
+        }
+        policy.wait(retry).await;
+        retry = retry.saturating_add(1);
     }
 }
 
 #[component]
 fn HabitList() -> impl IntoView {
+    // Kept as a plain list so the creator can add to it, failing to load is shown next to it
+    let (load_error, set_load_error) = create_signal(None::<String>);
     let habits = create_local_resource(
         move || (),
-        |_| async move { get_client().get_habits().await },
+        move |_| async move {
+            match get_client().get_habits().await {
+                Ok(habits) => {
+                    set_load_error(None);
+                    habits
+                }
+                Err(err) => {
+                    set_load_error(Some(err.to_string()));
+                    Vec::new()
+                }
+            }
+        },
     );
     let (show_creator, update_show_creator) = create_signal(false);
 
+    let (live_error, set_live_error) = create_signal(None::<String>);
+    let (follow, stop) = abortable(follow_updates(get_client(), habits, set_live_error));
+    spawn_local(async move {
+        let _ = follow.await;
+    });
+    on_cleanup(move || stop.abort());
+
     view! {
+        <ExportLinks/>
+        <PushToggle/>
         <button on:click=move |_| update_show_creator(true) disabled=move || show_creator>
             New
         </button>
@@ -114,6 +277,8 @@ fn HabitList() -> impl IntoView {
         </Show>
         <br/>
         <h1>Habit List</h1>
+        <span class="error">{load_error}</span>
+        <span class="error">{live_error}</span>
         <Transition fallback=move || {
             view! { "loading..." }
         }>
@@ -130,15 +295,23 @@ fn HabitList() -> impl IntoView {
 
 #[component]
 fn DebugPage() -> impl IntoView {
-    let version = create_local_resource(
+    let server = create_local_resource(
         move || (),
-        |_| async move { get_client().get_version().await },
+        |_| async move {
+            let client = get_client();
+            match client.check_compatibility().await {
+                Ok(info) => format!(
+                    "Backend-Core version is: {} (API versions {:?})",
+                    info.version, info.api_versions
+                ),
+                Err(err) => err.to_string(),
+            }
+        },
     );
 
     view! {
         <h2>
-            Backend-Core version is: {move || version} <br/> Frontend-Core version is:
-            {haby_api_wrapper::VERSION}
+            {move || server} <br/> Frontend-Core version is: {haby_api_wrapper::VERSION}
         </h2>
     }
 }
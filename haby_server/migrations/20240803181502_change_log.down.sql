@@ -0,0 +1,9 @@
+DROP TRIGGER IF EXISTS record_event_change ON events;
+DROP TRIGGER IF EXISTS record_habit_change ON habits;
+DROP FUNCTION IF EXISTS record_change();
+
+DROP INDEX IF EXISTS idx_changes_seq;
+DROP TABLE IF EXISTS "changes";
+DROP SEQUENCE IF EXISTS change_seq;
+
+DROP TYPE IF EXISTS entity_kind;
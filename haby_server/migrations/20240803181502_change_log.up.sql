@@ -0,0 +1,42 @@
+CREATE TYPE entity_kind AS ENUM ('habit', 'event');
+
+CREATE SEQUENCE change_seq;
+
+--- Only the latest change of each row is kept, deletes are kept as tombstones
+CREATE TABLE "changes" (
+    entity entity_kind NOT NULL,
+    entity_id INTEGER NOT NULL,
+    seq BIGINT NOT NULL DEFAULT nextval('change_seq'),
+    deleted BOOLEAN NOT NULL,
+    PRIMARY KEY (entity, entity_id)
+);
+
+CREATE INDEX idx_changes_seq ON changes(seq);
+
+CREATE OR REPLACE FUNCTION record_change()
+RETURNS TRIGGER AS $$
+DECLARE
+    row_id INTEGER;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        row_id := OLD.id;
+    ELSE
+        row_id := NEW.id;
+    END IF;
+
+    INSERT INTO changes (entity, entity_id, deleted)
+    VALUES (TG_ARGV[0]::entity_kind, row_id, TG_OP = 'DELETE')
+    ON CONFLICT (entity, entity_id)
+    DO UPDATE SET seq = EXCLUDED.seq, deleted = EXCLUDED.deleted;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER record_habit_change
+AFTER INSERT OR UPDATE OR DELETE ON habits
+FOR EACH ROW EXECUTE FUNCTION record_change('habit');
+
+CREATE TRIGGER record_event_change
+AFTER INSERT OR UPDATE OR DELETE ON events
+FOR EACH ROW EXECUTE FUNCTION record_change('event');
@@ -0,0 +1,19 @@
+CREATE OR REPLACE FUNCTION record_change()
+RETURNS TRIGGER AS $$
+DECLARE
+    row_id INTEGER;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        row_id := OLD.id;
+    ELSE
+        row_id := NEW.id;
+    END IF;
+
+    INSERT INTO changes (entity, entity_id, deleted)
+    VALUES (TG_ARGV[0]::entity_kind, row_id, TG_OP = 'DELETE')
+    ON CONFLICT (entity, entity_id)
+    DO UPDATE SET seq = EXCLUDED.seq, deleted = EXCLUDED.deleted;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,29 @@
+--- Same as before, but also announces the change to anyone listening on `haby_changes`
+CREATE OR REPLACE FUNCTION record_change()
+RETURNS TRIGGER AS $$
+DECLARE
+    row_id INTEGER;
+    change_seq_value BIGINT;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        row_id := OLD.id;
+    ELSE
+        row_id := NEW.id;
+    END IF;
+
+    INSERT INTO changes (entity, entity_id, deleted)
+    VALUES (TG_ARGV[0]::entity_kind, row_id, TG_OP = 'DELETE')
+    ON CONFLICT (entity, entity_id)
+    DO UPDATE SET seq = EXCLUDED.seq, deleted = EXCLUDED.deleted
+    RETURNING seq INTO change_seq_value;
+
+    PERFORM pg_notify('haby_changes', json_build_object(
+        'entity', TG_ARGV[0],
+        'id', row_id,
+        'deleted', TG_OP = 'DELETE',
+        'cursor', change_seq_value
+    )::text);
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
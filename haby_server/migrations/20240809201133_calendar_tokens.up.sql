@@ -0,0 +1,5 @@
+--- Secret tokens that give read only access to the calendar feed
+CREATE TABLE "calendar_tokens" (
+    token TEXT PRIMARY KEY DEFAULT replace(gen_random_uuid()::text, '-', ''),
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,17 @@
+--- One row per habit once its reminders are configured or one was sent
+CREATE TABLE "reminder_settings" (
+    habit_id INTEGER PRIMARY KEY REFERENCES habits(id) ON DELETE CASCADE,
+    quiet_start TIME,
+    quiet_end TIME,
+    snoozed_until TIMESTAMP,
+    --- So a habit is only reminded about once a day
+    last_sent DATE,
+    CHECK ((quiet_start IS NULL) = (quiet_end IS NULL))
+);
+
+CREATE TABLE "push_subscriptions" (
+    endpoint TEXT PRIMARY KEY,
+    p256dh TEXT NOT NULL,
+    auth TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,34 @@
+CREATE TYPE webhook_event AS ENUM (
+    'habit_created',
+    'habit_updated',
+    'habit_deleted',
+    'event_recorded',
+    'ping'
+);
+
+CREATE TABLE "webhooks" (
+    id SERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    events webhook_event[] NOT NULL,
+    secret TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+--- Updated after every attempt, so it always shows the latest outcome
+CREATE TABLE "webhook_deliveries" (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id INTEGER NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+    event webhook_event NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    status_code INTEGER,
+    error TEXT,
+    succeeded BOOLEAN NOT NULL DEFAULT false,
+    --- When the worker tries again, NULL once it succeeded or gave up
+    next_attempt_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX idx_webhook_deliveries_webhook_id ON webhook_deliveries(webhook_id, id);
+CREATE INDEX idx_webhook_deliveries_next_attempt_at ON webhook_deliveries(next_attempt_at)
+    WHERE next_attempt_at IS NOT NULL;
@@ -0,0 +1,12 @@
+CREATE TYPE token_scope AS ENUM ('read_habits', 'write_events', 'admin');
+
+CREATE TABLE "api_tokens" (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    --- The first few characters of the token, the rest is only kept as a hash
+    prefix TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    scopes token_scope[] NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMP
+);
@@ -0,0 +1,2 @@
+--- Set by batch uploads, so retrying a batch does not record its events twice
+ALTER TABLE "events" ADD COLUMN idempotency_key TEXT UNIQUE;
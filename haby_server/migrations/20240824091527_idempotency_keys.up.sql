@@ -0,0 +1,15 @@
+--- Responses to requests sent with an `Idempotency-Key` header, so retries get the same answer
+CREATE TABLE "idempotency_keys" (
+    key TEXT PRIMARY KEY,
+    --- The method, path and credentials of the first request, a key can't be reused for another
+    fingerprint TEXT NOT NULL,
+    --- The response is empty while the first request is still being handled
+    status SMALLINT,
+    content_type TEXT,
+    body BYTEA,
+    --- A claim without a response is taken over after a while, its request may have died
+    claimed_at TIMESTAMP NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_idempotency_keys_expires_at ON idempotency_keys (expires_at);
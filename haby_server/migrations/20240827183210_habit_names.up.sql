@@ -0,0 +1,28 @@
+--- Names are unique regardless of case now, older duplicates get a number appended to stay apart.
+--- The number is the lowest that makes a free name, and the name is cut short to make room for it
+--- within the 100 characters names can have.
+DO $$
+DECLARE
+    duplicate RECORD;
+    n INTEGER;
+    suffix TEXT;
+    renamed TEXT;
+BEGIN
+    FOR duplicate IN
+        SELECT h.id, h.name
+        FROM habits h
+        WHERE EXISTS (SELECT 1 FROM habits o WHERE lower(o.name) = lower(h.name) AND o.id < h.id)
+        ORDER BY h.id
+    LOOP
+        n := 2;
+        LOOP
+            suffix := ' (' || n || ')';
+            renamed := left(duplicate.name, 100 - length(suffix)) || suffix;
+            EXIT WHEN NOT EXISTS (SELECT 1 FROM habits WHERE lower(name) = lower(renamed));
+            n := n + 1;
+        END LOOP;
+        UPDATE habits SET name = renamed WHERE id = duplicate.id;
+    END LOOP;
+END $$;
+
+CREATE UNIQUE INDEX idx_habits_name_lower ON habits (lower(name));
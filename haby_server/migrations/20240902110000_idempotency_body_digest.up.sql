@@ -0,0 +1,3 @@
+--- A SHA-256 of the body of the first request, a key can't be reused with another body. Empty
+--- when the route never read a body.
+ALTER TABLE "idempotency_keys" ADD COLUMN body_digest TEXT;
@@ -0,0 +1,3 @@
+--- Picked by the request holding the key, so a request whose claim was taken over can't store
+--- its response or release the key anymore
+ALTER TABLE "idempotency_keys" ADD COLUMN claim TEXT;
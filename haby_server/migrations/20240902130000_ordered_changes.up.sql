@@ -0,0 +1,34 @@
+--- Same as `notify_changes`, but changes commit in the order of their sequence numbers
+CREATE OR REPLACE FUNCTION record_change()
+RETURNS TRIGGER AS $$
+DECLARE
+    row_id INTEGER;
+    change_seq_value BIGINT;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        row_id := OLD.id;
+    ELSE
+        row_id := NEW.id;
+    END IF;
+
+    --- Sequence values are handed out when a change is written but become visible when the
+    --- transaction commits, so a sync could see a later change before an earlier one and move
+    --- its cursor past it. Holding this lock until the commit makes them commit in order.
+    PERFORM pg_advisory_xact_lock(hashtext('changes'));
+
+    INSERT INTO changes (entity, entity_id, deleted)
+    VALUES (TG_ARGV[0]::entity_kind, row_id, TG_OP = 'DELETE')
+    ON CONFLICT (entity, entity_id)
+    DO UPDATE SET seq = EXCLUDED.seq, deleted = EXCLUDED.deleted
+    RETURNING seq INTO change_seq_value;
+
+    PERFORM pg_notify('haby_changes', json_build_object(
+        'entity', TG_ARGV[0],
+        'id', row_id,
+        'deleted', TG_OP = 'DELETE',
+        'cursor', change_seq_value
+    )::text);
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,128 @@
+--- The same schema as the Postgres migrations up to `habit_names`
+---
+--- SQLite has no enums, arrays or triggers in plpgsql. Enums are checked text, arrays are JSON
+--- arrays, and what the triggers did (checking span parts, deleting events when the recording
+--- type changes and writing the change log) is done by the server in the same transaction.
+
+CREATE TABLE "habits" (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    color TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('habit', 'addiction')),
+    recording_type TEXT NOT NULL CHECK (recording_type IN ('point', 'span')),
+    every INTEGER CHECK (every > 0)
+);
+
+--- `lower` only folds ASCII in SQLite, unlike in Postgres
+CREATE UNIQUE INDEX idx_habits_name_lower ON habits (lower(name));
+
+CREATE TABLE "events" (
+    id INTEGER PRIMARY KEY,
+    habit_id INTEGER NOT NULL REFERENCES habits(id) ON DELETE CASCADE,
+    time DATETIME NOT NULL,
+    span_part TEXT CHECK (span_part IN ('start', 'end')),
+    --- Set by batch uploads, so retrying a batch does not record its events twice
+    idempotency_key TEXT UNIQUE
+);
+
+CREATE INDEX idx_events_time_activity_id ON events(time, habit_id);
+CREATE INDEX idx_events_activity_id ON events(habit_id);
+
+--- Hands out the `seq` of changes, a single row
+CREATE TABLE "change_seq" (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    value INTEGER NOT NULL
+);
+
+INSERT INTO change_seq (id, value) VALUES (1, 0);
+
+--- Only the latest change of each row is kept, deletes are kept as tombstones
+CREATE TABLE "changes" (
+    entity TEXT NOT NULL CHECK (entity IN ('habit', 'event')),
+    entity_id INTEGER NOT NULL,
+    seq INTEGER NOT NULL,
+    deleted BOOLEAN NOT NULL,
+    PRIMARY KEY (entity, entity_id)
+);
+
+CREATE INDEX idx_changes_seq ON changes(seq);
+
+--- Secret tokens that give read only access to the calendar feed
+CREATE TABLE "calendar_tokens" (
+    token TEXT PRIMARY KEY DEFAULT (lower(hex(randomblob(16)))),
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+--- One row per habit once its reminders are configured or one was sent
+CREATE TABLE "reminder_settings" (
+    habit_id INTEGER PRIMARY KEY REFERENCES habits(id) ON DELETE CASCADE,
+    quiet_start TIME,
+    quiet_end TIME,
+    snoozed_until DATETIME,
+    --- So a habit is only reminded about once a day
+    last_sent DATE,
+    CHECK ((quiet_start IS NULL) = (quiet_end IS NULL))
+);
+
+CREATE TABLE "push_subscriptions" (
+    endpoint TEXT PRIMARY KEY,
+    p256dh TEXT NOT NULL,
+    auth TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE "webhooks" (
+    id INTEGER PRIMARY KEY,
+    url TEXT NOT NULL,
+    --- A JSON array of webhook events
+    events TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+--- Updated after every attempt, so it always shows the latest outcome
+CREATE TABLE "webhook_deliveries" (
+    id INTEGER PRIMARY KEY,
+    webhook_id INTEGER NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    status_code INTEGER,
+    error TEXT,
+    succeeded BOOLEAN NOT NULL DEFAULT false,
+    --- When the worker tries again, NULL once it succeeded or gave up
+    next_attempt_at DATETIME,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_webhook_deliveries_webhook_id ON webhook_deliveries(webhook_id, id);
+CREATE INDEX idx_webhook_deliveries_next_attempt_at ON webhook_deliveries(next_attempt_at)
+    WHERE next_attempt_at IS NOT NULL;
+
+CREATE TABLE "api_tokens" (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    --- The first few characters of the token, the rest is only kept as a hash
+    prefix TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    --- A JSON array of token scopes
+    scopes TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME
+);
+
+--- Responses to requests sent with an `Idempotency-Key` header, so retries get the same answer
+CREATE TABLE "idempotency_keys" (
+    key TEXT PRIMARY KEY,
+    --- The method, path and credentials of the first request, a key can't be reused for another
+    fingerprint TEXT NOT NULL,
+    --- The response is empty while the first request is still being handled
+    status INTEGER,
+    content_type TEXT,
+    body BLOB,
+    --- A claim without a response is taken over after a while, its request may have died
+    claimed_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL
+);
+
+CREATE INDEX idx_idempotency_keys_expires_at ON idempotency_keys (expires_at);
@@ -0,0 +1,240 @@
+use std::marker::PhantomData;
+
+use haby_core::api::{ApiToken, CreateApiToken, NewApiToken};
+use haby_core::TokenScope;
+use rand::distributions::{Alphanumeric, DistString};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::{delete, get, post, State};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::db::Db;
+use crate::error::Error;
+use crate::limits::{limit, LimitedJson};
+
+/// Every token starts with this, so they are easy to spot in leaked logs and configs
+const TOKEN_PREFIX: &str = "haby_";
+
+/// How much of a token is stored in the clear to tell tokens apart
+const SHOWN_LENGTH: usize = 12;
+
+/// The `auth` table of the Rocket config
+///
+/// There are no accounts, so tokens are off by default. Create an admin token before setting
+/// `required`, or there is no way back in.
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Config {
+    /// Reject requests without a valid token
+    pub required: bool,
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Auth", |rocket| async move {
+        match rocket.figment().focus("auth").extract::<Config>() {
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(err) => {
+                error!("Invalid auth config: {err}");
+                Err(rocket)
+            }
+        }
+    })
+}
+
+/// Marker types for the scope a route needs
+pub mod scope {
+    use haby_core::TokenScope;
+
+    pub trait Scope: Send + Sync {
+        const SCOPE: TokenScope;
+    }
+
+    pub struct ReadHabits;
+    pub struct WriteEvents;
+    pub struct Admin;
+
+    impl Scope for ReadHabits {
+        const SCOPE: TokenScope = TokenScope::ReadHabits;
+    }
+    impl Scope for WriteEvents {
+        const SCOPE: TokenScope = TokenScope::WriteEvents;
+    }
+    impl Scope for Admin {
+        const SCOPE: TokenScope = TokenScope::Admin;
+    }
+}
+
+/// A request guard for routes that need the scope `S`
+///
+/// A token that is sent is always checked, even when tokens are not required.
+pub struct Auth<S>(PhantomData<S>);
+
+/// The scopes a request was granted, for routes like `/graphql` that check them per operation
+///
+/// `None` when no token was sent and tokens are not required, which allows everything.
+#[derive(Clone)]
+pub struct Grants(Option<Vec<TokenScope>>);
+
+impl Grants {
+    pub fn allows(&self, scope: TokenScope) -> bool {
+        match &self.0 {
+            None => true,
+            Some(scopes) => scopes.contains(&scope) || scopes.contains(&TokenScope::Admin),
+        }
+    }
+
+    /// Whether the request sent a valid token
+    pub fn has_token(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// The error for a missing scope, the same `Auth` answers with
+    pub fn missing(scope: TokenScope) -> String {
+        format!("The token is missing the {scope:?} scope")
+    }
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+async fn grants(request: &Request<'_>) -> Result<Grants, Error> {
+    let required = request
+        .rocket()
+        .state::<Config>()
+        .is_some_and(|config| config.required);
+
+    let Some(header) = request.headers().get_one("Authorization") else {
+        if required {
+            return Err(Error::new(
+                Status::Unauthorized,
+                String::from("Missing API token"),
+            ));
+        }
+        return Ok(Grants(None));
+    };
+    let Some(token) = header.strip_prefix("Bearer ") else {
+        return Err(Error::new(
+            Status::Unauthorized,
+            String::from("Expected a bearer token"),
+        ));
+    };
+    let Some(db) = request.rocket().state::<Db>() else {
+        return Err(Error::new(
+            Status::InternalServerError,
+            String::from("No database"),
+        ));
+    };
+
+    let scopes = db.use_token(&hash(token)).await;
+
+    match scopes {
+        Ok(Some(scopes)) => Ok(Grants(Some(scopes))),
+        Ok(None) => Err(Error::new(
+            Status::Unauthorized,
+            String::from("Unknown API token"),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Grants {
+    type Error = String;
+
+    /// Cached, so a request only looks up its token once however many guards need it
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let grants: &Result<Grants, Error> = request.local_cache_async(grants(request)).await;
+        match grants {
+            Ok(grants) => Outcome::Success(grants),
+            Err(err) => Outcome::Error((err.status, err.message.clone())),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, S: scope::Scope> FromRequest<'r> for Auth<S> {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let grants = rocket::outcome::try_outcome!(request.guard::<&Grants>().await);
+        if !grants.allows(S::SCOPE) {
+            return Outcome::Error((Status::Forbidden, Grants::missing(S::SCOPE)));
+        }
+        Outcome::Success(Self(PhantomData))
+    }
+}
+
+#[utoipa::path(
+    tag = "tokens",
+    responses(
+        (status = 200, description = "Every token, without secrets", body = Vec<ApiToken>),
+    ),
+)]
+#[get("/tokens")]
+pub async fn get_tokens(
+    _auth: Auth<scope::Admin>,
+    db: &State<Db>,
+) -> Result<Json<Vec<ApiToken>>, Error> {
+    Ok(Json(db.tokens().await?))
+}
+
+#[utoipa::path(
+    tag = "tokens",
+    request_body = CreateApiToken,
+    responses(
+        (status = 200, description = "The token and its secret, which is only shown once", body = NewApiToken),
+        (status = 400, description = "The token was rejected", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[post("/tokens", data = "<token>")]
+pub async fn create_token(
+    _auth: Auth<scope::Admin>,
+    token: LimitedJson<CreateApiToken, limit::Json>,
+    db: &State<Db>,
+) -> Result<Json<NewApiToken>, Error> {
+    if token.scopes.is_empty() {
+        return Err(Error::new(
+            Status::BadRequest,
+            String::from("A token needs at least one scope"),
+        ));
+    }
+
+    let secret = format!(
+        "{TOKEN_PREFIX}{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 40)
+    );
+    let token = db
+        .create_token(
+            &token.name,
+            &secret[..SHOWN_LENGTH],
+            &hash(&secret),
+            &token.scopes,
+        )
+        .await
+        .map_err(Error::rejected)?;
+
+    Ok(Json(NewApiToken { token, secret }))
+}
+
+#[utoipa::path(
+    tag = "tokens",
+    responses(
+        (status = 200, description = "The token was revoked"),
+        (status = 404, description = "Unknown token", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[delete("/tokens/<id>")]
+pub async fn revoke_token(_auth: Auth<scope::Admin>, id: i32, db: &State<Db>) -> Result<(), Error> {
+    if !db.revoke_token(id).await? {
+        return Err(Error::new(
+            Status::NotFound,
+            format!("No token with id {id}"),
+        ));
+    }
+    Ok(())
+}
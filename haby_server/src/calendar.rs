@@ -0,0 +1,71 @@
+use haby_core::ical::Calendar;
+use rocket::http::{ContentType, Status};
+use rocket::{delete, get, post, State};
+use sqlx::types::chrono::Utc;
+
+use crate::auth::{scope, Auth};
+use crate::db::Db;
+use crate::error::Error;
+
+/// Create a new secret token for subscribing to `/calendar.ics`
+#[utoipa::path(
+    tag = "calendar",
+    responses((status = 200, description = "The new feed token", body = String)),
+)]
+#[post("/calendar/tokens")]
+pub async fn create_calendar_token(
+    _auth: Auth<scope::Admin>,
+    db: &State<Db>,
+) -> Result<String, Error> {
+    Ok(db.create_calendar_token().await?)
+}
+
+#[utoipa::path(
+    tag = "calendar",
+    responses(
+        (status = 200, description = "The token was revoked"),
+        (status = 404, description = "Unknown token", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[delete("/calendar/tokens/<token>")]
+pub async fn delete_calendar_token(
+    _auth: Auth<scope::Admin>,
+    token: &str,
+    db: &State<Db>,
+) -> Result<(), Error> {
+    if !db.delete_calendar_token(token).await? {
+        return Err(Error::new(
+            Status::NotFound,
+            String::from("Unknown calendar token"),
+        ));
+    }
+    Ok(())
+}
+
+/// Habit schedules and logged spans for calendar apps to subscribe to
+#[utoipa::path(
+    tag = "calendar",
+    security(()),
+    responses(
+        (status = 200, description = "The iCalendar feed", body = String, content_type = "text/calendar"),
+        (status = 404, description = "Unknown token", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[get("/calendar.ics?<token>")]
+pub async fn get_calendar(token: &str, db: &State<Db>) -> Result<(ContentType, String), Error> {
+    let known = db.calendar_token_exists(token).await?;
+    if !known {
+        return Err(Error::new(
+            Status::NotFound,
+            String::from("Unknown calendar token"),
+        ));
+    }
+
+    let habits = db.habits().await?;
+    // Schedules only need the latest event of a habit, spans need all of them
+    let events = db.calendar_events().await?;
+
+    let now = Utc::now().naive_utc();
+    let calendar = Calendar::from_habits(&habits, &events, now.date());
+    Ok((ContentType::Calendar, calendar.to_ics(now)))
+}
@@ -0,0 +1,1050 @@
+//! A backend that keeps everything in memory, so handler tests run without a database
+//!
+//! It follows the rules of the other backends, the ones Postgres enforces with constraints and
+//! triggers included, and fails the same way: rejected writes are a [`Violation`] and a closed
+//! one fails with `PoolClosed` like a closed pool would. Clones share their data.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use haby_core::api::{
+    ApiToken,
+    BatchEvent,
+    BatchItem,
+    Change,
+    ConflictPolicy,
+    CreateEvent,
+    CreateHabit,
+    Export,
+    ImportReport,
+    LiveUpdate,
+    PushKeys,
+    PushSubscription,
+    ReminderSettings,
+    SyncResponse,
+    Tombstone,
+    Webhook,
+    WebhookDelivery,
+};
+use haby_core::{
+    EntityKind,
+    Event,
+    Habit,
+    HabitKind,
+    RecordingType,
+    SpanPart,
+    TokenScope,
+    WebhookEvent,
+};
+use rocket::futures::stream::{self, BoxStream};
+use rocket::futures::StreamExt;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::broadcast::{self};
+use sqlx::error::ErrorKind;
+use sqlx::types::chrono::{NaiveDate, NaiveDateTime, Utc};
+
+use super::{
+    check_span_part,
+    unknown_habit,
+    DueDelivery,
+    ExportRow,
+    IdempotencyKey,
+    KindCount,
+    PoolStats,
+    ReminderState,
+    Repository,
+    Violation,
+    WebhookTarget,
+};
+
+/// How many changes a slow listener can fall behind before it is told it lagged
+const CAPACITY: usize = 256;
+
+#[derive(Clone)]
+pub struct Memory {
+    state: Arc<Mutex<State>>,
+    changes: broadcast::Sender<Change>,
+    closed: Arc<AtomicBool>,
+}
+
+/// The tables, ids count up from 1 like serial columns
+#[derive(Clone, Default)]
+struct State {
+    habits: BTreeMap<i32, Habit>,
+    habit_ids: i32,
+    events: BTreeMap<i32, Event>,
+    event_ids: i32,
+    /// The idempotency keys of events recorded in a batch, by event id
+    event_keys: BTreeMap<i32, String>,
+    /// The latest change of every row
+    changes: Vec<Change>,
+    seq: i64,
+    calendar_tokens: HashSet<String>,
+    reminders: BTreeMap<i32, (ReminderSettings, Option<NaiveDate>)>,
+    push_subscriptions: BTreeMap<String, PushKeys>,
+    /// With their secrets
+    webhooks: BTreeMap<i32, (Webhook, String)>,
+    webhook_ids: i32,
+    /// With their payloads and when they are due
+    deliveries: BTreeMap<i64, (WebhookDelivery, String, Option<NaiveDateTime>)>,
+    delivery_ids: i64,
+    /// With their hashes
+    tokens: BTreeMap<i32, (ApiToken, String)>,
+    token_ids: i32,
+    /// With the claim holding them, when it was claimed and when it expires
+    idempotency_keys: HashMap<String, (IdempotencyKey, String, NaiveDateTime, NaiveDateTime)>,
+}
+
+impl Memory {
+    /// Like `Pool::close`, every call fails with `PoolClosed` afterwards
+    pub async fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn state(&self) -> sqlx::Result<MutexGuard<'_, State>> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(sqlx::Error::PoolClosed);
+        }
+        // A test that panicked while holding the lock left nothing half written
+        Ok(self.state.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Tell live listeners about changes
+    fn announce(&self, changes: impl IntoIterator<Item = Change>) {
+        for change in changes {
+            // Only fails when nobody is listening
+            let _ = self.changes.send(change);
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        let (changes, _) = broadcast::channel(CAPACITY);
+        Self {
+            state: Arc::default(),
+            changes,
+            closed: Arc::default(),
+        }
+    }
+}
+
+/// What the database reports for a reference to a row that does not exist
+fn foreign_key(table: &str, column: &str, id: impl fmt::Display) -> sqlx::Error {
+    Violation::error(
+        ErrorKind::ForeignKeyViolation,
+        format!("Key ({column})=({id}) is not present in table \"{table}\""),
+    )
+}
+
+/// Stream a snapshot of rows, or the error of taking it
+fn snapshot<T: Send + 'static>(rows: sqlx::Result<Vec<T>>) -> BoxStream<'static, sqlx::Result<T>> {
+    match rows {
+        Ok(rows) => stream::iter(rows.into_iter().map(Ok)).boxed(),
+        Err(err) => stream::iter([Err(err)]).boxed(),
+    }
+}
+
+/// The latest of `events` for every habit
+fn latest<'a>(events: impl Iterator<Item = &'a Event>) -> HashMap<i32, &'a Event> {
+    let mut latest = HashMap::<i32, &Event>::new();
+    for event in events {
+        latest
+            .entry(event.habit_id)
+            .and_modify(|latest| {
+                if event.time > latest.time {
+                    *latest = event;
+                }
+            })
+            .or_insert(event);
+    }
+    latest
+}
+
+impl State {
+    /// Write a change to the change log, what the `record_change` trigger does in Postgres
+    fn record_change(&mut self, entity: EntityKind, id: i32, deleted: bool) -> Change {
+        self.seq += 1;
+        let change = Change {
+            entity,
+            id,
+            deleted,
+            cursor: self.seq,
+        };
+        self.changes
+            .retain(|change| change.entity != entity || change.id != id);
+        self.changes.push(change);
+        change
+    }
+
+    /// Names are unique ignoring case
+    fn check_name(&self, name: &str, id: Option<i32>) -> sqlx::Result<()> {
+        let taken = self
+            .habits
+            .values()
+            .any(|habit| Some(habit.id) != id && habit.name.to_lowercase() == name.to_lowercase());
+        if taken {
+            return Err(Violation::error(
+                ErrorKind::UniqueViolation,
+                "duplicate key value violates unique constraint \"idx_habits_name_lower\"",
+            ));
+        }
+        Ok(())
+    }
+
+    fn insert_habit(&mut self, habit: &CreateHabit) -> sqlx::Result<(i32, Change)> {
+        self.check_name(&habit.name, None)?;
+        self.habit_ids += 1;
+        let id = self.habit_ids;
+        self.habits.insert(id, habit.clone().with_id(id));
+        Ok((id, self.record_change(EntityKind::Habit, id, false)))
+    }
+
+    /// Delete every event of a habit, returning their tombstones
+    fn delete_events(&mut self, habit_id: i32) -> Vec<Change> {
+        let ids: Vec<i32> = self
+            .events
+            .values()
+            .filter(|event| event.habit_id == habit_id)
+            .map(|event| event.id)
+            .collect();
+        ids.into_iter()
+            .map(|id| {
+                self.events.remove(&id);
+                self.event_keys.remove(&id);
+                self.record_change(EntityKind::Event, id, true)
+            })
+            .collect()
+    }
+
+    /// Update a habit, deleting its events when the recording type changed
+    ///
+    /// `None` when there is no habit with that id.
+    fn update_habit(&mut self, id: i32, habit: &CreateHabit) -> sqlx::Result<Option<Vec<Change>>> {
+        let Some(recording_type) = self.habits.get(&id).map(|habit| habit.recording_type) else {
+            return Ok(None);
+        };
+        self.check_name(&habit.name, Some(id))?;
+        self.habits.insert(id, habit.clone().with_id(id));
+
+        let mut changes = Vec::new();
+        if recording_type != habit.recording_type {
+            changes = self.delete_events(id);
+        }
+        changes.push(self.record_change(EntityKind::Habit, id, false));
+        Ok(Some(changes))
+    }
+
+    /// Insert an event, `None` when its idempotency key was used before
+    fn insert_event(
+        &mut self,
+        event: &CreateEvent,
+        idempotency_key: Option<&str>,
+    ) -> sqlx::Result<Option<(i32, Change)>> {
+        let Some(habit) = self.habits.get(&event.habit_id) else {
+            return Err(foreign_key("habits", "habit_id", event.habit_id));
+        };
+        check_span_part(habit.recording_type, event.span_part)?;
+        if idempotency_key.is_some() && self.event_with_key(idempotency_key).is_some() {
+            return Ok(None);
+        }
+
+        self.event_ids += 1;
+        let id = self.event_ids;
+        self.events.insert(id, event.with_id(id));
+        if let Some(key) = idempotency_key {
+            self.event_keys.insert(id, key.to_owned());
+        }
+        Ok(Some((id, self.record_change(EntityKind::Event, id, false))))
+    }
+
+    fn event_with_key(&self, key: Option<&str>) -> Option<&Event> {
+        let (id, _) = self
+            .event_keys
+            .iter()
+            .find(|(_, event_key)| Some(event_key.as_str()) == key)?;
+        self.events.get(id)
+    }
+
+    fn check_habit(&self, table: &str, habit_id: i32) -> sqlx::Result<()> {
+        if !self.habits.contains_key(&habit_id) {
+            return Err(foreign_key(table, "habit_id", habit_id));
+        }
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for Memory {
+    async fn habits(&self) -> sqlx::Result<Vec<Habit>> {
+        Ok(self.state()?.habits.values().cloned().collect())
+    }
+
+    async fn habit(&self, id: i32) -> sqlx::Result<Option<Habit>> {
+        Ok(self.state()?.habits.get(&id).cloned())
+    }
+
+    async fn event(&self, id: i32) -> sqlx::Result<Option<Event>> {
+        Ok(self.state()?.events.get(&id).cloned())
+    }
+
+    async fn events(
+        &self,
+        habit_id: i32,
+        since: Option<NaiveDateTime>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Event>> {
+        let state = self.state()?;
+        let mut events: Vec<Event> = state
+            .events
+            .values()
+            .filter(|event| event.habit_id == habit_id)
+            .filter(|event| since.is_none_or(|since| event.time > since))
+            .cloned()
+            .collect();
+        events.sort_by_key(|event| std::cmp::Reverse((event.time, event.id)));
+        events.truncate(usize::try_from(limit).unwrap_or(0));
+        Ok(events)
+    }
+
+    async fn latest_events(&self, habit_ids: &[i32], per_habit: i64) -> sqlx::Result<Vec<Event>> {
+        let state = self.state()?;
+        let mut events: Vec<Event> = state
+            .events
+            .values()
+            .filter(|event| habit_ids.contains(&event.habit_id))
+            .cloned()
+            .collect();
+        events.sort_by_key(|event| std::cmp::Reverse((event.time, event.id)));
+
+        let mut counts = HashMap::<i32, i64>::new();
+        events.retain(|event| {
+            let count = counts.entry(event.habit_id).or_default();
+            *count += 1;
+            *count <= per_habit
+        });
+        Ok(events)
+    }
+
+    async fn create_habit(&self, habit: &CreateHabit) -> sqlx::Result<i32> {
+        let (id, change) = self.state()?.insert_habit(habit)?;
+        self.announce([change]);
+        Ok(id)
+    }
+
+    async fn update_habit(&self, id: i32, habit: &CreateHabit) -> sqlx::Result<bool> {
+        let Some(changes) = self.state()?.update_habit(id, habit)? else {
+            return Ok(false);
+        };
+        self.announce(changes);
+        Ok(true)
+    }
+
+    async fn delete_habit(&self, id: i32) -> sqlx::Result<bool> {
+        let mut state = self.state()?;
+        if state.habits.remove(&id).is_none() {
+            return Ok(false);
+        }
+        state.reminders.remove(&id);
+        let mut changes = state.delete_events(id);
+        changes.push(state.record_change(EntityKind::Habit, id, true));
+        drop(state);
+
+        self.announce(changes);
+        Ok(true)
+    }
+
+    async fn create_event(&self, event: &CreateEvent) -> sqlx::Result<i32> {
+        let (id, change) = self
+            .state()?
+            .insert_event(event, None)?
+            .expect("Events without an idempotency key never conflict");
+        self.announce([change]);
+        Ok(id)
+    }
+
+    async fn create_events(&self, events: Vec<BatchEvent>) -> sqlx::Result<Vec<BatchItem>> {
+        let mut state = self.state()?;
+        let mut results = Vec::with_capacity(events.len());
+        let mut changes = Vec::new();
+
+        for BatchEvent {
+            event,
+            idempotency_key,
+        } in events
+        {
+            // Nothing is written before an event is checked, so a rejected one leaves no trace
+            let result = match state.insert_event(&event, idempotency_key.as_deref()) {
+                Ok(Some((id, change))) => {
+                    changes.push(change);
+                    BatchItem::Created {
+                        event: event.with_id(id),
+                    }
+                }
+                Ok(None) => BatchItem::Duplicate {
+                    event: state
+                        .event_with_key(idempotency_key.as_deref())
+                        .cloned()
+                        .expect("The event of a used key exists"),
+                },
+                Err(err) => BatchItem::Rejected {
+                    error: err.to_string(),
+                },
+            };
+            results.push(result);
+        }
+        drop(state);
+
+        self.announce(changes);
+        Ok(results)
+    }
+
+    async fn clear(&self) -> sqlx::Result<()> {
+        *self.state()? = State::default();
+        Ok(())
+    }
+
+    async fn changes_since(&self, since: i64) -> sqlx::Result<SyncResponse> {
+        let state = self.state()?;
+        let changes = state.changes.iter().filter(|change| change.cursor > since);
+
+        Ok(SyncResponse {
+            cursor: state
+                .changes
+                .iter()
+                .map(|change| change.cursor)
+                .max()
+                .unwrap_or(since),
+            habits: changes
+                .clone()
+                .filter(|change| change.entity == EntityKind::Habit)
+                .filter_map(|change| state.habits.get(&change.id).cloned())
+                .collect(),
+            events: changes
+                .clone()
+                .filter(|change| change.entity == EntityKind::Event)
+                .filter_map(|change| state.events.get(&change.id).cloned())
+                .collect(),
+            tombstones: changes
+                .filter(|change| change.deleted)
+                .map(|change| Tombstone {
+                    entity: change.entity,
+                    id: change.id,
+                })
+                .collect(),
+        })
+    }
+
+    async fn listen(&self) -> sqlx::Result<BoxStream<'static, LiveUpdate>> {
+        drop(self.state()?);
+        let updates = stream::unfold(self.changes.subscribe(), |mut receiver| async move {
+            let update = match receiver.recv().await {
+                Ok(change) => LiveUpdate::Change(change),
+                Err(RecvError::Lagged(_)) => LiveUpdate::Lagged,
+                Err(RecvError::Closed) => return None,
+            };
+            Some((update, receiver))
+        });
+        Ok(updates.boxed())
+    }
+
+    fn export_events(&self) -> BoxStream<'_, sqlx::Result<Event>> {
+        snapshot(
+            self.state()
+                .map(|state| state.events.values().cloned().collect()),
+        )
+    }
+
+    fn export_rows(&self) -> BoxStream<'_, sqlx::Result<ExportRow>> {
+        snapshot(self.state().map(|state| {
+            let mut rows = Vec::new();
+            for habit in state.habits.values() {
+                let mut events: Vec<&Event> = state
+                    .events
+                    .values()
+                    .filter(|event| event.habit_id == habit.id)
+                    .collect();
+                events.sort_by_key(|event| (event.time, event.id));
+
+                if events.is_empty() {
+                    rows.push(ExportRow {
+                        habit: habit.clone(),
+                        event: None,
+                    });
+                }
+                rows.extend(events.into_iter().map(|event| ExportRow {
+                    habit: habit.clone(),
+                    event: Some(event.clone()),
+                }));
+            }
+            rows
+        }))
+    }
+
+    async fn import(
+        &self,
+        export: Export,
+        on_conflict: ConflictPolicy,
+        dry_run: bool,
+    ) -> sqlx::Result<ImportReport> {
+        let mut state = self.state()?;
+        // Everything is imported into a copy, which replaces the data unless this is a dry run
+        let mut draft = state.clone();
+        let mut changes = Vec::new();
+        let mut report = ImportReport {
+            dry_run,
+            ..Default::default()
+        };
+
+        // Maps the ids in the export to the ids in our data, `None` for skipped habits
+        let mut habit_ids = HashMap::new();
+        for mut habit in export.habits {
+            habit.name = habit.name.trim().to_owned();
+            let existing = draft
+                .habits
+                .values()
+                .find(|existing| existing.name.to_lowercase() == habit.name.to_lowercase())
+                .map(|existing| (existing.id, existing.name.clone()));
+
+            let id = match (existing, on_conflict) {
+                (None, _) => {
+                    let (id, change) = draft.insert_habit(&habit.as_create())?;
+                    changes.push(change);
+                    report.habits_created.push(habit.name);
+                    Some(id)
+                }
+                (Some(_), ConflictPolicy::Skip) => {
+                    report.habits_skipped.push(habit.name);
+                    None
+                }
+                (Some((id, _)), ConflictPolicy::Merge) => {
+                    report.habits_merged.push(habit.name);
+                    Some(id)
+                }
+                (Some((id, name)), ConflictPolicy::Overwrite) => {
+                    // Only the settings are overwritten, the name keeps its case
+                    let settings = CreateHabit {
+                        name,
+                        ..habit.as_create()
+                    };
+                    changes.extend(draft.update_habit(id, &settings)?.unwrap_or_default());
+                    report.habits_overwritten.push(habit.name);
+                    Some(id)
+                }
+            };
+            habit_ids.insert(habit.id, id);
+        }
+
+        for event in export.events {
+            let habit_id = match habit_ids.get(&event.habit_id) {
+                Some(Some(id)) => *id,
+                Some(None) => {
+                    report.events_skipped += 1;
+                    continue;
+                }
+                None => return Err(unknown_habit(&event)),
+            };
+
+            let exists = draft.events.values().any(|existing| {
+                existing.habit_id == habit_id
+                    && existing.time == event.time
+                    && existing.span_part == event.span_part
+            });
+            if exists {
+                report.events_skipped += 1;
+                continue;
+            }
+            let create = CreateEvent {
+                habit_id,
+                time: event.time,
+                span_part: event.span_part,
+            };
+            let (_, change) = draft
+                .insert_event(&create, None)?
+                .expect("Events without an idempotency key never conflict");
+            changes.push(change);
+            report.events_created += 1;
+        }
+
+        if !dry_run {
+            *state = draft;
+            drop(state);
+            self.announce(changes);
+        }
+        Ok(report)
+    }
+
+    async fn create_calendar_token(&self) -> sqlx::Result<String> {
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        self.state()?.calendar_tokens.insert(token.clone());
+        Ok(token)
+    }
+
+    async fn delete_calendar_token(&self, token: &str) -> sqlx::Result<bool> {
+        Ok(self.state()?.calendar_tokens.remove(token))
+    }
+
+    async fn calendar_token_exists(&self, token: &str) -> sqlx::Result<bool> {
+        Ok(self.state()?.calendar_tokens.contains(token))
+    }
+
+    async fn calendar_events(&self) -> sqlx::Result<Vec<Event>> {
+        let state = self.state()?;
+        let latest = latest(state.events.values());
+        Ok(state
+            .events
+            .values()
+            .filter(|event| {
+                let span = state
+                    .habits
+                    .get(&event.habit_id)
+                    .is_some_and(|habit| habit.recording_type == RecordingType::Span);
+                span || latest[&event.habit_id].id == event.id
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn latest_check_ins(&self) -> sqlx::Result<Vec<Event>> {
+        let state = self.state()?;
+        let check_ins = state
+            .events
+            .values()
+            .filter(|event| event.span_part != Some(SpanPart::End));
+        Ok(latest(check_ins).into_values().cloned().collect())
+    }
+
+    async fn reminder_states(&self) -> sqlx::Result<Vec<ReminderState>> {
+        Ok(self
+            .state()?
+            .reminders
+            .iter()
+            .map(|(habit_id, (settings, last_sent))| ReminderState {
+                habit_id: *habit_id,
+                settings: settings.clone(),
+                last_sent: *last_sent,
+            })
+            .collect())
+    }
+
+    async fn reminder_settings(&self, habit_id: i32) -> sqlx::Result<Option<ReminderSettings>> {
+        Ok(self
+            .state()?
+            .reminders
+            .get(&habit_id)
+            .map(|(settings, _)| settings.clone()))
+    }
+
+    async fn set_reminder_settings(
+        &self,
+        habit_id: i32,
+        settings: &ReminderSettings,
+    ) -> sqlx::Result<()> {
+        let mut state = self.state()?;
+        state.check_habit("habits", habit_id)?;
+        state.reminders.entry(habit_id).or_default().0 = settings.clone();
+        Ok(())
+    }
+
+    async fn mark_reminded(&self, habit_id: i32, day: NaiveDate) -> sqlx::Result<()> {
+        let mut state = self.state()?;
+        state.check_habit("habits", habit_id)?;
+        state.reminders.entry(habit_id).or_default().1 = Some(day);
+        Ok(())
+    }
+
+    async fn snooze(&self, habit_id: i32, until: NaiveDateTime) -> sqlx::Result<NaiveDateTime> {
+        let mut state = self.state()?;
+        state.check_habit("habits", habit_id)?;
+        let (settings, last_sent) = state.reminders.entry(habit_id).or_default();
+        settings.snoozed_until = Some(until);
+        *last_sent = None;
+        Ok(until)
+    }
+
+    async fn push_subscriptions(&self) -> sqlx::Result<Vec<PushSubscription>> {
+        Ok(self
+            .state()?
+            .push_subscriptions
+            .iter()
+            .map(|(endpoint, keys)| PushSubscription {
+                endpoint: endpoint.clone(),
+                keys: keys.clone(),
+            })
+            .collect())
+    }
+
+    async fn save_push_subscription(&self, subscription: &PushSubscription) -> sqlx::Result<()> {
+        self.state()?
+            .push_subscriptions
+            .insert(subscription.endpoint.clone(), subscription.keys.clone());
+        Ok(())
+    }
+
+    async fn delete_push_subscription(&self, endpoint: &str) -> sqlx::Result<bool> {
+        Ok(self.state()?.push_subscriptions.remove(endpoint).is_some())
+    }
+
+    async fn webhooks(&self) -> sqlx::Result<Vec<Webhook>> {
+        Ok(self
+            .state()?
+            .webhooks
+            .values()
+            .map(|(webhook, _)| webhook.clone())
+            .collect())
+    }
+
+    async fn create_webhook(
+        &self,
+        url: &str,
+        events: &[WebhookEvent],
+        secret: &str,
+    ) -> sqlx::Result<i32> {
+        let mut state = self.state()?;
+        state.webhook_ids += 1;
+        let id = state.webhook_ids;
+        let webhook = Webhook {
+            id,
+            url: url.to_owned(),
+            events: events.to_vec(),
+        };
+        state.webhooks.insert(id, (webhook, secret.to_owned()));
+        Ok(id)
+    }
+
+    async fn delete_webhook(&self, id: i32) -> sqlx::Result<bool> {
+        let mut state = self.state()?;
+        if state.webhooks.remove(&id).is_none() {
+            return Ok(false);
+        }
+        state
+            .deliveries
+            .retain(|_, (delivery, _, _)| delivery.webhook_id != id);
+        Ok(true)
+    }
+
+    async fn webhook_target(&self, id: i32) -> sqlx::Result<Option<WebhookTarget>> {
+        Ok(self
+            .state()?
+            .webhooks
+            .get(&id)
+            .map(|(webhook, secret)| WebhookTarget {
+                id,
+                url: webhook.url.clone(),
+                secret: secret.clone(),
+            }))
+    }
+
+    async fn webhook_targets(&self, event: WebhookEvent) -> sqlx::Result<Vec<WebhookTarget>> {
+        Ok(self
+            .state()?
+            .webhooks
+            .values()
+            .filter(|(webhook, _)| webhook.events.contains(&event))
+            .map(|(webhook, secret)| WebhookTarget {
+                id: webhook.id,
+                url: webhook.url.clone(),
+                secret: secret.clone(),
+            })
+            .collect())
+    }
+
+    async fn queue_delivery(
+        &self,
+        webhook_id: i32,
+        event: WebhookEvent,
+        payload: &str,
+        queued: bool,
+    ) -> sqlx::Result<i64> {
+        let mut state = self.state()?;
+        if !state.webhooks.contains_key(&webhook_id) {
+            return Err(foreign_key("webhooks", "webhook_id", webhook_id));
+        }
+        state.delivery_ids += 1;
+        let id = state.delivery_ids;
+        let delivery = WebhookDelivery {
+            id,
+            webhook_id,
+            event,
+            attempts: 0,
+            status_code: None,
+            error: None,
+            succeeded: false,
+            created_at: Utc::now().naive_utc(),
+        };
+        let due = queued.then(|| Utc::now().naive_utc());
+        state
+            .deliveries
+            .insert(id, (delivery, payload.to_owned(), due));
+        Ok(id)
+    }
+
+    async fn due_deliveries(
+        &self,
+        limit: i64,
+        lease_minutes: i32,
+    ) -> sqlx::Result<Vec<DueDelivery>> {
+        let now = Utc::now().naive_utc();
+        let mut state = self.state()?;
+        let mut due: Vec<_> = state
+            .deliveries
+            .iter()
+            .filter_map(|(id, (_, _, due))| due.filter(|due| *due <= now).map(|due| (due, *id)))
+            .collect();
+        due.sort();
+        due.truncate(usize::try_from(limit).unwrap_or(0));
+
+        let mut claimed = Vec::new();
+        for (_, id) in due {
+            let Some((delivery, payload, due)) = state.deliveries.get_mut(&id) else {
+                continue;
+            };
+            *due = Some(now + Duration::from_secs(u64::try_from(lease_minutes).unwrap_or(0) * 60));
+            let (delivery, payload) = (delivery.clone(), payload.clone());
+            let Some((webhook, secret)) = state.webhooks.get(&delivery.webhook_id) else {
+                continue;
+            };
+            claimed.push(DueDelivery {
+                id,
+                target: WebhookTarget {
+                    id: webhook.id,
+                    url: webhook.url.clone(),
+                    secret: secret.clone(),
+                },
+                event: delivery.event,
+                payload,
+                attempts: delivery.attempts,
+            });
+        }
+        Ok(claimed)
+    }
+
+    async fn record_attempt(
+        &self,
+        delivery: i64,
+        attempt: i32,
+        status_code: Option<i32>,
+        error: Option<&str>,
+        retry_ms: Option<i64>,
+    ) -> sqlx::Result<()> {
+        if let Some((delivery, _, due)) = self.state()?.deliveries.get_mut(&delivery) {
+            delivery.attempts = attempt;
+            delivery.status_code = status_code;
+            delivery.error = error.map(str::to_owned);
+            delivery.succeeded = error.is_none();
+            *due = retry_ms.map(|retry_ms| {
+                Utc::now().naive_utc() + Duration::from_millis(u64::try_from(retry_ms).unwrap_or(0))
+            });
+        }
+        Ok(())
+    }
+
+    async fn delivery(&self, id: i64) -> sqlx::Result<Option<WebhookDelivery>> {
+        Ok(self
+            .state()?
+            .deliveries
+            .get(&id)
+            .map(|(delivery, _, _)| delivery.clone()))
+    }
+
+    async fn deliveries(&self, webhook_id: i32) -> sqlx::Result<Vec<WebhookDelivery>> {
+        Ok(self
+            .state()?
+            .deliveries
+            .values()
+            .rev()
+            .filter(|(delivery, _, _)| delivery.webhook_id == webhook_id)
+            .take(100)
+            .map(|(delivery, _, _)| delivery.clone())
+            .collect())
+    }
+
+    async fn use_token(&self, token_hash: &str) -> sqlx::Result<Option<Vec<TokenScope>>> {
+        let mut state = self.state()?;
+        let Some((token, _)) = state
+            .tokens
+            .values_mut()
+            .find(|(_, hash)| hash == token_hash)
+        else {
+            return Ok(None);
+        };
+        token.last_used_at = Some(Utc::now().naive_utc());
+        Ok(Some(token.scopes.clone()))
+    }
+
+    async fn tokens(&self) -> sqlx::Result<Vec<ApiToken>> {
+        Ok(self
+            .state()?
+            .tokens
+            .values()
+            .map(|(token, _)| token.clone())
+            .collect())
+    }
+
+    async fn create_token(
+        &self,
+        name: &str,
+        prefix: &str,
+        token_hash: &str,
+        scopes: &[TokenScope],
+    ) -> sqlx::Result<ApiToken> {
+        let mut state = self.state()?;
+        if state.tokens.values().any(|(_, hash)| hash == token_hash) {
+            return Err(Violation::error(
+                ErrorKind::UniqueViolation,
+                "duplicate key value violates unique constraint \"api_tokens_token_hash_key\"",
+            ));
+        }
+        state.token_ids += 1;
+        let token = ApiToken {
+            id: state.token_ids,
+            name: name.to_owned(),
+            prefix: prefix.to_owned(),
+            scopes: scopes.to_vec(),
+            created_at: Utc::now().naive_utc(),
+            last_used_at: None,
+        };
+        state
+            .tokens
+            .insert(token.id, (token.clone(), token_hash.to_owned()));
+        Ok(token)
+    }
+
+    async fn revoke_token(&self, id: i32) -> sqlx::Result<bool> {
+        Ok(self.state()?.tokens.remove(&id).is_some())
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        ttl_hours: i32,
+        lease_minutes: i32,
+    ) -> sqlx::Result<Option<String>> {
+        let mut state = self.state()?;
+        let now = Utc::now().naive_utc();
+        let lease = Duration::from_secs(u64::try_from(lease_minutes).unwrap_or(0) * 60);
+        let claimed =
+            state
+                .idempotency_keys
+                .get(key)
+                .is_some_and(|(existing, _, claimed_at, expires_at)| {
+                    *expires_at >= now && (existing.status.is_some() || *claimed_at >= now - lease)
+                });
+        if claimed {
+            return Ok(None);
+        }
+
+        let existing = IdempotencyKey {
+            fingerprint: fingerprint.to_owned(),
+            status: None,
+            content_type: None,
+            body: None,
+            body_digest: None,
+        };
+        let expires_at = now + Duration::from_secs(u64::try_from(ttl_hours).unwrap_or(0) * 60 * 60);
+        let claim = super::new_claim();
+        state
+            .idempotency_keys
+            .insert(key.to_owned(), (existing, claim.clone(), now, expires_at));
+        Ok(Some(claim))
+    }
+
+    async fn idempotency_key(&self, key: &str) -> sqlx::Result<Option<IdempotencyKey>> {
+        Ok(self
+            .state()?
+            .idempotency_keys
+            .get(key)
+            .map(|(existing, ..)| existing.clone()))
+    }
+
+    async fn store_idempotent_response(
+        &self,
+        key: &str,
+        claim: &str,
+        status: i16,
+        content_type: Option<String>,
+        body: &[u8],
+        body_digest: Option<&str>,
+    ) -> sqlx::Result<()> {
+        if let Some((existing, holder, ..)) = self.state()?.idempotency_keys.get_mut(key) {
+            if holder == claim {
+                existing.status = Some(status);
+                existing.content_type = content_type;
+                existing.body = Some(body.to_vec());
+                existing.body_digest = body_digest.map(String::from);
+            }
+        }
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, key: &str, claim: &str) -> sqlx::Result<()> {
+        let mut state = self.state()?;
+        if state
+            .idempotency_keys
+            .get(key)
+            .is_some_and(|(_, holder, ..)| holder == claim)
+        {
+            state.idempotency_keys.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn remove_expired_idempotency_keys(&self) -> sqlx::Result<()> {
+        let now = Utc::now().naive_utc();
+        self.state()?
+            .idempotency_keys
+            .retain(|_, (.., expires_at)| *expires_at >= now);
+        Ok(())
+    }
+
+    async fn ping(&self) -> sqlx::Result<()> {
+        drop(self.state()?);
+        Ok(())
+    }
+
+    async fn pending_migrations(&self) -> sqlx::Result<usize> {
+        drop(self.state()?);
+        Ok(0)
+    }
+
+    async fn kind_counts(&self) -> sqlx::Result<Vec<KindCount>> {
+        let state = self.state()?;
+        let counts = [HabitKind::Habit, HabitKind::Addiction]
+            .into_iter()
+            .map(|kind| {
+                let habits: Vec<i32> = state
+                    .habits
+                    .values()
+                    .filter(|habit| habit.kind == kind)
+                    .map(|habit| habit.id)
+                    .collect();
+                let events = state
+                    .events
+                    .values()
+                    .filter(|event| habits.contains(&event.habit_id))
+                    .count();
+                KindCount {
+                    kind,
+                    habits: habits.len() as i64,
+                    events: events as i64,
+                }
+            })
+            // Like grouping the habits by kind, kinds without habits are left out
+            .filter(|count| count.habits > 0)
+            .collect();
+        Ok(counts)
+    }
+
+    /// There is no pool, so no connections either
+    fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            connections: 0,
+            idle: 0,
+            max_connections: 0,
+        }
+    }
+}
@@ -0,0 +1,451 @@
+//! Everything the server stores, behind a trait so it can live in Postgres or SQLite
+//!
+//! Postgres is the default and what the server was written for. SQLite, behind the `sqlite`
+//! feature, is for self-hosting a single user on small hardware. Which one is used depends on
+//! the scheme of `DATABASE_URL`. The tests also run against an in-memory backend, which needs
+//! no database at all.
+//!
+//! Methods return `sqlx::Error`, so the routes tell missing databases and rejected writes apart
+//! the same way for every backend. Rules Postgres enforces with triggers are checked by the
+//! other backends themselves and reported as a [`Violation`].
+//!
+//! Every method of the database backends runs in a span named after it, so slow queries show up
+//! in the logs next to the request they belong to.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use haby_core::api::{
+    ApiToken,
+    BatchEvent,
+    BatchItem,
+    ConflictPolicy,
+    CreateEvent,
+    CreateHabit,
+    Export,
+    ImportReport,
+    LiveUpdate,
+    PushSubscription,
+    ReminderSettings,
+    SyncResponse,
+    Webhook,
+    WebhookDelivery,
+};
+use haby_core::{Event, Habit, HabitKind, TokenScope, WebhookEvent};
+use rocket::futures::stream::BoxStream;
+use sqlx::error::{DatabaseError, ErrorKind};
+use sqlx::migrate::Migrator;
+use sqlx::types::chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+#[cfg(test)]
+pub mod memory;
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// The repository every route and background task goes through, shared as Rocket state
+#[derive(Clone)]
+pub struct Db(Arc<dyn Repository>);
+
+impl Db {
+    pub fn new(repository: impl Repository + 'static) -> Self {
+        Self(Arc::new(repository))
+    }
+
+    /// Connect to and migrate the database at `url`, SQLite for `sqlite:` urls and Postgres
+    /// otherwise
+    pub async fn connect(url: &str) -> sqlx::Result<Self> {
+        if url.starts_with("sqlite:") {
+            #[cfg(feature = "sqlite")]
+            return Ok(Self::new(sqlite::Sqlite::connect(url).await?));
+            #[cfg(not(feature = "sqlite"))]
+            return Err(sqlx::Error::Configuration(
+                "The server was built without the sqlite feature".into(),
+            ));
+        }
+        Ok(Self::new(postgres::Postgres::connect(url).await?))
+    }
+}
+
+impl Deref for Db {
+    type Target = dyn Repository;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl From<sqlx::PgPool> for Db {
+    fn from(pool: sqlx::PgPool) -> Self {
+        Self::new(postgres::Postgres::new(pool))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<sqlx::SqlitePool> for Db {
+    fn from(pool: sqlx::SqlitePool) -> Self {
+        Self::new(sqlite::Sqlite::new(pool))
+    }
+}
+
+#[cfg(test)]
+impl From<memory::Memory> for Db {
+    fn from(memory: memory::Memory) -> Self {
+        Self::new(memory)
+    }
+}
+
+/// What is left of an `Idempotency-Key` once it was claimed
+#[derive(Clone)]
+pub struct IdempotencyKey {
+    /// Of the request that claimed it, see `idempotency::fingerprint`
+    pub fingerprint: String,
+    /// The response, all `None` while the first request is still being handled
+    pub status: Option<i16>,
+    pub content_type: Option<String>,
+    pub body: Option<Vec<u8>>,
+    /// Of the body of the request that claimed it, `None` when its route never read one
+    pub body_digest: Option<String>,
+}
+
+/// Where a webhook delivery is sent
+pub struct WebhookTarget {
+    pub id: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+/// A queued webhook delivery that is due to be tried
+pub struct DueDelivery {
+    pub id: i64,
+    pub target: WebhookTarget,
+    pub event: WebhookEvent,
+    pub payload: String,
+    /// How often it was tried already
+    pub attempts: i32,
+}
+
+/// A line of the CSV export, habits without events get one with `event` left out
+pub struct ExportRow {
+    pub habit: Habit,
+    pub event: Option<Event>,
+}
+
+/// The reminder settings of a habit, and the day it was last reminded about
+pub struct ReminderState {
+    pub habit_id: i32,
+    pub settings: ReminderSettings,
+    pub last_sent: Option<NaiveDate>,
+}
+
+/// How many habits of a kind there are, and how many events they have together
+pub struct KindCount {
+    pub kind: HabitKind,
+    pub habits: i64,
+    pub events: i64,
+}
+
+pub struct PoolStats {
+    pub connections: u32,
+    pub idle: u32,
+    pub max_connections: u32,
+}
+
+#[rocket::async_trait]
+pub trait Repository: Send + Sync {
+    // Habits and events
+
+    /// Ordered by id
+    async fn habits(&self) -> sqlx::Result<Vec<Habit>>;
+    async fn habit(&self, id: i32) -> sqlx::Result<Option<Habit>>;
+    async fn event(&self, id: i32) -> sqlx::Result<Option<Event>>;
+    /// The events of a habit after `since`, newest first
+    async fn events(
+        &self,
+        habit_id: i32,
+        since: Option<NaiveDateTime>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Event>>;
+    /// The latest `per_habit` events of each of `habit_ids`, newest first
+    async fn latest_events(&self, habit_ids: &[i32], per_habit: i64) -> sqlx::Result<Vec<Event>>;
+    async fn create_habit(&self, habit: &CreateHabit) -> sqlx::Result<i32>;
+    /// Changing the recording type deletes the events of the habit, they would not fit anymore
+    ///
+    /// `false` when there is no habit with that id.
+    async fn update_habit(&self, id: i32, habit: &CreateHabit) -> sqlx::Result<bool>;
+    /// Deletes its events too, `false` when there is no habit with that id
+    async fn delete_habit(&self, id: i32) -> sqlx::Result<bool>;
+    /// Span habits need a span part on their events, point habits can't have one
+    async fn create_event(&self, event: &CreateEvent) -> sqlx::Result<i32>;
+    /// Record many events in one transaction
+    ///
+    /// Every event is checked on its own, so a rejected one does not stop the rest of the batch.
+    /// Only losing the database fails the whole batch.
+    async fn create_events(&self, events: Vec<BatchEvent>) -> sqlx::Result<Vec<BatchItem>>;
+    /// Empty every table, for the integration tests
+    async fn clear(&self) -> sqlx::Result<()>;
+
+    // Sync and live updates
+
+    /// Everything that changed after the `since` cursor, read in a single snapshot so the
+    /// returned cursor matches the returned rows
+    async fn changes_since(&self, since: i64) -> sqlx::Result<SyncResponse>;
+    /// Every habit and event change from now on, `Lagged` when some were missed
+    async fn listen(&self) -> sqlx::Result<BoxStream<'static, LiveUpdate>>;
+
+    // Export and import
+
+    /// Every event, ordered by id
+    fn export_events(&self) -> BoxStream<'_, sqlx::Result<Event>>;
+    /// Every habit with each of its events, ordered by habit, then time
+    fn export_rows(&self) -> BoxStream<'_, sqlx::Result<ExportRow>>;
+    /// Import everything in one transaction, which a dry run rolls back
+    ///
+    /// Habits are matched by name, ignoring case, and events by habit, time and span part. Every
+    /// event has to belong to a habit of the export.
+    async fn import(
+        &self,
+        export: Export,
+        on_conflict: ConflictPolicy,
+        dry_run: bool,
+    ) -> sqlx::Result<ImportReport>;
+
+    // Calendar feed
+
+    async fn create_calendar_token(&self) -> sqlx::Result<String>;
+    async fn delete_calendar_token(&self, token: &str) -> sqlx::Result<bool>;
+    async fn calendar_token_exists(&self, token: &str) -> sqlx::Result<bool>;
+    /// Every event of span habits, and the latest event of the other habits
+    async fn calendar_events(&self) -> sqlx::Result<Vec<Event>>;
+
+    // Reminders
+
+    /// The latest event of every habit that is not the end of a span
+    async fn latest_check_ins(&self) -> sqlx::Result<Vec<Event>>;
+    async fn reminder_states(&self) -> sqlx::Result<Vec<ReminderState>>;
+    async fn reminder_settings(&self, habit_id: i32) -> sqlx::Result<Option<ReminderSettings>>;
+    async fn set_reminder_settings(
+        &self,
+        habit_id: i32,
+        settings: &ReminderSettings,
+    ) -> sqlx::Result<()>;
+    async fn mark_reminded(&self, habit_id: i32, day: NaiveDate) -> sqlx::Result<()>;
+    /// Also forgets the last reminder, so the habit is reminded about again right after
+    async fn snooze(&self, habit_id: i32, until: NaiveDateTime) -> sqlx::Result<NaiveDateTime>;
+    async fn push_subscriptions(&self) -> sqlx::Result<Vec<PushSubscription>>;
+    async fn save_push_subscription(&self, subscription: &PushSubscription) -> sqlx::Result<()>;
+    async fn delete_push_subscription(&self, endpoint: &str) -> sqlx::Result<bool>;
+
+    // Webhooks
+
+    /// Ordered by id
+    async fn webhooks(&self) -> sqlx::Result<Vec<Webhook>>;
+    async fn create_webhook(
+        &self,
+        url: &str,
+        events: &[WebhookEvent],
+        secret: &str,
+    ) -> sqlx::Result<i32>;
+    async fn delete_webhook(&self, id: i32) -> sqlx::Result<bool>;
+    async fn webhook_target(&self, id: i32) -> sqlx::Result<Option<WebhookTarget>>;
+    /// The webhooks subscribed to `event`
+    async fn webhook_targets(&self, event: WebhookEvent) -> sqlx::Result<Vec<WebhookTarget>>;
+    /// `queued` deliveries are due right away, the others are only sent by the caller
+    async fn queue_delivery(
+        &self,
+        webhook_id: i32,
+        event: WebhookEvent,
+        payload: &str,
+        queued: bool,
+    ) -> sqlx::Result<i64>;
+    /// Claim up to `limit` due deliveries, oldest first
+    ///
+    /// Claimed deliveries are due again after `lease_minutes`, in case the server stops before
+    /// their attempt is recorded.
+    async fn due_deliveries(
+        &self,
+        limit: i64,
+        lease_minutes: i32,
+    ) -> sqlx::Result<Vec<DueDelivery>>;
+    /// Store how the latest attempt went, it succeeded when there is no `error`
+    ///
+    /// The delivery is due again in `retry_ms`, or never if that is `None`.
+    async fn record_attempt(
+        &self,
+        delivery: i64,
+        attempt: i32,
+        status_code: Option<i32>,
+        error: Option<&str>,
+        retry_ms: Option<i64>,
+    ) -> sqlx::Result<()>;
+    async fn delivery(&self, id: i64) -> sqlx::Result<Option<WebhookDelivery>>;
+    /// The latest 100 deliveries to a webhook, newest first
+    async fn deliveries(&self, webhook_id: i32) -> sqlx::Result<Vec<WebhookDelivery>>;
+
+    // API tokens
+
+    /// The scopes of the token with this hash, which is marked as used
+    async fn use_token(&self, token_hash: &str) -> sqlx::Result<Option<Vec<TokenScope>>>;
+    /// Ordered by id
+    async fn tokens(&self) -> sqlx::Result<Vec<ApiToken>>;
+    async fn create_token(
+        &self,
+        name: &str,
+        prefix: &str,
+        token_hash: &str,
+        scopes: &[TokenScope],
+    ) -> sqlx::Result<ApiToken>;
+    async fn revoke_token(&self, id: i32) -> sqlx::Result<bool>;
+
+    // Idempotency keys
+
+    /// Claim `key` for a request, unless an unexpired claim exists
+    ///
+    /// A claim still waiting for its response is taken over once it is `lease_minutes` old, the
+    /// request that made it probably never finished. Returns the claim to store the response
+    /// or release the key with, which fail silently once the claim was taken over.
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        ttl_hours: i32,
+        lease_minutes: i32,
+    ) -> sqlx::Result<Option<String>>;
+    async fn idempotency_key(&self, key: &str) -> sqlx::Result<Option<IdempotencyKey>>;
+    async fn store_idempotent_response(
+        &self,
+        key: &str,
+        claim: &str,
+        status: i16,
+        content_type: Option<String>,
+        body: &[u8],
+        body_digest: Option<&str>,
+    ) -> sqlx::Result<()>;
+    async fn release_idempotency_key(&self, key: &str, claim: &str) -> sqlx::Result<()>;
+    async fn remove_expired_idempotency_keys(&self) -> sqlx::Result<()>;
+
+    // Health and metrics
+
+    async fn ping(&self) -> sqlx::Result<()>;
+    /// How many migrations the server was built with that did not run successfully
+    async fn pending_migrations(&self) -> sqlx::Result<usize>;
+    async fn kind_counts(&self) -> sqlx::Result<Vec<KindCount>>;
+    fn pool_stats(&self) -> PoolStats;
+}
+
+/// A write that broke a rule the backend checks itself, where Postgres has a trigger or
+/// constraint for it
+///
+/// It is a `sqlx::Error::Database`, so it is rejected like any other constraint violation.
+#[derive(Debug)]
+pub struct Violation {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Violation {
+    pub fn error(kind: ErrorKind, message: impl Into<String>) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(Self {
+            kind,
+            message: message.into(),
+        }))
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+impl DatabaseError for Violation {
+    fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn code(&self) -> Option<Cow<'_, str>> {
+        None
+    }
+
+    fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> ErrorKind {
+        // `ErrorKind` is not `Clone`
+        match self.kind {
+            ErrorKind::UniqueViolation => ErrorKind::UniqueViolation,
+            ErrorKind::ForeignKeyViolation => ErrorKind::ForeignKeyViolation,
+            ErrorKind::NotNullViolation => ErrorKind::NotNullViolation,
+            ErrorKind::CheckViolation => ErrorKind::CheckViolation,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// What Postgres' `check_span_part` trigger raises, for the backends that check it themselves
+pub fn check_span_part(
+    recording_type: haby_core::RecordingType,
+    span_part: Option<haby_core::SpanPart>,
+) -> sqlx::Result<()> {
+    use haby_core::RecordingType;
+
+    match (recording_type, span_part) {
+        (RecordingType::Point, Some(_)) => Err(Violation::error(
+            ErrorKind::CheckViolation,
+            "span_part must be NULL when recording_type is point",
+        )),
+        (RecordingType::Span, None) => Err(Violation::error(
+            ErrorKind::CheckViolation,
+            "span_part must not be NULL when recording_type is not point",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// An event of an import whose habit is not part of it
+pub fn unknown_habit(event: &Event) -> sqlx::Error {
+    Violation::error(
+        ErrorKind::ForeignKeyViolation,
+        format!(
+            "Event {} belongs to habit {}, which is not in the import",
+            event.id, event.habit_id
+        ),
+    )
+}
+
+/// Tells the claims of an idempotency key apart, see `claim_idempotency_key`
+fn new_claim() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+fn quiet_hours(
+    start: Option<NaiveTime>,
+    end: Option<NaiveTime>,
+) -> Option<haby_core::reminders::QuietHours> {
+    Some(haby_core::reminders::QuietHours {
+        start: start?,
+        end: end?,
+    })
+}
+
+/// How many migrations of `migrator` are not among the `applied` versions
+fn pending(migrator: &Migrator, applied: &[i64]) -> usize {
+    migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| !applied.contains(&migration.version))
+        .count()
+}
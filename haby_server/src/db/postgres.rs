@@ -0,0 +1,1165 @@
+//! The Postgres backend
+//!
+//! Triggers keep events in line with their habit and write the change log, which they also
+//! announce with `NOTIFY`, so live updates include writes made through other server replicas.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use haby_core::api::{
+    ApiToken,
+    BatchEvent,
+    BatchItem,
+    Change,
+    ConflictPolicy,
+    CreateEvent,
+    CreateHabit,
+    Export,
+    ImportReport,
+    LiveUpdate,
+    PushKeys,
+    PushSubscription,
+    ReminderSettings,
+    SyncResponse,
+    Tombstone,
+    Webhook,
+    WebhookDelivery,
+};
+use haby_core::{
+    Color,
+    EntityKind,
+    Event,
+    Habit,
+    HabitKind,
+    RecordingType,
+    SpanPart,
+    TokenScope,
+    WebhookEvent,
+};
+use rocket::futures::stream::{self, BoxStream};
+use rocket::futures::StreamExt;
+use sqlx::postgres::{PgListener, PgPoolOptions};
+use sqlx::types::chrono::{NaiveDate, NaiveDateTime};
+use sqlx::PgPool;
+use tracing::{error, instrument};
+
+use super::{
+    pending,
+    quiet_hours,
+    unknown_habit,
+    DueDelivery,
+    ExportRow,
+    IdempotencyKey,
+    KindCount,
+    PoolStats,
+    ReminderState,
+    Repository,
+    WebhookTarget,
+};
+use crate::error::unavailable;
+
+/// The migrations the server is built with, `/health/ready` checks they all ran
+pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
+
+/// The channel the `record_change` trigger notifies on
+const CHANNEL: &str = "haby_changes";
+
+pub struct Postgres {
+    pool: PgPool,
+}
+
+impl Postgres {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn connect(url: &str) -> sqlx::Result<Self> {
+        // Requests fail with a 503 soon when the database is gone, instead of waiting for a
+        // connection for the default 30 seconds
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_secs(5))
+            .connect(url)
+            .await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(Self::new(pool))
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for Postgres {
+    #[instrument(skip_all)]
+    async fn habits(&self) -> sqlx::Result<Vec<Habit>> {
+        sqlx::query_as!(
+            Habit,
+            r#"SELECT id,
+                    name,
+                    color AS "color: Color",
+                    kind AS "kind: HabitKind",
+                    recording_type AS "recording_type: RecordingType",
+                    every
+            FROM habits
+            ORDER BY id"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn habit(&self, id: i32) -> sqlx::Result<Option<Habit>> {
+        sqlx::query_as!(
+            Habit,
+            r#"SELECT id,
+                    name,
+                    color AS "color: Color",
+                    kind AS "kind: HabitKind",
+                    recording_type AS "recording_type: RecordingType",
+                    every
+            FROM habits
+            WHERE id = $1"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn event(&self, id: i32) -> sqlx::Result<Option<Event>> {
+        sqlx::query_as!(
+            Event,
+            r#"SELECT id, habit_id, time, span_part AS "span_part: SpanPart"
+            FROM events
+            WHERE id = $1"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id, limit = limit))]
+    async fn events(
+        &self,
+        habit_id: i32,
+        since: Option<NaiveDateTime>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Event>> {
+        sqlx::query_as!(
+            Event,
+            r#"SELECT id, habit_id, time, span_part AS "span_part: SpanPart"
+            FROM events
+            WHERE habit_id = $1 AND ($2::timestamp IS NULL OR time > $2)
+            ORDER BY time DESC, id DESC
+            LIMIT $3"#,
+            habit_id,
+            since,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(per_habit = per_habit))]
+    async fn latest_events(&self, habit_ids: &[i32], per_habit: i64) -> sqlx::Result<Vec<Event>> {
+        sqlx::query_as!(
+            Event,
+            r#"SELECT id AS "id!",
+                    habit_id AS "habit_id!",
+                    time AS "time!",
+                    span_part AS "span_part: SpanPart"
+            FROM (
+                SELECT *, ROW_NUMBER() OVER (PARTITION BY habit_id ORDER BY time DESC, id DESC) AS n
+                FROM events
+                WHERE habit_id = ANY($1)
+            ) e
+            WHERE n <= $2
+            ORDER BY time DESC, id DESC"#,
+            habit_ids,
+            per_habit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn create_habit(&self, habit: &CreateHabit) -> sqlx::Result<i32> {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO habits (name, color, kind, recording_type, every)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id
+            "#,
+            habit.name,
+            habit.color.to_hex(),
+            habit.kind as HabitKind,
+            habit.recording_type as RecordingType,
+            habit.every,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn update_habit(&self, id: i32, habit: &CreateHabit) -> sqlx::Result<bool> {
+        let res = sqlx::query!(
+            r#"
+                UPDATE habits
+                SET name=$2,color=$3,kind=$4,recording_type=$5,every=$6
+                WHERE id=$1
+            "#,
+            id,
+            habit.name,
+            habit.color.to_hex(),
+            habit.kind as HabitKind,
+            habit.recording_type as RecordingType,
+            habit.every,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn delete_habit(&self, id: i32) -> sqlx::Result<bool> {
+        let res = sqlx::query!("DELETE FROM habits WHERE id=$1", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all)]
+    async fn create_event(&self, event: &CreateEvent) -> sqlx::Result<i32> {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO events (habit_id, time, span_part)
+            VALUES ($1, $2, $3)
+            RETURNING id
+            "#,
+            event.habit_id,
+            event.time,
+            event.span_part as Option<SpanPart>,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn create_events(&self, events: Vec<BatchEvent>) -> sqlx::Result<Vec<BatchItem>> {
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(events.len());
+
+        for BatchEvent {
+            event,
+            idempotency_key,
+        } in events
+        {
+            // A savepoint, so a failed insert only rolls back this event
+            let mut savepoint = sqlx::Connection::begin(&mut *tx).await?;
+            let result = async {
+                let inserted = sqlx::query_scalar!(
+                    r#"
+                    INSERT INTO events (habit_id, time, span_part, idempotency_key)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (idempotency_key) DO NOTHING
+                    RETURNING id
+                    "#,
+                    event.habit_id,
+                    event.time,
+                    event.span_part as Option<SpanPart>,
+                    idempotency_key,
+                )
+                .fetch_optional(&mut *savepoint)
+                .await?;
+
+                Ok::<_, sqlx::Error>(match inserted {
+                    Some(id) => BatchItem::Created {
+                        event: event.with_id(id),
+                    },
+                    None => BatchItem::Duplicate {
+                        event: sqlx::query_as!(
+                            Event,
+                            r#"SELECT id, habit_id, time, span_part AS "span_part: SpanPart"
+                            FROM events
+                            WHERE idempotency_key = $1"#,
+                            idempotency_key
+                        )
+                        .fetch_one(&mut *savepoint)
+                        .await?,
+                    },
+                })
+            }
+            .await;
+
+            match result {
+                Ok(item) => {
+                    savepoint.commit().await?;
+                    results.push(item);
+                }
+                // The rest of the batch would fail the same way
+                Err(err) if unavailable(&err) => return Err(err),
+                Err(err) => {
+                    savepoint.rollback().await?;
+                    results.push(BatchItem::Rejected {
+                        error: err.to_string(),
+                    });
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    #[instrument(skip_all)]
+    async fn clear(&self) -> sqlx::Result<()> {
+        sqlx::query!("TRUNCATE TABLE events, habits, changes, calendar_tokens, reminder_settings, push_subscriptions, webhooks, webhook_deliveries, api_tokens, idempotency_keys;",)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(since = since))]
+    async fn changes_since(&self, since: i64) -> sqlx::Result<SyncResponse> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+            .execute(&mut *tx)
+            .await?;
+
+        let cursor = sqlx::query_scalar!(
+            r#"SELECT COALESCE(MAX(seq), $1) AS "cursor!" FROM changes"#,
+            since
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let habits = sqlx::query_as!(
+            Habit,
+            r#"SELECT h.id,
+                    h.name,
+                    h.color AS "color: Color",
+                    h.kind AS "kind: HabitKind",
+                    h.recording_type AS "recording_type: RecordingType",
+                    h.every
+            FROM habits h
+            JOIN changes c ON c.entity = 'habit' AND c.entity_id = h.id
+            WHERE c.seq > $1"#,
+            since
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let events = sqlx::query_as!(
+            Event,
+            r#"SELECT e.id,
+                    e.habit_id,
+                    e.time,
+                    e.span_part AS "span_part: SpanPart"
+            FROM events e
+            JOIN changes c ON c.entity = 'event' AND c.entity_id = e.id
+            WHERE c.seq > $1"#,
+            since
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let tombstones = sqlx::query_as!(
+            Tombstone,
+            r#"SELECT entity AS "entity: EntityKind",
+                    entity_id AS id
+            FROM changes
+            WHERE seq > $1 AND deleted"#,
+            since
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(SyncResponse {
+            cursor,
+            habits,
+            events,
+            tombstones,
+        })
+    }
+
+    #[instrument(skip_all)]
+    async fn listen(&self) -> sqlx::Result<BoxStream<'static, LiveUpdate>> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANNEL).await?;
+
+        let updates = stream::unfold(listener, |mut listener| async move {
+            loop {
+                let update = match listener.recv().await {
+                    Ok(notification) => {
+                        match rocket::serde::json::from_str::<Change>(notification.payload()) {
+                            Ok(change) => LiveUpdate::Change(change),
+                            Err(err) => {
+                                error!("Invalid change notification: {err}");
+                                continue;
+                            }
+                        }
+                    }
+                    Err(sqlx::Error::PoolClosed) => return None,
+                    // The listener reconnects on the next `recv`, but anything sent in between
+                    // is lost
+                    Err(err) => {
+                        error!("Lost change listener connection: {err}");
+                        LiveUpdate::Lagged
+                    }
+                };
+                return Some((update, listener));
+            }
+        });
+        Ok(updates.boxed())
+    }
+
+    fn export_events(&self) -> BoxStream<'_, sqlx::Result<Event>> {
+        sqlx::query_as!(
+            Event,
+            r#"SELECT id,
+                    habit_id,
+                    time,
+                    span_part AS "span_part: SpanPart"
+            FROM events
+            ORDER BY id"#
+        )
+        .fetch(&self.pool)
+    }
+
+    fn export_rows(&self) -> BoxStream<'_, sqlx::Result<ExportRow>> {
+        sqlx::query!(
+            r#"SELECT h.id,
+                    h.name,
+                    h.color AS "color: Color",
+                    h.kind AS "kind: HabitKind",
+                    h.recording_type AS "recording_type: RecordingType",
+                    h.every,
+                    e.id AS "event_id?",
+                    e.time AS "time?",
+                    e.span_part AS "span_part?: SpanPart"
+            FROM habits h
+            LEFT JOIN events e ON e.habit_id = h.id
+            ORDER BY h.id, e.time, e.id"#
+        )
+        .fetch(&self.pool)
+        .map(|row| {
+            row.map(|row| ExportRow {
+                event: row.event_id.zip(row.time).map(|(id, time)| Event {
+                    id,
+                    habit_id: row.id,
+                    time,
+                    span_part: row.span_part,
+                }),
+                habit: Habit {
+                    id: row.id,
+                    name: row.name,
+                    color: row.color,
+                    kind: row.kind,
+                    recording_type: row.recording_type,
+                    every: row.every,
+                },
+            })
+        })
+        .boxed()
+    }
+
+    #[instrument(skip_all)]
+    async fn import(
+        &self,
+        export: Export,
+        on_conflict: ConflictPolicy,
+        dry_run: bool,
+    ) -> sqlx::Result<ImportReport> {
+        let mut tx = self.pool.begin().await?;
+        let mut report = ImportReport {
+            dry_run,
+            ..Default::default()
+        };
+
+        // Maps the ids in the export to the ids in our database, `None` for skipped habits
+        let mut habit_ids = HashMap::new();
+        for mut habit in export.habits {
+            habit.name = habit.name.trim().to_owned();
+            let existing = sqlx::query_scalar!(
+                "SELECT id FROM habits WHERE lower(name) = lower($1)",
+                habit.name
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            let id = match (existing, on_conflict) {
+                (None, _) => {
+                    let id = sqlx::query_scalar!(
+                        r#"
+                        INSERT INTO habits (name, color, kind, recording_type, every)
+                        VALUES ($1, $2, $3, $4, $5)
+                        RETURNING id
+                        "#,
+                        habit.name,
+                        habit.color.to_hex(),
+                        habit.kind as HabitKind,
+                        habit.recording_type as RecordingType,
+                        habit.every,
+                    )
+                    .fetch_one(&mut *tx)
+                    .await?;
+                    report.habits_created.push(habit.name);
+                    Some(id)
+                }
+                (Some(_), ConflictPolicy::Skip) => {
+                    report.habits_skipped.push(habit.name);
+                    None
+                }
+                (Some(id), ConflictPolicy::Merge) => {
+                    report.habits_merged.push(habit.name);
+                    Some(id)
+                }
+                (Some(id), ConflictPolicy::Overwrite) => {
+                    sqlx::query!(
+                        r#"
+                        UPDATE habits
+                        SET color=$2,kind=$3,recording_type=$4,every=$5
+                        WHERE id=$1
+                        "#,
+                        id,
+                        habit.color.to_hex(),
+                        habit.kind as HabitKind,
+                        habit.recording_type as RecordingType,
+                        habit.every,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    report.habits_overwritten.push(habit.name);
+                    Some(id)
+                }
+            };
+            habit_ids.insert(habit.id, id);
+        }
+
+        for event in export.events {
+            let habit_id = match habit_ids.get(&event.habit_id) {
+                Some(Some(id)) => *id,
+                Some(None) => {
+                    report.events_skipped += 1;
+                    continue;
+                }
+                None => return Err(unknown_habit(&event)),
+            };
+
+            let inserted = sqlx::query!(
+                r#"
+                INSERT INTO events (habit_id, time, span_part)
+                SELECT $1, $2, $3
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM events
+                    WHERE habit_id = $1 AND time = $2 AND span_part IS NOT DISTINCT FROM $3
+                )
+                "#,
+                habit_id,
+                event.time,
+                event.span_part as Option<SpanPart>,
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+            if inserted == 0 {
+                report.events_skipped += 1;
+            } else {
+                report.events_created += 1;
+            }
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+        Ok(report)
+    }
+
+    #[instrument(skip_all)]
+    async fn create_calendar_token(&self) -> sqlx::Result<String> {
+        sqlx::query_scalar!("INSERT INTO calendar_tokens DEFAULT VALUES RETURNING token")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_calendar_token(&self, token: &str) -> sqlx::Result<bool> {
+        let res = sqlx::query!("DELETE FROM calendar_tokens WHERE token = $1", token)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all)]
+    async fn calendar_token_exists(&self, token: &str) -> sqlx::Result<bool> {
+        sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM calendar_tokens WHERE token = $1) AS "known!""#,
+            token
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn calendar_events(&self) -> sqlx::Result<Vec<Event>> {
+        sqlx::query_as!(
+            Event,
+            r#"SELECT e.id,
+                    e.habit_id,
+                    e.time,
+                    e.span_part AS "span_part: SpanPart"
+            FROM events e
+            JOIN habits h ON h.id = e.habit_id
+            WHERE h.recording_type = 'span'
+                OR e.id IN (
+                    SELECT DISTINCT ON (habit_id) id
+                    FROM events
+                    ORDER BY habit_id, time DESC
+                )"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn latest_check_ins(&self) -> sqlx::Result<Vec<Event>> {
+        sqlx::query_as!(
+            Event,
+            r#"SELECT DISTINCT ON (habit_id)
+                    id,
+                    habit_id,
+                    time,
+                    span_part AS "span_part: SpanPart"
+            FROM events
+            WHERE span_part IS DISTINCT FROM 'end'
+            ORDER BY habit_id, time DESC"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn reminder_states(&self) -> sqlx::Result<Vec<ReminderState>> {
+        let rows = sqlx::query!(
+            "SELECT habit_id, quiet_start, quiet_end, snoozed_until, last_sent FROM reminder_settings"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| ReminderState {
+                habit_id: row.habit_id,
+                settings: ReminderSettings {
+                    quiet_hours: quiet_hours(row.quiet_start, row.quiet_end),
+                    snoozed_until: row.snoozed_until,
+                },
+                last_sent: row.last_sent,
+            })
+            .collect())
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id))]
+    async fn reminder_settings(&self, habit_id: i32) -> sqlx::Result<Option<ReminderSettings>> {
+        let row = sqlx::query!(
+            "SELECT quiet_start, quiet_end, snoozed_until FROM reminder_settings WHERE habit_id = $1",
+            habit_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| ReminderSettings {
+            quiet_hours: quiet_hours(row.quiet_start, row.quiet_end),
+            snoozed_until: row.snoozed_until,
+        }))
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id))]
+    async fn set_reminder_settings(
+        &self,
+        habit_id: i32,
+        settings: &ReminderSettings,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO reminder_settings (habit_id, quiet_start, quiet_end, snoozed_until)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (habit_id) DO UPDATE
+            SET quiet_start = EXCLUDED.quiet_start,
+                quiet_end = EXCLUDED.quiet_end,
+                snoozed_until = EXCLUDED.snoozed_until"#,
+            habit_id,
+            settings.quiet_hours.map(|quiet| quiet.start),
+            settings.quiet_hours.map(|quiet| quiet.end),
+            settings.snoozed_until,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id))]
+    async fn mark_reminded(&self, habit_id: i32, day: NaiveDate) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO reminder_settings (habit_id, last_sent) VALUES ($1, $2)
+            ON CONFLICT (habit_id) DO UPDATE SET last_sent = EXCLUDED.last_sent"#,
+            habit_id,
+            day
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id))]
+    async fn snooze(&self, habit_id: i32, until: NaiveDateTime) -> sqlx::Result<NaiveDateTime> {
+        sqlx::query_scalar!(
+            r#"INSERT INTO reminder_settings (habit_id, snoozed_until) VALUES ($1, $2)
+            ON CONFLICT (habit_id) DO UPDATE
+            SET snoozed_until = EXCLUDED.snoozed_until, last_sent = NULL
+            RETURNING snoozed_until AS "snoozed_until!""#,
+            habit_id,
+            until
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn push_subscriptions(&self) -> sqlx::Result<Vec<PushSubscription>> {
+        let rows = sqlx::query!("SELECT endpoint, p256dh, auth FROM push_subscriptions")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| PushSubscription {
+                endpoint: row.endpoint,
+                keys: PushKeys {
+                    p256dh: row.p256dh,
+                    auth: row.auth,
+                },
+            })
+            .collect())
+    }
+
+    #[instrument(skip_all)]
+    async fn save_push_subscription(&self, subscription: &PushSubscription) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO push_subscriptions (endpoint, p256dh, auth) VALUES ($1, $2, $3)
+            ON CONFLICT (endpoint) DO UPDATE SET p256dh = EXCLUDED.p256dh, auth = EXCLUDED.auth"#,
+            subscription.endpoint,
+            subscription.keys.p256dh,
+            subscription.keys.auth,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_push_subscription(&self, endpoint: &str) -> sqlx::Result<bool> {
+        let res = sqlx::query!(
+            "DELETE FROM push_subscriptions WHERE endpoint = $1",
+            endpoint
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all)]
+    async fn webhooks(&self) -> sqlx::Result<Vec<Webhook>> {
+        sqlx::query_as!(
+            Webhook,
+            r#"SELECT id, url, events AS "events: Vec<WebhookEvent>" FROM webhooks ORDER BY id"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn create_webhook(
+        &self,
+        url: &str,
+        events: &[WebhookEvent],
+        secret: &str,
+    ) -> sqlx::Result<i32> {
+        sqlx::query_scalar!(
+            "INSERT INTO webhooks (url, events, secret) VALUES ($1, $2, $3) RETURNING id",
+            url,
+            events as &[WebhookEvent],
+            secret
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn delete_webhook(&self, id: i32) -> sqlx::Result<bool> {
+        let res = sqlx::query!("DELETE FROM webhooks WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn webhook_target(&self, id: i32) -> sqlx::Result<Option<WebhookTarget>> {
+        sqlx::query_as!(
+            WebhookTarget,
+            "SELECT id, url, secret FROM webhooks WHERE id = $1",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn webhook_targets(&self, event: WebhookEvent) -> sqlx::Result<Vec<WebhookTarget>> {
+        sqlx::query_as!(
+            WebhookTarget,
+            "SELECT id, url, secret FROM webhooks WHERE $1 = ANY(events)",
+            event as WebhookEvent
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(webhook_id = webhook_id))]
+    async fn queue_delivery(
+        &self,
+        webhook_id: i32,
+        event: WebhookEvent,
+        payload: &str,
+        queued: bool,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"INSERT INTO webhook_deliveries (webhook_id, event, payload, next_attempt_at)
+            VALUES ($1, $2, $3, CASE WHEN $4 THEN now() END)
+            RETURNING id"#,
+            webhook_id,
+            event as WebhookEvent,
+            payload,
+            queued
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(limit = limit, lease_minutes = lease_minutes))]
+    async fn due_deliveries(
+        &self,
+        limit: i64,
+        lease_minutes: i32,
+    ) -> sqlx::Result<Vec<DueDelivery>> {
+        let rows = sqlx::query!(
+            r#"UPDATE webhook_deliveries d
+            SET next_attempt_at = now() + make_interval(mins => $2)
+            FROM webhooks w
+            WHERE w.id = d.webhook_id AND d.id IN (
+                SELECT id FROM webhook_deliveries
+                WHERE next_attempt_at <= now()
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING d.id,
+                w.id AS webhook_id,
+                w.url,
+                w.secret,
+                d.event AS "event: WebhookEvent",
+                d.payload,
+                d.attempts"#,
+            limit,
+            lease_minutes
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DueDelivery {
+                id: row.id,
+                target: WebhookTarget {
+                    id: row.webhook_id,
+                    url: row.url,
+                    secret: row.secret,
+                },
+                event: row.event,
+                payload: row.payload,
+                attempts: row.attempts,
+            })
+            .collect())
+    }
+
+    #[instrument(skip_all, fields(delivery = delivery, attempt = attempt))]
+    async fn record_attempt(
+        &self,
+        delivery: i64,
+        attempt: i32,
+        status_code: Option<i32>,
+        error: Option<&str>,
+        retry_ms: Option<i64>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"UPDATE webhook_deliveries
+            SET attempts = $2,
+                status_code = $3,
+                error = $4,
+                succeeded = $5,
+                next_attempt_at = now() + $6::BIGINT * INTERVAL '1 millisecond'
+            WHERE id = $1"#,
+            delivery,
+            attempt,
+            status_code,
+            error,
+            error.is_none(),
+            retry_ms,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn delivery(&self, id: i64) -> sqlx::Result<Option<WebhookDelivery>> {
+        sqlx::query_as!(
+            WebhookDelivery,
+            r#"SELECT id,
+                    webhook_id,
+                    event AS "event: WebhookEvent",
+                    attempts,
+                    status_code,
+                    error,
+                    succeeded,
+                    created_at
+            FROM webhook_deliveries
+            WHERE id = $1"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(webhook_id = webhook_id))]
+    async fn deliveries(&self, webhook_id: i32) -> sqlx::Result<Vec<WebhookDelivery>> {
+        sqlx::query_as!(
+            WebhookDelivery,
+            r#"SELECT id,
+                    webhook_id,
+                    event AS "event: WebhookEvent",
+                    attempts,
+                    status_code,
+                    error,
+                    succeeded,
+                    created_at
+            FROM webhook_deliveries
+            WHERE webhook_id = $1
+            ORDER BY id DESC
+            LIMIT 100"#,
+            webhook_id
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn use_token(&self, token_hash: &str) -> sqlx::Result<Option<Vec<TokenScope>>> {
+        sqlx::query_scalar!(
+            r#"UPDATE api_tokens SET last_used_at = now()
+            WHERE token_hash = $1
+            RETURNING scopes AS "scopes: Vec<TokenScope>""#,
+            token_hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn tokens(&self) -> sqlx::Result<Vec<ApiToken>> {
+        sqlx::query_as!(
+            ApiToken,
+            r#"SELECT id,
+                    name,
+                    prefix,
+                    scopes AS "scopes: Vec<TokenScope>",
+                    created_at,
+                    last_used_at
+            FROM api_tokens
+            ORDER BY id"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn create_token(
+        &self,
+        name: &str,
+        prefix: &str,
+        token_hash: &str,
+        scopes: &[TokenScope],
+    ) -> sqlx::Result<ApiToken> {
+        sqlx::query_as!(
+            ApiToken,
+            r#"INSERT INTO api_tokens (name, prefix, token_hash, scopes)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id,
+                    name,
+                    prefix,
+                    scopes AS "scopes: Vec<TokenScope>",
+                    created_at,
+                    last_used_at"#,
+            name,
+            prefix,
+            token_hash,
+            scopes as &[TokenScope]
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn revoke_token(&self, id: i32) -> sqlx::Result<bool> {
+        let res = sqlx::query!("DELETE FROM api_tokens WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all, fields(ttl_hours = ttl_hours, lease_minutes = lease_minutes))]
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        ttl_hours: i32,
+        lease_minutes: i32,
+    ) -> sqlx::Result<Option<String>> {
+        let claim = super::new_claim();
+        let claimed = sqlx::query_scalar!(
+            r#"
+            INSERT INTO idempotency_keys (key, fingerprint, claim, expires_at)
+            VALUES ($1, $2, $5, now() + make_interval(hours => $3))
+            ON CONFLICT (key) DO UPDATE
+            SET fingerprint = EXCLUDED.fingerprint,
+                claim = EXCLUDED.claim,
+                status = NULL,
+                content_type = NULL,
+                body = NULL,
+                body_digest = NULL,
+                claimed_at = EXCLUDED.claimed_at,
+                expires_at = EXCLUDED.expires_at
+            WHERE idempotency_keys.expires_at < now()
+                OR (idempotency_keys.status IS NULL
+                    AND idempotency_keys.claimed_at < now() - make_interval(mins => $4))
+            RETURNING key
+            "#,
+            key,
+            fingerprint,
+            ttl_hours,
+            lease_minutes,
+            claim,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(claimed.map(|_| claim))
+    }
+
+    #[instrument(skip_all)]
+    async fn idempotency_key(&self, key: &str) -> sqlx::Result<Option<IdempotencyKey>> {
+        sqlx::query_as!(
+            IdempotencyKey,
+            r#"
+            SELECT fingerprint, status, content_type, body, body_digest
+            FROM idempotency_keys
+            WHERE key = $1
+            "#,
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[instrument(skip_all)]
+    async fn store_idempotent_response(
+        &self,
+        key: &str,
+        claim: &str,
+        status: i16,
+        content_type: Option<String>,
+        body: &[u8],
+        body_digest: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE idempotency_keys
+            SET status = $2, content_type = $3, body = $4, body_digest = $5
+            WHERE key = $1 AND claim = $6
+            "#,
+            key,
+            status,
+            content_type,
+            body,
+            body_digest,
+            claim,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn release_idempotency_key(&self, key: &str, claim: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE key = $1 AND claim = $2",
+            key,
+            claim
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn remove_expired_idempotency_keys(&self) -> sqlx::Result<()> {
+        sqlx::query!("DELETE FROM idempotency_keys WHERE expires_at < now()")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn ping(&self) -> sqlx::Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn pending_migrations(&self) -> sqlx::Result<usize> {
+        // Not a macro, the table is created by `sqlx migrate` and may not be there at build time
+        let applied: Vec<i64> =
+            sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(pending(&MIGRATOR, &applied))
+    }
+
+    #[instrument(skip_all)]
+    async fn kind_counts(&self) -> sqlx::Result<Vec<KindCount>> {
+        sqlx::query_as!(
+            KindCount,
+            r#"SELECT h.kind AS "kind: HabitKind",
+                    COUNT(DISTINCT h.id) AS "habits!",
+                    COUNT(e.id) AS "events!"
+            FROM habits h
+            LEFT JOIN events e ON e.habit_id = h.id
+            GROUP BY h.kind"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            connections: self.pool.size(),
+            idle: self.pool.num_idle() as u32,
+            max_connections: self.pool.options().get_max_connections(),
+        }
+    }
+}
@@ -0,0 +1,1368 @@
+//! The SQLite backend, for running the server on small hardware without a Postgres next to it
+//!
+//! The queries are checked at runtime only, the query macros can't check against two databases.
+//! What the Postgres triggers do happens here, in the transaction of the write: span parts are
+//! checked, events of a habit whose recording type changed are deleted, and every change is
+//! written to the change log. Live updates are announced in process after the commit, so they
+//! only include writes made through this server.
+//!
+//! Writes with `RETURNING` outside a transaction fetch all their rows. sqlx stops stepping a
+//! statement after the first row otherwise, and SQLite only commits the write once the statement
+//! ran to the end, which is not until the connection runs its next statement.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use haby_core::api::{
+    ApiToken,
+    BatchEvent,
+    BatchItem,
+    Change,
+    ConflictPolicy,
+    CreateEvent,
+    CreateHabit,
+    Export,
+    ImportReport,
+    LiveUpdate,
+    PushKeys,
+    PushSubscription,
+    ReminderSettings,
+    SyncResponse,
+    Tombstone,
+    Webhook,
+    WebhookDelivery,
+};
+use haby_core::{
+    Color,
+    EntityKind,
+    Event,
+    Habit,
+    HabitKind,
+    RecordingType,
+    SpanPart,
+    TokenScope,
+    WebhookEvent,
+};
+use rocket::futures::stream::{self, BoxStream};
+use rocket::futures::StreamExt;
+use rocket::serde::json::serde_json;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::broadcast::{self};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::types::chrono::{NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use sqlx::{SqliteConnection, SqlitePool, Transaction};
+use tracing::instrument;
+
+use super::{
+    check_span_part,
+    pending,
+    quiet_hours,
+    unknown_habit,
+    DueDelivery,
+    ExportRow,
+    IdempotencyKey,
+    KindCount,
+    PoolStats,
+    ReminderState,
+    Repository,
+    WebhookTarget,
+};
+use crate::error::unavailable;
+
+/// The migrations the server is built with, `/health/ready` checks they all ran
+pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// How many changes a slow listener can fall behind before it is told it lagged
+const CAPACITY: usize = 256;
+
+type HabitRow = (i32, String, Color, HabitKind, RecordingType, Option<i32>);
+type EventRow = (i32, i32, NaiveDateTime, Option<SpanPart>);
+type TokenRow = (
+    i32,
+    String,
+    String,
+    String,
+    NaiveDateTime,
+    Option<NaiveDateTime>,
+);
+type DeliveryRow = (
+    i64,
+    i32,
+    WebhookEvent,
+    i32,
+    Option<i32>,
+    Option<String>,
+    bool,
+    NaiveDateTime,
+);
+type IdempotencyKeyRow = (
+    String,
+    Option<i16>,
+    Option<String>,
+    Option<Vec<u8>>,
+    Option<String>,
+);
+
+pub struct Sqlite {
+    pool: SqlitePool,
+    changes: broadcast::Sender<Change>,
+}
+
+impl Sqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        let (changes, _) = broadcast::channel(CAPACITY);
+        Self { pool, changes }
+    }
+
+    /// `url` is like `sqlite://haby.db`, the file is created when it does not exist
+    pub async fn connect(url: &str) -> sqlx::Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5))
+            .foreign_keys(true);
+        let pool = SqlitePoolOptions::new()
+            .acquire_timeout(Duration::from_secs(5))
+            .connect_with(options)
+            .await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(Self::new(pool))
+    }
+
+    /// Start a transaction that writes, holding the write lock from the start
+    ///
+    /// SQLite transactions start out reading, and one that starts writing after another
+    /// connection wrote fails right away instead of waiting for the lock.
+    async fn begin_write(&self) -> sqlx::Result<Transaction<'static, sqlx::Sqlite>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE change_seq SET value = value WHERE id = 1")
+            .execute(&mut *tx)
+            .await?;
+        Ok(tx)
+    }
+
+    /// Tell live listeners about committed changes
+    fn announce(&self, changes: impl IntoIterator<Item = Change>) {
+        for change in changes {
+            // Only fails when nobody is listening
+            let _ = self.changes.send(change);
+        }
+    }
+}
+
+fn habit((id, name, color, kind, recording_type, every): HabitRow) -> Habit {
+    Habit {
+        id,
+        name,
+        color,
+        kind,
+        recording_type,
+        every,
+    }
+}
+
+fn event((id, habit_id, time, span_part): EventRow) -> Event {
+    Event {
+        id,
+        habit_id,
+        time,
+        span_part,
+    }
+}
+
+fn token((id, name, prefix, scopes, created_at, last_used_at): TokenRow) -> sqlx::Result<ApiToken> {
+    Ok(ApiToken {
+        id,
+        name,
+        prefix,
+        scopes: from_json(&scopes)?,
+        created_at,
+        last_used_at,
+    })
+}
+
+fn delivery(
+    (id, webhook_id, event, attempts, status_code, error, succeeded, created_at): DeliveryRow,
+) -> WebhookDelivery {
+    WebhookDelivery {
+        id,
+        webhook_id,
+        event,
+        attempts,
+        status_code,
+        error,
+        succeeded,
+        created_at,
+    }
+}
+
+/// The only row of a write with `RETURNING`, see the module docs for why they fetch all rows
+fn only<T>(rows: Vec<T>) -> sqlx::Result<T> {
+    rows.into_iter().next().ok_or(sqlx::Error::RowNotFound)
+}
+
+/// Arrays are stored as JSON, SQLite has no array type
+fn to_json(value: &impl rocket::serde::Serialize) -> String {
+    serde_json::to_string(value).expect("Arrays of enums serialize")
+}
+
+fn from_json<T: rocket::serde::de::DeserializeOwned>(json: &str) -> sqlx::Result<T> {
+    serde_json::from_str(json).map_err(|err| sqlx::Error::Decode(Box::new(err)))
+}
+
+/// Write a change to the change log, what the `record_change` trigger does in Postgres
+async fn record_change(
+    conn: &mut SqliteConnection,
+    entity: EntityKind,
+    id: i32,
+    deleted: bool,
+) -> sqlx::Result<Change> {
+    let cursor: i64 =
+        sqlx::query_scalar("UPDATE change_seq SET value = value + 1 WHERE id = 1 RETURNING value")
+            .fetch_one(&mut *conn)
+            .await?;
+    sqlx::query(
+        r#"INSERT INTO changes (entity, entity_id, seq, deleted)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (entity, entity_id)
+        DO UPDATE SET seq = excluded.seq, deleted = excluded.deleted"#,
+    )
+    .bind(entity)
+    .bind(id)
+    .bind(cursor)
+    .bind(deleted)
+    .execute(&mut *conn)
+    .await?;
+    Ok(Change {
+        entity,
+        id,
+        deleted,
+        cursor,
+    })
+}
+
+/// Delete every event of a habit, returning their tombstones
+async fn delete_events(conn: &mut SqliteConnection, habit_id: i32) -> sqlx::Result<Vec<Change>> {
+    let ids: Vec<i32> = sqlx::query_scalar("DELETE FROM events WHERE habit_id = $1 RETURNING id")
+        .bind(habit_id)
+        .fetch_all(&mut *conn)
+        .await?;
+    let mut changes = Vec::with_capacity(ids.len());
+    for id in ids {
+        changes.push(record_change(conn, EntityKind::Event, id, true).await?);
+    }
+    Ok(changes)
+}
+
+/// Update a habit, deleting its events when the recording type changed
+///
+/// `None` when there is no habit with that id.
+async fn update_habit(
+    conn: &mut SqliteConnection,
+    id: i32,
+    habit: &CreateHabit,
+) -> sqlx::Result<Option<Vec<Change>>> {
+    let recording_type: Option<RecordingType> =
+        sqlx::query_scalar("SELECT recording_type FROM habits WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&mut *conn)
+            .await?;
+    let Some(recording_type) = recording_type else {
+        return Ok(None);
+    };
+
+    sqlx::query(
+        r#"UPDATE habits
+        SET name = $2, color = $3, kind = $4, recording_type = $5, every = $6
+        WHERE id = $1"#,
+    )
+    .bind(id)
+    .bind(&habit.name)
+    .bind(habit.color)
+    .bind(habit.kind)
+    .bind(habit.recording_type)
+    .bind(habit.every)
+    .execute(&mut *conn)
+    .await?;
+
+    let mut changes = Vec::new();
+    if recording_type != habit.recording_type {
+        changes = delete_events(conn, id).await?;
+    }
+    changes.push(record_change(conn, EntityKind::Habit, id, false).await?);
+    Ok(Some(changes))
+}
+
+/// Check the span part of an event fits its habit, a missing habit is left to the foreign key
+async fn check_event(conn: &mut SqliteConnection, event: &CreateEvent) -> sqlx::Result<()> {
+    let recording_type: Option<RecordingType> =
+        sqlx::query_scalar("SELECT recording_type FROM habits WHERE id = $1")
+            .bind(event.habit_id)
+            .fetch_optional(&mut *conn)
+            .await?;
+    match recording_type {
+        Some(recording_type) => check_span_part(recording_type, event.span_part),
+        None => Ok(()),
+    }
+}
+
+/// Insert a checked event, `None` when its idempotency key was used before
+async fn insert_event(
+    conn: &mut SqliteConnection,
+    event: &CreateEvent,
+    idempotency_key: Option<&str>,
+) -> sqlx::Result<Option<(i32, Change)>> {
+    check_event(conn, event).await?;
+    let id: Option<i32> = sqlx::query_scalar(
+        r#"INSERT INTO events (habit_id, time, span_part, idempotency_key)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (idempotency_key) DO NOTHING
+        RETURNING id"#,
+    )
+    .bind(event.habit_id)
+    .bind(event.time)
+    .bind(event.span_part)
+    .bind(idempotency_key)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    match id {
+        Some(id) => {
+            let change = record_change(conn, EntityKind::Event, id, false).await?;
+            Ok(Some((id, change)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for Sqlite {
+    #[instrument(skip_all)]
+    async fn habits(&self) -> sqlx::Result<Vec<Habit>> {
+        let rows = sqlx::query_as::<_, HabitRow>(
+            "SELECT id, name, color, kind, recording_type, every FROM habits ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(habit).collect())
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn habit(&self, id: i32) -> sqlx::Result<Option<Habit>> {
+        let row = sqlx::query_as::<_, HabitRow>(
+            "SELECT id, name, color, kind, recording_type, every FROM habits WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(habit))
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn event(&self, id: i32) -> sqlx::Result<Option<Event>> {
+        let row = sqlx::query_as::<_, EventRow>(
+            "SELECT id, habit_id, time, span_part FROM events WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(event))
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id, limit = limit))]
+    async fn events(
+        &self,
+        habit_id: i32,
+        since: Option<NaiveDateTime>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Event>> {
+        let rows = sqlx::query_as::<_, EventRow>(
+            r#"SELECT id, habit_id, time, span_part
+            FROM events
+            WHERE habit_id = $1 AND ($2 IS NULL OR time > $2)
+            ORDER BY time DESC, id DESC
+            LIMIT $3"#,
+        )
+        .bind(habit_id)
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(event).collect())
+    }
+
+    #[instrument(skip_all, fields(per_habit = per_habit))]
+    async fn latest_events(&self, habit_ids: &[i32], per_habit: i64) -> sqlx::Result<Vec<Event>> {
+        let rows = sqlx::query_as::<_, EventRow>(
+            r#"SELECT id, habit_id, time, span_part FROM (
+                SELECT *, ROW_NUMBER() OVER (PARTITION BY habit_id ORDER BY time DESC, id DESC) AS n
+                FROM events
+                WHERE habit_id IN (SELECT value FROM json_each($1))
+            )
+            WHERE n <= $2
+            ORDER BY time DESC, id DESC"#,
+        )
+        .bind(sqlx::types::Json(habit_ids))
+        .bind(per_habit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(event).collect())
+    }
+
+    #[instrument(skip_all)]
+    async fn create_habit(&self, habit: &CreateHabit) -> sqlx::Result<i32> {
+        let mut tx = self.begin_write().await?;
+        let id = sqlx::query_scalar(
+            r#"INSERT INTO habits (name, color, kind, recording_type, every)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id"#,
+        )
+        .bind(&habit.name)
+        .bind(habit.color)
+        .bind(habit.kind)
+        .bind(habit.recording_type)
+        .bind(habit.every)
+        .fetch_one(&mut *tx)
+        .await?;
+        let change = record_change(&mut tx, EntityKind::Habit, id, false).await?;
+        tx.commit().await?;
+
+        self.announce([change]);
+        Ok(id)
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn update_habit(&self, id: i32, habit: &CreateHabit) -> sqlx::Result<bool> {
+        let mut tx = self.begin_write().await?;
+        let Some(changes) = update_habit(&mut tx, id, habit).await? else {
+            return Ok(false);
+        };
+        tx.commit().await?;
+
+        self.announce(changes);
+        Ok(true)
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn delete_habit(&self, id: i32) -> sqlx::Result<bool> {
+        let mut tx = self.begin_write().await?;
+        // The foreign key would delete them too, but without tombstones
+        let mut changes = delete_events(&mut tx, id).await?;
+        let res = sqlx::query("DELETE FROM habits WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        changes.push(record_change(&mut tx, EntityKind::Habit, id, true).await?);
+        tx.commit().await?;
+
+        self.announce(changes);
+        Ok(true)
+    }
+
+    #[instrument(skip_all)]
+    async fn create_event(&self, event: &CreateEvent) -> sqlx::Result<i32> {
+        let mut tx = self.begin_write().await?;
+        let (id, change) = insert_event(&mut tx, event, None)
+            .await?
+            .expect("Events without an idempotency key never conflict");
+        tx.commit().await?;
+
+        self.announce([change]);
+        Ok(id)
+    }
+
+    #[instrument(skip_all)]
+    async fn create_events(&self, events: Vec<BatchEvent>) -> sqlx::Result<Vec<BatchItem>> {
+        let mut tx = self.begin_write().await?;
+        let mut results = Vec::with_capacity(events.len());
+        let mut changes = Vec::new();
+
+        for BatchEvent {
+            event,
+            idempotency_key,
+        } in events
+        {
+            // A savepoint, so a failed insert only rolls back this event
+            let mut savepoint = sqlx::Connection::begin(&mut *tx).await?;
+            let result = async {
+                match insert_event(&mut savepoint, &event, idempotency_key.as_deref()).await? {
+                    Some((id, change)) => {
+                        let event = event.with_id(id);
+                        Ok((BatchItem::Created { event }, Some(change)))
+                    }
+                    None => {
+                        let row = sqlx::query_as::<_, EventRow>(
+                            "SELECT id, habit_id, time, span_part FROM events WHERE idempotency_key = $1",
+                        )
+                        .bind(&idempotency_key)
+                        .fetch_one(&mut *savepoint)
+                        .await?;
+                        let event = self::event(row);
+                        Ok::<_, sqlx::Error>((BatchItem::Duplicate { event }, None))
+                    }
+                }
+            }
+            .await;
+
+            match result {
+                Ok((item, change)) => {
+                    savepoint.commit().await?;
+                    changes.extend(change);
+                    results.push(item);
+                }
+                // The rest of the batch would fail the same way
+                Err(err) if unavailable(&err) => return Err(err),
+                Err(err) => {
+                    savepoint.rollback().await?;
+                    results.push(BatchItem::Rejected {
+                        error: err.to_string(),
+                    });
+                }
+            }
+        }
+        tx.commit().await?;
+
+        self.announce(changes);
+        Ok(results)
+    }
+
+    #[instrument(skip_all)]
+    async fn clear(&self) -> sqlx::Result<()> {
+        let mut tx = self.begin_write().await?;
+        for table in [
+            "events",
+            "reminder_settings",
+            "habits",
+            "changes",
+            "calendar_tokens",
+            "push_subscriptions",
+            "webhook_deliveries",
+            "webhooks",
+            "api_tokens",
+            "idempotency_keys",
+        ] {
+            sqlx::query(&format!("DELETE FROM {table}"))
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    #[instrument(skip_all, fields(since = since))]
+    async fn changes_since(&self, since: i64) -> sqlx::Result<SyncResponse> {
+        // Every read of a transaction sees the same snapshot
+        let mut tx = self.pool.begin().await?;
+
+        let cursor = sqlx::query_scalar("SELECT COALESCE(MAX(seq), $1) FROM changes")
+            .bind(since)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let habits = sqlx::query_as::<_, HabitRow>(
+            r#"SELECT h.id, h.name, h.color, h.kind, h.recording_type, h.every
+            FROM habits h
+            JOIN changes c ON c.entity = 'habit' AND c.entity_id = h.id
+            WHERE c.seq > $1"#,
+        )
+        .bind(since)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let events = sqlx::query_as::<_, EventRow>(
+            r#"SELECT e.id, e.habit_id, e.time, e.span_part
+            FROM events e
+            JOIN changes c ON c.entity = 'event' AND c.entity_id = e.id
+            WHERE c.seq > $1"#,
+        )
+        .bind(since)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let tombstones = sqlx::query_as::<_, (EntityKind, i32)>(
+            "SELECT entity, entity_id FROM changes WHERE seq > $1 AND deleted",
+        )
+        .bind(since)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(SyncResponse {
+            cursor,
+            habits: habits.into_iter().map(habit).collect(),
+            events: events.into_iter().map(event).collect(),
+            tombstones: tombstones
+                .into_iter()
+                .map(|(entity, id)| Tombstone { entity, id })
+                .collect(),
+        })
+    }
+
+    #[instrument(skip_all)]
+    async fn listen(&self) -> sqlx::Result<BoxStream<'static, LiveUpdate>> {
+        let updates = stream::unfold(self.changes.subscribe(), |mut receiver| async move {
+            let update = match receiver.recv().await {
+                Ok(change) => LiveUpdate::Change(change),
+                Err(RecvError::Lagged(_)) => LiveUpdate::Lagged,
+                Err(RecvError::Closed) => return None,
+            };
+            Some((update, receiver))
+        });
+        Ok(updates.boxed())
+    }
+
+    fn export_events(&self) -> BoxStream<'_, sqlx::Result<Event>> {
+        sqlx::query_as::<_, EventRow>(
+            "SELECT id, habit_id, time, span_part FROM events ORDER BY id",
+        )
+        .fetch(&self.pool)
+        .map(|row| row.map(event))
+        .boxed()
+    }
+
+    fn export_rows(&self) -> BoxStream<'_, sqlx::Result<ExportRow>> {
+        type Row = (
+            i32,
+            String,
+            Color,
+            HabitKind,
+            RecordingType,
+            Option<i32>,
+            Option<i32>,
+            Option<NaiveDateTime>,
+            Option<SpanPart>,
+        );
+        sqlx::query_as::<_, Row>(
+            r#"SELECT h.id, h.name, h.color, h.kind, h.recording_type, h.every,
+                    e.id, e.time, e.span_part
+            FROM habits h
+            LEFT JOIN events e ON e.habit_id = h.id
+            ORDER BY h.id, e.time, e.id"#,
+        )
+        .fetch(&self.pool)
+        .map(|row| {
+            let (id, name, color, kind, recording_type, every, event_id, time, span_part) = row?;
+            Ok(ExportRow {
+                habit: habit((id, name, color, kind, recording_type, every)),
+                event: event_id
+                    .zip(time)
+                    .map(|(event_id, time)| event((event_id, id, time, span_part))),
+            })
+        })
+        .boxed()
+    }
+
+    #[instrument(skip_all)]
+    async fn import(
+        &self,
+        export: Export,
+        on_conflict: ConflictPolicy,
+        dry_run: bool,
+    ) -> sqlx::Result<ImportReport> {
+        let mut tx = self.begin_write().await?;
+        let mut changes = Vec::new();
+        let mut report = ImportReport {
+            dry_run,
+            ..Default::default()
+        };
+
+        // Maps the ids in the export to the ids in our database, `None` for skipped habits
+        let mut habit_ids = HashMap::new();
+        for mut habit in export.habits {
+            habit.name = habit.name.trim().to_owned();
+            let existing: Option<(i32, String)> =
+                sqlx::query_as("SELECT id, name FROM habits WHERE lower(name) = lower($1)")
+                    .bind(&habit.name)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+
+            let id = match (existing, on_conflict) {
+                (None, _) => {
+                    let id = sqlx::query_scalar(
+                        r#"INSERT INTO habits (name, color, kind, recording_type, every)
+                        VALUES ($1, $2, $3, $4, $5)
+                        RETURNING id"#,
+                    )
+                    .bind(&habit.name)
+                    .bind(habit.color)
+                    .bind(habit.kind)
+                    .bind(habit.recording_type)
+                    .bind(habit.every)
+                    .fetch_one(&mut *tx)
+                    .await?;
+                    changes.push(record_change(&mut tx, EntityKind::Habit, id, false).await?);
+                    report.habits_created.push(habit.name);
+                    Some(id)
+                }
+                (Some(_), ConflictPolicy::Skip) => {
+                    report.habits_skipped.push(habit.name);
+                    None
+                }
+                (Some((id, _)), ConflictPolicy::Merge) => {
+                    report.habits_merged.push(habit.name);
+                    Some(id)
+                }
+                (Some((id, name)), ConflictPolicy::Overwrite) => {
+                    // Only the settings are overwritten, the name keeps its case
+                    let settings = CreateHabit {
+                        name,
+                        ..habit.as_create()
+                    };
+                    changes.extend(
+                        update_habit(&mut tx, id, &settings)
+                            .await?
+                            .unwrap_or_default(),
+                    );
+                    report.habits_overwritten.push(habit.name);
+                    Some(id)
+                }
+            };
+            habit_ids.insert(habit.id, id);
+        }
+
+        for event in export.events {
+            let habit_id = match habit_ids.get(&event.habit_id) {
+                Some(Some(id)) => *id,
+                Some(None) => {
+                    report.events_skipped += 1;
+                    continue;
+                }
+                None => return Err(unknown_habit(&event)),
+            };
+
+            let create = CreateEvent {
+                habit_id,
+                time: event.time,
+                span_part: event.span_part,
+            };
+            check_event(&mut tx, &create).await?;
+            let inserted: Option<i32> = sqlx::query_scalar(
+                r#"INSERT INTO events (habit_id, time, span_part)
+                SELECT $1, $2, $3
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM events
+                    WHERE habit_id = $1 AND time = $2 AND span_part IS $3
+                )
+                RETURNING id"#,
+            )
+            .bind(habit_id)
+            .bind(event.time)
+            .bind(event.span_part)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            match inserted {
+                Some(id) => {
+                    changes.push(record_change(&mut tx, EntityKind::Event, id, false).await?);
+                    report.events_created += 1;
+                }
+                None => report.events_skipped += 1,
+            }
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+            self.announce(changes);
+        }
+        Ok(report)
+    }
+
+    #[instrument(skip_all)]
+    async fn create_calendar_token(&self) -> sqlx::Result<String> {
+        let rows = sqlx::query_scalar("INSERT INTO calendar_tokens DEFAULT VALUES RETURNING token")
+            .fetch_all(&self.pool)
+            .await?;
+        only(rows)
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_calendar_token(&self, token: &str) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM calendar_tokens WHERE token = $1")
+            .bind(token)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all)]
+    async fn calendar_token_exists(&self, token: &str) -> sqlx::Result<bool> {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM calendar_tokens WHERE token = $1)")
+            .bind(token)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    #[instrument(skip_all)]
+    async fn calendar_events(&self) -> sqlx::Result<Vec<Event>> {
+        let rows = sqlx::query_as::<_, EventRow>(
+            r#"SELECT e.id, e.habit_id, e.time, e.span_part
+            FROM events e
+            JOIN habits h ON h.id = e.habit_id
+            WHERE h.recording_type = 'span'
+                OR e.id IN (
+                    SELECT id FROM (
+                        SELECT id, ROW_NUMBER() OVER (PARTITION BY habit_id ORDER BY time DESC) AS n
+                        FROM events
+                    )
+                    WHERE n = 1
+                )"#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(event).collect())
+    }
+
+    #[instrument(skip_all)]
+    async fn latest_check_ins(&self) -> sqlx::Result<Vec<Event>> {
+        let rows = sqlx::query_as::<_, EventRow>(
+            r#"SELECT id, habit_id, time, span_part FROM (
+                SELECT *, ROW_NUMBER() OVER (PARTITION BY habit_id ORDER BY time DESC) AS n
+                FROM events
+                WHERE span_part IS NOT 'end'
+            )
+            WHERE n = 1"#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(event).collect())
+    }
+
+    #[instrument(skip_all)]
+    async fn reminder_states(&self) -> sqlx::Result<Vec<ReminderState>> {
+        type Row = (
+            i32,
+            Option<NaiveTime>,
+            Option<NaiveTime>,
+            Option<NaiveDateTime>,
+            Option<NaiveDate>,
+        );
+        let rows = sqlx::query_as::<_, Row>(
+            "SELECT habit_id, quiet_start, quiet_end, snoozed_until, last_sent FROM reminder_settings",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(habit_id, quiet_start, quiet_end, snoozed_until, last_sent)| ReminderState {
+                    habit_id,
+                    settings: ReminderSettings {
+                        quiet_hours: quiet_hours(quiet_start, quiet_end),
+                        snoozed_until,
+                    },
+                    last_sent,
+                },
+            )
+            .collect())
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id))]
+    async fn reminder_settings(&self, habit_id: i32) -> sqlx::Result<Option<ReminderSettings>> {
+        let row = sqlx::query_as::<_, (Option<NaiveTime>, Option<NaiveTime>, Option<NaiveDateTime>)>(
+            "SELECT quiet_start, quiet_end, snoozed_until FROM reminder_settings WHERE habit_id = $1",
+        )
+        .bind(habit_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(
+            row.map(|(quiet_start, quiet_end, snoozed_until)| ReminderSettings {
+                quiet_hours: quiet_hours(quiet_start, quiet_end),
+                snoozed_until,
+            }),
+        )
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id))]
+    async fn set_reminder_settings(
+        &self,
+        habit_id: i32,
+        settings: &ReminderSettings,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO reminder_settings (habit_id, quiet_start, quiet_end, snoozed_until)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (habit_id) DO UPDATE
+            SET quiet_start = excluded.quiet_start,
+                quiet_end = excluded.quiet_end,
+                snoozed_until = excluded.snoozed_until"#,
+        )
+        .bind(habit_id)
+        .bind(settings.quiet_hours.map(|quiet| quiet.start))
+        .bind(settings.quiet_hours.map(|quiet| quiet.end))
+        .bind(settings.snoozed_until)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id))]
+    async fn mark_reminded(&self, habit_id: i32, day: NaiveDate) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO reminder_settings (habit_id, last_sent) VALUES ($1, $2)
+            ON CONFLICT (habit_id) DO UPDATE SET last_sent = excluded.last_sent"#,
+        )
+        .bind(habit_id)
+        .bind(day)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(habit_id = habit_id))]
+    async fn snooze(&self, habit_id: i32, until: NaiveDateTime) -> sqlx::Result<NaiveDateTime> {
+        let rows = sqlx::query_scalar(
+            r#"INSERT INTO reminder_settings (habit_id, snoozed_until) VALUES ($1, $2)
+            ON CONFLICT (habit_id) DO UPDATE
+            SET snoozed_until = excluded.snoozed_until, last_sent = NULL
+            RETURNING snoozed_until"#,
+        )
+        .bind(habit_id)
+        .bind(until)
+        .fetch_all(&self.pool)
+        .await?;
+        only(rows)
+    }
+
+    #[instrument(skip_all)]
+    async fn push_subscriptions(&self) -> sqlx::Result<Vec<PushSubscription>> {
+        let rows = sqlx::query_as::<_, (String, String, String)>(
+            "SELECT endpoint, p256dh, auth FROM push_subscriptions",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(endpoint, p256dh, auth)| PushSubscription {
+                endpoint,
+                keys: PushKeys { p256dh, auth },
+            })
+            .collect())
+    }
+
+    #[instrument(skip_all)]
+    async fn save_push_subscription(&self, subscription: &PushSubscription) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO push_subscriptions (endpoint, p256dh, auth) VALUES ($1, $2, $3)
+            ON CONFLICT (endpoint) DO UPDATE SET p256dh = excluded.p256dh, auth = excluded.auth"#,
+        )
+        .bind(&subscription.endpoint)
+        .bind(&subscription.keys.p256dh)
+        .bind(&subscription.keys.auth)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_push_subscription(&self, endpoint: &str) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM push_subscriptions WHERE endpoint = $1")
+            .bind(endpoint)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all)]
+    async fn webhooks(&self) -> sqlx::Result<Vec<Webhook>> {
+        let rows = sqlx::query_as::<_, (i32, String, String)>(
+            "SELECT id, url, events FROM webhooks ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(id, url, events)| {
+                Ok(Webhook {
+                    id,
+                    url,
+                    events: from_json(&events)?,
+                })
+            })
+            .collect()
+    }
+
+    #[instrument(skip_all)]
+    async fn create_webhook(
+        &self,
+        url: &str,
+        events: &[WebhookEvent],
+        secret: &str,
+    ) -> sqlx::Result<i32> {
+        let rows = sqlx::query_scalar(
+            "INSERT INTO webhooks (url, events, secret) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(url)
+        .bind(to_json(&events))
+        .bind(secret)
+        .fetch_all(&self.pool)
+        .await?;
+        only(rows)
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn delete_webhook(&self, id: i32) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM webhooks WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn webhook_target(&self, id: i32) -> sqlx::Result<Option<WebhookTarget>> {
+        let row = sqlx::query_as::<_, (i32, String, String)>(
+            "SELECT id, url, secret FROM webhooks WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(id, url, secret)| WebhookTarget { id, url, secret }))
+    }
+
+    #[instrument(skip_all)]
+    async fn webhook_targets(&self, event: WebhookEvent) -> sqlx::Result<Vec<WebhookTarget>> {
+        let rows = sqlx::query_as::<_, (i32, String, String)>(
+            r#"SELECT id, url, secret FROM webhooks
+            WHERE EXISTS (SELECT 1 FROM json_each(webhooks.events) WHERE value = $1)"#,
+        )
+        .bind(event)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, url, secret)| WebhookTarget { id, url, secret })
+            .collect())
+    }
+
+    #[instrument(skip_all, fields(webhook_id = webhook_id))]
+    async fn queue_delivery(
+        &self,
+        webhook_id: i32,
+        event: WebhookEvent,
+        payload: &str,
+        queued: bool,
+    ) -> sqlx::Result<i64> {
+        let rows = sqlx::query_scalar(
+            r#"INSERT INTO webhook_deliveries (webhook_id, event, payload, next_attempt_at)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id"#,
+        )
+        .bind(webhook_id)
+        .bind(event)
+        .bind(payload)
+        .bind(queued.then(|| Utc::now().naive_utc()))
+        .fetch_all(&self.pool)
+        .await?;
+        only(rows)
+    }
+
+    #[instrument(skip_all, fields(limit = limit, lease_minutes = lease_minutes))]
+    async fn due_deliveries(
+        &self,
+        limit: i64,
+        lease_minutes: i32,
+    ) -> sqlx::Result<Vec<DueDelivery>> {
+        let now = Utc::now().naive_utc();
+        // SQLite runs one write at a time, so claiming needs no row locks
+        let rows = sqlx::query_as::<_, (i64, i32, String, String, WebhookEvent, String, i32)>(
+            r#"UPDATE webhook_deliveries
+            SET next_attempt_at = $3
+            WHERE id IN (
+                SELECT id FROM webhook_deliveries
+                WHERE next_attempt_at <= $2
+                ORDER BY next_attempt_at
+                LIMIT $1
+            )
+            RETURNING id,
+                webhook_id,
+                (SELECT url FROM webhooks WHERE webhooks.id = webhook_id),
+                (SELECT secret FROM webhooks WHERE webhooks.id = webhook_id),
+                event,
+                payload,
+                attempts"#,
+        )
+        .bind(limit)
+        .bind(now)
+        .bind(now + Duration::from_secs(u64::try_from(lease_minutes).unwrap_or(0) * 60))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(id, webhook_id, url, secret, event, payload, attempts)| DueDelivery {
+                    id,
+                    target: WebhookTarget {
+                        id: webhook_id,
+                        url,
+                        secret,
+                    },
+                    event,
+                    payload,
+                    attempts,
+                },
+            )
+            .collect())
+    }
+
+    #[instrument(skip_all, fields(delivery = delivery, attempt = attempt))]
+    async fn record_attempt(
+        &self,
+        delivery: i64,
+        attempt: i32,
+        status_code: Option<i32>,
+        error: Option<&str>,
+        retry_ms: Option<i64>,
+    ) -> sqlx::Result<()> {
+        let retry_at = retry_ms.map(|retry_ms| {
+            Utc::now().naive_utc() + Duration::from_millis(u64::try_from(retry_ms).unwrap_or(0))
+        });
+        sqlx::query(
+            r#"UPDATE webhook_deliveries
+            SET attempts = $2, status_code = $3, error = $4, succeeded = $5, next_attempt_at = $6
+            WHERE id = $1"#,
+        )
+        .bind(delivery)
+        .bind(attempt)
+        .bind(status_code)
+        .bind(error)
+        .bind(error.is_none())
+        .bind(retry_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn delivery(&self, id: i64) -> sqlx::Result<Option<WebhookDelivery>> {
+        let row = sqlx::query_as::<_, DeliveryRow>(
+            r#"SELECT id, webhook_id, event, attempts, status_code, error, succeeded, created_at
+            FROM webhook_deliveries
+            WHERE id = $1"#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(delivery))
+    }
+
+    #[instrument(skip_all, fields(webhook_id = webhook_id))]
+    async fn deliveries(&self, webhook_id: i32) -> sqlx::Result<Vec<WebhookDelivery>> {
+        let rows = sqlx::query_as::<_, DeliveryRow>(
+            r#"SELECT id, webhook_id, event, attempts, status_code, error, succeeded, created_at
+            FROM webhook_deliveries
+            WHERE webhook_id = $1
+            ORDER BY id DESC
+            LIMIT 100"#,
+        )
+        .bind(webhook_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(delivery).collect())
+    }
+
+    #[instrument(skip_all)]
+    async fn use_token(&self, token_hash: &str) -> sqlx::Result<Option<Vec<TokenScope>>> {
+        let scopes: Option<String> = sqlx::query_scalar(
+            "UPDATE api_tokens SET last_used_at = $2 WHERE token_hash = $1 RETURNING scopes",
+        )
+        .bind(token_hash)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(&self.pool)
+        .await?
+        .pop();
+        scopes.map(|scopes| from_json(&scopes)).transpose()
+    }
+
+    #[instrument(skip_all)]
+    async fn tokens(&self) -> sqlx::Result<Vec<ApiToken>> {
+        let rows = sqlx::query_as::<_, TokenRow>(
+            r#"SELECT id, name, prefix, scopes, created_at, last_used_at
+            FROM api_tokens
+            ORDER BY id"#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(token).collect()
+    }
+
+    #[instrument(skip_all)]
+    async fn create_token(
+        &self,
+        name: &str,
+        prefix: &str,
+        token_hash: &str,
+        scopes: &[TokenScope],
+    ) -> sqlx::Result<ApiToken> {
+        let rows = sqlx::query_as::<_, TokenRow>(
+            r#"INSERT INTO api_tokens (name, prefix, token_hash, scopes)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, name, prefix, scopes, created_at, last_used_at"#,
+        )
+        .bind(name)
+        .bind(prefix)
+        .bind(token_hash)
+        .bind(to_json(&scopes))
+        .fetch_all(&self.pool)
+        .await?;
+        token(only(rows)?)
+    }
+
+    #[instrument(skip_all, fields(id = id))]
+    async fn revoke_token(&self, id: i32) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM api_tokens WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    #[instrument(skip_all, fields(ttl_hours = ttl_hours, lease_minutes = lease_minutes))]
+    async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        ttl_hours: i32,
+        lease_minutes: i32,
+    ) -> sqlx::Result<Option<String>> {
+        let now = Utc::now().naive_utc();
+        let claim = super::new_claim();
+        let claimed: Option<String> = sqlx::query_scalar(
+            r#"INSERT INTO idempotency_keys (key, fingerprint, claim, claimed_at, expires_at)
+            VALUES ($1, $2, $6, $4, $3)
+            ON CONFLICT (key) DO UPDATE
+            SET fingerprint = excluded.fingerprint,
+                claim = excluded.claim,
+                status = NULL,
+                content_type = NULL,
+                body = NULL,
+                body_digest = NULL,
+                claimed_at = excluded.claimed_at,
+                expires_at = excluded.expires_at
+            WHERE idempotency_keys.expires_at < $4
+                OR (idempotency_keys.status IS NULL AND idempotency_keys.claimed_at < $5)
+            RETURNING key"#,
+        )
+        .bind(key)
+        .bind(fingerprint)
+        .bind(now + Duration::from_secs(u64::try_from(ttl_hours).unwrap_or(0) * 60 * 60))
+        .bind(now)
+        .bind(now - Duration::from_secs(u64::try_from(lease_minutes).unwrap_or(0) * 60))
+        .bind(&claim)
+        .fetch_all(&self.pool)
+        .await?
+        .pop();
+        Ok(claimed.map(|_| claim))
+    }
+
+    #[instrument(skip_all)]
+    async fn idempotency_key(&self, key: &str) -> sqlx::Result<Option<IdempotencyKey>> {
+        let row = sqlx::query_as::<_, IdempotencyKeyRow>(
+            "SELECT fingerprint, status, content_type, body, body_digest FROM idempotency_keys
+            WHERE key = $1",
+        )
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(
+            |(fingerprint, status, content_type, body, body_digest)| IdempotencyKey {
+                fingerprint,
+                status,
+                content_type,
+                body,
+                body_digest,
+            },
+        ))
+    }
+
+    #[instrument(skip_all)]
+    async fn store_idempotent_response(
+        &self,
+        key: &str,
+        claim: &str,
+        status: i16,
+        content_type: Option<String>,
+        body: &[u8],
+        body_digest: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE idempotency_keys SET status = $2, content_type = $3, body = $4, body_digest = $5
+            WHERE key = $1 AND claim = $6",
+        )
+        .bind(key)
+        .bind(status)
+        .bind(content_type)
+        .bind(body)
+        .bind(body_digest)
+        .bind(claim)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn release_idempotency_key(&self, key: &str, claim: &str) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM idempotency_keys WHERE key = $1 AND claim = $2")
+            .bind(key)
+            .bind(claim)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn remove_expired_idempotency_keys(&self) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM idempotency_keys WHERE expires_at < $1")
+            .bind(Utc::now().naive_utc())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn ping(&self) -> sqlx::Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn pending_migrations(&self) -> sqlx::Result<usize> {
+        let applied: Vec<i64> =
+            sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(pending(&MIGRATOR, &applied))
+    }
+
+    #[instrument(skip_all)]
+    async fn kind_counts(&self) -> sqlx::Result<Vec<KindCount>> {
+        let rows = sqlx::query_as::<_, (HabitKind, i64, i64)>(
+            r#"SELECT h.kind, COUNT(DISTINCT h.id), COUNT(e.id)
+            FROM habits h
+            LEFT JOIN events e ON e.habit_id = h.id
+            GROUP BY h.kind"#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(kind, habits, events)| KindCount {
+                kind,
+                habits,
+                events,
+            })
+            .collect())
+    }
+
+    fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            connections: self.pool.size(),
+            idle: self.pool.num_idle() as u32,
+            max_connections: self.pool.options().get_max_connections(),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! The error every route returns
+//!
+//! Database errors are split into ones where the database can't be reached, which are answered
+//! with `503 Service Unavailable` and a `Retry-After` so clients come back later, and everything
+//! else.
+
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder};
+use rocket::{catch, catchers, Catcher, Request};
+
+/// How long clients should wait before retrying when the database is down, in seconds
+const RETRY_AFTER: u64 = 5;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// `message` is the body, field errors are sent as serialized `ValidationErrors` in it
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub status: Status,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(status: Status, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// For writes the database refused, like a missing foreign key, which are the clients fault
+    /// unless the database is down
+    pub fn rejected(err: sqlx::Error) -> Self {
+        if unavailable(&err) {
+            return err.into();
+        }
+        Self::new(Status::BadRequest, err.to_string())
+    }
+}
+
+impl From<(Status, String)> for Error {
+    fn from((status, message): (Status, String)) -> Self {
+        Self { status, message }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        if unavailable(&err) {
+            return Self::new(
+                Status::ServiceUnavailable,
+                format!("The database is unavailable: {err}"),
+            );
+        }
+        Self::new(Status::InternalServerError, err.to_string())
+    }
+}
+
+/// Whether `err` means the database can't be reached right now, rather than that the query failed
+pub fn unavailable(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed
+        | sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_) => true,
+        // SQLite stayed busy or locked by another write for longer than the busy timeout, its
+        // codes are numbers with the primary code in the lowest byte
+        #[cfg(feature = "sqlite")]
+        sqlx::Error::Database(db)
+            if db.try_downcast_ref::<sqlx::sqlite::SqliteError>().is_some() =>
+        {
+            db.code()
+                .and_then(|code| code.parse::<i32>().ok())
+                .is_some_and(|code| matches!(code & 0xff, 5 | 6))
+        }
+        // Connection exceptions, the server shutting down or starting up, and too many clients
+        sqlx::Error::Database(db) => db.code().is_some_and(|code| {
+            code.starts_with("08") || matches!(&*code, "57P01" | "57P02" | "57P03" | "53300")
+        }),
+        _ => false,
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut res = (self.status, self.message).respond_to(req)?;
+        if self.status == Status::ServiceUnavailable {
+            res.set_header(Header::new("Retry-After", RETRY_AFTER.to_string()));
+        }
+        Ok(res)
+    }
+}
+
+/// Request guards can only fail with a status, these give the failures a body and `503` its
+/// `Retry-After`
+///
+/// The default one replaces Rockets HTML pages, so unknown routes get a message like every
+/// other error.
+pub fn catchers() -> Vec<Catcher> {
+    catchers![service_unavailable, default]
+}
+
+#[catch(default)]
+fn default(status: Status, _: &Request<'_>) -> Error {
+    Error::new(status, status.reason().unwrap_or("Unknown error"))
+}
+
+#[catch(503)]
+fn service_unavailable() -> Error {
+    Error::new(
+        Status::ServiceUnavailable,
+        "The database is unavailable, try again later",
+    )
+}
@@ -0,0 +1,166 @@
+use either::Either;
+use haby_core::api::ExportFormat;
+use haby_core::{Habit, HabitKind, RecordingType, SpanPart};
+use rocket::futures::StreamExt;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::stream::TextStream;
+use rocket::serde::json::serde_json;
+use rocket::serde::Serialize;
+use rocket::{get, Responder, State};
+use tracing::error;
+
+use crate::auth::{scope, Auth};
+use crate::db::{Db, ExportRow};
+use crate::error::Error;
+
+#[derive(Responder)]
+pub struct Download<R> {
+    inner: R,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+/// Download every habit and event, as `json` (the default) or `csv`
+///
+/// Events are streamed straight from the database, so large histories are never fully in memory.
+#[utoipa::path(
+    tag = "export",
+    params(("format" = Option<ExportFormat>, Query)),
+    responses(
+        (
+            status = 200,
+            description = "The export as an attachment",
+            content(
+                (haby_core::api::Export = "application/json"),
+                (String = "text/csv"),
+            ),
+        ),
+        (status = 400, description = "Unknown format", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[get("/export?<format>")]
+pub async fn get_export(
+    _auth: Auth<scope::ReadHabits>,
+    format: Option<&str>,
+    db: &State<Db>,
+) -> Result<Download<Either<TextStream![String], TextStream![String]>>, Error> {
+    let format = match format {
+        Some(format) => format.parse().map_err(|err| (Status::BadRequest, err))?,
+        None => ExportFormat::default(),
+    };
+    let db = Db::clone(db);
+
+    let (body, content_type) = match format {
+        ExportFormat::Json => {
+            let habits = db.habits().await?;
+
+            (Either::Left(json_export(db, habits)), ContentType::JSON)
+        }
+        ExportFormat::Csv => (Either::Right(csv_export(db)), ContentType::CSV),
+    };
+
+    Ok(Download {
+        inner: body,
+        content_type,
+        disposition: Header::new(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"haby-{}.{}\"",
+                haby_core::VERSION,
+                format.as_str()
+            ),
+        ),
+    })
+}
+
+/// Streams the same shape as `haby_core::api::Export`
+fn json_export(db: Db, habits: Vec<Habit>) -> TextStream![String] {
+    TextStream! {
+        yield format!(
+            r#"{{"version":{},"habits":{},"events":["#,
+            serde_json::to_string(haby_core::VERSION).unwrap(),
+            serde_json::to_string(&habits).unwrap(),
+        );
+
+        let mut events = db.export_events();
+
+        let mut first = true;
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(event) => {
+                    let separator = if first { "" } else { "," };
+                    first = false;
+                    yield format!("{separator}{}", serde_json::to_string(&event).unwrap());
+                }
+                Err(err) => {
+                    // The status is already sent, so all we can do is cut the export short
+                    error!("Export failed halfway: {err}");
+                    return;
+                }
+            }
+        }
+
+        yield String::from("]}");
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CsvRow {
+    habit_id: i32,
+    habit_name: String,
+    color: String,
+    kind: HabitKind,
+    recording_type: RecordingType,
+    every: Option<i32>,
+    event_id: Option<i32>,
+    time: Option<sqlx::types::chrono::NaiveDateTime>,
+    span_part: Option<SpanPart>,
+}
+
+/// Habits without any events still get a single row, with the event columns left empty
+fn csv_export(db: Db) -> TextStream![String] {
+    TextStream! {
+        let mut rows = db.export_rows();
+
+        let mut first = true;
+        while let Some(row) = rows.next().await {
+            let row = match row {
+                Ok(row) => row,
+                Err(err) => {
+                    error!("Export failed halfway: {err}");
+                    return;
+                }
+            };
+
+            let ExportRow { habit, event } = row;
+            let row = CsvRow {
+                habit_id: habit.id,
+                habit_name: habit.name,
+                color: habit.color.to_hex(),
+                kind: habit.kind,
+                recording_type: habit.recording_type,
+                every: habit.every,
+                event_id: event.as_ref().map(|event| event.id),
+                time: event.as_ref().map(|event| event.time),
+                span_part: event.and_then(|event| event.span_part),
+            };
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(first)
+                .from_writer(Vec::new());
+            first = false;
+
+            if let Err(err) = writer.serialize(row) {
+                error!("Export failed halfway: {err}");
+                return;
+            }
+            match writer.into_inner() {
+                Ok(line) => yield String::from_utf8_lossy(&line).into_owned(),
+                Err(err) => {
+                    error!("Export failed halfway: {err}");
+                    return;
+                }
+            }
+        }
+    }
+}
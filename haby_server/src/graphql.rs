@@ -0,0 +1,538 @@
+//! A GraphQL schema over the same habits and events as the REST routes, for clients that want a
+//! habit, its recent events and its stats in one round trip
+//!
+//! Subscriptions are served as server-sent events from `POST /graphql/stream`, one response per
+//! event, since Rocket has no websockets.
+
+use std::sync::Arc;
+
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{
+    Context,
+    Enum,
+    ErrorExtensions,
+    InputObject,
+    Object,
+    SimpleObject,
+    Subscription,
+};
+use haby_core::api::LiveUpdate;
+use haby_core::validation::ValidationErrors;
+use haby_core::TokenScope;
+use rocket::futures::{Stream, StreamExt};
+use rocket::response::content::RawHtml;
+use rocket::response::stream::{Event as SseEvent, EventStream};
+use rocket::serde::json::Json;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::OnceCell;
+use rocket::{get, post, Shutdown, State};
+use sqlx::types::chrono::{Local, NaiveDate, NaiveDateTime};
+
+use crate::auth::Grants;
+use crate::db::Db;
+use crate::error::Error;
+use crate::habits;
+use crate::limits::{limit, LimitedJson};
+use crate::live::Changes;
+use crate::webhooks::Webhooks;
+
+pub type Schema = async_graphql::Schema<Query, Mutation, SubscriptionRoot>;
+
+/// Deep enough for the introspection query of GraphiQL
+const MAX_DEPTH: usize = 16;
+
+/// Every field costs 1, a list of events costs its `limit` times its fields
+const MAX_COMPLEXITY: usize = 2000;
+
+/// The most events `Habit.events` returns at once
+const MAX_EVENTS: i64 = 500;
+
+/// How many of the latest events of every habit `Habit.stats` looks at
+const STATS_EVENTS: i64 = 1000;
+
+pub fn schema() -> Schema {
+    async_graphql::Schema::build(Query, Mutation, SubscriptionRoot)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// The errors of the shared handlers keep their HTTP status as the `status` extension
+///
+/// Validation errors are split up again, into a readable message and a `fields` extension.
+fn error(Error { status, message }: Error) -> async_graphql::Error {
+    let invalid = rocket::serde::json::from_str::<ValidationErrors>(&message).ok();
+    let message = match &invalid {
+        Some(errors) => errors.to_string(),
+        None => message,
+    };
+    async_graphql::Error::new(message).extend_with(|_, ext| {
+        ext.set("status", status.code);
+        if let Some(fields) = invalid.and_then(|errors| async_graphql::to_value(errors.errors).ok())
+        {
+            ext.set("fields", fields);
+        }
+    })
+}
+
+fn require(ctx: &Context<'_>, scope: TokenScope) -> async_graphql::Result<()> {
+    if ctx.data_unchecked::<Grants>().allows(scope) {
+        return Ok(());
+    }
+    Err(error(Error::new(
+        rocket::http::Status::Forbidden,
+        Grants::missing(scope),
+    )))
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "haby_core::HabitKind")]
+enum HabitKind {
+    Habit,
+    Addiction,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "haby_core::RecordingType")]
+enum RecordingType {
+    Point,
+    Span,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "haby_core::SpanPart")]
+enum SpanPart {
+    Start,
+    End,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "haby_core::EntityKind")]
+enum EntityKind {
+    Habit,
+    Event,
+}
+
+/// With the habits returned alongside it, whose stats are loaded together
+struct Habit(haby_core::Habit, Arc<StatsEvents>);
+
+impl Habit {
+    fn all(habits: Vec<haby_core::Habit>) -> Vec<Self> {
+        let stats = Arc::new(StatsEvents {
+            habit_ids: habits.iter().map(|habit| habit.id).collect(),
+            events: OnceCell::new(),
+        });
+        habits
+            .into_iter()
+            .map(|habit| Self(habit, stats.clone()))
+            .collect()
+    }
+
+    fn one(habit: haby_core::Habit) -> Self {
+        let stats = Arc::new(StatsEvents {
+            habit_ids: vec![habit.id],
+            events: OnceCell::new(),
+        });
+        Self(habit, stats)
+    }
+}
+
+#[Object]
+impl Habit {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// As `RRGGBB` hex
+    async fn color(&self) -> String {
+        self.0.color.to_hex()
+    }
+
+    async fn kind(&self) -> HabitKind {
+        self.0.kind.into()
+    }
+
+    async fn recording_type(&self) -> RecordingType {
+        self.0.recording_type.into()
+    }
+
+    async fn every(&self) -> Option<i32> {
+        self.0.every
+    }
+
+    /// The latest events, newest first, `limit` is between 1 and 500
+    #[graphql(complexity = "limit.clamp(1, MAX_EVENTS) as usize * child_complexity")]
+    async fn events(
+        &self,
+        ctx: &Context<'_>,
+        since: Option<NaiveDateTime>,
+        #[graphql(default = 50)] limit: i64,
+    ) -> async_graphql::Result<Vec<Event>> {
+        if !(1..=MAX_EVENTS).contains(&limit) {
+            return Err(error(Error::new(
+                rocket::http::Status::BadRequest,
+                format!("The limit has to be between 1 and {MAX_EVENTS}"),
+            )));
+        }
+        let db = ctx.data_unchecked::<Db>();
+        let events = habits::get_events(db, self.0.id, since, limit)
+            .await
+            .map_err(error)?;
+        Ok(events.into_iter().map(Event::from).collect())
+    }
+
+    /// Only the latest 1000 events count, so the longest streak can be longer
+    #[graphql(complexity = 10)]
+    async fn stats(&self, ctx: &Context<'_>) -> async_graphql::Result<Stats> {
+        let db = ctx.data_unchecked::<Db>();
+        let stats = &self.1;
+        let events = stats
+            .events
+            .get_or_try_init(|| habits::get_latest_events(db, &stats.habit_ids, STATS_EVENTS))
+            .await
+            .map_err(error)?;
+        let today = Local::now().date_naive();
+        let streak = haby_core::stats::streak(&self.0, events, today);
+
+        Ok(Stats {
+            current_streak: streak.current,
+            longest_streak: streak.longest,
+            next_due: haby_core::stats::next_due(&self.0, events),
+            running_since: haby_core::stats::running_since(&self.0, events),
+        })
+    }
+}
+
+/// The events of habits returned together for `Habit.stats`, loaded once for all of them instead
+/// of per habit
+struct StatsEvents {
+    habit_ids: Vec<i32>,
+    events: OnceCell<Vec<haby_core::Event>>,
+}
+
+#[derive(SimpleObject)]
+struct Event {
+    id: i32,
+    habit_id: i32,
+    time: NaiveDateTime,
+    span_part: Option<SpanPart>,
+}
+
+impl From<haby_core::Event> for Event {
+    fn from(event: haby_core::Event) -> Self {
+        Self {
+            id: event.id,
+            habit_id: event.habit_id,
+            time: event.time,
+            span_part: event.span_part.map(SpanPart::from),
+        }
+    }
+}
+
+/// See `haby_core::stats`
+#[derive(SimpleObject)]
+struct Stats {
+    current_streak: u32,
+    longest_streak: u32,
+    next_due: Option<NaiveDate>,
+    running_since: Option<NaiveDateTime>,
+}
+
+#[derive(SimpleObject)]
+struct Change {
+    entity: EntityKind,
+    id: i32,
+    deleted: bool,
+    cursor: i64,
+}
+
+#[derive(InputObject)]
+struct HabitInput {
+    name: String,
+    /// As `#RRGGBB` or `#RGB` hex, the `#` is optional
+    color: String,
+    kind: HabitKind,
+    recording_type: RecordingType,
+    every: Option<i32>,
+}
+
+impl TryFrom<HabitInput> for haby_core::api::CreateHabit {
+    type Error = async_graphql::Error;
+
+    fn try_from(input: HabitInput) -> Result<Self, Self::Error> {
+        let color = input.color.parse::<haby_core::Color>().map_err(|err| {
+            let mut errors = ValidationErrors::default();
+            errors.add("color", err.to_string());
+            error(crate::habits::invalid(errors))
+        })?;
+        Ok(Self {
+            name: input.name,
+            color,
+            kind: input.kind.into(),
+            recording_type: input.recording_type.into(),
+            every: input.every,
+        })
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn habits(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Habit>> {
+        require(ctx, TokenScope::ReadHabits)?;
+        let habits = habits::get_habits(ctx.data_unchecked::<Db>())
+            .await
+            .map_err(error)?;
+        Ok(Habit::all(habits))
+    }
+
+    async fn habit(&self, ctx: &Context<'_>, id: i32) -> async_graphql::Result<Habit> {
+        require(ctx, TokenScope::ReadHabits)?;
+        let habit = habits::get_habit(ctx.data_unchecked::<Db>(), id)
+            .await
+            .map_err(error)?;
+        Ok(Habit::one(habit))
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    async fn create_habit(
+        &self,
+        ctx: &Context<'_>,
+        habit: HabitInput,
+    ) -> async_graphql::Result<Habit> {
+        require(ctx, TokenScope::Admin)?;
+        let habit = habits::create_habit(
+            ctx.data_unchecked::<Db>(),
+            ctx.data_unchecked::<Webhooks>(),
+            habit.try_into()?,
+        )
+        .await
+        .map_err(error)?;
+        Ok(Habit::one(habit))
+    }
+
+    /// `null` when there is no habit with that id
+    async fn update_habit(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        habit: HabitInput,
+    ) -> async_graphql::Result<Option<Habit>> {
+        require(ctx, TokenScope::Admin)?;
+        let habit = habits::update_habit(
+            ctx.data_unchecked::<Db>(),
+            ctx.data_unchecked::<Webhooks>(),
+            id,
+            habit.try_into()?,
+        )
+        .await
+        .map_err(error)?;
+        Ok(habit.map(Habit::one))
+    }
+
+    /// Delete the habit and all its events
+    async fn delete_habit(&self, ctx: &Context<'_>, id: i32) -> async_graphql::Result<bool> {
+        require(ctx, TokenScope::Admin)?;
+        habits::delete_habit(
+            ctx.data_unchecked::<Db>(),
+            ctx.data_unchecked::<Webhooks>(),
+            id,
+        )
+        .await
+        .map_err(error)?;
+        Ok(true)
+    }
+
+    /// Record the habit as done, at `time` or now, span habits are started or stopped
+    async fn check_in(
+        &self,
+        ctx: &Context<'_>,
+        habit_id: i32,
+        time: Option<NaiveDateTime>,
+    ) -> async_graphql::Result<Event> {
+        require(ctx, TokenScope::WriteEvents)?;
+        let event = habits::check_in(
+            ctx.data_unchecked::<Db>(),
+            ctx.data_unchecked::<Webhooks>(),
+            habit_id,
+            time.unwrap_or_else(|| Local::now().naive_local()),
+        )
+        .await
+        .map_err(error)?;
+        Ok(event.into())
+    }
+}
+
+pub struct SubscriptionRoot;
+
+/// Receive the changes of `changes`, as a stream that ends when the server shuts down
+fn updates(changes: &Changes) -> impl Stream<Item = LiveUpdate> {
+    rocket::futures::stream::unfold(changes.subscribe(), |mut receiver| async move {
+        let update = match receiver.recv().await {
+            Ok(update) => update,
+            Err(RecvError::Lagged(_)) => LiveUpdate::Lagged,
+            Err(RecvError::Closed) => return None,
+        };
+        Some((update, receiver))
+    })
+}
+
+fn lagged() -> async_graphql::Error {
+    async_graphql::Error::new("Missed changes, refetch everything")
+        .extend_with(|_, ext| ext.set("lagged", true))
+}
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Every habit and event change, like `GET /live`
+    async fn changes(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<Change>>> {
+        require(ctx, TokenScope::ReadHabits)?;
+        Ok(
+            updates(ctx.data_unchecked::<Changes>()).map(|update| match update {
+                LiveUpdate::Change(change) => Ok(Change {
+                    entity: change.entity.into(),
+                    id: change.id,
+                    deleted: change.deleted,
+                    cursor: change.cursor,
+                }),
+                LiveUpdate::Lagged => Err(lagged()),
+            }),
+        )
+    }
+
+    /// Events as they are recorded, of every habit or only of `habit_id`
+    async fn events(
+        &self,
+        ctx: &Context<'_>,
+        habit_id: Option<i32>,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<Event>>> {
+        require(ctx, TokenScope::ReadHabits)?;
+        let db = ctx.data_unchecked::<Db>().clone();
+
+        let created = updates(ctx.data_unchecked::<Changes>()).filter_map(|update| async move {
+            match update {
+                LiveUpdate::Change(change)
+                    if change.entity == haby_core::EntityKind::Event && !change.deleted =>
+                {
+                    Some(Ok(change.id))
+                }
+                LiveUpdate::Change(_) => None,
+                LiveUpdate::Lagged => Some(Err(lagged())),
+            }
+        });
+        Ok(created.filter_map(move |id| {
+            let db = db.clone();
+            async move {
+                let event = match id {
+                    Ok(id) => habits::get_event(&db, id).await,
+                    Err(err) => return Some(Err(err)),
+                };
+                match event {
+                    Ok(event) if habit_id.is_none_or(|id| event.habit_id == id) => {
+                        Some(Ok(event.into()))
+                    }
+                    Ok(_) => None,
+                    // Deleted again before it could be looked up
+                    Err(err) if err.status == rocket::http::Status::NotFound => None,
+                    Err(err) => Some(Err(error(err))),
+                }
+            }
+        }))
+    }
+}
+
+/// Everything the resolvers need from Rocket, attached to every request
+fn with_data(
+    request: async_graphql::Request,
+    grants: &Grants,
+    db: &Db,
+    webhooks: &Webhooks,
+    changes: &Changes,
+) -> async_graphql::Request {
+    request
+        .data(grants.clone())
+        .data(Db::clone(db))
+        .data(webhooks.clone())
+        .data(changes.clone())
+}
+
+/// Run a query or mutation
+#[utoipa::path(
+    tag = "graphql",
+    request_body(content = Object, description = "A GraphQL request"),
+    responses((status = 200, description = "The GraphQL response", body = Object)),
+)]
+#[post("/graphql", data = "<request>")]
+pub async fn post_graphql(
+    grants: &Grants,
+    request: LimitedJson<async_graphql::Request, limit::Json>,
+    schema: &State<Schema>,
+    db: &State<Db>,
+    webhooks: &State<Webhooks>,
+    changes: &State<Changes>,
+) -> Json<async_graphql::Response> {
+    let request = with_data(request.into_inner(), grants, db, webhooks, changes);
+    Json(schema.execute(request).await)
+}
+
+/// Run a subscription, every response is sent as a server-sent event
+#[utoipa::path(
+    tag = "graphql",
+    request_body(content = Object, description = "A GraphQL subscription request"),
+    responses(
+        (status = 200, description = "A GraphQL response per server-sent event", body = Object, content_type = "text/event-stream"),
+    ),
+)]
+#[post("/graphql/stream", data = "<request>")]
+pub fn post_graphql_stream(
+    grants: &Grants,
+    request: Json<async_graphql::Request>,
+    schema: &State<Schema>,
+    db: &State<Db>,
+    webhooks: &State<Webhooks>,
+    changes: &State<Changes>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let request = with_data(request.into_inner(), grants, db, webhooks, changes);
+    let mut responses = schema.execute_stream(request);
+    EventStream! {
+        loop {
+            let response = select! {
+                response = responses.next() => match response {
+                    Some(response) => response,
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield SseEvent::json(&response);
+        }
+    }
+}
+
+/// GraphiQL, to explore the schema in the browser
+#[utoipa::path(
+    tag = "graphql",
+    security(()),
+    responses((status = 200, description = "The GraphiQL page", body = String, content_type = "text/html")),
+)]
+#[get("/graphql")]
+pub fn get_graphiql() -> RawHtml<String> {
+    RawHtml(
+        GraphiQLSource::build()
+            .endpoint(&format!("{}/graphql", crate::API_BASE))
+            .finish(),
+    )
+}
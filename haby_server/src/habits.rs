@@ -0,0 +1,184 @@
+//! Reading and writing habits and events, shared by the REST routes and GraphQL so both check
+//! and notify the same way
+
+use haby_core::api::{BatchEvent, BatchItem, CreateEvent, CreateHabit};
+use haby_core::validation::{ValidationErrors, NAME_TAKEN};
+use haby_core::{Event, Habit, RecordingType, SpanPart, WebhookEvent};
+use rocket::http::Status;
+use rocket::serde::json::json;
+use sqlx::types::chrono::NaiveDateTime;
+use tracing::instrument;
+
+use crate::db::Db;
+use crate::error::{Error, Result};
+use crate::webhooks::Webhooks;
+
+/// Field-level errors are sent as JSON, so clients can show them next to their inputs
+pub fn invalid(errors: ValidationErrors) -> Error {
+    let body = rocket::serde::json::to_string(&errors).expect("Validation errors serialize");
+    Error::new(Status::UnprocessableEntity, body)
+}
+
+/// The only unique column a habit write can clash on is the name
+pub fn habit_write_error(err: sqlx::Error) -> Error {
+    match err.as_database_error() {
+        Some(db) if db.is_unique_violation() => {
+            let mut errors = ValidationErrors::default();
+            errors.add("name", NAME_TAKEN);
+            invalid(errors)
+        }
+        _ => Error::rejected(err),
+    }
+}
+
+#[instrument(skip_all)]
+pub async fn get_habits(db: &Db) -> Result<Vec<Habit>> {
+    Ok(db.habits().await?)
+}
+
+#[instrument(skip_all, fields(id = id))]
+pub async fn get_habit(db: &Db, id: i32) -> Result<Habit> {
+    db.habit(id)
+        .await?
+        .ok_or_else(|| Error::new(Status::NotFound, format!("No habit with id {id}")))
+}
+
+#[instrument(skip_all, fields(id = id))]
+pub async fn get_event(db: &Db, id: i32) -> Result<Event> {
+    db.event(id)
+        .await?
+        .ok_or_else(|| Error::new(Status::NotFound, format!("No event with id {id}")))
+}
+
+/// The events of a habit after `since`, newest first
+#[instrument(skip_all, fields(habit_id = habit_id))]
+pub async fn get_events(
+    db: &Db,
+    habit_id: i32,
+    since: Option<NaiveDateTime>,
+    limit: i64,
+) -> Result<Vec<Event>> {
+    Ok(db.events(habit_id, since, limit).await?)
+}
+
+/// The latest `per_habit` events of each of `habit_ids`, newest first
+#[instrument(skip_all, fields(per_habit = per_habit))]
+pub async fn get_latest_events(db: &Db, habit_ids: &[i32], per_habit: i64) -> Result<Vec<Event>> {
+    Ok(db.latest_events(habit_ids, per_habit).await?)
+}
+
+#[instrument(skip_all)]
+pub async fn create_habit(db: &Db, webhooks: &Webhooks, habit: CreateHabit) -> Result<Habit> {
+    let habit = habit.normalized();
+    habit.validate().map_err(invalid)?;
+
+    let id = db.create_habit(&habit).await.map_err(habit_write_error)?;
+
+    let habit = habit.with_id(id);
+    webhooks
+        .notify(db, WebhookEvent::HabitCreated, &habit)
+        .await;
+    Ok(habit)
+}
+
+/// The updated habit, or `None` when there is no habit with that id
+#[instrument(skip_all, fields(id = id))]
+pub async fn update_habit(
+    db: &Db,
+    webhooks: &Webhooks,
+    id: i32,
+    habit: CreateHabit,
+) -> Result<Option<Habit>> {
+    let habit = habit.normalized();
+    habit.validate().map_err(invalid)?;
+
+    let updated = db
+        .update_habit(id, &habit)
+        .await
+        .map_err(habit_write_error)?;
+
+    if !updated {
+        return Ok(None);
+    }
+    let habit = habit.with_id(id);
+    webhooks
+        .notify(db, WebhookEvent::HabitUpdated, &habit)
+        .await;
+    Ok(Some(habit))
+}
+
+#[instrument(skip_all, fields(id = id))]
+pub async fn delete_habit(db: &Db, webhooks: &Webhooks, id: i32) -> Result<()> {
+    let deleted = db.delete_habit(id).await.map_err(Error::rejected)?;
+
+    if !deleted {
+        return Err(Error::new(
+            Status::NotFound,
+            format!("No habit with id {id}"),
+        ));
+    }
+    webhooks
+        .notify(db, WebhookEvent::HabitDeleted, json!({ "id": id }))
+        .await;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn create_event(db: &Db, webhooks: &Webhooks, event: CreateEvent) -> Result<Event> {
+    let id = db.create_event(&event).await.map_err(Error::rejected)?;
+
+    let event = event.with_id(id);
+    webhooks
+        .notify(db, WebhookEvent::EventRecorded, &event)
+        .await;
+    Ok(event)
+}
+
+/// Record many events in one transaction
+///
+/// Every event is checked on its own, so a rejected one does not stop the rest of the batch.
+#[instrument(skip_all, fields(count = events.len()))]
+pub async fn create_events(
+    db: &Db,
+    webhooks: &Webhooks,
+    events: Vec<BatchEvent>,
+) -> Result<Vec<BatchItem>> {
+    let results = db.create_events(events).await?;
+
+    for result in &results {
+        if let BatchItem::Created { event } = result {
+            webhooks
+                .notify(db, WebhookEvent::EventRecorded, event)
+                .await;
+        }
+    }
+    Ok(results)
+}
+
+/// Record that the habit was done at `time`, span habits are started or stopped
+#[instrument(skip_all, fields(habit_id = habit_id))]
+pub async fn check_in(
+    db: &Db,
+    webhooks: &Webhooks,
+    habit_id: i32,
+    time: NaiveDateTime,
+) -> Result<Event> {
+    let habit = get_habit(db, habit_id).await?;
+    let span_part = match habit.recording_type {
+        RecordingType::Point => None,
+        RecordingType::Span => {
+            let last = get_events(db, habit_id, None, 1).await?;
+            match haby_core::stats::running_since(&habit, &last) {
+                Some(_) => Some(SpanPart::End),
+                None => Some(SpanPart::Start),
+            }
+        }
+    };
+
+    let event = CreateEvent {
+        habit_id,
+        time,
+        span_part,
+    };
+    create_event(db, webhooks, event).await
+}
@@ -0,0 +1,115 @@
+//! Health checks for container orchestration
+//!
+//! Liveness only says the server answers requests, readiness also checks the database can be
+//! reached and has every migration applied, so the server is not sent traffic it can't handle.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use haby_core::api::{Health, HealthCheck, HealthStatus};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::tokio::time::timeout;
+use rocket::{get, routes, Route, State};
+
+use crate::db::Db;
+
+/// A check that takes longer than this counts as down, orchestrators give up on slow checks anyway
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+type CheckResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+pub fn routes() -> Vec<Route> {
+    routes![live, ready]
+}
+
+/// Whether the server is running at all
+#[utoipa::path(
+    tag = "meta",
+    security(()),
+    responses((status = 200, description = "The server is up", body = Health)),
+)]
+#[get("/health/live")]
+pub fn live() -> Json<Health> {
+    Json(health(BTreeMap::new()))
+}
+
+/// Whether the server can handle requests, which needs a migrated database
+#[utoipa::path(
+    tag = "meta",
+    security(()),
+    responses(
+        (status = 200, description = "Every component is up", body = Health),
+        (status = 503, description = "Some component is down", body = Health),
+    ),
+)]
+#[get("/health/ready")]
+pub async fn ready(db: &State<Db>) -> (Status, Json<Health>) {
+    let mut checks = BTreeMap::new();
+    let database = check(async {
+        db.ping().await?;
+        CheckResult::Ok(())
+    })
+    .await;
+    // Without a database there is no point in asking it about migrations
+    let migrations = if database.status == HealthStatus::Up {
+        check(migrations(db)).await
+    } else {
+        HealthCheck {
+            status: HealthStatus::Down,
+            latency_ms: 0.0,
+            error: Some(String::from("The database is down")),
+        }
+    };
+    checks.insert(String::from("database"), database);
+    checks.insert(String::from("migrations"), migrations);
+
+    let health = health(checks);
+    let status = match health.status {
+        HealthStatus::Up => Status::Ok,
+        HealthStatus::Down => Status::ServiceUnavailable,
+    };
+    (status, Json(health))
+}
+
+fn health(checks: BTreeMap<String, HealthCheck>) -> Health {
+    let status = if checks
+        .values()
+        .all(|check| check.status == HealthStatus::Up)
+    {
+        HealthStatus::Up
+    } else {
+        HealthStatus::Down
+    };
+    Health {
+        status,
+        version: String::from(haby_core::VERSION),
+        checks,
+    }
+}
+
+async fn check(check: impl Future<Output = CheckResult>) -> HealthCheck {
+    let start = Instant::now();
+    let result = match timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Timed out after {CHECK_TIMEOUT:?}").into()),
+    };
+    HealthCheck {
+        status: match result {
+            Ok(()) => HealthStatus::Up,
+            Err(_) => HealthStatus::Down,
+        },
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        error: result.err().map(|err| err.to_string()),
+    }
+}
+
+/// Fails unless every migration the server was built with ran successfully
+async fn migrations(db: &Db) -> CheckResult {
+    let pending = db.pending_migrations().await?;
+    if pending > 0 {
+        return Err(format!("{pending} migrations are not applied").into());
+    }
+    Ok(())
+}
@@ -0,0 +1,350 @@
+//! `Idempotency-Key` support for `POST`, `PUT` and `PATCH` requests
+//!
+//! The first request with a key is handled as usual and its response is stored. Retries with the
+//! same key are rerouted to [`replay`], which sends the stored response again without touching
+//! the habits or events a second time.
+//!
+//! Bodies are read by the routes, so the fairing can't look at them. Routes hand theirs to
+//! [`record_body`] and a digest of it is stored with the response, [`replay`] only replays for
+//! the same body.
+
+use std::io::Cursor;
+use std::time::Duration;
+
+use haby_core::api::{IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED};
+use rocket::data::Limits;
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::serde::Deserialize;
+use rocket::{post, routes, Build, Data, Request, Response, Rocket, Route};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::db::Db;
+use crate::error::Error;
+use crate::limits::{self, limit};
+use crate::logging;
+
+/// Keys are picked by clients, this keeps them from filling the table with huge ones
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Where retries are sent, it is not part of the API
+const REPLAY_URI: &str = "/__idempotency";
+
+/// The `idempotency` table of the Rocket config
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+struct Config {
+    /// How long a response is kept for retries
+    ttl_hours: i32,
+    /// How long retries wait for the first request before they take over its key
+    lease_minutes: i32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ttl_hours: 24,
+            lease_minutes: 5,
+        }
+    }
+}
+
+pub fn fairing() -> Idempotency {
+    Idempotency
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![replay]
+}
+
+pub struct Idempotency;
+
+/// What the fairing decided about a request, kept in its local cache
+enum Key {
+    None,
+    /// The first request with this key, its response has to be stored with the claim
+    Owned {
+        key: String,
+        claim: String,
+    },
+    Replay(Result<Box<Stored>, Error>),
+}
+
+#[derive(Clone)]
+struct Stored {
+    status: Status,
+    content_type: Option<ContentType>,
+    body: Vec<u8>,
+    /// Of the body of the first request, see [`record_body`]
+    body_digest: Option<String>,
+}
+
+/// The digest of the request body, kept in the local cache
+struct BodyDigest(Option<String>);
+
+/// Remember the body of a request sent with a key, so retries with another body are rejected
+/// instead of getting the response to this one
+///
+/// Every route that reads a body has to call this, `LimitedJson` does.
+pub fn record_body(req: &Request<'_>, body: &[u8]) {
+    if req.headers().contains(IDEMPOTENCY_KEY) {
+        req.local_cache(|| BodyDigest(Some(digest(body))));
+    }
+}
+
+fn digest(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+#[rocket::async_trait]
+impl Fairing for Idempotency {
+    fn info(&self) -> Info {
+        Info {
+            name: "Idempotency keys",
+            kind: Kind::Ignite | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let Some(db) = rocket.state::<Db>() else {
+            error!("Idempotency keys need a database");
+            return Err(rocket);
+        };
+        let config: Config = match rocket.figment().focus("idempotency").extract() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Invalid idempotency config: {err}");
+                return Err(rocket);
+            }
+        };
+
+        rocket::tokio::spawn(remove_expired(db.clone()));
+        Ok(rocket.manage(config))
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if !matches!(req.method(), Method::Post | Method::Put | Method::Patch) {
+            return;
+        }
+        let Some(key) = req.headers().get_one(IDEMPOTENCY_KEY).map(String::from) else {
+            return;
+        };
+
+        let key = if key.is_empty()
+            || key.len() > MAX_KEY_LENGTH
+            || !key.chars().all(|c| c.is_ascii_graphic())
+        {
+            Key::Replay(Err(Error::new(
+                Status::BadRequest,
+                format!(
+                    "{IDEMPOTENCY_KEY} has to be 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+                ),
+            )))
+        } else {
+            let (Some(db), Some(config)) =
+                (req.rocket().state::<Db>(), req.rocket().state::<Config>())
+            else {
+                return;
+            };
+            match reserve(db, config, &key, &fingerprint(req)).await {
+                Ok(key) => key,
+                // Handling the request without a key is better than not handling it at all
+                Err(err) => {
+                    error!("Checking the idempotency key failed: {err}");
+                    return;
+                }
+            }
+        };
+
+        let replay = matches!(key, Key::Replay(_));
+        req.local_cache(|| key);
+        if replay {
+            // `POST`, so the replay can still read the body
+            req.set_method(Method::Post);
+            req.set_uri(Origin::parse(REPLAY_URI).expect("The replay URI is valid"));
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Key::Owned { key, claim } = req.local_cache(|| Key::None) else {
+            return;
+        };
+        let Some(db) = req.rocket().state::<Db>() else {
+            return;
+        };
+
+        let streamed = res.content_type() == Some(ContentType::EventStream);
+        let result = if streamed || !is_final(res.status()) {
+            // Let a retry run the request again
+            db.release_idempotency_key(key, claim).await
+        } else {
+            let body = match res.body_mut().to_bytes().await {
+                Ok(body) => body,
+                Err(err) => {
+                    error!("Failed to read the response for an idempotency key: {err}");
+                    return;
+                }
+            };
+            res.set_sized_body(body.len(), Cursor::new(body.clone()));
+            // The replay is sent for another request, which gets its own id
+            let body = if res.status().code >= 400 {
+                logging::without_request_id(&body).unwrap_or(body)
+            } else {
+                body
+            };
+            let body_digest = &req.local_cache(|| BodyDigest(None)).0;
+            db.store_idempotent_response(
+                key,
+                claim,
+                res.status().code as i16,
+                res.content_type()
+                    .map(|content_type| content_type.to_string()),
+                &body,
+                body_digest.as_deref(),
+            )
+            .await
+        };
+        if let Err(err) = result {
+            error!("Failed to store the response for an idempotency key: {err}");
+        }
+    }
+}
+
+/// The method, path and a hash of the credentials, so a key only replays for the same request
+/// by the same client, the body is checked by [`replay`]
+fn fingerprint(req: &Request<'_>) -> String {
+    let auth = req.headers().get_one("Authorization").unwrap_or_default();
+    format!(
+        "{} {} {}",
+        req.method(),
+        req.uri(),
+        hex::encode(Sha256::digest(auth))
+    )
+}
+
+/// Responses worth replaying, the rest could turn out differently when retried
+fn is_final(status: Status) -> bool {
+    !(status.class().is_server_error()
+        || status == Status::Unauthorized
+        || status == Status::Forbidden
+        || status == Status::TooManyRequests)
+}
+
+/// Claim `key` for this request, or what to answer when an earlier request already did
+async fn reserve(
+    db: &Db,
+    config: &Config,
+    key: &str,
+    fingerprint: &str,
+) -> Result<Key, sqlx::Error> {
+    let claim = db
+        .claim_idempotency_key(key, fingerprint, config.ttl_hours, config.lease_minutes)
+        .await?;
+    if let Some(claim) = claim {
+        return Ok(Key::Owned {
+            key: key.to_owned(),
+            claim,
+        });
+    }
+
+    let Some(existing) = db.idempotency_key(key).await? else {
+        // Removed since the insert, by the first request failing or expiring
+        return Ok(Key::Replay(Err(Error::new(
+            Status::Conflict,
+            String::from("The first request with this key just failed, try again"),
+        ))));
+    };
+
+    let replay = if existing.fingerprint != fingerprint {
+        Err(Error::new(
+            Status::UnprocessableEntity,
+            format!("This {IDEMPOTENCY_KEY} was already used for a different request"),
+        ))
+    } else if let (Some(status), Some(body)) = (existing.status, existing.body) {
+        Ok(Box::new(Stored {
+            status: Status::new(status as u16),
+            content_type: existing
+                .content_type
+                .and_then(|content_type| content_type.parse().ok()),
+            body,
+            body_digest: existing.body_digest,
+        }))
+    } else {
+        Err(Error::new(
+            Status::Conflict,
+            String::from("The request with this key is still being handled"),
+        ))
+    };
+    Ok(Key::Replay(replay))
+}
+
+async fn remove_expired(db: Db) {
+    let mut ticks = rocket::tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        ticks.tick().await;
+        match db.remove_expired_idempotency_keys().await {
+            Ok(_) => {}
+            Err(sqlx::Error::PoolClosed) => break,
+            Err(err) => error!("Removing expired idempotency keys failed: {err}"),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Key {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(|| Key::None))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Stored {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .header(Header::new(IDEMPOTENT_REPLAYED, "true"))
+            .sized_body(self.body.len(), Cursor::new(self.body));
+        if let Some(content_type) = self.content_type {
+            response.header(content_type);
+        }
+        response.ok()
+    }
+}
+
+/// Answers retries with the stored response, requests are only sent here by the fairing
+///
+/// A retry has to send the same body as the first request, when that request's route read one.
+#[post("/__idempotency", data = "<body>")]
+async fn replay(key: &Key, body: Data<'_>, limits: &Limits) -> Result<Stored, Error> {
+    let stored = match key {
+        Key::Replay(Ok(stored)) => stored,
+        Key::Replay(Err(err)) => return Err(err.clone()),
+        _ => {
+            return Err(Error::new(
+                Status::NotFound,
+                String::from("Nothing to replay"),
+            ))
+        }
+    };
+    if let Some(expected) = &stored.body_digest {
+        // No route takes more than an import
+        let body = body
+            .open(limits::size::<limit::Import>(limits))
+            .into_bytes()
+            .await
+            .map_err(|err| Error::new(Status::BadRequest, err.to_string()))?;
+        if !body.is_complete() || digest(&body) != *expected {
+            return Err(Error::new(
+                Status::UnprocessableEntity,
+                format!("This {IDEMPOTENCY_KEY} was already used for a different request"),
+            ));
+        }
+    }
+    Ok(Stored::clone(stored))
+}
@@ -0,0 +1,131 @@
+use std::collections::HashSet;
+
+use haby_core::api::{ConflictPolicy, Export, ImportReport};
+use haby_core::validation::ValidationErrors;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{post, State};
+
+use crate::auth::{scope, Auth};
+use crate::db::Db;
+use crate::error::Error;
+use crate::habits;
+use crate::limits::{limit, LimitedJson, LimitedString};
+
+type ImportResult = Result<Json<ImportReport>, Error>;
+
+/// Import a `GET /export?format=json` export
+///
+/// Habits are matched by name, ignoring case, and events by habit, time and span part, so
+/// importing the same file twice does not create duplicates.
+///
+/// Imports don't notify webhooks, a backup would send a delivery for every row. Webhook
+/// subscribers can catch up with `GET /sync`.
+#[utoipa::path(
+    tag = "export",
+    params(("on_conflict" = Option<ConflictPolicy>, Query)),
+    request_body = Export,
+    responses(
+        (status = 200, description = "What was, or would be, imported", body = ImportReport),
+        (status = 400, description = "The import was rejected", body = haby_core::api::ErrorBody),
+        (status = 413, description = "The file is too large", body = haby_core::api::ErrorBody),
+        (status = 422, description = "Some habits are invalid", body = haby_core::validation::ValidationErrors),
+    ),
+)]
+#[post("/import?<dry_run>&<on_conflict>", data = "<export>")]
+pub async fn import_json(
+    _auth: Auth<scope::Admin>,
+    export: LimitedJson<Export, limit::Import>,
+    dry_run: Option<bool>,
+    on_conflict: Option<&str>,
+    db: &State<Db>,
+) -> ImportResult {
+    let on_conflict = parse_policy(on_conflict)?;
+    import(
+        export.into_inner(),
+        on_conflict,
+        dry_run.unwrap_or(false),
+        db,
+    )
+    .await
+}
+
+/// Import the `Checkmarks.csv` from a Loop Habit Tracker export, without notifying webhooks
+#[utoipa::path(
+    tag = "export",
+    params(("on_conflict" = Option<ConflictPolicy>, Query)),
+    request_body(content = String, content_type = "text/csv"),
+    responses(
+        (status = 200, description = "What was, or would be, imported", body = ImportReport),
+        (status = 400, description = "The import was rejected", body = haby_core::api::ErrorBody),
+        (status = 413, description = "The file is too large", body = haby_core::api::ErrorBody),
+        (status = 422, description = "Some habits are invalid", body = haby_core::validation::ValidationErrors),
+    ),
+)]
+#[post("/import/loop?<dry_run>&<on_conflict>", data = "<csv>")]
+pub async fn import_loop(
+    _auth: Auth<scope::Admin>,
+    csv: LimitedString<limit::Import>,
+    dry_run: Option<bool>,
+    on_conflict: Option<&str>,
+    db: &State<Db>,
+) -> ImportResult {
+    let on_conflict = parse_policy(on_conflict)?;
+
+    let export =
+        haby_core::import::loop_habit_tracker(&csv).map_err(|err| (Status::BadRequest, err))?;
+    import(export, on_conflict, dry_run.unwrap_or(false), db).await
+}
+
+fn parse_policy(on_conflict: Option<&str>) -> Result<ConflictPolicy, Error> {
+    match on_conflict {
+        Some(policy) => policy
+            .parse()
+            .map_err(|err| Error::new(Status::BadRequest, err)),
+        None => Ok(ConflictPolicy::default()),
+    }
+}
+
+/// Everything happens in one transaction, which a dry run simply rolls back
+///
+/// No webhooks are notified, see `import_json`.
+async fn import(
+    export: Export,
+    on_conflict: ConflictPolicy,
+    dry_run: bool,
+    db: &Db,
+) -> ImportResult {
+    let mut errors = ValidationErrors::default();
+    for (i, habit) in export.habits.iter().enumerate() {
+        if let Err(invalid) = habit.as_create().validate() {
+            errors
+                .errors
+                .extend(invalid.nested(&format!("habits[{i}]")).errors);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(habits::invalid(errors));
+    }
+
+    // Checked before the database sees the import, so the message names the event
+    let habit_ids: HashSet<_> = export.habits.iter().map(|habit| habit.id).collect();
+    if let Some(event) = export
+        .events
+        .iter()
+        .find(|event| !habit_ids.contains(&event.habit_id))
+    {
+        return Err(Error::new(
+            Status::BadRequest,
+            format!(
+                "Event {} belongs to habit {}, which is not in the import",
+                event.id, event.habit_id
+            ),
+        ));
+    }
+
+    let report = db
+        .import(export, on_conflict, dry_run)
+        .await
+        .map_err(Error::rejected)?;
+    Ok(Json(report))
+}
@@ -0,0 +1,137 @@
+//! Body size limits per kind of request
+//!
+//! Rockets `Json` only knows the single `json` limit and doesn't let [`idempotency`] see the
+//! body, so routes use [`LimitedJson`] instead. The limits can be changed in the `limits` table
+//! of the Rocket config, like `limits.habit = "32 KiB"`.
+
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use rocket::data::{ByteUnit, FromData, Limits, Outcome};
+use rocket::http::Status;
+use rocket::serde::de::DeserializeOwned;
+use rocket::serde::json::serde_json;
+use rocket::{Data, Request};
+
+use crate::idempotency;
+
+/// Marker types for the limit a route uses
+pub mod limit {
+    use rocket::data::ByteUnit;
+
+    pub trait Limit: Send + Sync {
+        /// The key in the `limits` config
+        const NAME: &'static str;
+        const DEFAULT: ByteUnit;
+    }
+
+    pub struct Habit;
+    pub struct Event;
+    pub struct Batch;
+    pub struct Import;
+    /// Rockets own limit, for routes without a limit of their own
+    pub struct Json;
+
+    impl Limit for Habit {
+        const NAME: &'static str = "habit";
+        const DEFAULT: ByteUnit = ByteUnit::Kibibyte(16);
+    }
+    impl Limit for Event {
+        const NAME: &'static str = "event";
+        const DEFAULT: ByteUnit = ByteUnit::Kibibyte(4);
+    }
+    /// Enough for `MAX_BATCH` events
+    impl Limit for Batch {
+        const NAME: &'static str = "batch";
+        const DEFAULT: ByteUnit = ByteUnit::Mebibyte(1);
+    }
+    impl Limit for Import {
+        const NAME: &'static str = "import";
+        const DEFAULT: ByteUnit = ByteUnit::Mebibyte(16);
+    }
+    impl Limit for Json {
+        const NAME: &'static str = "json";
+        const DEFAULT: ByteUnit = ByteUnit::Mebibyte(1);
+    }
+}
+
+/// The configured size limit of `L`
+pub fn size<L: limit::Limit>(limits: &Limits) -> ByteUnit {
+    limits.get(L::NAME).unwrap_or(L::DEFAULT)
+}
+
+/// A JSON body of at most the `L` limit, larger ones are rejected with `413 Payload Too Large`
+pub struct LimitedJson<T, L>(T, PhantomData<L>);
+
+impl<T, L> LimitedJson<T, L> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T, L> Deref for LimitedJson<T, L> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// A text body of at most the `L` limit, like a CSV import
+pub struct LimitedString<L>(String, PhantomData<L>);
+
+impl<L> Deref for LimitedString<L> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Read the whole body, and hand it to [`idempotency::record_body`]
+async fn read<L: limit::Limit>(
+    req: &Request<'_>,
+    data: Data<'_>,
+) -> Result<String, (Status, String)> {
+    let limit = size::<L>(req.limits());
+    let body = match data.open(limit).into_string().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => {
+            return Err((
+                Status::PayloadTooLarge,
+                format!("The body is limited to {limit}"),
+            ))
+        }
+        Err(err) => return Err((Status::BadRequest, err.to_string())),
+    };
+    idempotency::record_body(req, body.as_bytes());
+    Ok(body)
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned, L: limit::Limit> FromData<'r> for LimitedJson<T, L> {
+    type Error = String;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
+        let body = match read::<L>(req, data).await {
+            Ok(body) => body,
+            Err(err) => return Outcome::Error(err),
+        };
+        match serde_json::from_str(&body) {
+            Ok(value) => Outcome::Success(LimitedJson(value, PhantomData)),
+            Err(err) => Outcome::Error((Status::UnprocessableEntity, err.to_string())),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, L: limit::Limit> FromData<'r> for LimitedString<L> {
+    type Error = String;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
+        match read::<L>(req, data).await {
+            Ok(body) => Outcome::Success(LimitedString(body, PhantomData)),
+            Err(err) => Outcome::Error(err),
+        }
+    }
+}
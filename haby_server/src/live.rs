@@ -0,0 +1,90 @@
+use haby_core::api::LiveUpdate;
+use rocket::fairing::AdHoc;
+use rocket::futures::stream::BoxStream;
+use rocket::futures::StreamExt;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::broadcast::{self};
+use rocket::{get, Shutdown, State};
+use tracing::error;
+
+use crate::auth::{scope, Auth};
+use crate::db::Db;
+
+/// How many updates a slow subscriber can fall behind before it is told it lagged
+const CAPACITY: usize = 256;
+
+#[derive(Clone)]
+pub struct Changes(broadcast::Sender<LiveUpdate>);
+
+impl Changes {
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveUpdate> {
+        self.0.subscribe()
+    }
+}
+
+/// Listens for database changes and fans them out to every live subscriber
+///
+/// With Postgres the changes come from the database, so subscribers see writes made through any
+/// server replica. SQLite only knows the writes of this server.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Live changes", |rocket| async move {
+        let Some(db) = rocket.state::<Db>() else {
+            error!("Live changes need a database");
+            return Err(rocket);
+        };
+
+        let updates = match db.listen().await {
+            Ok(updates) => updates,
+            Err(err) => {
+                error!("Failed to listen for changes: {err}");
+                return Err(rocket);
+            }
+        };
+
+        let (sender, _) = broadcast::channel(CAPACITY);
+        rocket::tokio::spawn(forward_changes(updates, sender.clone()));
+
+        Ok(rocket.manage(Changes(sender)))
+    })
+}
+
+async fn forward_changes(
+    mut updates: BoxStream<'static, LiveUpdate>,
+    sender: broadcast::Sender<LiveUpdate>,
+) {
+    while let Some(update) = updates.next().await {
+        // Only fails when there are no subscribers
+        let _ = sender.send(update);
+    }
+}
+
+/// Server-sent events with every habit and event change as it happens
+#[utoipa::path(
+    tag = "sync",
+    responses(
+        (status = 200, description = "A `LiveUpdate` per server-sent event", body = LiveUpdate, content_type = "text/event-stream"),
+    ),
+)]
+#[get("/live")]
+pub fn get_live(
+    _auth: Auth<scope::ReadHabits>,
+    changes: &State<Changes>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut receiver = changes.subscribe();
+    EventStream! {
+        loop {
+            let update = select! {
+                update = receiver.recv() => match update {
+                    Ok(update) => update,
+                    Err(RecvError::Lagged(_)) => LiveUpdate::Lagged,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&update);
+        }
+    }
+}
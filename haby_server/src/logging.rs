@@ -0,0 +1,343 @@
+//! Logging with `tracing`, and request ids to find the log lines of a request
+//!
+//! Every request gets an id, either the `X-Request-Id` the client sent or a new one. It is sent
+//! back in the same header, added to every error body and logged with everything that happens
+//! while the route runs. Rockets own `log` lines end up here as well.
+
+use std::io::{Cursor, Write};
+use std::time::Instant;
+
+use haby_core::api::{ErrorBody, REQUEST_ID};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Header};
+use rocket::request::{FromRequest, Outcome};
+use rocket::route::{self, Handler};
+use rocket::serde::json::serde_json::{self, Map, Value};
+use rocket::serde::Deserialize;
+use rocket::{Data, Request, Response, Route};
+use sqlx::types::chrono::Utc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{error, info, warn, Instrument, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Ids from clients that are longer than this are replaced, they end up in every log line
+const MAX_ID_LENGTH: usize = 128;
+
+/// The `logging` table of the Rocket config
+///
+/// ```toml
+/// [default.logging]
+/// format = "pretty"
+/// filter = "info,sqlx=debug"
+/// ```
+///
+/// `RUST_LOG` takes precedence over `filter`.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+struct Config {
+    format: Format,
+    /// An `EnvFilter` directive
+    filter: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            format: Format::Json,
+            filter: String::from("info"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+enum Format {
+    /// One JSON object per line, for log collectors
+    Json,
+    /// For people reading along in a terminal
+    Pretty,
+}
+
+/// Install the global subscriber, this has to happen before Rocket sets up its own logger
+pub fn init(figment: &Figment) {
+    let config = match figment.focus("logging").extract::<Config>() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid logging config, using the defaults: {err}");
+            Config::default()
+        }
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.filter))
+        .unwrap_or_else(|err| {
+            eprintln!("Invalid log filter {:?}: {err}", config.filter);
+            EnvFilter::new("info")
+        });
+
+    let registry = tracing_subscriber::registry().with(filter);
+    let result = match config.format {
+        Format::Json => registry.with(JsonLayer).try_init(),
+        Format::Pretty => registry.with(tracing_subscriber::fmt::layer()).try_init(),
+    };
+    if let Err(err) = result {
+        eprintln!("Logging is already set up: {err}");
+    }
+}
+
+/// Writes events as JSON lines, with the fields of the spans they are in flattened into them so
+/// every line of a request has its `request_id`
+struct JsonLayer;
+
+/// The fields of a span, kept in its extensions
+struct SpanFields(Map<String, Value>);
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // Added by `tracing-log` for events from the `log` crate, they are normalized instead
+        if !field.name().starts_with("log.") {
+            self.0.insert(field.name().to_owned(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.insert(field, value.to_string().into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut JsonVisitor(&mut fields.0));
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut line = Map::new();
+        line.insert(String::from("timestamp"), Utc::now().to_rfc3339().into());
+        line.insert(String::from("level"), metadata.level().as_str().into());
+        line.insert(String::from("target"), metadata.target().into());
+        if let Some(scope) = ctx.event_scope(event) {
+            let mut spans = Vec::new();
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    line.extend(fields.0.clone());
+                }
+                spans.push(span.name());
+            }
+            line.insert(String::from("spans"), spans.join(":").into());
+        }
+        event.record(&mut JsonVisitor(&mut line));
+
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", Value::Object(line));
+    }
+}
+
+/// The id of the current request
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn new() -> Self {
+        Self(hex::encode(rand::random::<[u8; 16]>()))
+    }
+
+    /// The id the client sent, if it is reasonable to log
+    fn from_client(id: &str) -> Option<Self> {
+        let valid =
+            !id.is_empty() && id.len() <= MAX_ID_LENGTH && id.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(id.to_owned()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(RequestId::new))
+    }
+}
+
+pub fn fairing() -> RequestIds {
+    RequestIds
+}
+
+pub struct RequestIds;
+
+/// When the request came in, kept in the local cache
+struct Start(Instant);
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = req
+            .headers()
+            .get_one(REQUEST_ID)
+            .and_then(RequestId::from_client)
+            .unwrap_or_else(RequestId::new);
+        req.local_cache(|| id);
+        req.local_cache(|| Start(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let id = &req.local_cache(RequestId::new).0;
+        res.set_header(Header::new(REQUEST_ID, id.clone()));
+
+        let latency_ms = req
+            .local_cache(|| Start(Instant::now()))
+            .0
+            .elapsed()
+            .as_secs_f64()
+            * 1000.0;
+        let status = res.status();
+        let method = req.method().as_str();
+        let uri = req.uri().path().as_str();
+        if status.code < 400 {
+            info!(
+                request_id = id,
+                method,
+                uri,
+                status = status.code,
+                latency_ms,
+                "Request"
+            );
+            return;
+        }
+
+        // Error bodies are short strings or JSON, streams are only used for successful responses
+        let mut body = match res.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(err) => {
+                error!(request_id = id, "Failed to read the error response: {err}");
+                return;
+            }
+        };
+        let message = String::from_utf8_lossy(&body);
+        if status.code >= 500 {
+            error!(request_id = id, method, uri, status = status.code, latency_ms, error = %message, "Request failed");
+        } else {
+            warn!(request_id = id, method, uri, status = status.code, latency_ms, error = %message, "Request rejected");
+        }
+        if let Some(json) = with_request_id(&body, id) {
+            body = json;
+            res.set_header(ContentType::JSON);
+        }
+        res.set_sized_body(body.len(), Cursor::new(body));
+    }
+}
+
+/// Error bodies get the request id, so it shows up in bug reports
+///
+/// JSON objects like `ValidationErrors` get a `request_id` field, plain messages are sent as an
+/// `ErrorBody`. Routes return their JSON errors as plain strings, so this goes by the body and
+/// not the content type.
+fn with_request_id(body: &[u8], id: &str) -> Option<Vec<u8>> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(mut object)) => {
+            object.insert(String::from("request_id"), id.into());
+            serde_json::to_vec(&object).ok()
+        }
+        _ => serde_json::to_vec(&ErrorBody {
+            message: String::from_utf8_lossy(body).into_owned(),
+            request_id: id.to_owned(),
+        })
+        .ok(),
+    }
+}
+
+/// An error body as the route answered it, without the `request_id` [`with_request_id`] added
+///
+/// Bodies kept to be sent again, like idempotent responses, get the id of the request they are
+/// sent for instead.
+pub fn without_request_id(body: &[u8]) -> Option<Vec<u8>> {
+    let Ok(Value::Object(mut object)) = serde_json::from_slice::<Value>(body) else {
+        return None;
+    };
+    object.remove("request_id")?;
+    serde_json::to_vec(&object).ok()
+}
+
+/// Run the handlers of `routes` in a span with the request id, so the log lines of sqlx and the
+/// route itself can be told apart by request
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let id = &req.local_cache(RequestId::new).0;
+        let route = req.route().map(|route| route.uri.to_string());
+        let span = tracing::info_span!("request", request_id = id, route);
+        self.0.handle(req, data).instrument(span).await
+    }
+}
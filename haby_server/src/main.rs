@@ -1,133 +1,268 @@
+use auth::{scope, Auth};
+use db::Db;
+use error::Error;
+use haby_core::api::{BatchEvent, BatchItem, ServerInfo, API_VERSION, MAX_BATCH};
+use limits::{limit, LimitedJson};
 use rocket::http::Status;
 use rocket::serde::json::Json;
-use rocket::{get, launch, post, put, routes, State};
+use rocket::{delete, get, launch, post, put, routes, State};
+use webhooks::Webhooks;
 
-struct Db(sqlx::PgPool);
+mod auth;
+mod calendar;
+mod db;
+mod error;
+mod export;
+mod graphql;
+mod habits;
+mod health;
+mod idempotency;
+mod import;
+mod limits;
+mod live;
+mod logging;
+mod metrics;
+mod openapi;
+mod rate_limit;
+mod reminders;
+mod sync;
+mod web_push;
+mod webhooks;
 
+/// Used when `DATABASE_URL` is not set
 const DB_HOST: &str = "postgresql://postgres:viv@db:5432";
 
-impl Db {
-    async fn prod() -> Self {
-        let pool = sqlx::PgPool::connect(DB_HOST).await.unwrap();
-        sqlx::migrate!().run(&pool).await.unwrap();
-        Self(pool)
-    }
+/// Where the routes of the current `API_VERSION` are mounted
+const API_BASE: &str = "/api/v1";
 
-    fn with(pool: sqlx::PgPool) -> Self {
-        Self(pool)
-    }
+/// Which API versions are mounted, the one route that never moves
+#[utoipa::path(
+    tag = "meta",
+    security(()),
+    responses((status = 200, description = "The versions of the server", body = ServerInfo)),
+)]
+#[get("/api")]
+fn get_server_info() -> Json<ServerInfo> {
+    Json(ServerInfo {
+        version: String::from(haby_core::VERSION),
+        api_versions: vec![API_VERSION],
+    })
 }
 
 /// Get the `core` version that is in use
+#[utoipa::path(
+    tag = "meta",
+    security(()),
+    responses((status = 200, description = "The core version", body = String)),
+)]
 #[get("/version")]
 fn get_version() -> &'static str {
     haby_core::VERSION
 }
 
+#[utoipa::path(
+    tag = "habits",
+    responses(
+        (status = 200, description = "Every habit", body = Vec<haby_core::Habit>),
+        (status = 503, description = "The database is unavailable", body = haby_core::api::ErrorBody),
+    ),
+)]
 #[get("/habits")]
-async fn get_habits(pool: &State<Db>) -> Json<Vec<haby_core::Habit>> {
-    let habits = sqlx::query_as!(
-        haby_core::Habit,
-        r#"SELECT id,
-                name,
-                color,
-                kind AS "kind: haby_core::HabitKind",
-                recording_type AS "recording_type: haby_core::RecordingType",
-                every
-        FROM habits"#
-    )
-    .fetch_all(&pool.0)
-    .await
-    .unwrap();
-
-    Json(habits)
+async fn get_habits(
+    _auth: Auth<scope::ReadHabits>,
+    db: &State<Db>,
+) -> Result<Json<Vec<haby_core::Habit>>, Error> {
+    Ok(Json(habits::get_habits(db).await?))
 }
 
+#[utoipa::path(
+    tag = "habits",
+    request_body = haby_core::api::CreateHabit,
+    responses(
+        (status = 200, description = "The id of the new habit", body = String),
+        (status = 400, description = "The habit was rejected", body = haby_core::api::ErrorBody),
+        (status = 422, description = "Some fields are invalid", body = haby_core::validation::ValidationErrors),
+    ),
+)]
 #[post("/habits", data = "<habit>")]
 async fn create_habit(
-    habit: Json<haby_core::api::CreateHabit>,
-    pool: &State<Db>,
-) -> Result<String, (Status, String)> {
-    let res = sqlx::query!(
-        r#"
-        INSERT INTO habits (name, color, kind, recording_type, every)
-        VALUES ($1, $2, $3, $4, $5)
-        RETURNING id
-        "#,
-        habit.name,
-        habit.color.to_hex(),
-        habit.kind as haby_core::HabitKind,
-        habit.recording_type as haby_core::RecordingType,
-        habit.every,
-    )
-    .fetch_one(&pool.0)
-    .await;
-
-    match res {
-        Ok(res) => Ok(res.id.to_string()),
-        Err(err) => Err((Status::BadRequest, err.to_string())),
-    }
+    _auth: Auth<scope::Admin>,
+    habit: LimitedJson<haby_core::api::CreateHabit, limit::Habit>,
+    db: &State<Db>,
+    webhooks: &State<Webhooks>,
+) -> Result<String, Error> {
+    let habit = habits::create_habit(db, webhooks, habit.into_inner()).await?;
+    Ok(habit.id.to_string())
 }
 
+#[utoipa::path(
+    tag = "habits",
+    request_body = haby_core::api::CreateHabit,
+    responses(
+        (status = 200, description = "The habit was updated"),
+        (status = 400, description = "The habit was rejected", body = haby_core::api::ErrorBody),
+        (status = 422, description = "Some fields are invalid", body = haby_core::validation::ValidationErrors),
+    ),
+)]
 #[put("/habit/<id>", data = "<habit>")]
 async fn update_habit(
-    habit: Json<haby_core::api::CreateHabit>,
+    _auth: Auth<scope::Admin>,
+    habit: LimitedJson<haby_core::api::CreateHabit, limit::Habit>,
+    id: i32,
+    db: &State<Db>,
+    webhooks: &State<Webhooks>,
+) -> Result<(), Error> {
+    habits::update_habit(db, webhooks, id, habit.into_inner()).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    tag = "habits",
+    responses(
+        (status = 200, description = "The habit and its events were deleted"),
+        (status = 400, description = "The habit could not be deleted", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[delete("/habit/<id>")]
+async fn delete_habit(
+    _auth: Auth<scope::Admin>,
     id: i32,
-    pool: &State<Db>,
-) -> Result<(), (Status, String)> {
-    let res = sqlx::query!(
-        r#"
-            UPDATE habits
-            SET name=$2,color=$3,kind=$4,recording_type=$5,every=$6
-            WHERE id=$1
-        "#,
-        id,
-        habit.name,
-        habit.color.to_hex(),
-        habit.kind as haby_core::HabitKind,
-        habit.recording_type as haby_core::RecordingType,
-        habit.every,
-    )
-    .execute(&pool.0)
-    .await;
-
-    match res {
-        Ok(_) => Ok(()),
-        Err(err) => Err((Status::BadRequest, err.to_string())),
+    db: &State<Db>,
+    webhooks: &State<Webhooks>,
+) -> Result<(), Error> {
+    habits::delete_habit(db, webhooks, id).await
+}
+
+#[utoipa::path(
+    tag = "events",
+    request_body = haby_core::api::CreateEvent,
+    responses(
+        (status = 200, description = "The id of the new event", body = String),
+        (status = 400, description = "The event was rejected", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[post("/events", data = "<event>")]
+async fn create_event(
+    _auth: Auth<scope::WriteEvents>,
+    event: LimitedJson<haby_core::api::CreateEvent, limit::Event>,
+    db: &State<Db>,
+    webhooks: &State<Webhooks>,
+) -> Result<String, Error> {
+    let event = habits::create_event(db, webhooks, event.into_inner()).await?;
+    Ok(event.id.to_string())
+}
+
+/// Record many events at once, like an offline queue or a wearable export
+///
+/// Each event gets its own result, in the order they were sent.
+#[utoipa::path(
+    tag = "events",
+    request_body = Vec<BatchEvent>,
+    responses(
+        (status = 200, description = "What happened to each event", body = Vec<BatchItem>),
+        (status = 413, description = "Too many events in one batch", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[post("/events/batch", data = "<events>")]
+async fn create_events(
+    _auth: Auth<scope::WriteEvents>,
+    events: LimitedJson<Vec<BatchEvent>, limit::Batch>,
+    db: &State<Db>,
+    webhooks: &State<Webhooks>,
+) -> Result<Json<Vec<BatchItem>>, Error> {
+    if events.len() > MAX_BATCH {
+        return Err(Error::new(
+            Status::PayloadTooLarge,
+            format!("Batches are limited to {MAX_BATCH} events"),
+        ));
     }
+    let results = habits::create_events(db, webhooks, events.into_inner()).await?;
+    Ok(Json(results))
 }
 
+#[utoipa::path(
+    tag = "meta",
+    responses(
+        (status = 200, description = "Every table was emptied"),
+        (status = 503, description = "The database is unavailable", body = haby_core::api::ErrorBody),
+    ),
+)]
 #[post("/test/clear")]
-async fn clear_db(pool: &State<Db>) {
-    sqlx::query!("TRUNCATE TABLE events, habits;",)
-        .execute(&pool.0)
-        .await
-        .unwrap();
+async fn clear_db(_auth: Auth<scope::Admin>, db: &State<Db>) -> Result<(), Error> {
+    db.clear().await?;
+    Ok(())
 }
 
 fn rocket_no_db() -> rocket::Rocket<rocket::Build> {
     let cors = rocket_cors::CorsOptions::default();
     rocket::Rocket::build()
+        .mount("/", logging::traced(routes![get_server_info]))
+        .register("/", error::catchers())
+        .mount("/", health::routes())
+        .mount("/", metrics::routes())
         .mount(
-            "/",
-            routes![
+            API_BASE,
+            logging::traced(routes![
                 get_version,
                 get_habits,
                 create_habit,
                 update_habit,
-                clear_db
-            ],
+                delete_habit,
+                create_event,
+                create_events,
+                clear_db,
+                sync::get_sync,
+                live::get_live,
+                export::get_export,
+                import::import_json,
+                import::import_loop,
+                calendar::create_calendar_token,
+                calendar::delete_calendar_token,
+                calendar::get_calendar,
+                reminders::get_reminder_settings,
+                reminders::set_reminder_settings,
+                reminders::snooze,
+                reminders::get_push_key,
+                reminders::create_push_subscription,
+                reminders::delete_push_subscription,
+                webhooks::get_webhooks,
+                webhooks::create_webhook,
+                webhooks::delete_webhook,
+                webhooks::get_deliveries,
+                webhooks::test_webhook,
+                auth::get_tokens,
+                auth::create_token,
+                auth::revoke_token,
+                graphql::post_graphql,
+                graphql::post_graphql_stream,
+                graphql::get_graphiql
+            ]),
         )
+        .manage(graphql::schema())
+        .mount("/", openapi::routes())
+        .mount("/", idempotency::routes())
+        .mount("/", rate_limit::routes())
+        .attach(logging::fairing())
+        .attach(metrics::fairing())
         .attach(cors.to_cors().unwrap())
+        .attach(auth::fairing())
+        .attach(rate_limit::fairing())
+        .attach(idempotency::fairing())
+        .attach(live::fairing())
+        .attach(reminders::fairing())
+        .attach(webhooks::fairing())
 }
 
-fn rocket_with_pool(pool: sqlx::PgPool) -> rocket::Rocket<rocket::Build> {
-    rocket_no_db().manage(Db::with(pool))
+#[cfg(test)]
+fn rocket_with_pool(pool: impl Into<Db>) -> rocket::Rocket<rocket::Build> {
+    rocket_no_db().manage(pool.into())
 }
 
 #[launch]
 async fn rocket() -> _ {
-    rocket_no_db().manage(Db::prod().await)
+    logging::init(&rocket::Config::figment());
+    let url = std::env::var("DATABASE_URL").unwrap_or_else(|_| String::from(DB_HOST));
+    rocket_no_db().manage(Db::connect(&url).await.unwrap())
 }
 
 #[cfg(test)]
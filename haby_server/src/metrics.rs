@@ -0,0 +1,256 @@
+//! Prometheus metrics at `GET /metrics`
+//!
+//! The fairing counts requests and their latency per route. Pool stats and the domain gauges are
+//! read when the endpoint is scraped, so they are never stale.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use haby_core::HabitKind;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{get, routes, Build, Data, Request, Response, Rocket, Route, State};
+
+use crate::auth::{scope, Auth};
+use crate::db::Db;
+use crate::error::Error;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub fn fairing() -> Metrics {
+    Metrics
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_metrics]
+}
+
+pub struct Metrics;
+
+/// Requests by method and route, routes are the mounted patterns like `/api/v1/habits/<id>` so
+/// the number of series stays small
+#[derive(Default)]
+pub struct Requests {
+    counts: BTreeMap<(String, String, u16), u64>,
+    latencies: BTreeMap<(String, String), Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Not cumulative, that is done when rendering
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// When the request came in, kept in the local cache
+struct Start(Instant);
+
+#[rocket::async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Metrics",
+            kind: Kind::Ignite | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        Ok(rocket.manage(Mutex::new(Requests::default())))
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| Start(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(requests) = req.rocket().state::<Mutex<Requests>>() else {
+            return;
+        };
+        let seconds = req
+            .local_cache(|| Start(Instant::now()))
+            .0
+            .elapsed()
+            .as_secs_f64();
+        let method = req.method().to_string();
+        let route = req
+            .route()
+            .map(|route| route.uri.to_string())
+            .unwrap_or_else(|| String::from("unmatched"));
+
+        let mut requests = requests.lock().unwrap();
+        *requests
+            .counts
+            .entry((method.clone(), route.clone(), res.status().code))
+            .or_default() += 1;
+        requests
+            .latencies
+            .entry((method, route))
+            .or_default()
+            .observe(seconds);
+    }
+}
+
+/// Metrics in the Prometheus text format
+#[utoipa::path(
+    tag = "meta",
+    responses(
+        (status = 200, description = "Request, pool and habit metrics", body = String, content_type = "text/plain"),
+    ),
+)]
+#[get("/metrics")]
+pub async fn get_metrics(
+    _auth: Auth<scope::ReadHabits>,
+    db: &State<Db>,
+    requests: &State<Mutex<Requests>>,
+) -> Result<(ContentType, String), Error> {
+    let habits = db.kind_counts().await?;
+
+    let mut out = String::new();
+    {
+        let requests = requests.lock().unwrap();
+        header(
+            &mut out,
+            "haby_http_requests_total",
+            "counter",
+            "HTTP requests by route and status",
+        );
+        for ((method, route, status), count) in &requests.counts {
+            let labels = labels(&[
+                ("method", method),
+                ("route", route),
+                ("status", &status.to_string()),
+            ]);
+            writeln!(out, "haby_http_requests_total{{{labels}}} {count}").unwrap();
+        }
+
+        header(
+            &mut out,
+            "haby_http_request_duration_seconds",
+            "histogram",
+            "Time until the response headers were sent",
+        );
+        for ((method, route), histogram) in &requests.latencies {
+            let labels = labels(&[("method", method), ("route", route)]);
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "haby_http_request_duration_seconds_bucket{{{labels},le=\"{le}\"}} {cumulative}"
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "haby_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "haby_http_request_duration_seconds_sum{{{labels}}} {}",
+                histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "haby_http_request_duration_seconds_count{{{labels}}} {}",
+                histogram.count
+            )
+            .unwrap();
+        }
+    }
+
+    let pool = db.pool_stats();
+    let pool_stats = [
+        (
+            "haby_db_pool_connections",
+            "Open database connections",
+            pool.connections,
+        ),
+        (
+            "haby_db_pool_idle_connections",
+            "Open database connections that are not in use",
+            pool.idle,
+        ),
+        (
+            "haby_db_pool_max_connections",
+            "The most connections the pool opens",
+            pool.max_connections,
+        ),
+    ];
+    for (name, help, value) in pool_stats {
+        header(&mut out, name, "gauge", help);
+        writeln!(out, "{name} {value}").unwrap();
+    }
+
+    // Kinds without habits are missing from the query, they are reported as 0 all the same
+    let kinds = [HabitKind::Habit, HabitKind::Addiction].map(|kind| {
+        let row = habits.iter().find(|row| row.kind == kind);
+        (
+            kind_label(kind),
+            row.map_or(0, |row| row.habits),
+            row.map_or(0, |row| row.events),
+        )
+    });
+    header(&mut out, "haby_habits", "gauge", "Habits by kind");
+    for (kind, habits, _) in kinds {
+        writeln!(out, "haby_habits{{kind=\"{kind}\"}} {habits}").unwrap();
+    }
+    header(
+        &mut out,
+        "haby_events",
+        "gauge",
+        "Recorded events by the kind of their habit",
+    );
+    for (kind, _, events) in kinds {
+        writeln!(out, "haby_events{{kind=\"{kind}\"}} {events}").unwrap();
+    }
+
+    Ok((
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        out,
+    ))
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+fn labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn kind_label(kind: HabitKind) -> &'static str {
+    match kind {
+        HabitKind::Habit => "habit",
+        HabitKind::Addiction => "addiction",
+    }
+}
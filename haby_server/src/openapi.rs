@@ -0,0 +1,96 @@
+//! The OpenAPI document, generated from the `#[utoipa::path]` attributes on the routes
+//!
+//! New routes have to be listed in `paths` too, the `openapi_covers_every_route` test catches the
+//! ones that are not.
+
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{
+    auth,
+    calendar,
+    export,
+    graphql,
+    health,
+    import,
+    live,
+    metrics,
+    reminders,
+    sync,
+    webhooks,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "haby", description = "Track habits and addictions"),
+    paths(crate::get_server_info, health::live, health::ready, metrics::get_metrics),
+    nest((path = "/api/v1", api = V1)),
+    // Query parameters only refer to their schemas, so these are not picked up from the paths
+    components(schemas(haby_core::api::ConflictPolicy, haby_core::api::ExportFormat)),
+    modifiers(&BearerToken),
+    security(("token" = [])),
+)]
+pub struct ApiDoc;
+
+/// Everything under `API_BASE`
+#[derive(OpenApi)]
+#[openapi(paths(
+    crate::get_version,
+    crate::get_habits,
+    crate::create_habit,
+    crate::update_habit,
+    crate::delete_habit,
+    crate::create_event,
+    crate::create_events,
+    crate::clear_db,
+    sync::get_sync,
+    live::get_live,
+    export::get_export,
+    import::import_json,
+    import::import_loop,
+    calendar::create_calendar_token,
+    calendar::delete_calendar_token,
+    calendar::get_calendar,
+    reminders::get_reminder_settings,
+    reminders::set_reminder_settings,
+    reminders::snooze,
+    reminders::get_push_key,
+    reminders::create_push_subscription,
+    reminders::delete_push_subscription,
+    webhooks::get_webhooks,
+    webhooks::create_webhook,
+    webhooks::delete_webhook,
+    webhooks::get_deliveries,
+    webhooks::test_webhook,
+    auth::get_tokens,
+    auth::create_token,
+    auth::revoke_token,
+    graphql::post_graphql,
+    graphql::post_graphql_stream,
+    graphql::get_graphiql,
+))]
+struct V1;
+
+/// Tokens are only checked when one is sent or `auth.required` is set, see `auth::Auth`
+struct BearerToken;
+
+impl Modify for BearerToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("An API token from `POST /tokens`"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// `GET /openapi.json` and the Swagger UI at `/docs`
+pub fn routes() -> SwaggerUi {
+    SwaggerUi::new("/docs/<_..>").url("/openapi.json", ApiDoc::openapi())
+}
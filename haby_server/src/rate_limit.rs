@@ -0,0 +1,299 @@
+//! Token bucket rate limiting for everything under `API_BASE`
+//!
+//! Every request is counted per client IP, and requests with a valid token per token as well, so
+//! neither sending made up tokens nor spreading a token over many IPs gets around the limit.
+//! The IP is the address of the connection, unless `trust_proxy` is set. Only set it behind a
+//! reverse proxy that overwrites Rockets `ip_header` (`X-Real-IP` by default), anyone else can
+//! send a different one with every request.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Header, Method};
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::Deserialize;
+use rocket::{get, routes, Build, Data, Request, Responder, Rocket, Route};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::auth::Grants;
+use crate::API_BASE;
+
+/// Where limited requests are sent, it is not part of the API
+const LIMITED_URI: &str = "/__rate_limited";
+
+/// Forget buckets that filled up again once there are this many, so the map doesn't grow forever
+const PRUNE_AT: usize = 10_000;
+
+/// Pruning looks at every bucket, so it runs this often at most
+const PRUNE_EVERY: Duration = Duration::from_secs(10);
+
+/// When there are this many buckets, the half used least recently is forgotten
+const MAX_BUCKETS: usize = 100_000;
+
+/// The `rate_limit` table of the Rocket config
+///
+/// ```toml
+/// [default.rate_limit]
+/// trust_proxy = true
+/// write = { burst = 30, per_minute = 120 }
+/// ```
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+struct Config {
+    enabled: bool,
+    /// Take the client IP from Rockets `ip_header` instead of the connection
+    trust_proxy: bool,
+    /// `GET` requests
+    read: Limit,
+    /// Every other method
+    write: Limit,
+    /// `/import` routes, which are a lot more work than other writes
+    import: Limit,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trust_proxy: false,
+            read: Limit {
+                burst: 120,
+                per_minute: 600,
+            },
+            write: Limit {
+                burst: 30,
+                per_minute: 120,
+            },
+            import: Limit {
+                burst: 3,
+                per_minute: 6,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(crate = "rocket::serde")]
+struct Limit {
+    /// How many requests can be made at once
+    burst: u32,
+    /// How fast the burst refills
+    per_minute: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Group {
+    Read,
+    Write,
+    Import,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<(Group, String), Bucket>,
+    pruned: Option<Instant>,
+}
+
+struct Limiter {
+    config: Config,
+    buckets: Mutex<Buckets>,
+}
+
+impl Limiter {
+    fn limit(&self, group: Group) -> Limit {
+        match group {
+            Group::Read => self.config.read,
+            Group::Write => self.config.write,
+            Group::Import => self.config.import,
+        }
+    }
+
+    /// Take a token from the bucket of every one of `clients`, or how long until they all have
+    /// one again
+    ///
+    /// Nothing is taken unless every bucket has a token.
+    fn take(&self, group: Group, clients: Vec<String>, now: Instant) -> Result<(), Duration> {
+        let limit = self.limit(group);
+        let burst = f64::from(limit.burst.max(1));
+        let per_second = f64::from(limit.per_minute.max(1)) / 60.0;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.prune(|group| self.limit(group), now);
+
+        let mut wait = Duration::ZERO;
+        for client in &clients {
+            let bucket = buckets
+                .buckets
+                .entry((group, client.clone()))
+                .or_insert(Bucket {
+                    tokens: burst,
+                    updated: now,
+                });
+            let refilled = now.duration_since(bucket.updated).as_secs_f64() * per_second;
+            bucket.tokens = (bucket.tokens + refilled).min(burst);
+            bucket.updated = now;
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for client in clients {
+            if let Some(bucket) = buckets.buckets.get_mut(&(group, client)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Buckets {
+    /// Forget buckets that filled up again, and the ones used least recently when there are too
+    /// many
+    fn prune(&mut self, limit: impl Fn(Group) -> Limit, now: Instant) {
+        let due = self
+            .pruned
+            .is_none_or(|pruned| now.duration_since(pruned) >= PRUNE_EVERY);
+        if self.buckets.len() >= PRUNE_AT && due {
+            self.pruned = Some(now);
+            self.buckets.retain(|(group, _), bucket| {
+                let limit = limit(*group);
+                let refill = f64::from(limit.per_minute.max(1)) / 60.0;
+                let tokens =
+                    bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill;
+                tokens < f64::from(limit.burst.max(1))
+            });
+        }
+
+        // Only happens every `MAX_BUCKETS / 2` new clients, however fast they come
+        if self.buckets.len() >= MAX_BUCKETS {
+            let mut updated: Vec<Instant> =
+                self.buckets.values().map(|bucket| bucket.updated).collect();
+            let (_, cutoff, _) = updated.select_nth_unstable(MAX_BUCKETS / 2);
+            let cutoff = *cutoff;
+            self.buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+    }
+}
+
+pub fn fairing() -> RateLimit {
+    RateLimit
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![limited]
+}
+
+pub struct RateLimit;
+
+/// Set in the local cache of requests that were rerouted to [`limited`]
+struct RetryAfter(Option<Duration>);
+
+#[rocket::async_trait]
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit",
+            kind: Kind::Ignite | Kind::Request,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        match rocket.figment().focus("rate_limit").extract::<Config>() {
+            Ok(config) => Ok(rocket.manage(Limiter {
+                config,
+                buckets: Mutex::default(),
+            })),
+            Err(err) => {
+                error!("Invalid rate limit config: {err}");
+                Err(rocket)
+            }
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(limiter) = req.rocket().state::<Limiter>() else {
+            return;
+        };
+        let Some(path) = req.uri().path().as_str().strip_prefix(API_BASE) else {
+            return;
+        };
+        if !limiter.config.enabled {
+            return;
+        }
+
+        let group = if path.starts_with("/import") {
+            Group::Import
+        } else if matches!(req.method(), Method::Get | Method::Head | Method::Options) {
+            Group::Read
+        } else {
+            Group::Write
+        };
+        let mut clients = Vec::with_capacity(2);
+        let ip = if limiter.config.trust_proxy {
+            req.client_ip()
+        } else {
+            req.remote().map(|remote| remote.ip())
+        };
+        if let Some(ip) = ip {
+            clients.push(format!("ip {ip}"));
+        }
+        // Only tokens that exist get a bucket, anyone can make up new ones. The lookup is cached,
+        // so the route does not look the token up again.
+        if let Some(auth) = req.headers().get_one("Authorization") {
+            if let Outcome::Success(grants) = req.guard::<&Grants>().await {
+                if grants.has_token() {
+                    clients.push(format!("token {}", hex::encode(Sha256::digest(auth))));
+                }
+            }
+        }
+        if clients.is_empty() {
+            return;
+        }
+
+        if let Err(wait) = limiter.take(group, clients, Instant::now()) {
+            req.local_cache(|| RetryAfter(Some(wait)));
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(LIMITED_URI).expect("The rate limit URI is valid"));
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RetryAfter {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(|| RetryAfter(None)))
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 429)]
+struct TooManyRequests {
+    inner: String,
+    retry_after: Header<'static>,
+}
+
+/// Answers requests over the limit, they are only sent here by the fairing
+#[get("/__rate_limited")]
+fn limited(retry_after: &RetryAfter) -> Option<TooManyRequests> {
+    let wait = retry_after.0?;
+    // Rounded up, retrying a moment too early would only be rejected again
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Some(TooManyRequests {
+        inner: format!("Too many requests, try again in {seconds} seconds"),
+        retry_after: Header::new("Retry-After", seconds.to_string()),
+    })
+}
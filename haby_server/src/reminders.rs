@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use haby_core::api::{PushSubscription, ReminderSettings};
+use haby_core::reminders::Reminder;
+use haby_core::HabitKind;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::{delete, get, post, put, State};
+use sqlx::types::chrono::{Local, NaiveDateTime};
+use tracing::error;
+
+use crate::auth::{scope, Auth};
+use crate::db::Db;
+use crate::error::Error;
+use crate::limits::{limit, LimitedJson};
+use crate::web_push::{self, PushError, VapidKey};
+
+/// The `reminders` table of the Rocket config, every sink is optional
+///
+/// ```toml
+/// [default.reminders]
+/// interval = 60
+/// webhooks = ["https://example.com/haby"]
+/// email = { host = "smtp.example.com", from = "haby@example.com", to = "me@example.com" }
+/// push = { private_key = "...", subject = "mailto:me@example.com" }
+/// ```
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Config {
+    /// Seconds between checking for due habits
+    pub interval: u64,
+    pub webhooks: Vec<String>,
+    pub email: Option<EmailConfig>,
+    pub push: Option<PushConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval: 60,
+            webhooks: Vec::new(),
+            email: None,
+            push: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EmailConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    /// Only turn this off for a local SMTP server
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: String,
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+/// Generate a private key with
+/// `openssl ecparam -genkey -name prime256v1 | openssl ec -outform DER | tail -c +8 | head -c 32 | basenc --base64url`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PushConfig {
+    pub private_key: String,
+    pub subject: String,
+}
+
+/// Somewhere a reminder can be delivered to
+#[rocket::async_trait]
+pub trait Sink: Send + Sync {
+    async fn send(&self, reminder: &Reminder) -> Result<(), String>;
+}
+
+/// Posts the reminder as JSON
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[rocket::async_trait]
+impl Sink for Webhook {
+    async fn send(&self, reminder: &Reminder) -> Result<(), String> {
+        self.client
+            .post(&self.url)
+            .json(reminder)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("Webhook {} failed: {err}", self.url))?;
+        Ok(())
+    }
+}
+
+pub struct Email {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Mailbox,
+}
+
+impl Email {
+    pub fn new(config: &EmailConfig) -> Result<Self, String> {
+        let mut transport = if config.tls {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)
+                .map_err(|err| format!("Invalid SMTP host: {err}"))?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+        };
+        if let Some(port) = config.port {
+            transport = transport.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let mailbox = |address: &str| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|err| format!("Invalid email address {address:?}: {err}"))
+        };
+        Ok(Self {
+            transport: transport.build(),
+            from: mailbox(&config.from)?,
+            to: mailbox(&config.to)?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Sink for Email {
+    async fn send(&self, reminder: &Reminder) -> Result<(), String> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(self.to.clone())
+            .subject(reminder.title())
+            .body(reminder.body())
+            .map_err(|err| err.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|err| format!("Sending email failed: {err}"))?;
+        Ok(())
+    }
+}
+
+/// Sends to every browser that subscribed through `/push/subscriptions`
+pub struct WebPush {
+    client: reqwest::Client,
+    db: Db,
+    vapid: VapidKey,
+}
+
+#[rocket::async_trait]
+impl Sink for WebPush {
+    async fn send(&self, reminder: &Reminder) -> Result<(), String> {
+        let subscriptions = self
+            .db
+            .push_subscriptions()
+            .await
+            .map_err(|err| err.to_string())?;
+        // The service worker shows this as is, so it doesn't need to know how to word a reminder
+        let payload = rocket::serde::json::json!({
+            "title": reminder.title(),
+            "body": reminder.body(),
+            "reminder": reminder,
+        })
+        .to_string();
+
+        let mut errors = Vec::new();
+        for subscription in subscriptions {
+            match web_push::send(&self.client, &self.vapid, &subscription, payload.as_bytes()).await
+            {
+                Ok(()) => {}
+                Err(PushError::Gone) => {
+                    self.db
+                        .delete_push_subscription(&subscription.endpoint)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+                Err(PushError::Other(err)) => errors.push(err),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+}
+
+/// Every configured sink, and the push key browsers need to subscribe
+pub struct Sinks {
+    sinks: Vec<Box<dyn Sink>>,
+    push_key: Option<String>,
+}
+
+impl Sinks {
+    fn new(config: Config, db: &Db) -> Result<Self, String> {
+        let client = reqwest::Client::new();
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+        let mut push_key = None;
+
+        for url in config.webhooks {
+            sinks.push(Box::new(Webhook {
+                client: client.clone(),
+                url,
+            }));
+        }
+        if let Some(email) = config.email {
+            sinks.push(Box::new(Email::new(&email)?));
+        }
+        if let Some(push) = config.push {
+            let vapid = VapidKey::new(&push.private_key, push.subject)?;
+            push_key = Some(vapid.public_key());
+            sinks.push(Box::new(WebPush {
+                client,
+                db: db.clone(),
+                vapid,
+            }));
+        }
+
+        Ok(Self { sinks, push_key })
+    }
+}
+
+/// Checks for due habits every `interval` seconds and sends reminders to the configured sinks
+///
+/// Without any sinks there is nothing to check for.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Reminders", |rocket| async move {
+        let Some(db) = rocket.state::<Db>() else {
+            error!("Reminders need a database");
+            return Err(rocket);
+        };
+        let config: Config = match rocket.figment().focus("reminders").extract() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Invalid reminders config: {err}");
+                return Err(rocket);
+            }
+        };
+
+        let interval = Duration::from_secs(config.interval.max(1));
+        let sinks = match Sinks::new(config, db) {
+            Ok(sinks) => Arc::new(sinks),
+            Err(err) => {
+                error!("{err}");
+                return Err(rocket);
+            }
+        };
+
+        if !sinks.sinks.is_empty() {
+            rocket::tokio::spawn(schedule(db.clone(), sinks.clone(), interval));
+        }
+        Ok(rocket.manage(sinks))
+    })
+}
+
+async fn schedule(db: Db, sinks: Arc<Sinks>, interval: Duration) {
+    let mut ticks = rocket::tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        match send_due(&db, &sinks.sinks, Local::now().naive_local()).await {
+            Ok(_) => {}
+            Err(sqlx::Error::PoolClosed) => break,
+            Err(err) => error!("Checking for due habits failed: {err}"),
+        }
+    }
+}
+
+/// Send a reminder for every habit that is due at `now` and was not reminded about yet today
+///
+/// A failing sink does not stop the others, and the habit still counts as reminded so a broken
+/// sink doesn't lead to a reminder every interval.
+pub async fn send_due(
+    db: &Db,
+    sinks: &[Box<dyn Sink>],
+    now: NaiveDateTime,
+) -> Result<Vec<Reminder>, sqlx::Error> {
+    let mut habits = db.habits().await?;
+    habits.retain(|habit| habit.every.is_some() && habit.kind == HabitKind::Habit);
+
+    // The schedule only depends on the latest check-in or span start
+    let events = db.latest_check_ins().await?;
+
+    let settings: HashMap<_, _> = db
+        .reminder_states()
+        .await?
+        .into_iter()
+        .map(|state| (state.habit_id, (state.settings, state.last_sent)))
+        .collect();
+
+    let today = now.date();
+    let mut sent = Vec::new();
+    for habit in &habits {
+        let (settings, last_sent) = settings.get(&habit.id).cloned().unwrap_or_default();
+        if last_sent == Some(today) {
+            continue;
+        }
+        let Some(reminder) = haby_core::reminders::due(habit, &events, &settings, now) else {
+            continue;
+        };
+
+        for sink in sinks {
+            if let Err(err) = sink.send(&reminder).await {
+                error!("Failed to send reminder for {}: {err}", habit.name);
+            }
+        }
+        db.mark_reminded(habit.id, today).await?;
+        sent.push(reminder);
+    }
+    Ok(sent)
+}
+
+#[utoipa::path(
+    tag = "reminders",
+    responses(
+        (status = 200, description = "The reminder settings of the habit", body = ReminderSettings),
+    ),
+)]
+#[get("/habit/<id>/reminders")]
+pub async fn get_reminder_settings(
+    _auth: Auth<scope::ReadHabits>,
+    id: i32,
+    db: &State<Db>,
+) -> Result<Json<ReminderSettings>, Error> {
+    let settings = db.reminder_settings(id).await?.unwrap_or_default();
+    Ok(Json(settings))
+}
+
+#[utoipa::path(
+    tag = "reminders",
+    request_body = ReminderSettings,
+    responses(
+        (status = 200, description = "The settings were saved"),
+        (status = 404, description = "Unknown habit", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[put("/habit/<id>/reminders", data = "<settings>")]
+pub async fn set_reminder_settings(
+    _auth: Auth<scope::Admin>,
+    id: i32,
+    settings: LimitedJson<ReminderSettings, limit::Json>,
+    db: &State<Db>,
+) -> Result<(), Error> {
+    db.set_reminder_settings(id, &settings)
+        .await
+        .map_err(Error::rejected)?;
+    Ok(())
+}
+
+/// Hold off reminders for a habit, an hour by default
+///
+/// Returns when the snooze ends, the habit will be reminded about again after that.
+#[utoipa::path(
+    tag = "reminders",
+    responses(
+        (status = 200, description = "When reminders start again", body = NaiveDateTime),
+        (status = 404, description = "Unknown habit", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[post("/habit/<id>/snooze?<minutes>")]
+pub async fn snooze(
+    _auth: Auth<scope::WriteEvents>,
+    id: i32,
+    minutes: Option<u32>,
+    db: &State<Db>,
+) -> Result<Json<NaiveDateTime>, Error> {
+    let until =
+        Local::now().naive_local() + Duration::from_secs(u64::from(minutes.unwrap_or(60)) * 60);
+    db.snooze(id, until)
+        .await
+        .map(Json)
+        .map_err(Error::rejected)
+}
+
+/// The key browsers need to subscribe to push reminders, 404 when push is not configured
+#[utoipa::path(
+    tag = "reminders",
+    responses(
+        (status = 200, description = "The VAPID public key to subscribe with", body = String),
+        (status = 404, description = "Push is not configured", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[get("/push/key")]
+pub fn get_push_key(
+    _auth: Auth<scope::ReadHabits>,
+    sinks: &State<Arc<Sinks>>,
+) -> Result<String, Error> {
+    sinks.push_key.clone().ok_or_else(|| {
+        Error::new(
+            Status::NotFound,
+            String::from("Push reminders are not configured"),
+        )
+    })
+}
+
+#[utoipa::path(
+    tag = "reminders",
+    request_body = PushSubscription,
+    responses((status = 200, description = "The subscription was saved")),
+)]
+#[post("/push/subscriptions", data = "<subscription>")]
+pub async fn create_push_subscription(
+    _auth: Auth<scope::Admin>,
+    subscription: LimitedJson<PushSubscription, limit::Json>,
+    db: &State<Db>,
+) -> Result<(), Error> {
+    db.save_push_subscription(&subscription).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    tag = "reminders",
+    responses((status = 200, description = "The subscription was removed")),
+)]
+#[delete("/push/subscriptions?<endpoint>")]
+pub async fn delete_push_subscription(
+    _auth: Auth<scope::Admin>,
+    endpoint: &str,
+    db: &State<Db>,
+) -> Result<(), Error> {
+    if !db.delete_push_subscription(endpoint).await? {
+        return Err(Error::new(
+            Status::NotFound,
+            String::from("Unknown push subscription"),
+        ));
+    }
+    Ok(())
+}
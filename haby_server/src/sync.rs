@@ -0,0 +1,26 @@
+use haby_core::api::SyncResponse;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+
+use crate::auth::{scope, Auth};
+use crate::db::Db;
+use crate::error::Error;
+
+/// Get everything that changed after the `since` cursor, or everything if it is left out
+///
+/// All reads happen in a single snapshot so the returned cursor matches the returned rows.
+#[utoipa::path(
+    tag = "sync",
+    responses(
+        (status = 200, description = "Everything that changed after `since`", body = SyncResponse),
+    ),
+)]
+#[get("/sync?<since>")]
+pub async fn get_sync(
+    _auth: Auth<scope::ReadHabits>,
+    since: Option<i64>,
+    db: &State<Db>,
+) -> Result<Json<SyncResponse>, Error> {
+    let since = since.unwrap_or(0);
+    Ok(Json(db.changes_since(since).await?))
+}
@@ -1,186 +1,2312 @@
+use haby_core::WebhookEvent;
 use rocket::local::asynchronous::Client;
 use rocket::uri;
 
 use super::*;
 
-#[sqlx::test]
-async fn version_returns_core_version(pool: sqlx::PgPool) {
-    let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
-    let response = client.get(uri!(get_version)).dispatch().await;
+/// Runs a test against Postgres as `<test>::postgres`, against SQLite as `<test>::sqlite` when it
+/// is built in, and against the in-memory backend as `<test>::memory`
+///
+/// Tests that need a real database, like ones reaching into its tables, are marked `database`
+/// and skip the in-memory backend.
+macro_rules! db_test {
+    (async fn $name:ident($pool:ident) $body:block) => {
+        db_test! {
+            @databases $name($pool) $body
+
+            #[rocket::async_test]
+            async fn memory() {
+                let $pool = crate::db::memory::Memory::default();
+                $body
+            }
+        }
+    };
+    (database async fn $name:ident($pool:ident) $body:block) => {
+        db_test! { @databases $name($pool) $body }
+    };
+    (@databases $name:ident($pool:ident) $body:block $($backend:item)*) => {
+        mod $name {
+            use super::*;
+
+            #[sqlx::test]
+            async fn postgres($pool: sqlx::PgPool) $body
+
+            #[cfg(feature = "sqlite")]
+            #[sqlx::test(migrations = "migrations/sqlite")]
+            async fn sqlite($pool: sqlx::SqlitePool) $body
 
-    let ver = response.into_string().await.unwrap();
-    assert_eq!(ver, haby_core::VERSION);
+            $($backend)*
+        }
+    };
 }
 
-#[sqlx::test]
-async fn clear_db_clears_db(pool: sqlx::PgPool) {
-    let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
-
-    let habit = haby_core::api::CreateHabit {
-        name: String::from("Test Habit"),
-        color: haby_core::Color { r: 0, g: 0, b: 0 },
-        kind: haby_core::HabitKind::Habit,
-        recording_type: haby_core::RecordingType::Point,
-        every: Some(1),
+/// `uri!` for routes mounted under `API_BASE`
+macro_rules! v1 {
+    ($($route:tt)*) => {
+        uri!("/api/v1", $($route)*)
     };
+}
 
-    client
-        .post(uri!(create_habit))
-        .json(&habit)
-        .dispatch()
-        .await;
-    client.post(uri!(clear_db)).dispatch().await;
+db_test! {
+    async fn version_returns_core_version(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let response = client.get(v1!(get_version)).dispatch().await;
 
-    let response = client.get(uri!(get_habits)).dispatch().await;
-    let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
-    assert_eq!(res, vec![]);
+        let ver = response.into_string().await.unwrap();
+        assert_eq!(ver, haby_core::VERSION);
+    }
 }
 
-#[sqlx::test]
-async fn habit_table_starts_empty(pool: sqlx::PgPool) {
-    let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
-    let response = client.get(uri!(get_habits)).dispatch().await;
+db_test! {
+    async fn server_info_lists_api_versions(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let response = client.get(uri!(get_server_info)).dispatch().await;
 
-    let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
-    assert_eq!(res, vec![]);
+        let info: ServerInfo = response.into_json().await.unwrap();
+        assert_eq!(info.version, haby_core::VERSION);
+        assert!(info.api_versions.contains(&API_VERSION));
+        assert_eq!(API_BASE, format!("/api/v{API_VERSION}"));
+
+        let response = client.get("/habits").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
 }
 
-#[sqlx::test]
-async fn habit_get_returns_inserted_habits(pool: sqlx::PgPool) {
-    let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
-
-    let habit = haby_core::api::CreateHabit {
-        name: String::from("Test Habit"),
-        color: haby_core::Color { r: 0, g: 0, b: 0 },
-        kind: haby_core::HabitKind::Habit,
-        recording_type: haby_core::RecordingType::Point,
-        every: Some(1),
-    };
+db_test! {
+    async fn clear_db_clears_db(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
 
-    let res = client
-        .post(uri!(create_habit))
-        .json(&habit)
-        .dispatch()
-        .await;
-    let status = res.status().class();
-    assert!(status.is_success(), "Expected success, got {:?}", status);
+        let habit = haby_core::api::CreateHabit {
+            name: String::from("Test Habit"),
+            color: haby_core::Color { r: 0, g: 0, b: 0 },
+            kind: haby_core::HabitKind::Habit,
+            recording_type: haby_core::RecordingType::Point,
+            every: Some(1),
+        };
+
+        client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        client.post(v1!(clear_db)).dispatch().await;
+
+        let response = client.get(v1!(get_habits)).dispatch().await;
+        let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        assert_eq!(res, vec![]);
+    }
+}
+
+db_test! {
+    async fn habit_table_starts_empty(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let response = client.get(v1!(get_habits)).dispatch().await;
+
+        let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        assert_eq!(res, vec![]);
+    }
+}
+
+db_test! {
+    async fn habit_get_returns_inserted_habits(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let habit = haby_core::api::CreateHabit {
+            name: String::from("Test Habit"),
+            color: haby_core::Color { r: 0, g: 0, b: 0 },
+            kind: haby_core::HabitKind::Habit,
+            recording_type: haby_core::RecordingType::Point,
+            every: Some(1),
+        };
+
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let status = res.status().class();
+        assert!(status.is_success(), "Expected success, got {:?}", status);
+
+        let id = res.into_string().await.unwrap().parse().unwrap();
+
+        let response = client.get(v1!(get_habits)).dispatch().await;
+        let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+
+        assert_eq!(res.len(), 1, "Returns only one habit after habit creation");
+        let res = res.into_iter().next().unwrap();
+        assert_eq!(res, habit.with_id(id));
+    }
+}
+
+db_test! {
+    async fn habit_insert_dupplicates(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let habit = haby_core::api::CreateHabit {
+            name: String::from("Test Habit"),
+            color: haby_core::Color { r: 0, g: 0, b: 0 },
+            kind: haby_core::HabitKind::Habit,
+            recording_type: haby_core::RecordingType::Point,
+            every: Some(1),
+        };
+
+        client.post(v1!(create_habit)).json(&habit).dispatch().await;
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&habit)
+            .dispatch()
+            .await
+            .status()
+            .class();
+        assert!(
+            res.is_client_error(),
+            "Expected client error, got {:?}",
+            res
+        );
+    }
+}
+
+db_test! {
+    async fn habit_update(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let mut habit = haby_core::api::CreateHabit {
+            name: String::from("Test Habit"),
+            color: haby_core::Color { r: 0, g: 0, b: 0 },
+            kind: haby_core::HabitKind::Habit,
+            recording_type: haby_core::RecordingType::Point,
+            every: Some(1),
+        };
+
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let id = res.into_string().await.unwrap().parse().unwrap();
+
+        habit.name = String::from("Nice!");
+        habit.color.r = 255;
+
+        client
+            .put(v1!(update_habit(id)))
+            .json(&habit)
+            .dispatch()
+            .await;
+
+        let response = client.get(v1!(get_habits)).dispatch().await;
+        let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+
+        assert_eq!(res.len(), 1);
+        let res = res.into_iter().next().unwrap();
+        assert_eq!(res, habit.with_id(id));
+    }
+}
+
+db_test! {
+    async fn habit_fields_are_validated(pool) {
+        use haby_core::validation::{ValidationErrors, NAME_TAKEN};
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
 
-    let id = res.into_string().await.unwrap().parse().unwrap();
+        let invalid = haby_core::api::CreateHabit {
+            name: String::from("   "),
+            every: Some(0),
+            ..Default::default()
+        };
+        let res = client
+            .post(v1!(create_habit))
+            .json(&invalid)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+        let errors: ValidationErrors = res.into_json().await.unwrap();
+        assert!(errors.field("name").is_some());
+        assert!(errors.field("every").is_some());
 
-    let response = client.get(uri!(get_habits)).dispatch().await;
-    let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        let habit = haby_core::api::CreateHabit {
+            name: String::from("  Run "),
+            ..Default::default()
+        };
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
 
-    assert_eq!(res.len(), 1, "Returns only one habit after habit creation");
-    let res = res.into_iter().next().unwrap();
-    assert_eq!(res, habit.with_id(id));
+        let habits: Vec<haby_core::Habit> = client
+            .get(v1!(get_habits))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(habits[0].name, "Run", "The name is stored trimmed");
+
+        let duplicate = haby_core::api::CreateHabit {
+            name: String::from("RUN"),
+            ..Default::default()
+        };
+        let res = client
+            .post(v1!(create_habit))
+            .json(&duplicate)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+        let errors: ValidationErrors = res.into_json().await.unwrap();
+        assert_eq!(errors.field("name"), Some(NAME_TAKEN));
+    }
+}
+
+/// Duplicates from before names were unique are renamed to names that are free and short enough
+#[sqlx::test(migrations = false)]
+async fn habit_names_migration_renames_duplicates(pool: sqlx::PgPool) {
+    use haby_core::validation::MAX_NAME_LENGTH;
+
+    const UNIQUE_NAMES: i64 = 20240827183210;
+
+    let migrations = db::postgres::MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration());
+    let (before, after): (Vec<_>, Vec<_>) =
+        migrations.partition(|migration| migration.version < UNIQUE_NAMES);
+    for migration in before {
+        sqlx::raw_sql(&migration.sql).execute(&pool).await.unwrap();
+    }
+
+    let long = "x".repeat(MAX_NAME_LENGTH);
+    // Appending the id to the third habit would give the name of the second
+    for name in ["Run", "Run (3)", "run", &long, &long.to_uppercase()] {
+        sqlx::query("INSERT INTO habits (name, color, kind, recording_type) VALUES ($1, '0000ff', 'habit', 'point')")
+            .bind(name)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    for migration in after {
+        sqlx::raw_sql(&migration.sql).execute(&pool).await.unwrap();
+    }
+
+    let habits = Db::from(pool).habits().await.unwrap();
+    let names: Vec<_> = habits.iter().map(|habit| habit.name.as_str()).collect();
+    let shortened = format!("{} (2)", &long.to_uppercase()[..MAX_NAME_LENGTH - 4]);
+    assert_eq!(names, ["Run", "Run (3)", "run (2)", &long, &shortened]);
+    for habit in habits {
+        assert!(habit.as_create().validate().is_ok(), "{}", habit.name);
+    }
+}
+
+db_test! {
+    async fn habit_colors_can_be_hex(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let res = client
+            .post(v1!(create_habit))
+            .header(rocket::http::ContentType::JSON)
+            .body(r##"{"name":"Run","color":"#0f0","kind":"Habit","recording_type":"Point","every":null}"##)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Ok);
+        let habits: Vec<haby_core::Habit> = client
+            .get(v1!(get_habits))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(habits[0].color, haby_core::Color::rgb(0, 255, 0));
+
+        let res = client
+            .post(v1!(create_habit))
+            .header(rocket::http::ContentType::JSON)
+            .body(r#"{"name":"Read","color":"green","kind":"Habit","recording_type":"Point","every":null}"#)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+    }
 }
 
+db_test! {
+    async fn habit_update_dupplicates(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let mut habit = haby_core::api::CreateHabit {
+            name: String::from("1"),
+            color: haby_core::Color { r: 0, g: 0, b: 0 },
+            kind: haby_core::HabitKind::Habit,
+            recording_type: haby_core::RecordingType::Point,
+            every: Some(1),
+        };
+
+        client.post(v1!(create_habit)).json(&habit).dispatch().await;
+
+        habit.name = String::from("2");
+
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        habit.name = String::from("1");
+        let res = client
+            .put(v1!(update_habit(id)))
+            .json(&habit)
+            .dispatch()
+            .await
+            .status()
+            .class();
+        assert!(
+            res.is_client_error(),
+            "Expected request to fail, got {:?}",
+            res
+        );
+    }
+}
+
+db_test! {
+    async fn sync_from_start_returns_everything(pool) {
+        let db = Db::from(pool);
+        let client = Client::tracked(rocket_with_pool(db.clone()))
+            .await
+            .unwrap();
+
+        let habit = haby_core::api::CreateHabit::default();
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        db.create_event(&haby_core::api::CreateEvent {
+            habit_id: id,
+            time: "2024-08-03T12:00:00".parse().unwrap(),
+            span_part: None,
+        })
+        .await
+        .unwrap();
+
+        let response = client.get(v1!(sync::get_sync(_))).dispatch().await;
+        let res: haby_core::api::SyncResponse = response.into_json().await.unwrap();
+
+        assert_eq!(res.habits, vec![habit.with_id(id)]);
+        assert_eq!(res.events.len(), 1);
+        assert_eq!(res.events[0].habit_id, id);
+        assert_eq!(res.tombstones, vec![]);
+    }
+}
+
+db_test! {
+    async fn sync_since_cursor_only_returns_newer(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let mut habit = haby_core::api::CreateHabit::default();
+        client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        habit.name = String::from("Other");
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let response = client.get(v1!(sync::get_sync(_))).dispatch().await;
+        let first: haby_core::api::SyncResponse = response.into_json().await.unwrap();
+        assert_eq!(first.habits.len(), 2);
+
+        habit.name = String::from("Renamed");
+        client
+            .put(v1!(update_habit(id)))
+            .json(&habit)
+            .dispatch()
+            .await;
+
+        let response = client
+            .get(v1!(sync::get_sync(Some(first.cursor))))
+            .dispatch()
+            .await;
+        let second: haby_core::api::SyncResponse = response.into_json().await.unwrap();
+
+        assert!(second.cursor > first.cursor);
+        assert_eq!(second.habits, vec![habit.with_id(id)]);
+    }
+}
+
+/// A change written before another one but committed after it must not end up behind the cursor
+/// of a sync that ran in between. The other backends write one transaction at a time anyway.
 #[sqlx::test]
-async fn habit_insert_dupplicates(pool: sqlx::PgPool) {
-    let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
-
-    let habit = haby_core::api::CreateHabit {
-        name: String::from("Test Habit"),
-        color: haby_core::Color { r: 0, g: 0, b: 0 },
-        kind: haby_core::HabitKind::Habit,
-        recording_type: haby_core::RecordingType::Point,
-        every: Some(1),
+async fn sync_cursor_follows_commit_order(pool: sqlx::PgPool) {
+    use std::time::Duration;
+
+    let db = Db::from(pool.clone());
+
+    let mut first = pool.begin().await.unwrap();
+    sqlx::query("INSERT INTO habits (name, color, kind, recording_type) VALUES ('First', '0000ff', 'habit', 'point')")
+        .execute(&mut *first)
+        .await
+        .unwrap();
+
+    let second = rocket::tokio::spawn({
+        let db = db.clone();
+        async move {
+            let habit = haby_core::api::CreateHabit {
+                name: String::from("Second"),
+                ..Default::default()
+            };
+            db.create_habit(&habit).await
+        }
+    });
+    rocket::tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let before = db.changes_since(0).await.unwrap();
+    first.commit().await.unwrap();
+    second.await.unwrap().unwrap();
+    let after = db.changes_since(before.cursor).await.unwrap();
+
+    let mut names: Vec<_> = before
+        .habits
+        .into_iter()
+        .chain(after.habits)
+        .map(|habit| habit.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["First", "Second"]);
+}
+
+db_test! {
+    async fn sync_returns_tombstones(pool) {
+        let db = Db::from(pool);
+        let client = Client::tracked(rocket_with_pool(db.clone()))
+            .await
+            .unwrap();
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let response = client.get(v1!(sync::get_sync(_))).dispatch().await;
+        let first: haby_core::api::SyncResponse = response.into_json().await.unwrap();
+
+        assert!(db.delete_habit(id).await.unwrap());
+
+        let response = client
+            .get(v1!(sync::get_sync(Some(first.cursor))))
+            .dispatch()
+            .await;
+        let second: haby_core::api::SyncResponse = response.into_json().await.unwrap();
+
+        assert_eq!(second.habits, vec![]);
+        assert_eq!(
+            second.tombstones,
+            vec![haby_core::api::Tombstone {
+                entity: haby_core::EntityKind::Habit,
+                id
+            }]
+        );
+    }
+}
+
+db_test! {
+    async fn live_pushes_changes(pool) {
+        use rocket::tokio::io::AsyncReadExt;
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let mut live = client.get(v1!(live::get_live)).dispatch().await;
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let mut message = Vec::new();
+        let read_message = async {
+            let mut chunk = [0; 1024];
+            while !message.ends_with(b"\n\n") {
+                let read = live.read(&mut chunk).await.unwrap();
+                message.extend_from_slice(&chunk[..read]);
+            }
+        };
+        rocket::tokio::time::timeout(std::time::Duration::from_secs(5), read_message)
+            .await
+            .expect("Expected a live update");
+
+        let message = std::str::from_utf8(&message).unwrap();
+        let data = message
+            .lines()
+            .find_map(|line| line.strip_prefix("data:"))
+            .unwrap();
+        let update: haby_core::api::LiveUpdate = rocket::serde::json::from_str(data).unwrap();
+
+        match update {
+            haby_core::api::LiveUpdate::Change(change) => {
+                assert_eq!(change.entity, haby_core::EntityKind::Habit);
+                assert_eq!(change.id, id);
+                assert!(!change.deleted);
+            }
+            haby_core::api::LiveUpdate::Lagged => panic!("Expected a change, got {update:?}"),
+        }
+    }
+}
+
+db_test! {
+    async fn export_json_matches_core_format(pool) {
+        let db = Db::from(pool);
+        let client = Client::tracked(rocket_with_pool(db.clone()))
+            .await
+            .unwrap();
+
+        let habit = haby_core::api::CreateHabit::default();
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        for time in ["2024-08-03T12:00:00", "2024-08-04T12:00:00"] {
+            db.create_event(&haby_core::api::CreateEvent {
+                habit_id: id,
+                time: time.parse().unwrap(),
+                span_part: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let response = client
+            .get(v1!(export::get_export(Some("json"))))
+            .dispatch()
+            .await;
+        assert_eq!(
+            response.content_type(),
+            Some(rocket::http::ContentType::JSON)
+        );
+
+        let export: haby_core::api::Export = response.into_json().await.unwrap();
+        assert_eq!(export.version, haby_core::VERSION);
+        assert_eq!(export.habits, vec![habit.with_id(id)]);
+        assert_eq!(export.events.len(), 2);
+    }
+}
+
+db_test! {
+    async fn export_csv_has_row_per_event(pool) {
+        let db = Db::from(pool);
+        let client = Client::tracked(rocket_with_pool(db.clone()))
+            .await
+            .unwrap();
+
+        let mut habit = haby_core::api::CreateHabit::default();
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        habit.name = String::from("No events, but still, exported");
+        client.post(v1!(create_habit)).json(&habit).dispatch().await;
+
+        for _ in 0..3 {
+            db.create_event(&haby_core::api::CreateEvent {
+                habit_id: id,
+                time: "2024-08-03T12:00:00".parse().unwrap(),
+                span_part: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let response = client
+            .get(v1!(export::get_export(Some("csv"))))
+            .dispatch()
+            .await;
+        assert_eq!(
+            response.content_type(),
+            Some(rocket::http::ContentType::CSV)
+        );
+
+        let body = response.into_string().await.unwrap();
+        let lines: Vec<_> = body.lines().collect();
+        assert!(lines[0].starts_with("habit_id,habit_name,"));
+        assert_eq!(lines.len(), 1 + 3 + 1);
+        assert!(lines[4].contains("\"No events, but still, exported\""));
+    }
+}
+
+db_test! {
+    async fn export_unknown_format(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let response = client
+            .get(v1!(export::get_export(Some("xml"))))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+}
+
+fn import_fixture() -> haby_core::api::Export {
+    let habit = haby_core::api::CreateHabit::default().with_id(7);
+    let event = |id, time: &str| haby_core::Event {
+        id,
+        habit_id: 7,
+        time: time.parse().unwrap(),
+        span_part: None,
     };
 
-    client
-        .post(uri!(create_habit))
-        .json(&habit)
-        .dispatch()
-        .await;
+    haby_core::api::Export {
+        version: String::from(haby_core::VERSION),
+        habits: vec![habit],
+        events: vec![
+            event(1, "2024-08-03T12:00:00"),
+            event(2, "2024-08-04T12:00:00"),
+        ],
+    }
+}
+
+db_test! {
+    async fn import_is_idempotent(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let response = client
+            .post(v1!(import::import_json(_, _)))
+            .json(&import_fixture())
+            .dispatch()
+            .await;
+        let first: haby_core::api::ImportReport = response.into_json().await.unwrap();
+        assert_eq!(first.habits_created, vec![String::from("New Habit")]);
+        assert_eq!(first.events_created, 2);
+
+        let response = client
+            .post(v1!(import::import_json(_, _)))
+            .json(&import_fixture())
+            .dispatch()
+            .await;
+        let second: haby_core::api::ImportReport = response.into_json().await.unwrap();
+        assert_eq!(second.habits_merged, vec![String::from("New Habit")]);
+        assert_eq!(second.events_created, 0);
+        assert_eq!(second.events_skipped, 2);
+
+        let response = client
+            .get(v1!(export::get_export(Some("json"))))
+            .dispatch()
+            .await;
+        let export: haby_core::api::Export = response.into_json().await.unwrap();
+        assert_eq!(export.habits.len(), 1);
+        assert_eq!(export.events.len(), 2);
+    }
+}
+
+db_test! {
+    async fn import_dry_run_changes_nothing(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let response = client
+            .post(v1!(import::import_json(Some(true), _)))
+            .json(&import_fixture())
+            .dispatch()
+            .await;
+        let report: haby_core::api::ImportReport = response.into_json().await.unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.events_created, 2);
+
+        let response = client.get(v1!(get_habits)).dispatch().await;
+        let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        assert_eq!(res, vec![]);
+    }
+}
+
+db_test! {
+    async fn import_conflict_skip(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let habit = haby_core::api::CreateHabit {
+            every: Some(3),
+            ..Default::default()
+        };
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let response = client
+            .post(v1!(import::import_json(_, Some("skip"))))
+            .json(&import_fixture())
+            .dispatch()
+            .await;
+        let report: haby_core::api::ImportReport = response.into_json().await.unwrap();
+        assert_eq!(report.habits_skipped, vec![String::from("New Habit")]);
+        assert_eq!(report.events_skipped, 2);
+
+        let response = client.get(v1!(get_habits)).dispatch().await;
+        let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        assert_eq!(res, vec![habit.with_id(id)]);
+    }
+}
+
+db_test! {
+    async fn import_loop_checkmarks(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let response = client
+            .post(v1!(import::import_loop(_, _)))
+            // Loop ends every line with a comma
+            .body("Date,Water,Reading,\n2024-08-02,2,2,\n2024-08-01,2,0,\n")
+            .dispatch()
+            .await;
+        let report: haby_core::api::ImportReport = response.into_json().await.unwrap();
+        assert_eq!(report.habits_created.len(), 2);
+        assert_eq!(report.events_created, 3);
+    }
+}
+
+db_test! {
+    async fn calendar_needs_token(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let response = client
+            .get(v1!(calendar::get_calendar("not-a-token")))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .post(v1!(calendar::create_calendar_token))
+            .dispatch()
+            .await;
+        let token = response.into_string().await.unwrap();
+
+        let response = client
+            .get(v1!(calendar::get_calendar(&token)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        client
+            .delete(v1!(calendar::delete_calendar_token(&token)))
+            .dispatch()
+            .await;
+        let response = client
+            .get(v1!(calendar::get_calendar(&token)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
+
+db_test! {
+    async fn calendar_has_habit_schedules(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let habit = haby_core::api::CreateHabit {
+            name: String::from("Water, plants"),
+            every: Some(3),
+            ..Default::default()
+        };
+        client.post(v1!(create_habit)).json(&habit).dispatch().await;
+
+        let response = client
+            .post(v1!(calendar::create_calendar_token))
+            .dispatch()
+            .await;
+        let token = response.into_string().await.unwrap();
+
+        let response = client
+            .get(v1!(calendar::get_calendar(&token)))
+            .dispatch()
+            .await;
+        assert_eq!(
+            response.content_type(),
+            Some(rocket::http::ContentType::Calendar)
+        );
+
+        let ics = response.into_string().await.unwrap();
+        assert!(ics.contains("RRULE:FREQ=DAILY;INTERVAL=3\r\n"));
+        assert!(ics.contains("SUMMARY:Water\\, plants\r\n"));
+    }
+}
+
+db_test! {
+    async fn habit_delete(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let res = client.delete(v1!(delete_habit(id))).dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+
+        let response = client.get(v1!(get_habits)).dispatch().await;
+        let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        assert_eq!(res, vec![]);
+
+        let res = client.delete(v1!(delete_habit(id))).dispatch().await;
+        assert_eq!(res.status(), Status::NotFound);
+    }
+}
+
+db_test! {
+    async fn event_create(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let habit_id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let event = haby_core::api::CreateEvent {
+            habit_id,
+            time: "2024-08-03T12:00:00".parse().unwrap(),
+            span_part: None,
+        };
+        let res = client.post(v1!(create_event)).json(&event).dispatch().await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let response = client.get(v1!(sync::get_sync(_))).dispatch().await;
+        let res: haby_core::api::SyncResponse = response.into_json().await.unwrap();
+        assert_eq!(res.events, vec![event.with_id(id)]);
+    }
+}
+
+db_test! {
+    async fn event_span_part_must_match_habit(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let habit_id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let event = haby_core::api::CreateEvent {
+            habit_id,
+            time: "2024-08-03T12:00:00".parse().unwrap(),
+            span_part: Some(haby_core::SpanPart::Start),
+        };
+        let res = client.post(v1!(create_event)).json(&event).dispatch().await;
+        assert!(
+            res.status().class().is_client_error(),
+            "Expected client error, got {:?}",
+            res.status()
+        );
+    }
+}
+
+db_test! {
+    async fn event_batch_is_idempotent(pool) {
+        use haby_core::api::{BatchEvent, BatchItem, CreateEvent};
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let habit_id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let event = |time: &str, span_part, key: &str| BatchEvent {
+            event: CreateEvent {
+                habit_id,
+                time: time.parse().unwrap(),
+                span_part,
+            },
+            idempotency_key: Some(String::from(key)),
+        };
+        let batch = vec![
+            event("2024-08-20T08:00:00", None, "a"),
+            event("2024-08-20T09:00:00", Some(haby_core::SpanPart::Start), "b"),
+            event("2024-08-21T08:00:00", None, "c"),
+        ];
+
+        let res = client
+            .post(v1!(create_events))
+            .json(&batch)
+            .dispatch()
+            .await;
+        let first: Vec<BatchItem> = res.into_json().await.unwrap();
+        assert!(matches!(first[0], BatchItem::Created { .. }));
+        assert!(matches!(first[1], BatchItem::Rejected { .. }));
+        assert!(matches!(first[2], BatchItem::Created { .. }));
+
+        // Retrying the whole batch records nothing new
+        let res = client
+            .post(v1!(create_events))
+            .json(&batch)
+            .dispatch()
+            .await;
+        let retry: Vec<BatchItem> = res.into_json().await.unwrap();
+        match (&first[0], &retry[0]) {
+            (BatchItem::Created { event }, BatchItem::Duplicate { event: duplicate }) => {
+                assert_eq!(event, duplicate)
+            }
+            results => panic!("Expected a duplicate, got {results:?}"),
+        }
+        assert!(matches!(retry[1], BatchItem::Rejected { .. }));
+        assert!(matches!(retry[2], BatchItem::Duplicate { .. }));
+
+        let res = client.get(v1!(sync::get_sync(_))).dispatch().await;
+        let sync: haby_core::api::SyncResponse = res.into_json().await.unwrap();
+        assert_eq!(sync.events.len(), 2);
+    }
+}
+
+db_test! {
+    async fn event_batch_is_limited(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let event = haby_core::api::BatchEvent::from(haby_core::api::CreateEvent {
+            habit_id: 1,
+            time: "2024-08-20T08:00:00".parse().unwrap(),
+            span_part: None,
+        });
+        let batch = vec![event; haby_core::api::MAX_BATCH + 1];
+        let res = client
+            .post(v1!(create_events))
+            .json(&batch)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::PayloadTooLarge);
+    }
+}
+
+db_test! {
+    async fn rate_limit_per_client(pool) {
+        let figment = rocket::Config::figment()
+            .merge(("rate_limit.write.burst", 2))
+            .merge(("rate_limit.write.per_minute", 1));
+        let client = Client::tracked(rocket_with_pool(pool).configure(figment))
+            .await
+            .unwrap();
+
+        let create = |name: &str, ip: &str| {
+            client
+                .post(v1!(create_habit))
+                .remote(format!("{ip}:1234").parse().unwrap())
+                .json(&haby_core::api::CreateHabit {
+                    name: String::from(name),
+                    ..Default::default()
+                })
+        };
+        assert_eq!(
+            create("1", "10.0.0.1").dispatch().await.status(),
+            Status::Ok
+        );
+        assert_eq!(
+            create("2", "10.0.0.1").dispatch().await.status(),
+            Status::Ok
+        );
+
+        let res = create("3", "10.0.0.1").dispatch().await;
+        assert_eq!(res.status(), Status::TooManyRequests);
+        let retry_after: u64 = res
+            .headers()
+            .get_one("Retry-After")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after), "{retry_after}");
 
-    let res = client
-        .post(uri!(create_habit))
-        .json(&habit)
-        .dispatch()
+        // Other clients and reads have their own buckets
+        assert_eq!(
+            create("3", "10.0.0.2").dispatch().await.status(),
+            Status::Ok
+        );
+        let res = client
+            .get(v1!(get_habits))
+            .remote("10.0.0.1:1234".parse().unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Ok);
+    }
+}
+
+db_test! {
+    async fn rate_limit_ignores_spoofed_ip_header(pool) {
+        use rocket::http::Header;
+
+        for trust_proxy in [false, true] {
+            let figment = rocket::Config::figment()
+                .merge(("rate_limit.trust_proxy", trust_proxy))
+                .merge(("rate_limit.write.burst", 2))
+                .merge(("rate_limit.write.per_minute", 1));
+            let client = Client::tracked(rocket_with_pool(pool.clone()).configure(figment))
+                .await
+                .unwrap();
+
+            let mut statuses = Vec::new();
+            for i in 0..3 {
+                let res = client
+                    .post(v1!(create_habit))
+                    .remote("10.0.0.1:1234".parse().unwrap())
+                    .header(Header::new("X-Real-IP", format!("10.0.1.{i}")))
+                    .json(&haby_core::api::CreateHabit {
+                        name: format!("{trust_proxy} {i}"),
+                        ..Default::default()
+                    })
+                    .dispatch()
+                    .await;
+                statuses.push(res.status());
+            }
+            let last = if trust_proxy {
+                Status::Ok
+            } else {
+                Status::TooManyRequests
+            };
+            assert_eq!(statuses, [Status::Ok, Status::Ok, last]);
+        }
+    }
+}
+
+db_test! {
+    async fn rate_limit_counts_tokens_and_ips(pool) {
+        use haby_core::api::{CreateApiToken, NewApiToken};
+        use rocket::http::Header;
+
+        let figment = rocket::Config::figment()
+            .merge(("rate_limit.write.burst", 2))
+            .merge(("rate_limit.write.per_minute", 1));
+        let client = Client::tracked(rocket_with_pool(pool).configure(figment))
+            .await
+            .unwrap();
+
+        let res = client
+            .post(v1!(auth::create_token))
+            .remote("10.0.0.9:1234".parse().unwrap())
+            .json(&CreateApiToken {
+                name: String::from("script"),
+                scopes: vec![haby_core::TokenScope::Admin],
+            })
+            .dispatch()
+            .await;
+        let token: NewApiToken = res.into_json().await.unwrap();
+
+        let create = |token: &str, ip: &str| {
+            client
+                .post(v1!(create_habit))
+                .remote(format!("{ip}:1234").parse().unwrap())
+                .header(Header::new("Authorization", format!("Bearer {token}")))
+                .json(&haby_core::api::CreateHabit {
+                    name: format!("{token} {ip}"),
+                    ..Default::default()
+                })
+        };
+
+        // Made up tokens don't get a bucket of their own
+        for made_up in ["haby_a", "haby_b"] {
+            let res = create(made_up, "10.0.0.1").dispatch().await;
+            assert_eq!(res.status(), Status::Unauthorized);
+        }
+        let res = create("haby_c", "10.0.0.1").dispatch().await;
+        assert_eq!(res.status(), Status::TooManyRequests);
+
+        // A token is limited however many IPs it is used from
+        let res = create(&token.secret, "10.0.0.2").dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        let res = create(&token.secret, "10.0.0.3").dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        let res = create(&token.secret, "10.0.0.4").dispatch().await;
+        assert_eq!(res.status(), Status::TooManyRequests);
+    }
+}
+
+db_test! {
+    async fn body_size_is_limited(pool) {
+        let figment = rocket::Config::figment().merge(("limits.habit", "128 B"));
+        let client = Client::tracked(rocket_with_pool(pool).configure(figment))
+            .await
+            .unwrap();
+
+        let habit = haby_core::api::CreateHabit {
+            name: "x".repeat(200),
+            ..Default::default()
+        };
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        assert_eq!(res.status(), Status::PayloadTooLarge);
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Ok);
+    }
+}
+
+db_test! {
+    async fn idempotency_key_replays_response(pool) {
+        use rocket::http::Header;
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let create = || {
+            client
+                .post(v1!(create_habit))
+                .header(Header::new(haby_core::api::IDEMPOTENCY_KEY, "create-once"))
+                .json(&haby_core::api::CreateHabit::default())
+        };
+        let first = create().dispatch().await;
+        assert_eq!(first.status(), Status::Ok);
+        assert!(first
+            .headers()
+            .get_one(haby_core::api::IDEMPOTENT_REPLAYED)
+            .is_none());
+        let id = first.into_string().await.unwrap();
+
+        let retry = create().dispatch().await;
+        assert_eq!(retry.status(), Status::Ok);
+        assert_eq!(
+            retry.headers().get_one(haby_core::api::IDEMPOTENT_REPLAYED),
+            Some("true")
+        );
+        assert_eq!(retry.into_string().await.unwrap(), id);
+
+        let response = client.get(v1!(get_habits)).dispatch().await;
+        let habits: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        assert_eq!(habits.len(), 1, "The retry did not create another habit");
+    }
+}
+
+db_test! {
+    async fn idempotency_key_replays_errors_with_new_request_id(pool) {
+        use haby_core::api::{IDEMPOTENCY_KEY, REQUEST_ID};
+        use rocket::http::Header;
+
+        let db = Db::from(pool);
+        let client = Client::tracked(rocket_with_pool(db.clone()))
+            .await
+            .unwrap();
+
+        let create = |request_id: &'static str| {
+            client
+                .post(v1!(create_habit))
+                .header(Header::new(IDEMPOTENCY_KEY, "invalid-once"))
+                .header(Header::new(REQUEST_ID, request_id))
+                .json(&haby_core::api::CreateHabit {
+                    name: String::new(),
+                    ..Default::default()
+                })
+        };
+        let first = create("first-try").dispatch().await;
+        assert_eq!(first.status(), Status::UnprocessableEntity);
+        let first: rocket::serde::json::Value = first.into_json().await.unwrap();
+        assert_eq!(first["request_id"], "first-try");
+
+        let stored = db.idempotency_key("invalid-once").await.unwrap().unwrap();
+        let stored: rocket::serde::json::Value =
+            rocket::serde::json::from_slice(&stored.body.unwrap()).unwrap();
+        assert!(stored.get("request_id").is_none(), "{stored}");
+
+        let retry = create("second-try").dispatch().await;
+        assert_eq!(retry.status(), Status::UnprocessableEntity);
+        assert_eq!(
+            retry.headers().get_one(haby_core::api::IDEMPOTENT_REPLAYED),
+            Some("true")
+        );
+        assert_eq!(retry.headers().get_one(REQUEST_ID), Some("second-try"));
+        let retry: rocket::serde::json::Value = retry.into_json().await.unwrap();
+        assert_eq!(retry["request_id"], "second-try");
+        assert_eq!(retry["errors"], first["errors"]);
+    }
+}
+
+db_test! {
+    async fn idempotency_key_is_tied_to_request(pool) {
+        use rocket::http::Header;
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        client
+            .post(v1!(create_habit))
+            .header(Header::new(haby_core::api::IDEMPOTENCY_KEY, "reused"))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let res = client
+            .post(v1!(create_event))
+            .header(Header::new(haby_core::api::IDEMPOTENCY_KEY, "reused"))
+            .json(&haby_core::api::CreateEvent {
+                habit_id: 1,
+                time: "2024-08-20T08:00:00".parse().unwrap(),
+                span_part: None,
+            })
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+
+        // The same route with another body is a different request as well
+        let res = client
+            .post(v1!(create_habit))
+            .header(Header::new(haby_core::api::IDEMPOTENCY_KEY, "reused"))
+            .json(&haby_core::api::CreateHabit {
+                name: String::from("Another habit"),
+                ..Default::default()
+            })
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+        let response = client.get(v1!(get_habits)).dispatch().await;
+        let habits: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        assert_eq!(habits.len(), 1, "The other body was neither replayed nor created");
+
+        let res = client
+            .post(v1!(create_habit))
+            .header(Header::new(haby_core::api::IDEMPOTENCY_KEY, ""))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+}
+
+db_test! {
+    async fn idempotency_key_is_taken_over_after_lease(pool) {
+        let db = Db::from(pool);
+
+        let first = db.claim_idempotency_key("key", "POST /habits", 24, 5).await.unwrap();
+        assert!(first.is_some());
+        assert!(db.claim_idempotency_key("key", "POST /habits", 24, 5).await.unwrap().is_none());
+
+        // Like the first request died without a response, its lease is over right away
+        let second = db.claim_idempotency_key("key", "POST /habits", 24, 0).await.unwrap();
+        let (Some(first), Some(second)) = (first, second) else {
+            panic!("The claim was not taken over");
+        };
+        assert_ne!(first, second);
+
+        // The first request finishing late can't touch the claim that took over
+        db.store_idempotent_response("key", &first, 500, None, b"late", None)
+            .await
+            .unwrap();
+        db.release_idempotency_key("key", &first).await.unwrap();
+        let existing = db.idempotency_key("key").await.unwrap().unwrap();
+        assert_eq!(existing.status, None);
+
+        // Answered claims are kept until they expire
+        db.store_idempotent_response("key", &second, 200, None, b"1", None)
+            .await
+            .unwrap();
+        let existing = db.idempotency_key("key").await.unwrap().unwrap();
+        assert_eq!(existing.body.as_deref(), Some(&b"1"[..]));
+        assert!(db.claim_idempotency_key("key", "POST /habits", 24, 0).await.unwrap().is_none());
+    }
+}
+
+db_test! {
+    async fn reminder_settings_roundtrip(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let response = client
+            .get(v1!(reminders::get_reminder_settings(id)))
+            .dispatch()
+            .await;
+        let settings: haby_core::api::ReminderSettings = response.into_json().await.unwrap();
+        assert_eq!(settings, haby_core::api::ReminderSettings::default());
+
+        let settings = haby_core::api::ReminderSettings {
+            quiet_hours: Some("22:00-07:00".parse().unwrap()),
+            snoozed_until: None,
+        };
+        client
+            .put(v1!(reminders::set_reminder_settings(id)))
+            .json(&settings)
+            .dispatch()
+            .await;
+        let response = client
+            .get(v1!(reminders::get_reminder_settings(id)))
+            .dispatch()
+            .await;
+        assert_eq!(
+            response
+                .into_json::<haby_core::api::ReminderSettings>()
+                .await,
+            Some(settings)
+        );
+    }
+}
+
+db_test! {
+    async fn reminders_sent_once_a_day(pool) {
+        let db = Db::from(pool);
+        let client = Client::tracked(rocket_with_pool(db.clone()))
+            .await
+            .unwrap();
+
+        let habit = haby_core::api::CreateHabit {
+            every: Some(1),
+            ..Default::default()
+        };
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+        client
+            .post(v1!(create_event))
+            .json(&haby_core::api::CreateEvent {
+                habit_id: id,
+                time: "2024-08-10T09:00:00".parse().unwrap(),
+                span_part: None,
+            })
+            .dispatch()
+            .await;
+
+        // Snoozes are relative to the real clock, so the checks are relative to the snooze
+        let response = client
+            .post(v1!(reminders::snooze(id, Some(30))))
+            .dispatch()
+            .await;
+        let until: sqlx::types::chrono::NaiveDateTime = response.into_json().await.unwrap();
+        let minute = std::time::Duration::from_secs(60);
+
+        let sent = reminders::send_due(&db, &[], until - minute)
+            .await
+            .unwrap();
+        assert_eq!(sent, vec![]);
+
+        let sent = reminders::send_due(&db, &[], until + minute)
+            .await
+            .unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].habit_id, id);
+        let sent = reminders::send_due(&db, &[], until + minute * 2)
+            .await
+            .unwrap();
+        assert_eq!(sent, vec![]);
+    }
+}
+
+/// Speaks just enough SMTP to accept one message, which the handle resolves to
+async fn mock_smtp() -> (u16, rocket::tokio::task::JoinHandle<String>) {
+    use rocket::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let listener = rocket::tokio::net::TcpListener::bind("127.0.0.1:0")
         .await
-        .status()
-        .class();
-    assert!(
-        res.is_client_error(),
-        "Expected client error, got {:?}",
-        res
-    );
+        .unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = rocket::tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 mock ESMTP\r\n").await.unwrap();
+
+        let mut message = String::new();
+        let mut in_data = false;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if in_data {
+                if line == "." {
+                    in_data = false;
+                    write.write_all(b"250 Queued\r\n").await.unwrap();
+                } else {
+                    message.push_str(&line);
+                    message.push('\n');
+                }
+                continue;
+            }
+
+            let command = line.get(..4).unwrap_or(&line).to_ascii_uppercase();
+            let reply: &[u8] = match command.as_str() {
+                "DATA" => {
+                    in_data = true;
+                    b"354 Go ahead\r\n"
+                }
+                "QUIT" => {
+                    write.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                }
+                _ => b"250 OK\r\n",
+            };
+            write.write_all(reply).await.unwrap();
+        }
+        message
+    });
+    (port, handle)
 }
 
-#[sqlx::test]
-async fn habit_update(pool: sqlx::PgPool) {
-    let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
-
-    let mut habit = haby_core::api::CreateHabit {
-        name: String::from("Test Habit"),
-        color: haby_core::Color { r: 0, g: 0, b: 0 },
-        kind: haby_core::HabitKind::Habit,
-        recording_type: haby_core::RecordingType::Point,
-        every: Some(1),
+#[rocket::async_test]
+async fn email_reminder_reaches_smtp() {
+    use reminders::Sink;
+
+    let (port, message) = mock_smtp().await;
+    let email = reminders::Email::new(&reminders::EmailConfig {
+        host: String::from("127.0.0.1"),
+        port: Some(port),
+        tls: false,
+        username: None,
+        password: None,
+        from: String::from("haby@example.com"),
+        to: String::from("me@example.com"),
+    })
+    .unwrap();
+
+    let reminder = haby_core::reminders::Reminder {
+        habit_id: 1,
+        habit: String::from("Read"),
+        due: "2024-08-10".parse().unwrap(),
+        overdue_days: 2,
     };
+    email.send(&reminder).await.unwrap();
+
+    let message = message.await.unwrap();
+    assert!(message.contains("Subject: Time for Read"), "{message}");
+    assert!(message.contains("Overdue by 2 days"), "{message}");
+    assert!(message.contains("To: me@example.com"), "{message}");
+}
+
+/// The example from RFC 8291 section 5
+#[test]
+fn web_push_encrypts_like_rfc() {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    let decode = |value: &str| URL_SAFE_NO_PAD.decode(value).unwrap();
+    let secret =
+        p256::SecretKey::from_slice(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw"))
+            .unwrap();
+    let p256dh = decode(
+        "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4",
+    );
+    let auth = decode("BTBZMqHH6r4Tts7J_aSIgg");
+    let salt = decode("DGv6ra1nlYgDCS1FRnbzlw").try_into().unwrap();
+
+    let body = web_push::encrypt(
+        &p256dh,
+        &auth,
+        b"When I grow up, I want to be a watermelon",
+        salt,
+        &secret,
+    )
+    .unwrap();
+    assert_eq!(
+        URL_SAFE_NO_PAD.encode(body),
+        "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+    );
+}
+
+struct ReceivedHook {
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl ReceivedHook {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A plain HTTP server answering each request with the next of `statuses`, and passing the
+/// request on to the receiver
+async fn mock_webhook(
+    statuses: Vec<u16>,
+) -> (
+    String,
+    rocket::tokio::sync::mpsc::UnboundedReceiver<ReceivedHook>,
+) {
+    use rocket::tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let listener = rocket::tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = rocket::tokio::sync::mpsc::unbounded_channel();
+    rocket::tokio::spawn(async move {
+        for status in statuses {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+
+            let mut headers = Vec::new();
+            let mut line = String::new();
+            socket.read_line(&mut line).await.unwrap();
+            loop {
+                line.clear();
+                socket.read_line(&mut line).await.unwrap();
+                let Some((key, value)) = line.trim_end().split_once(": ") else {
+                    break;
+                };
+                headers.push((key.to_owned(), value.to_owned()));
+            }
+            let hook = ReceivedHook {
+                headers,
+                body: String::new(),
+            };
+            let length: usize = hook.header("content-length").unwrap().parse().unwrap();
+            let mut body = vec![0; length];
+            socket.read_exact(&mut body).await.unwrap();
+
+            socket
+                .get_mut()
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {status} Mock\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let _ = sender.send(ReceivedHook {
+                body: String::from_utf8(body).unwrap(),
+                ..hook
+            });
+        }
+    });
+    (url, receiver)
+}
+
+db_test! {
+    async fn webhook_signed_and_retried(pool) {
+        let figment = rocket::Config::figment()
+            .merge(("webhooks.backoff_ms", 10))
+            .merge(("webhooks.poll_ms", 10));
+        let client = Client::tracked(rocket_with_pool(pool).configure(figment))
+            .await
+            .unwrap();
+        let (url, mut received) = mock_webhook(vec![500, 200]).await;
+
+        let res = client
+            .post(v1!(webhooks::create_webhook))
+            .json(&haby_core::api::CreateWebhook {
+                url,
+                events: vec![WebhookEvent::HabitCreated],
+                secret: Some(String::from("hunter2")),
+            })
+            .dispatch()
+            .await;
+        let webhook: haby_core::api::NewWebhook = res.into_json().await.unwrap();
+
+        client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+
+        for _ in 0..2 {
+            let hook = received.recv().await.unwrap();
+            assert_eq!(hook.header("x-haby-event"), Some("habit_created"));
+            assert_eq!(
+                hook.header("x-haby-signature"),
+                Some(webhooks::sign("hunter2", &hook.body).as_str())
+            );
+            let payload: rocket::serde::json::Value =
+                rocket::serde::json::from_str(&hook.body).unwrap();
+            assert_eq!(payload["data"]["name"], "New Habit");
+        }
+
+        // The delivery log is written after the response went out
+        let mut delivery = None;
+        for _ in 0..50 {
+            let response = client
+                .get(v1!(webhooks::get_deliveries(webhook.webhook.id)))
+                .dispatch()
+                .await;
+            let deliveries: Vec<haby_core::api::WebhookDelivery> = response.into_json().await.unwrap();
+            if deliveries
+                .first()
+                .is_some_and(|delivery| delivery.succeeded)
+            {
+                delivery = deliveries.into_iter().next();
+                break;
+            }
+            rocket::tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let delivery = delivery.expect("The delivery succeeded");
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.status_code, Some(200));
+    }
+}
+
+db_test! {
+    async fn webhook_test_fire(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let (url, mut received) = mock_webhook(vec![404]).await;
+
+        let res = client
+            .post(v1!(webhooks::create_webhook))
+            .json(&haby_core::api::CreateWebhook {
+                url,
+                events: vec![],
+                secret: None,
+            })
+            .dispatch()
+            .await;
+        let webhook: haby_core::api::NewWebhook = res.into_json().await.unwrap();
+        assert_eq!(webhook.secret.len(), 32);
 
-    let res = client
-        .post(uri!(create_habit))
-        .json(&habit)
-        .dispatch()
+        let res = client
+            .post(v1!(webhooks::test_webhook(webhook.webhook.id)))
+            .dispatch()
+            .await;
+        let delivery: haby_core::api::WebhookDelivery = res.into_json().await.unwrap();
+        assert_eq!(delivery.event, WebhookEvent::Ping);
+        assert_eq!(delivery.status_code, Some(404));
+        assert!(!delivery.succeeded);
+
+        let hook = received.recv().await.unwrap();
+        assert_eq!(hook.header("x-haby-event"), Some("ping"));
+        assert_eq!(
+            hook.header("x-haby-signature"),
+            Some(webhooks::sign(&webhook.secret, &hook.body).as_str())
+        );
+    }
+}
+
+db_test! {
+    async fn webhook_deliveries_resume_on_start(pool) {
+        let db = Db::from(pool);
+        let (url, mut received) = mock_webhook(vec![200]).await;
+
+        // Left over by a server that stopped before sending it
+        let webhook = db
+            .create_webhook(&url, &[WebhookEvent::HabitCreated], "hunter2")
+            .await
+            .unwrap();
+        let delivery = db
+            .queue_delivery(webhook, WebhookEvent::HabitCreated, "{}", true)
+            .await
+            .unwrap();
+
+        let figment = rocket::Config::figment().merge(("webhooks.poll_ms", 10));
+        let client = Client::tracked(rocket_with_pool(db.clone()).configure(figment))
+            .await
+            .unwrap();
+
+        let hook = received.recv().await.unwrap();
+        assert_eq!(hook.header("x-haby-delivery"), Some(delivery.to_string().as_str()));
+        assert_eq!(hook.body, "{}");
+
+        let mut succeeded = false;
+        for _ in 0..50 {
+            let response = client
+                .get(v1!(webhooks::get_deliveries(webhook)))
+                .dispatch()
+                .await;
+            let deliveries: Vec<haby_core::api::WebhookDelivery> =
+                response.into_json().await.unwrap();
+            if deliveries[0].succeeded {
+                assert_eq!(deliveries[0].attempts, 1);
+                succeeded = true;
+                break;
+            }
+            rocket::tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(succeeded);
+        assert!(db.due_deliveries(10, 1).await.unwrap().is_empty());
+    }
+}
+
+db_test! {
+    async fn imports_do_not_notify_webhooks(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let (url, mut received) = mock_webhook(vec![200]).await;
+
+        let res = client
+            .post(v1!(webhooks::create_webhook))
+            .json(&haby_core::api::CreateWebhook {
+                url,
+                events: vec![WebhookEvent::HabitCreated, WebhookEvent::EventRecorded],
+                secret: None,
+            })
+            .dispatch()
+            .await;
+        let webhook: haby_core::api::NewWebhook = res.into_json().await.unwrap();
+
+        let response = client
+            .post(v1!(import::import_json(_, _)))
+            .json(&import_fixture())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // The mock answers once, so the first hook it sees is the only one
+        client
+            .post(v1!(webhooks::test_webhook(webhook.webhook.id)))
+            .dispatch()
+            .await;
+        let hook = received.recv().await.unwrap();
+        assert_eq!(hook.header("x-haby-event"), Some("ping"));
+
+        let response = client
+            .get(v1!(webhooks::get_deliveries(webhook.webhook.id)))
+            .dispatch()
+            .await;
+        let deliveries: Vec<haby_core::api::WebhookDelivery> = response.into_json().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+    }
+}
+
+db_test! {
+    async fn api_tokens_are_scoped(pool) {
+        use rocket::http::Header;
+
+        // Tokens can be created freely until they are required
+        let open = Client::tracked(rocket_with_pool(pool.clone()))
+            .await
+            .unwrap();
+        let create = |name: &str, scopes: Vec<haby_core::TokenScope>| {
+            open.post(v1!(auth::create_token))
+                .json(&haby_core::api::CreateApiToken {
+                    name: String::from(name),
+                    scopes,
+                })
+                .dispatch()
+        };
+        let reader: haby_core::api::NewApiToken =
+            create("reader", vec![haby_core::TokenScope::ReadHabits])
+                .await
+                .into_json()
+                .await
+                .unwrap();
+        let admin: haby_core::api::NewApiToken = create("admin", vec![haby_core::TokenScope::Admin])
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(reader.secret.starts_with(&reader.token.prefix));
+
+        let figment = rocket::Config::figment().merge(("auth.required", true));
+        let client = Client::tracked(rocket_with_pool(pool).configure(figment))
+            .await
+            .unwrap();
+        let bearer = |token: &haby_core::api::NewApiToken| {
+            Header::new("Authorization", format!("Bearer {}", token.secret))
+        };
+
+        let res = client.get(v1!(get_habits)).dispatch().await;
+        assert_eq!(res.status(), Status::Unauthorized);
+        let res = client
+            .get(v1!(get_habits))
+            .header(bearer(&reader))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .post(v1!(create_habit))
+            .header(bearer(&reader))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Forbidden);
+
+        let res = client
+            .get(v1!(auth::get_tokens))
+            .header(bearer(&admin))
+            .dispatch()
+            .await;
+        let tokens: Vec<haby_core::api::ApiToken> = res.into_json().await.unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens.iter().all(|token| token.last_used_at.is_some()));
+
+        client
+            .delete(v1!(auth::revoke_token(reader.token.id)))
+            .header(bearer(&admin))
+            .dispatch()
+            .await;
+        let res = client
+            .get(v1!(get_habits))
+            .header(bearer(&reader))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Unauthorized);
+    }
+}
+
+/// Send a GraphQL request and return the JSON response
+async fn graphql(
+    client: &Client,
+    token: Option<&str>,
+    query: &str,
+    variables: rocket::serde::json::Value,
+) -> rocket::serde::json::Value {
+    let mut request = client
+        .post(v1!(graphql::post_graphql))
+        .json(&rocket::serde::json::json!({ "query": query, "variables": variables }));
+    if let Some(token) = token {
+        request = request.header(rocket::http::Header::new(
+            "Authorization",
+            format!("Bearer {token}"),
+        ));
+    }
+    let response = request.dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    response.into_json().await.unwrap()
+}
+
+db_test! {
+    async fn graphql_habits_with_events_and_stats(pool) {
+        use rocket::serde::json::json;
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let created = graphql(
+            &client,
+            None,
+            "mutation($habit: HabitInput!) { createHabit(habit: $habit) { id color } }",
+            json!({ "habit": {
+                "name": "Fast",
+                "color": "FF8800",
+                "kind": "HABIT",
+                "recordingType": "SPAN",
+                "every": 1
+            } }),
+        )
         .await;
-    let id = res.into_string().await.unwrap().parse().unwrap();
+        assert_eq!(created["data"]["createHabit"]["color"], "FF8800");
+        let id = created["data"]["createHabit"]["id"].clone();
 
-    habit.name = String::from("Nice!");
-    habit.color.r = 255;
+        // A span habit is started by the first check-in and stopped by the next
+        let check_in = "mutation($id: Int!, $time: NaiveDateTime) { \
+            checkIn(habitId: $id, time: $time) { spanPart } }";
+        let start = graphql(
+            &client,
+            None,
+            check_in,
+            json!({ "id": id, "time": "2024-08-20T08:00:00" }),
+        )
+        .await;
+        assert_eq!(start["data"]["checkIn"]["spanPart"], "START");
+        let end = graphql(
+            &client,
+            None,
+            check_in,
+            json!({ "id": id, "time": "2024-08-20T20:00:00" }),
+        )
+        .await;
+        assert_eq!(end["data"]["checkIn"]["spanPart"], "END");
 
-    client
-        .put(uri!(update_habit(id)))
-        .json(&habit)
-        .dispatch()
+        let habits = graphql(
+            &client,
+            None,
+            "{ habits { name events(limit: 1) { spanPart } stats { longestStreak runningSince } } }",
+            json!({}),
+        )
         .await;
+        assert_eq!(
+            habits["data"]["habits"],
+            json!([{
+                "name": "Fast",
+                "events": [{ "spanPart": "END" }],
+                "stats": { "longestStreak": 1, "runningSince": null }
+            }])
+        );
 
-    let response = client.get(uri!(get_habits)).dispatch().await;
-    let res: Vec<haby_core::Habit> = response.into_json().await.unwrap();
+        // The same checks as REST, an unknown habit can't be checked in
+        let missing = graphql(&client, None, check_in, json!({ "id": 0 })).await;
+        assert_eq!(missing["errors"][0]["extensions"]["status"], 404);
 
-    assert_eq!(res.len(), 1);
-    let res = res.into_iter().next().unwrap();
-    assert_eq!(res, habit.with_id(id));
+        // REST sees what GraphQL wrote
+        let res = client.get(v1!(get_habits)).dispatch().await;
+        let habits: Vec<haby_core::Habit> = res.into_json().await.unwrap();
+        assert_eq!(habits[0].name, "Fast");
+    }
 }
 
-#[sqlx::test]
-async fn habit_update_dupplicates(pool: sqlx::PgPool) {
-    let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
-
-    let mut habit = haby_core::api::CreateHabit {
-        name: String::from("1"),
-        color: haby_core::Color { r: 0, g: 0, b: 0 },
-        kind: haby_core::HabitKind::Habit,
-        recording_type: haby_core::RecordingType::Point,
-        every: Some(1),
-    };
+db_test! {
+    async fn graphql_checks_scopes(pool) {
+        use rocket::serde::json::json;
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let reader: haby_core::api::NewApiToken = client
+            .post(v1!(auth::create_token))
+            .json(&haby_core::api::CreateApiToken {
+                name: String::from("reader"),
+                scopes: vec![haby_core::TokenScope::ReadHabits],
+            })
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
 
-    client
-        .post(uri!(create_habit))
-        .json(&habit)
-        .dispatch()
+        let habits = graphql(
+            &client,
+            Some(&reader.secret),
+            "{ habits { id } }",
+            json!({}),
+        )
         .await;
+        assert_eq!(habits["data"]["habits"], json!([]));
+
+        let delete = graphql(
+            &client,
+            Some(&reader.secret),
+            "mutation { deleteHabit(id: 1) }",
+            json!({}),
+        )
+        .await;
+        assert_eq!(delete["errors"][0]["extensions"]["status"], 403);
+    }
+}
 
-    habit.name = String::from("2");
+db_test! {
+    async fn graphql_limits_queries(pool) {
+        use rocket::serde::json::json;
 
-    let res = client
-        .post(uri!(create_habit))
-        .json(&habit)
-        .dispatch()
+        let db = Db::from(pool);
+        let client = Client::tracked(rocket_with_pool(db.clone()))
+            .await
+            .unwrap();
+
+        let mut ids = vec![];
+        for name in ["Read", "Run"] {
+            let id = db
+                .create_habit(&haby_core::api::CreateHabit {
+                    name: String::from(name),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            for day in 1..=3 {
+                db.create_event(&haby_core::api::CreateEvent {
+                    habit_id: id,
+                    time: format!("2024-08-0{day}T12:00:00").parse().unwrap(),
+                    span_part: None,
+                })
+                .await
+                .unwrap();
+            }
+            ids.push(id);
+        }
+
+        // Stats only load the latest events of the habits they are for
+        let latest = db.latest_events(&ids[..1], 2).await.unwrap();
+        assert!(latest.iter().all(|event| event.habit_id == ids[0]));
+        let latest = db.latest_events(&ids, 2).await.unwrap();
+        assert_eq!(latest.len(), 4);
+        for id in ids {
+            let days: Vec<_> = latest
+                .iter()
+                .filter(|event| event.habit_id == id)
+                .map(|event| event.time.to_string())
+                .collect();
+            assert_eq!(days, ["2024-08-03 12:00:00", "2024-08-02 12:00:00"]);
+        }
+
+        for limit in [0, -1, 501] {
+            let events = graphql(
+                &client,
+                None,
+                "query($limit: Int!) { habits { events(limit: $limit) { id } } }",
+                json!({ "limit": limit }),
+            )
+            .await;
+            assert_eq!(events["errors"][0]["extensions"]["status"], 400);
+        }
+
+        let complex = graphql(
+            &client,
+            None,
+            "{ a: habits { events(limit: 500) { id time } } \
+                b: habits { events(limit: 500) { id time } } }",
+            json!({}),
+        )
         .await;
-    let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+        assert_eq!(complex["data"], json!(null));
+        assert!(complex["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("too complex"));
+
+        // GraphiQL still gets its introspection query through
+        let introspection = graphql(
+            &client,
+            None,
+            "{ __schema { types { name fields { name type { ...TypeRef } } } } } \
+            fragment TypeRef on __Type { kind name ofType { kind name ofType { kind name \
+                ofType { kind name ofType { kind name ofType { kind name ofType { kind name \
+                ofType { kind name } } } } } } } }",
+            json!({}),
+        )
+        .await;
+        assert_eq!(introspection["errors"], json!(null));
+    }
+}
+
+db_test! {
+    async fn graphql_subscription_streams_events(pool) {
+        use rocket::serde::json::json;
+        use rocket::tokio::io::AsyncReadExt;
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let res = client
+            .post(v1!(create_habit))
+            .json(&haby_core::api::CreateHabit::default())
+            .dispatch()
+            .await;
+        let id: i32 = res.into_string().await.unwrap().parse().unwrap();
+
+        let mut stream = client
+            .post(v1!(graphql::post_graphql_stream))
+            .json(&json!({ "query": "subscription { events { habitId spanPart } }" }))
+            .dispatch()
+            .await;
+
+        let mut message = Vec::new();
+        let read_message = async {
+            let mut chunk = [0; 1024];
+            while !message.ends_with(b"\n\n") {
+                let read = stream.read(&mut chunk).await.unwrap();
+                message.extend_from_slice(&chunk[..read]);
+            }
+        };
+        // The subscription only starts listening once its body is read
+        let record = async {
+            rocket::tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            client
+                .post(v1!(create_event))
+                .json(&haby_core::api::CreateEvent {
+                    habit_id: id,
+                    time: "2024-08-20T08:00:00".parse().unwrap(),
+                    span_part: None,
+                })
+                .dispatch()
+                .await;
+        };
+        let (read, _) = rocket::tokio::join!(
+            rocket::tokio::time::timeout(std::time::Duration::from_secs(5), read_message),
+            record
+        );
+        read.expect("Expected a subscription response");
+
+        let message = std::str::from_utf8(&message).unwrap();
+        let data = message
+            .lines()
+            .find_map(|line| line.strip_prefix("data:"))
+            .unwrap();
+        let response: rocket::serde::json::Value = rocket::serde::json::from_str(data).unwrap();
+        assert_eq!(
+            response["data"]["events"],
+            json!({ "habitId": id, "spanPart": null })
+        );
+    }
+}
+
+/// The committed `openapi.json` is what clients are generated from, so it has to follow the routes
+///
+/// Run the tests with `UPDATE_OPENAPI=1` to rewrite it after changing the API.
+#[test]
+fn openapi_matches_snapshot() {
+    use utoipa::OpenApi;
+
+    let spec = openapi::ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+    if std::env::var_os("UPDATE_OPENAPI").is_some() {
+        std::fs::write(path, &spec).unwrap();
+    }
+
+    let snapshot = std::fs::read_to_string(path).unwrap_or_default();
+    assert!(
+        snapshot == spec,
+        "openapi.json is out of date, run the tests with UPDATE_OPENAPI=1 and commit the result"
+    );
+}
+
+#[test]
+fn openapi_covers_every_route() {
+    use utoipa::OpenApi;
+
+    let spec = openapi::ApiDoc::openapi();
+    let rocket = rocket_no_db();
+    let missing: Vec<_> = rocket
+        .routes()
+        .filter(|route| {
+            !route.uri.path().starts_with("/docs")
+                && !route.uri.path().starts_with("/__")
+                && route.uri.path() != "/openapi.json"
+        })
+        .filter(|route| {
+            let path = route.uri.path().replace('<', "{").replace('>', "}");
+            let method = route.method.as_str().to_lowercase();
+            let Some(item) = spec.paths.paths.get(&path) else {
+                return true;
+            };
+            let documented = rocket::serde::json::to_value(item).unwrap();
+            documented.get(&method).is_none()
+        })
+        .map(|route| format!("{} {}", route.method, route.uri))
+        .collect();
 
-    habit.name = String::from("1");
-    let res = client
-        .put(uri!(update_habit(id)))
-        .json(&habit)
-        .dispatch()
-        .await
-        .status()
-        .class();
     assert!(
-        res.is_client_error(),
-        "Expected request to fail, got {:?}",
-        res
+        missing.is_empty(),
+        "Routes missing from the OpenAPI spec: {missing:?}"
     );
 }
+
+db_test! {
+    async fn openapi_is_served(pool) {
+        use utoipa::OpenApi;
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+        let response = client.get("/openapi.json").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let served: rocket::serde::json::Value = response.into_json().await.unwrap();
+        assert_eq!(
+            served,
+            rocket::serde::json::to_value(openapi::ApiDoc::openapi()).unwrap()
+        );
+
+        let response = client.get("/docs/").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
+
+db_test! {
+    database async fn health_checks_database(pool) {
+        use haby_core::api::{Health, HealthStatus};
+
+        let client = Client::tracked(rocket_with_pool(pool.clone()))
+            .await
+            .unwrap();
+
+        let res = client.get("/health/live").dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        let health: Health = res.into_json().await.unwrap();
+        assert_eq!(health.status, HealthStatus::Up);
+
+        let res = client.get("/health/ready").dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        let health: Health = res.into_json().await.unwrap();
+        assert_eq!(health.status, HealthStatus::Up);
+        assert_eq!(health.checks["database"].status, HealthStatus::Up);
+        assert_eq!(health.checks["migrations"].status, HealthStatus::Up);
+
+        sqlx::query(
+            "DELETE FROM _sqlx_migrations WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let res = client.get("/health/ready").dispatch().await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        let health: Health = res.into_json().await.unwrap();
+        assert_eq!(health.checks["database"].status, HealthStatus::Up);
+        assert_eq!(health.checks["migrations"].status, HealthStatus::Down);
+        assert!(health.checks["migrations"].error.is_some());
+
+        pool.close().await;
+        let res = client.get("/health/ready").dispatch().await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        let health: Health = res.into_json().await.unwrap();
+        assert_eq!(health.checks["database"].status, HealthStatus::Down);
+    }
+}
+
+db_test! {
+    async fn metrics_count_requests_and_habits(pool) {
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let habit = haby_core::api::CreateHabit {
+            kind: haby_core::HabitKind::Addiction,
+            ..Default::default()
+        };
+        let res = client.post(v1!(create_habit)).json(&habit).dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        client.get(v1!(get_habits)).dispatch().await;
+        client.get(v1!(get_habits)).dispatch().await;
+
+        let res = client.get("/metrics").dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.content_type().unwrap().sub(), "plain");
+        let metrics = res.into_string().await.unwrap();
+        let lines: Vec<_> = metrics.lines().collect();
+        for line in [
+            r#"haby_http_requests_total{method="GET",route="/api/v1/habits",status="200"} 2"#,
+            r#"haby_http_requests_total{method="POST",route="/api/v1/habits",status="200"} 1"#,
+            r#"haby_http_request_duration_seconds_count{method="GET",route="/api/v1/habits"} 2"#,
+            r#"haby_http_request_duration_seconds_bucket{method="GET",route="/api/v1/habits",le="+Inf"} 2"#,
+            r#"haby_habits{kind="habit"} 0"#,
+            r#"haby_habits{kind="addiction"} 1"#,
+            r#"haby_events{kind="addiction"} 0"#,
+            "# TYPE haby_db_pool_connections gauge",
+        ] {
+            assert!(lines.contains(&line), "{line} is missing from\n{metrics}");
+        }
+    }
+}
+
+db_test! {
+    async fn request_ids_are_sent_back(pool) {
+        use haby_core::api::REQUEST_ID;
+        use rocket::http::Header;
+
+        let client = Client::tracked(rocket_with_pool(pool)).await.unwrap();
+
+        let res = client.get(v1!(get_habits)).dispatch().await;
+        let id = res.headers().get_one(REQUEST_ID).unwrap();
+        assert_eq!(
+            id.len(),
+            32,
+            "A new id is made up when the client sends none"
+        );
+
+        let invalid = haby_core::api::CreateHabit {
+            name: String::new(),
+            ..Default::default()
+        };
+        let res = client
+            .post(v1!(create_habit))
+            .header(Header::new(REQUEST_ID, "bug-report-42"))
+            .json(&invalid)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+        assert_eq!(res.headers().get_one(REQUEST_ID), Some("bug-report-42"));
+        let body: rocket::serde::json::Value = res.into_json().await.unwrap();
+        assert_eq!(body["request_id"], "bug-report-42");
+        assert!(body["errors"].is_array());
+
+        let res = client
+            .get(v1!(get_habits))
+            .header(Header::new(REQUEST_ID, "not an id"))
+            .dispatch()
+            .await;
+        assert_ne!(res.headers().get_one(REQUEST_ID), Some("not an id"));
+
+        // Plain messages are sent as JSON with the id as well
+        let res = client
+            .delete(v1!(webhooks::delete_webhook(9999)))
+            .header(Header::new(REQUEST_ID, "bug-report-43"))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(res.content_type(), Some(rocket::http::ContentType::JSON));
+        let body: haby_core::api::ErrorBody = res.into_json().await.unwrap();
+        assert_eq!(
+            body,
+            haby_core::api::ErrorBody {
+                message: String::from("No webhook with id 9999"),
+                request_id: String::from("bug-report-43"),
+            }
+        );
+
+        // Unknown routes too, instead of Rockets HTML page
+        let res = client.get("/api/v1/nothing/here").dispatch().await;
+        assert_eq!(res.status(), Status::NotFound);
+        let id = res.headers().get_one(REQUEST_ID).unwrap().to_owned();
+        let body: haby_core::api::ErrorBody = res.into_json().await.unwrap();
+        assert_eq!(body.message, "Not Found");
+        assert_eq!(body.request_id, id);
+    }
+}
+
+db_test! {
+    async fn database_outage_is_503(pool) {
+        use rocket::http::Header;
+
+        let client = Client::tracked(rocket_with_pool(pool.clone()))
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let res = client.get(v1!(get_habits)).dispatch().await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        assert!(res.headers().get_one("Retry-After").is_some());
+
+        let res = client.post(v1!(clear_db)).dispatch().await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+
+        // Not a batch where every event was rejected
+        let event = haby_core::api::CreateEvent {
+            habit_id: 1,
+            time: "2024-08-20T08:00:00".parse().unwrap(),
+            span_part: None,
+        };
+        let res = client
+            .post(v1!(create_events))
+            .json(&[haby_core::api::BatchEvent::from(event)])
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+
+        // Looking up the token fails in a request guard, which is answered by the catcher
+        let res = client
+            .get(v1!(get_habits))
+            .header(Header::new("Authorization", "Bearer haby_unknown"))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        assert!(res.headers().get_one("Retry-After").is_some());
+    }
+}
@@ -0,0 +1,157 @@
+//! Just enough of Web Push to send a notification: payload encryption (RFC 8291) and VAPID
+//! authentication (RFC 8292)
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hkdf::Hkdf;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use sha2::Sha256;
+use sqlx::types::chrono::Utc;
+
+/// The whole payload goes in a single record, so this only has to be bigger than it
+const RECORD_SIZE: u32 = 4096;
+
+/// How long push services should hold on to a notification for an offline browser
+const TTL_SECONDS: u32 = 24 * 60 * 60;
+
+/// The application server key browsers subscribe with
+pub struct VapidKey {
+    key: SigningKey,
+    /// Contact details push services can use, a `mailto:` or `https:` url
+    subject: String,
+}
+
+impl VapidKey {
+    /// `private_key` is the base64url encoded 32 byte P-256 secret
+    pub fn new(private_key: &str, subject: String) -> Result<Self, String> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(private_key.trim_end_matches('='))
+            .map_err(|err| format!("Invalid VAPID key: {err}"))?;
+        let key =
+            SigningKey::from_slice(&bytes).map_err(|err| format!("Invalid VAPID key: {err}"))?;
+        Ok(Self { key, subject })
+    }
+
+    /// The base64url encoded public key, what `PushManager.subscribe` wants as `applicationServerKey`
+    pub fn public_key(&self) -> String {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        URL_SAFE_NO_PAD.encode(point.as_bytes())
+    }
+
+    /// The `Authorization` header for a request to the push service at `endpoint`
+    fn authorization(&self, endpoint: &reqwest::Url, expires: i64) -> String {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = rocket::serde::json::json!({
+            "aud": endpoint.origin().ascii_serialization(),
+            "exp": expires,
+            "sub": self.subject,
+        });
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signature: Signature = self.key.sign(format!("{header}.{claims}").as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(signature.to_bytes());
+
+        format!(
+            "vapid t={header}.{claims}.{signature}, k={}",
+            self.public_key()
+        )
+    }
+}
+
+pub enum PushError {
+    /// The browser unsubscribed, the subscription should be forgotten
+    Gone,
+    Other(String),
+}
+
+/// Encrypt `payload` for the subscription and hand it to its push service
+pub async fn send(
+    client: &reqwest::Client,
+    vapid: &VapidKey,
+    subscription: &haby_core::api::PushSubscription,
+    payload: &[u8],
+) -> Result<(), PushError> {
+    let other = |err: String| PushError::Other(err);
+    let endpoint = reqwest::Url::parse(&subscription.endpoint)
+        .map_err(|err| other(format!("Invalid push endpoint: {err}")))?;
+    let p256dh = URL_SAFE_NO_PAD
+        .decode(subscription.keys.p256dh.trim_end_matches('='))
+        .map_err(|err| other(format!("Invalid p256dh key: {err}")))?;
+    let auth = URL_SAFE_NO_PAD
+        .decode(subscription.keys.auth.trim_end_matches('='))
+        .map_err(|err| other(format!("Invalid auth secret: {err}")))?;
+
+    let mut salt = [0; 16];
+    rand::Rng::fill(&mut rand::thread_rng(), &mut salt);
+    let secret = SecretKey::random(&mut rand::thread_rng());
+    let body = encrypt(&p256dh, &auth, payload, salt, &secret).map_err(other)?;
+
+    let expires = Utc::now().timestamp() + 12 * 60 * 60;
+    let response = client
+        .post(endpoint.clone())
+        .header("Authorization", vapid.authorization(&endpoint, expires))
+        .header("Content-Encoding", "aes128gcm")
+        .header("Content-Type", "application/octet-stream")
+        .header("TTL", TTL_SECONDS)
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| other(err.to_string()))?;
+
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE => Err(PushError::Gone),
+        status => Err(other(format!("Push service answered {status}"))),
+    }
+}
+
+/// The `aes128gcm` encrypted body, with the salt and server key in its header
+///
+/// `salt` and `secret` have to be new for every message.
+pub fn encrypt(
+    p256dh: &[u8],
+    auth: &[u8],
+    payload: &[u8],
+    salt: [u8; 16],
+    secret: &SecretKey,
+) -> Result<Vec<u8>, String> {
+    let user_agent_key =
+        PublicKey::from_sec1_bytes(p256dh).map_err(|err| format!("Invalid p256dh key: {err}"))?;
+    let server_key = secret.public_key().to_encoded_point(false);
+    let shared = p256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), user_agent_key.as_affine());
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(user_agent_key.to_encoded_point(false).as_bytes());
+    key_info.extend_from_slice(server_key.as_bytes());
+    let mut input_key = [0; 32];
+    Hkdf::<Sha256>::new(Some(auth), shared.raw_secret_bytes())
+        .expand(&key_info, &mut input_key)
+        .map_err(|err| err.to_string())?;
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), &input_key);
+    let mut content_key = [0; 16];
+    let mut nonce = [0; 12];
+    hkdf.expand(b"Content-Encoding: aes128gcm\0", &mut content_key)
+        .map_err(|err| err.to_string())?;
+    hkdf.expand(b"Content-Encoding: nonce\0", &mut nonce)
+        .map_err(|err| err.to_string())?;
+
+    // A single record, so it ends with the last record delimiter and needs no padding
+    let mut plaintext = payload.to_vec();
+    plaintext.push(2);
+    let ciphertext = Aes128Gcm::new_from_slice(&content_key)
+        .map_err(|err| err.to_string())?
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|err| err.to_string())?;
+
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(server_key.len() as u8);
+    body.extend_from_slice(server_key.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
@@ -0,0 +1,364 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use haby_core::api::{CreateWebhook, NewWebhook, Webhook, WebhookDelivery};
+use haby_core::WebhookEvent;
+use hmac::{Hmac, Mac};
+use rand::distributions::{Alphanumeric, DistString};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::{json, Json};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::Notify;
+use rocket::tokio::time::timeout;
+use rocket::{delete, get, post, State};
+use sha2::Sha256;
+use sqlx::types::chrono::Utc;
+use tracing::error;
+
+use crate::auth::{scope, Auth};
+use crate::db::{Db, DueDelivery, WebhookTarget};
+use crate::error::Error;
+use crate::limits::{limit, LimitedJson};
+
+/// The `webhooks` table of the Rocket config
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+struct Config {
+    /// How often a delivery is tried before giving up
+    attempts: u32,
+    /// The wait before the first retry, it doubles after every attempt
+    backoff_ms: u64,
+    /// How often the worker looks for due retries
+    poll_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff_ms: 1000,
+            poll_ms: 1000,
+        }
+    }
+}
+
+/// How many due deliveries the worker sends at once
+const BATCH: i64 = 50;
+
+/// How long a claimed delivery waits before another worker may send it, well above the timeout
+const LEASE_MINUTES: i32 = 1;
+
+/// Calls the webhooks subscribed to habit and event changes
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    attempts: u32,
+    backoff: Duration,
+    /// Wakes the worker up when a delivery is queued
+    queued: Arc<Notify>,
+}
+
+/// Sends queued deliveries in the background, see [`Webhooks::work`]
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Webhooks", |rocket| async move {
+        let Some(db) = rocket.state::<Db>() else {
+            error!("Webhooks need a database");
+            return Err(rocket);
+        };
+        let config: Config = match rocket.figment().focus("webhooks").extract() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Invalid webhooks config: {err}");
+                return Err(rocket);
+            }
+        };
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("The TLS backend is available");
+        let webhooks = Webhooks {
+            client,
+            attempts: config.attempts.max(1),
+            backoff: Duration::from_millis(config.backoff_ms),
+            queued: Arc::default(),
+        };
+        let poll = Duration::from_millis(config.poll_ms.max(1));
+        rocket::tokio::spawn(webhooks.clone().work(db.clone(), poll));
+        Ok(rocket.manage(webhooks))
+    })
+}
+
+impl Webhooks {
+    /// Queue a delivery to every webhook subscribed to `event`, they are sent in the background
+    ///
+    /// Failing to queue is only logged, the change itself already happened.
+    pub async fn notify(&self, db: &Db, event: WebhookEvent, data: impl Serialize) {
+        let targets = db.webhook_targets(event).await;
+        let targets = match targets {
+            Ok(targets) => targets,
+            Err(err) => {
+                error!("Failed to look up webhooks: {err}");
+                return;
+            }
+        };
+
+        let payload = payload(event, data);
+        for target in targets {
+            if let Err(err) = db.queue_delivery(target.id, event, &payload, true).await {
+                error!("Failed to queue webhook delivery: {err}");
+            }
+        }
+        self.queued.notify_one();
+    }
+
+    /// Send the due deliveries until the database is gone
+    ///
+    /// Deliveries live in the database, so the ones a stopped server did not finish are picked up
+    /// again on the next start.
+    async fn work(self, db: Db, poll: Duration) {
+        loop {
+            match db.due_deliveries(BATCH, LEASE_MINUTES).await {
+                Ok(due) => {
+                    let full = due.len() as i64 == BATCH;
+                    let sent = due.iter().map(|delivery| self.retry(&db, delivery));
+                    for result in rocket::futures::future::join_all(sent).await {
+                        if let Err(err) = result {
+                            error!("Failed to log webhook delivery: {err}");
+                        }
+                    }
+                    if full {
+                        continue;
+                    }
+                }
+                Err(sqlx::Error::PoolClosed) => break,
+                Err(err) => error!("Looking up due webhook deliveries failed: {err}"),
+            }
+            let _ = timeout(poll, self.queued.notified()).await;
+        }
+    }
+
+    /// Try a due delivery once more, and schedule the next attempt if it failed
+    ///
+    /// The wait doubles after every attempt. Client errors other than 429 are not retried,
+    /// sending the same payload again won't help.
+    async fn retry(&self, db: &Db, delivery: &DueDelivery) -> Result<(), sqlx::Error> {
+        let attempt = delivery.attempts + 1;
+        let outcome = self
+            .send(
+                &delivery.target,
+                delivery.id,
+                delivery.event,
+                &delivery.payload,
+            )
+            .await;
+        let retry_ms = (outcome.retry && attempt < self.attempts as i32).then(|| {
+            let backoff = self.backoff * 2u32.saturating_pow(attempt as u32 - 1);
+            i64::try_from(backoff.as_millis()).unwrap_or(i64::MAX)
+        });
+        db.record_attempt(
+            delivery.id,
+            attempt,
+            outcome.status,
+            outcome.error.as_deref(),
+            retry_ms,
+        )
+        .await
+    }
+
+    /// Send a delivery once
+    async fn send(
+        &self,
+        target: &WebhookTarget,
+        delivery: i64,
+        event: WebhookEvent,
+        payload: &str,
+    ) -> Outcome {
+        let result = self
+            .client
+            .post(&target.url)
+            .header("Content-Type", "application/json")
+            .header("X-Haby-Event", event_name(event))
+            .header("X-Haby-Delivery", delivery)
+            .header("X-Haby-Signature", sign(&target.secret, payload))
+            .body(payload.to_owned())
+            .send()
+            .await;
+
+        let (status, error, retry) = match result {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status()), None, false)
+            }
+            Ok(response) => {
+                let status = response.status();
+                let retry =
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                (Some(status), Some(format!("Answered {status}")), retry)
+            }
+            Err(err) => (err.status(), Some(err.to_string()), true),
+        };
+        Outcome {
+            status: status.map(|status| i32::from(status.as_u16())),
+            error,
+            retry,
+        }
+    }
+}
+
+/// How sending a delivery went
+struct Outcome {
+    status: Option<i32>,
+    /// `None` when it succeeded
+    error: Option<String>,
+    /// Whether trying again could help
+    retry: bool,
+}
+
+fn payload(event: WebhookEvent, data: impl Serialize) -> String {
+    json!({
+        "event": event,
+        "timestamp": Utc::now(),
+        "data": data,
+    })
+    .to_string()
+}
+
+fn event_name(event: WebhookEvent) -> String {
+    json!(event).as_str().unwrap_or_default().to_owned()
+}
+
+/// The `X-Haby-Signature` header, a hex encoded HMAC-SHA256 of the body keyed with the secret
+pub fn sign(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    responses((status = 200, description = "Every webhook", body = Vec<Webhook>)),
+)]
+#[get("/webhooks")]
+pub async fn get_webhooks(
+    _auth: Auth<scope::Admin>,
+    db: &State<Db>,
+) -> Result<Json<Vec<Webhook>>, Error> {
+    Ok(Json(db.webhooks().await?))
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    request_body = CreateWebhook,
+    responses(
+        (status = 200, description = "The webhook and its signing secret", body = NewWebhook),
+        (status = 400, description = "The webhook was rejected", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[post("/webhooks", data = "<webhook>")]
+pub async fn create_webhook(
+    _auth: Auth<scope::Admin>,
+    webhook: LimitedJson<CreateWebhook, limit::Json>,
+    db: &State<Db>,
+) -> Result<Json<NewWebhook>, Error> {
+    let webhook = webhook.into_inner();
+    if reqwest::Url::parse(&webhook.url).is_err() {
+        return Err(Error::new(
+            Status::BadRequest,
+            format!("{:?} is not a url", webhook.url),
+        ));
+    }
+    let secret = webhook
+        .secret
+        .unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 32));
+
+    let id = db
+        .create_webhook(&webhook.url, &webhook.events, &secret)
+        .await
+        .map_err(Error::rejected)?;
+
+    Ok(Json(NewWebhook {
+        webhook: Webhook {
+            id,
+            url: webhook.url,
+            events: webhook.events,
+        },
+        secret,
+    }))
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "The webhook was deleted"),
+        (status = 404, description = "Unknown webhook", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[delete("/webhooks/<id>")]
+pub async fn delete_webhook(
+    _auth: Auth<scope::Admin>,
+    id: i32,
+    db: &State<Db>,
+) -> Result<(), Error> {
+    if !db.delete_webhook(id).await? {
+        return Err(Error::new(
+            Status::NotFound,
+            format!("No webhook with id {id}"),
+        ));
+    }
+    Ok(())
+}
+
+/// The latest deliveries to a webhook, newest first
+#[utoipa::path(
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "The latest deliveries", body = Vec<WebhookDelivery>),
+    ),
+)]
+#[get("/webhooks/<id>/deliveries")]
+pub async fn get_deliveries(
+    _auth: Auth<scope::Admin>,
+    id: i32,
+    db: &State<Db>,
+) -> Result<Json<Vec<WebhookDelivery>>, Error> {
+    Ok(Json(db.deliveries(id).await?))
+}
+
+/// Send a `ping` right away and return how it went, without retrying
+#[utoipa::path(
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "How the ping went", body = WebhookDelivery),
+        (status = 404, description = "Unknown webhook", body = haby_core::api::ErrorBody),
+    ),
+)]
+#[post("/webhooks/<id>/test")]
+pub async fn test_webhook(
+    _auth: Auth<scope::Admin>,
+    id: i32,
+    webhooks: &State<Webhooks>,
+    db: &State<Db>,
+) -> Result<Json<WebhookDelivery>, Error> {
+    let target = db
+        .webhook_target(id)
+        .await?
+        .ok_or_else(|| Error::new(Status::NotFound, format!("No webhook with id {id}")))?;
+
+    let payload = payload(WebhookEvent::Ping, json!({ "webhook_id": id }));
+    let delivery = db
+        .queue_delivery(id, WebhookEvent::Ping, &payload, false)
+        .await?;
+    let outcome = webhooks
+        .send(&target, delivery, WebhookEvent::Ping, &payload)
+        .await;
+    db.record_attempt(delivery, 1, outcome.status, outcome.error.as_deref(), None)
+        .await?;
+
+    let delivery = db
+        .delivery(delivery)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+    Ok(Json(delivery))
+}